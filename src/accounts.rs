@@ -1,30 +1,123 @@
 use crate::{
     error::{AccountError, Error, TransactionError},
+    locks::LockReason,
     primitives::{Client, Funds, Tx},
+    snapshot::AccountSnapshot,
 };
-use serde::Serialize;
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize, ser::SerializeStruct};
 use std::collections::HashMap;
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Clone)]
 pub(crate) struct Account {
     client: Client,
-    #[serde(serialize_with = "round")]
     available: Funds,
-    #[serde(serialize_with = "round")]
     held: Funds,
-    #[serde(serialize_with = "round")]
     total: Funds,
     locked: bool,
-    #[serde(skip)]
+    /// Fees paid so far, net of the fees reversed by chargebacks.
+    fees: Funds,
+    /// The lock policy that locked the account, if known.
+    lock_reason: Option<LockReason>,
     disputed_transactions: HashMap<Tx, Funds>,
+    /// Funds held by each open authorization.
+    authorizations: HashMap<Tx, Funds>,
+    /// Number of deposits applied.
+    deposits: u64,
+    /// Number of disputes opened.
+    disputes: u64,
+    /// Total amount charged back.
+    charged_back: Funds,
 }
 
-/// Helper for serialize the [`Funds`] values to four decimal places, as requested.
-fn round<S>(funds: &Funds, serializer: S) -> Result<S::Ok, S::Error>
-where
-    S: serde::Serializer,
-{
-    serializer.serialize_str(&funds.round_dp(4).to_string())
+/// How [`Funds`] are rounded to a number of decimal places.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, Serialize, clap::ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum RoundingStrategy {
+    /// Halfway amounts go to the even neighbour, i.e. banker's rounding.
+    #[default]
+    HalfEven,
+    /// Halfway amounts go away from zero.
+    HalfUp,
+    /// Halfway amounts go towards zero.
+    HalfDown,
+    /// Towards zero, i.e. truncating.
+    Down,
+    /// Away from zero.
+    Up,
+}
+
+impl RoundingStrategy {
+    pub(crate) fn round(self, funds: Funds, places: u32) -> Funds {
+        let strategy = match self {
+            Self::HalfEven => rust_decimal::RoundingStrategy::MidpointNearestEven,
+            Self::HalfUp => rust_decimal::RoundingStrategy::MidpointAwayFromZero,
+            Self::HalfDown => rust_decimal::RoundingStrategy::MidpointTowardZero,
+            Self::Down => rust_decimal::RoundingStrategy::ToZero,
+            Self::Up => rust_decimal::RoundingStrategy::AwayFromZero,
+        };
+        funds.round_dp_with_strategy(places, strategy)
+    }
+}
+
+/// How the [`Funds`] of the accounts are rounded when written out. The accounts always keep the
+/// exact amounts.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct Rounding {
+    pub(crate) strategy: RoundingStrategy,
+    /// Number of decimal places of each field. Each field is rounded on its own, so the rounded
+    /// `available` and `held` may not add up to the rounded `total`.
+    pub(crate) available: u32,
+    pub(crate) held: u32,
+    pub(crate) total: u32,
+    pub(crate) fees: u32,
+}
+
+impl Default for Rounding {
+    /// Four decimal places, half to even, as requested.
+    fn default() -> Self {
+        Self::uniform(RoundingStrategy::HalfEven, 4)
+    }
+}
+
+impl Rounding {
+    /// The same number of decimal places for every field.
+    pub(crate) fn uniform(strategy: RoundingStrategy, places: u32) -> Self {
+        Self {
+            strategy,
+            available: places,
+            held: places,
+            total: places,
+            fees: places,
+        }
+    }
+
+    fn round(&self, funds: Funds, places: u32) -> String {
+        self.strategy.round(funds, places).to_string()
+    }
+}
+
+/// An [`Account`] as written out, with its funds rounded.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct AccountRow<'a> {
+    pub(crate) account: &'a Account,
+    rounding: Rounding,
+}
+
+impl Serialize for AccountRow<'_> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let (account, rounding) = (self.account, self.rounding);
+        let mut row = serializer.serialize_struct("Account", 7)?;
+        row.serialize_field("client", &account.client)?;
+        let available = rounding.round(account.available, rounding.available);
+        row.serialize_field("available", &available)?;
+        row.serialize_field("held", &rounding.round(account.held, rounding.held))?;
+        row.serialize_field("total", &rounding.round(account.total, rounding.total))?;
+        row.serialize_field("locked", &account.locked)?;
+        row.serialize_field("fees", &rounding.round(account.fees, rounding.fees))?;
+        row.serialize_field("lock_reason", &account.lock_reason)?;
+        row.end()
+    }
 }
 
 impl Account {
@@ -36,14 +129,124 @@ impl Account {
             held: Funds::ZERO,
             total: Funds::ZERO,
             locked: false,
+            fees: Funds::ZERO,
+            lock_reason: None,
             disputed_transactions: HashMap::new(),
+            authorizations: HashMap::new(),
+            deposits: 0,
+            disputes: 0,
+            charged_back: Funds::ZERO,
+        }
+    }
+
+    /// Captures the full state of the account, including the open disputes and authorizations.
+    pub(crate) fn snapshot(&self) -> AccountSnapshot {
+        let sorted = |held: &HashMap<Tx, Funds>| {
+            let mut held: Vec<(Tx, Funds)> = held.iter().map(|(tx, funds)| (*tx, *funds)).collect();
+            held.sort_by_key(|(tx, _)| *tx);
+            held
+        };
+
+        AccountSnapshot {
+            client: self.client,
+            available: self.available,
+            held: self.held,
+            total: self.total,
+            locked: self.locked,
+            fees: self.fees,
+            lock_reason: self.lock_reason,
+            disputed_transactions: sorted(&self.disputed_transactions),
+            authorizations: sorted(&self.authorizations),
+            deposits: self.deposits,
+            disputes: self.disputes,
+            charged_back: self.charged_back,
+        }
+    }
+
+    /// Rebuilds an account from a previously captured state.
+    pub(crate) fn from_snapshot(snapshot: AccountSnapshot) -> Self {
+        Self {
+            client: snapshot.client,
+            available: snapshot.available,
+            held: snapshot.held,
+            total: snapshot.total,
+            locked: snapshot.locked,
+            fees: snapshot.fees,
+            lock_reason: snapshot.lock_reason,
+            disputed_transactions: snapshot.disputed_transactions.into_iter().collect(),
+            authorizations: snapshot.authorizations.into_iter().collect(),
+            deposits: snapshot.deposits,
+            disputes: snapshot.disputes,
+            charged_back: snapshot.charged_back,
         }
     }
 
+    /// The client owning the account.
+    pub(crate) fn client(&self) -> Client {
+        self.client
+    }
+
+    /// Whether the account is locked.
+    pub(crate) fn is_locked(&self) -> bool {
+        self.locked
+    }
+
+    /// Funds that can be withdrawn.
+    pub(crate) fn available(&self) -> Funds {
+        self.available
+    }
+
+    /// Funds held by disputes and authorizations.
+    pub(crate) fn held(&self) -> Funds {
+        self.held
+    }
+
+    /// Available and held funds.
+    pub(crate) fn total(&self) -> Funds {
+        self.total
+    }
+
+    /// Number of disputes open at the moment.
+    pub(crate) fn open_disputes(&self) -> usize {
+        self.disputed_transactions.len()
+    }
+
+    /// Funds held by the disputes open at the moment.
+    pub(crate) fn disputed(&self) -> Funds {
+        self.disputed_transactions.values().sum()
+    }
+
+    /// Number of disputes opened for each deposit applied, if any deposit was.
+    pub(crate) fn dispute_ratio(&self) -> Option<Decimal> {
+        (self.deposits > 0).then(|| Decimal::from(self.disputes) / Decimal::from(self.deposits))
+    }
+
+    /// Total amount charged back.
+    pub(crate) fn charged_back(&self) -> Funds {
+        self.charged_back
+    }
+
+    /// Keeps track of a deposit, for the lock policies.
+    pub(crate) fn count_deposit(&mut self) {
+        self.deposits += 1;
+    }
+
+    /// Locks the account, recording the policy that fired.
+    pub(crate) fn lock(&mut self, reason: LockReason) {
+        self.locked = true;
+        self.lock_reason = Some(reason);
+    }
+
+    /// Unlocks the account. Only meant as an explicit operator action.
+    pub(crate) fn unlock(&mut self) {
+        self.locked = false;
+        self.lock_reason = None;
+    }
+
     /// Checks if the account is locked, and errors if so.
     fn locked(&self) -> Result<(), Error> {
         if self.locked {
-            return Err(AccountError::AccountLocked(self.client).into());
+            Err(AccountError::AccountLocked(self.client).into())
         } else {
             Ok(())
         }
@@ -56,17 +259,57 @@ impl Account {
             .ok_or(TransactionError::MissingDispute(tx).into())
     }
 
-    /// Update the `total` field from an account.
-    fn update_total(&mut self) -> Result<(), AccountError> {
-        if let Some(value) = self.available.checked_add(self.held) {
-            self.total = value;
-            Ok(())
-        } else {
-            Err(AccountError::Overflow(self.client))
+    fn get_authorized(&self, tx: Tx) -> Result<Funds, Error> {
+        self.authorizations
+            .get(&tx)
+            .cloned()
+            .ok_or(TransactionError::MissingAuthorization(tx).into())
+    }
+
+    /// Sets the `available` and `held` funds, and the `total` from them. Nothing is changed if the
+    /// total overflows, so every operation computes its new funds first and commits them last.
+    fn commit(&mut self, available: Funds, held: Funds) -> Result<(), AccountError> {
+        let total = available
+            .checked_add(held)
+            .ok_or(AccountError::Overflow(self.client))?;
+        self.available = available;
+        self.held = held;
+        self.total = total;
+        Ok(())
+    }
+
+    /// The funds after moving the given amount from `available` to `held`. Unless `overdraw` is
+    /// set, the available funds must cover it.
+    fn held_funds(&self, funds: Funds, overdraw: bool) -> Result<(Funds, Funds), Error> {
+        if !overdraw && self.available < funds {
+            return Err(AccountError::InsufficientFunds(self.client, funds, self.available).into());
         }
+
+        let available = self
+            .available
+            .checked_sub(funds)
+            .ok_or(AccountError::Underflow(self.client))?;
+        let held = self
+            .held
+            .checked_add(funds)
+            .ok_or(AccountError::Overflow(self.client))?;
+        Ok((available, held))
     }
 
-    /// Removes funds from an account.
+    /// The funds after moving the given amount from `held` back to `available`.
+    fn released_funds(&self, funds: Funds) -> Result<(Funds, Funds), Error> {
+        let available = self
+            .available
+            .checked_add(funds)
+            .ok_or(AccountError::Overflow(self.client))?;
+        let held = self
+            .held
+            .checked_sub(funds)
+            .ok_or(AccountError::Underflow(self.client))?;
+        Ok((available, held))
+    }
+
+    /// Adds funds to an account.
     ///
     /// It checks:
     /// - if the account is locked,
@@ -74,13 +317,12 @@ impl Account {
     pub(crate) fn credit(&mut self, funds: Funds) -> Result<(), Error> {
         self.locked()?;
 
-        if let Some(value) = self.available.checked_add(funds) {
-            self.available = value;
-            self.update_total()?;
-            Ok(())
-        } else {
-            Err(AccountError::Overflow(self.client).into())
-        }
+        let available = self
+            .available
+            .checked_add(funds)
+            .ok_or(AccountError::Overflow(self.client))?;
+        self.commit(available, self.held)?;
+        Ok(())
     }
 
     /// Removes funds from an account.
@@ -93,17 +335,16 @@ impl Account {
         self.locked()?;
 
         if self.available < funds {
-            return Err(AccountError::InsufficientFunds(self.client).into());
+            return Err(AccountError::InsufficientFunds(self.client, funds, self.available).into());
         }
 
         // NOTE: this should never error, since the check is done above.
-        if let Some(value) = self.available.checked_sub(funds) {
-            self.available = value;
-            self.update_total()?;
-            Ok(())
-        } else {
-            Err(AccountError::Underflow(self.client).into())
-        }
+        let available = self
+            .available
+            .checked_sub(funds)
+            .ok_or(AccountError::Underflow(self.client))?;
+        self.commit(available, self.held)?;
+        Ok(())
     }
 
     /// Opens a dispute for a [`Transaction`].
@@ -112,33 +353,28 @@ impl Account {
     /// - Reduce `available` by the disputed value.
     /// - Increase `held` by the same amount.
     pub(crate) fn dispute(&mut self, funds: Funds, tx: Tx) -> Result<(), Error> {
+        self.open_dispute(funds, tx, false)
+    }
+
+    /// Opens a dispute for a [`Transaction`] like [`Account::dispute`], even if it leaves the
+    /// available funds below zero.
+    pub(crate) fn dispute_overdrawn(&mut self, funds: Funds, tx: Tx) -> Result<(), Error> {
+        self.open_dispute(funds, tx, true)
+    }
+
+    fn open_dispute(&mut self, funds: Funds, tx: Tx, overdraw: bool) -> Result<(), Error> {
         self.locked()?;
 
         if self.disputed_transactions.contains_key(&tx) {
             return Err(TransactionError::ExistingDispute(tx).into());
         }
 
-        if self.available < funds {
-            return Err(AccountError::InsufficientFunds(self.client).into());
-        }
-
-        // NOTE: this operation should never error, since the check is done above.
-        if let Some(value) = self.available.checked_sub(funds) {
-            self.available = value;
-        } else {
-            return Err(AccountError::Underflow(self.client).into());
-        }
-
-        if let Some(value) = self.held.checked_add(funds) {
-            self.held = value;
-        } else {
-            return Err(AccountError::Overflow(self.client).into());
-        }
+        let (available, held) = self.held_funds(funds, overdraw)?;
+        self.commit(available, held)?;
 
         // Keep track of the disputed ammount for each "open" dispute.
         self.disputed_transactions.insert(tx, funds);
-
-        self.update_total()?;
+        self.disputes += 1;
         Ok(())
     }
 
@@ -149,68 +385,219 @@ impl Account {
     /// - Reduce `held` by the same amount.
     pub(crate) fn resolve(&mut self, tx: Tx) -> Result<(), Error> {
         self.locked()?;
+        self.resolve_received(tx)
+    }
 
+    /// Resolves the dispute of a transfer this account received, like [`Account::resolve`]. Done
+    /// even if the account is locked, since the dispute is not up to its client.
+    pub(crate) fn resolve_received(&mut self, tx: Tx) -> Result<(), Error> {
         let amount = self.get_disputed(tx)?;
+        let (available, held) = self.released_funds(amount)?;
+        self.commit(available, held)?;
 
-        if let Some(value) = self.held.checked_sub(amount) {
-            self.held = value;
-        } else {
-            return Err(AccountError::Underflow(self.client).into());
+        // Untrack the dispute if everything succeeded
+        self.disputed_transactions.remove(&tx);
+
+        Ok(())
+    }
+
+    /// Authorizes a two-phase withdrawal for a [`Transaction`]: the funds are held, like for
+    /// [`Account::dispute`], until they are captured or voided.
+    pub(crate) fn authorize(&mut self, funds: Funds, tx: Tx) -> Result<(), Error> {
+        self.locked()?;
+
+        if self.authorizations.contains_key(&tx) {
+            return Err(TransactionError::DuplicateFound(tx).into());
+        }
+
+        let (available, held) = self.held_funds(funds, false)?;
+        self.commit(available, held)?;
+        self.authorizations.insert(tx, funds);
+        Ok(())
+    }
+
+    /// Captures the funds held by an authorization: the given amount, or all that is left of it.
+    /// Returns the amount captured, and what is left held for later captures.
+    ///
+    /// The operations that are performed are:
+    /// - Reduce `held` by the captured amount.
+    /// - Reduce `total` by the same amount.
+    pub(crate) fn capture(
+        &mut self,
+        tx: Tx,
+        amount: Option<Funds>,
+    ) -> Result<(Funds, Funds), Error> {
+        self.locked()?;
+
+        let authorized = self.get_authorized(tx)?;
+        let captured = amount.unwrap_or(authorized);
+        if captured > authorized {
+            return Err(TransactionError::CaptureExceedsAuthorization(tx).into());
         }
 
-        if let Some(value) = self.available.checked_add(amount) {
-            self.available = value;
+        let held = self
+            .held
+            .checked_sub(captured)
+            .ok_or(AccountError::Underflow(self.client))?;
+        let left = authorized
+            .checked_sub(captured)
+            .ok_or(AccountError::Overflow(self.client))?;
+        self.commit(self.available, held)?;
+
+        if left.is_zero() {
+            self.authorizations.remove(&tx);
         } else {
-            return Err(AccountError::Overflow(self.client).into());
+            self.authorizations.insert(tx, left);
         }
+        Ok((captured, left))
+    }
 
-        self.update_total()?;
+    /// Releases what is left of an authorization back to `available`. Done even if the account is
+    /// locked, since it only gives the client its funds back.
+    pub(crate) fn void(&mut self, tx: Tx) -> Result<(), Error> {
+        let left = self.get_authorized(tx)?;
+        let (available, held) = self.released_funds(left)?;
+        self.commit(available, held)?;
+        self.authorizations.remove(&tx);
+        Ok(())
+    }
 
-        // Untrack the dispute if everything succeeded
-        self.disputed_transactions.remove(&tx);
+    /// Keeps track of a fee paid by the client. The fee itself is already deducted from the
+    /// credited or debited amount.
+    pub(crate) fn pay_fee(&mut self, fee: Funds) -> Result<(), Error> {
+        self.fees = self
+            .fees
+            .checked_add(fee)
+            .ok_or(AccountError::Overflow(self.client))?;
+        Ok(())
+    }
 
+    /// Takes back (part of) a fee paid by the client, when the transaction it was charged on is
+    /// charged back. Done even if the account is locked, since it is part of the chargeback.
+    pub(crate) fn reverse_fee(&mut self, fee: Funds) -> Result<(), Error> {
+        self.fees = self
+            .fees
+            .checked_sub(fee)
+            .ok_or(AccountError::Underflow(self.client))?;
         Ok(())
     }
 
-    /// Performs a chargeback for a transaction.
-    pub(crate) fn chargeback(&mut self, tx: Tx) -> Result<(), Error> {
-        let amount = self.get_disputed(tx)?;
+    /// Posts a fee collected from a client to this (house) account. Negative fees are reversals.
+    ///
+    /// The house account collects fees even if it is locked, and may go below zero when reversing
+    /// them.
+    pub(crate) fn collect_fee(&mut self, fee: Funds) -> Result<(), Error> {
+        self.adjust(fee)
+    }
 
-        if let Some(value) = self.held.checked_sub(amount) {
-            self.held = value;
-        } else {
-            return Err(AccountError::Underflow(self.client).into());
-        }
+    /// Checks that [`Account::collect_fee`] would post the given fee, without changing anything.
+    pub(crate) fn can_collect_fee(&self, fee: Funds) -> Result<(), Error> {
+        self.available
+            .checked_add(fee)
+            .and_then(|available| available.checked_add(self.held))
+            .ok_or(AccountError::Overflow(self.client))?;
+        Ok(())
+    }
 
-        self.locked = true;
+    /// Gives the funds of a transfer charged back to its sender. Done even if the account is
+    /// locked, since a chargeback is not up to the client.
+    pub(crate) fn reimburse(&mut self, funds: Funds) -> Result<(), Error> {
+        self.adjust(funds)
+    }
+
+    /// Applies a scheduled adjustment to the available funds: interest when positive, a
+    /// maintenance fee when negative. Done even if the account is locked, since it is not up to the
+    /// client.
+    pub(crate) fn adjust(&mut self, funds: Funds) -> Result<(), Error> {
+        let available = self
+            .available
+            .checked_add(funds)
+            .ok_or(AccountError::Overflow(self.client))?;
+        self.commit(available, self.held)?;
+        Ok(())
+    }
+
+    /// Performs a chargeback for a transaction, returning the amount charged back. Whether the
+    /// account gets locked is up to the lock policies.
+    ///
+    /// The held funds are withdrawn, so `total` decreases by the same amount. Done even if the
+    /// account is locked, since a chargeback is not up to the client.
+    pub(crate) fn chargeback(&mut self, tx: Tx) -> Result<Funds, Error> {
+        let amount = self.get_disputed(tx)?;
+
+        let held = self
+            .held
+            .checked_sub(amount)
+            .ok_or(AccountError::Underflow(self.client))?;
+        let charged_back = self
+            .charged_back
+            .checked_add(amount)
+            .ok_or(AccountError::Overflow(self.client))?;
+        self.commit(self.available, held)?;
+        self.charged_back = charged_back;
 
         // Untrack the dispute if everything succeeded
         self.disputed_transactions.remove(&tx);
 
-        Ok(())
+        Ok(amount)
     }
 }
 
 /// A collection of accounts, using a [`HashMap`] underneath.
-#[derive(Debug, Serialize)]
-pub(crate) struct Accounts(HashMap<Client, Account>);
+#[derive(Debug)]
+pub(crate) struct Accounts(HashMap<Client, Account>, Rounding);
 
 impl Accounts {
     pub(crate) fn new() -> Self {
-        Self(HashMap::new())
+        Self(HashMap::new(), Rounding::default())
+    }
+
+    /// Sets how the accounts are rounded when written out.
+    pub(crate) fn set_rounding(&mut self, rounding: Rounding) {
+        self.1 = rounding;
+    }
+
+    /// Iterates over all the accounts, in no particular order.
+    pub(crate) fn iter(&self) -> impl Iterator<Item = &Account> {
+        self.0.values()
+    }
+
+    /// Iterates over all the accounts as written out, in no particular order.
+    pub(crate) fn rows(&self) -> impl Iterator<Item = AccountRow<'_>> {
+        self.iter().map(|account| self.row(account))
+    }
+
+    /// An account as written out, with the rounding of this collection.
+    pub(crate) fn row<'a>(&self, account: &'a Account) -> AccountRow<'a> {
+        AccountRow {
+            account,
+            rounding: self.1,
+        }
     }
 
-    pub(crate) fn inner(self) -> HashMap<Client, Account> {
-        self.0
+    /// Inserts an account, replacing any previous account for the same client.
+    pub(crate) fn insert(&mut self, account: Account) {
+        self.0.insert(account.client, account);
     }
 
     /// Checks if an account exists, otherwise creates it.
     fn exists(&mut self, client: Client) {
-        if let Some(_account) = self.0.get(&client) {
-            return;
-        } else {
-            self.0.insert(client, Account::new(client));
-        }
+        self.0.entry(client).or_insert_with(|| Account::new(client));
+    }
+
+    /// Get mutable references to the accounts of two different clients, creating them if needed.
+    ///
+    /// Panics if the clients are the same.
+    pub(crate) fn get_pair_mut(&mut self, a: Client, b: Client) -> [&mut Account; 2] {
+        self.exists(a);
+        self.exists(b);
+        // SAFETY: safe to unwrap, since both accounts exist by previous step.
+        self.0.get_disjoint_mut([&a, &b]).map(Option::unwrap)
+    }
+
+    /// Get a reference to an account, if it exists.
+    pub(crate) fn get(&self, client: Client) -> Option<&Account> {
+        self.0.get(&client)
     }
 
     /// Get a mutable reference to an account. If the account does not exist, it creates one.
@@ -306,7 +693,7 @@ mod tests {
     }
 
     #[test]
-    fn test_chargeback_removes_funds_and_locks_account() {
+    fn test_chargeback_removes_funds_and_keeps_track_of_them() {
         let client = 1;
         let tx_id = 1;
         let mut acc = Account::new(client);
@@ -315,7 +702,8 @@ mod tests {
         acc.chargeback(tx_id).unwrap();
         assert_eq!(acc.available, funds(5.0));
         assert_eq!(acc.held, Funds::ZERO);
-        assert!(acc.locked);
+        assert_eq!(acc.total, funds(5.0));
+        assert_eq!(acc.charged_back(), funds(5.0));
     }
 
     #[test]
@@ -326,8 +714,188 @@ mod tests {
         acc.credit(funds(10.0)).unwrap();
         acc.dispute(funds(5.0), tx_id).unwrap();
         acc.chargeback(tx_id).unwrap();
+        acc.lock(LockReason::Chargebacks);
 
         assert!(acc.credit(funds(5.0)).is_err());
         assert!(acc.debit(funds(5.0)).is_err());
+
+        acc.unlock();
+        assert!(acc.credit(funds(5.0)).is_ok());
+    }
+
+    #[test]
+    fn test_overdrawn_dispute_leaves_available_below_zero() {
+        let mut acc = Account::new(1);
+        acc.credit(funds(10.0)).unwrap();
+        acc.debit(funds(8.0)).unwrap();
+
+        assert!(acc.dispute(funds(10.0), 1).is_err());
+        acc.dispute_overdrawn(funds(10.0), 1).unwrap();
+        assert_eq!(acc.available, funds(-8.0));
+        assert_eq!(acc.held, funds(10.0));
+    }
+
+    #[test]
+    fn test_rounding_strategies() {
+        let rounded = |strategy: RoundingStrategy| strategy.round(Decimal::new(-25, 1), 0);
+        assert_eq!(rounded(RoundingStrategy::HalfEven), Decimal::from(-2));
+        assert_eq!(rounded(RoundingStrategy::HalfUp), Decimal::from(-3));
+        assert_eq!(rounded(RoundingStrategy::HalfDown), Decimal::from(-2));
+        assert_eq!(rounded(RoundingStrategy::Down), Decimal::from(-2));
+        assert_eq!(rounded(RoundingStrategy::Up), Decimal::from(-3));
+    }
+
+    #[test]
+    fn test_rows_round_the_funds_but_not_the_accounts() {
+        let mut accounts = Accounts::new();
+        accounts
+            .get_mut(1)
+            .credit(Decimal::new(123_456_789, 5))
+            .unwrap();
+
+        let row =
+            |accounts: &Accounts| serde_json::to_value(accounts.row(accounts.get(1).unwrap()));
+        assert_eq!(row(&accounts).unwrap()["available"], "1234.5679");
+        accounts.set_rounding(Rounding {
+            held: 0,
+            ..Rounding::uniform(RoundingStrategy::Down, 2)
+        });
+        assert_eq!(row(&accounts).unwrap()["total"], "1234.56");
+        assert_eq!(row(&accounts).unwrap()["held"], "0");
+        assert_eq!(
+            accounts.get(1).unwrap().available(),
+            Decimal::new(123_456_789, 5)
+        );
+    }
+
+    /// An operation on an [`Account`], to throw at it in property tests.
+    #[derive(Debug, Clone)]
+    enum Operation {
+        Credit(Funds),
+        Debit(Funds),
+        Dispute(Funds, Tx),
+        DisputeOverdrawn(Funds, Tx),
+        Resolve(Tx),
+        Chargeback(Tx),
+        Authorize(Funds, Tx),
+        Capture(Tx, Option<Funds>),
+        Void(Tx),
+        CollectFee(Funds),
+        Adjust(Funds),
+    }
+
+    impl Operation {
+        fn apply(self, account: &mut Account) -> Result<(), Error> {
+            match self {
+                Operation::Credit(funds) => account.credit(funds),
+                Operation::Debit(funds) => account.debit(funds),
+                Operation::Dispute(funds, tx) => account.dispute(funds, tx),
+                Operation::DisputeOverdrawn(funds, tx) => account.dispute_overdrawn(funds, tx),
+                Operation::Resolve(tx) => account.resolve(tx),
+                Operation::Chargeback(tx) => account.chargeback(tx).map(|_| ()),
+                Operation::Authorize(funds, tx) => account.authorize(funds, tx),
+                Operation::Capture(tx, funds) => account.capture(tx, funds).map(|_| ()),
+                Operation::Void(tx) => account.void(tx),
+                Operation::CollectFee(funds) => account.collect_fee(funds),
+                Operation::Adjust(funds) => account.adjust(funds),
+            }
+        }
+    }
+
+    mod properties {
+        use super::*;
+        use proptest::prelude::*;
+
+        /// Funds of any size, including the bounds of [`Decimal`] so that overflows happen.
+        fn any_funds() -> impl Strategy<Value = Funds> {
+            prop_oneof![
+                (-1_000_000i64..1_000_000, 0u32..5).prop_map(|(n, scale)| Decimal::new(n, scale)),
+                any::<i64>().prop_map(Decimal::from),
+                Just(Decimal::MAX),
+                Just(Decimal::MIN),
+            ]
+        }
+
+        /// Transaction ids from a small range, so that operations hit the open disputes and
+        /// authorizations.
+        fn any_tx() -> impl Strategy<Value = Tx> {
+            0u32..4
+        }
+
+        fn any_account() -> impl Strategy<Value = Account> {
+            (
+                (any_funds(), any_funds(), any_funds(), any_funds()),
+                any::<bool>(),
+                proptest::collection::vec((any_tx(), any_funds()), 0..3),
+                proptest::collection::vec((any_tx(), any_funds()), 0..3),
+            )
+                .prop_map(
+                    |((available, held, fees, charged_back), locked, disputed, authorized)| {
+                        Account::from_snapshot(AccountSnapshot {
+                            client: 1,
+                            available,
+                            held,
+                            total: available.checked_add(held).unwrap_or(available),
+                            locked,
+                            fees,
+                            lock_reason: None,
+                            disputed_transactions: disputed,
+                            authorizations: authorized,
+                            deposits: 0,
+                            disputes: 0,
+                            charged_back,
+                        })
+                    },
+                )
+        }
+
+        fn any_operation() -> impl Strategy<Value = Operation> {
+            prop_oneof![
+                any_funds().prop_map(Operation::Credit),
+                any_funds().prop_map(Operation::Debit),
+                (any_funds(), any_tx()).prop_map(|(funds, tx)| Operation::Dispute(funds, tx)),
+                (any_funds(), any_tx())
+                    .prop_map(|(funds, tx)| Operation::DisputeOverdrawn(funds, tx)),
+                any_tx().prop_map(Operation::Resolve),
+                any_tx().prop_map(Operation::Chargeback),
+                (any_funds(), any_tx()).prop_map(|(funds, tx)| Operation::Authorize(funds, tx)),
+                (any_tx(), proptest::option::of(any_funds()))
+                    .prop_map(|(tx, funds)| Operation::Capture(tx, funds)),
+                any_tx().prop_map(Operation::Void),
+                any_funds().prop_map(Operation::CollectFee),
+                any_funds().prop_map(Operation::Adjust),
+            ]
+        }
+
+        /// The full state of an account, as written in snapshots.
+        fn serialized(account: &Account) -> String {
+            serde_json::to_string(&account.snapshot()).unwrap()
+        }
+
+        proptest! {
+            #[test]
+            fn test_failed_operation_leaves_the_account_unchanged(
+                mut account in any_account(),
+                operation in any_operation(),
+            ) {
+                let before = serialized(&account);
+                if operation.apply(&mut account).is_err() {
+                    prop_assert_eq!(serialized(&account), before);
+                }
+            }
+
+            #[test]
+            fn test_failed_operations_in_a_row_leave_the_account_unchanged(
+                mut account in any_account(),
+                operations in proptest::collection::vec(any_operation(), 1..20),
+            ) {
+                for operation in operations {
+                    let before = serialized(&account);
+                    if operation.apply(&mut account).is_err() {
+                        prop_assert_eq!(serialized(&account), before);
+                    }
+                }
+            }
+        }
     }
 }
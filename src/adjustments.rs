@@ -0,0 +1,322 @@
+//! This module defines the adjustment schedule: the interest paid and the maintenance fees charged
+//! to the accounts at regular intervals, depending on the tier of the client.
+//!
+//! The schedule is read from a JSON file, e.g.:
+//!
+//! ```json
+//! {
+//!   "house": 0,
+//!   "default_tier": "basic",
+//!   "clients": { "7": "savings" },
+//!   "tiers": {
+//!     "basic": { "every": 2592000, "fee": "2.5" },
+//!     "savings": { "every": 86400, "interest": { "percent": "0.01", "on": "held" } }
+//!   }
+//! }
+//! ```
+//!
+//! Adjustments are driven by the engine clock, i.e. by the transaction timestamps (or `tick` rows,
+//! which only move the clock). The intervals are aligned on the Unix epoch, so `86400` adjusts the
+//! accounts at every midnight (UTC) the clock goes past. Interest is paid by the `house` account,
+//! and fees are collected by it. Clients not listed in `clients` belong to `default_tier`, or have
+//! no adjustments if there is none.
+//!
+//! When the clock jumps past many intervals at once (e.g. a mistyped timestamp), only the latest
+//! `max_periods` adjustments of each account are applied, [`MAX_PERIODS`] by default, and the
+//! older ones are skipped.
+
+use crate::{
+    accounts::{Account, RoundingStrategy},
+    error::{Error, ScheduleError},
+    primitives::{Client, Funds, Timestamp},
+};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, fs, num::NonZeroU64, path::Path};
+
+/// Maximum number of adjustments due at once for an account, unless the schedule sets another.
+pub(crate) const MAX_PERIODS: u64 = 1000;
+
+/// The balance of an account interest is paid on.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Balance {
+    Available,
+    Held,
+    #[default]
+    Total,
+}
+
+impl Balance {
+    /// This balance of an account.
+    pub(crate) fn of(self, account: &Account) -> Funds {
+        match self {
+            Self::Available => account.available(),
+            Self::Held => account.held(),
+            Self::Total => account.total(),
+        }
+    }
+}
+
+/// How the interest paid on an account is computed.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct InterestRule {
+    /// Percentage of the balance paid at each adjustment, e.g. `0.01` pays 0.01%.
+    pub(crate) percent: Funds,
+    /// The balance the interest is paid on.
+    #[serde(default)]
+    pub(crate) on: Balance,
+}
+
+impl InterestRule {
+    /// The interest paid on an account, rounded to four decimal places with the given strategy.
+    /// Nothing is paid on negative balances. `None` on overflow.
+    pub(crate) fn interest(&self, account: &Account, strategy: RoundingStrategy) -> Option<Funds> {
+        let interest = self
+            .on
+            .of(account)
+            .max(Funds::ZERO)
+            .checked_mul(self.percent)?
+            .checked_div(Funds::ONE_HUNDRED)?;
+        Some(strategy.round(interest, 4))
+    }
+}
+
+/// Adjustments of a tier.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct TierAdjustments {
+    /// Number of seconds between two adjustments.
+    pub(crate) every: u64,
+    /// Interest paid at each adjustment.
+    #[serde(default)]
+    pub(crate) interest: Option<InterestRule>,
+    /// Maintenance fee charged at each adjustment. Accounts are never taken below zero by it, so
+    /// at most the available funds are charged.
+    #[serde(default)]
+    pub(crate) fee: Option<Funds>,
+}
+
+impl TierAdjustments {
+    fn is_valid(&self) -> bool {
+        self.every > 0
+            && self
+                .interest
+                .as_ref()
+                .is_none_or(|rule| rule.percent >= Funds::ZERO)
+            && self.fee.is_none_or(|fee| fee >= Funds::ZERO)
+    }
+
+    /// The times of the latest `max` adjustments after `since`, up to `now` included, and the
+    /// number of the older ones, which are skipped.
+    pub(crate) fn due(
+        &self,
+        since: Timestamp,
+        now: Timestamp,
+        max: u64,
+    ) -> (impl Iterator<Item = Timestamp>, u64) {
+        let every = self.every;
+        let (first, last) = (since / every + 1, now / every);
+        let kept = first.max(last.saturating_sub(max.saturating_sub(1)));
+        let skipped = kept.min(last.saturating_add(1)).saturating_sub(first);
+        ((kept..=last).map(move |period| period * every), skipped)
+    }
+}
+
+/// The adjustments of every client, and the account paying and collecting them.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct AdjustmentSchedule {
+    /// The account paying the interest and collecting the fees.
+    pub(crate) house: Client,
+    /// The tier of the clients not listed in `clients`.
+    #[serde(default)]
+    pub(crate) default_tier: Option<String>,
+    /// The tier of each client.
+    #[serde(default)]
+    pub(crate) clients: HashMap<Client, String>,
+    /// The adjustments of each tier, by name.
+    pub(crate) tiers: HashMap<String, TierAdjustments>,
+    /// Maximum number of adjustments due at once for an account. Defaults to [`MAX_PERIODS`].
+    #[serde(default)]
+    pub(crate) max_periods: Option<NonZeroU64>,
+}
+
+impl AdjustmentSchedule {
+    /// Reads and validates an adjustment schedule from a JSON file.
+    pub(crate) fn load(path: &Path) -> Result<Self, Error> {
+        let content = fs::read_to_string(path)?;
+        let schedule: Self = serde_json::from_str(&content).map_err(ScheduleError::Malformed)?;
+        schedule.validate()?;
+        Ok(schedule)
+    }
+
+    /// Checks that every tier referenced exists, and that every tier makes sense.
+    pub(crate) fn validate(&self) -> Result<(), ScheduleError> {
+        for tier in self.default_tier.iter().chain(self.clients.values()) {
+            if !self.tiers.contains_key(tier) {
+                return Err(ScheduleError::UnknownTier(tier.clone()));
+            }
+        }
+
+        for (name, tier) in &self.tiers {
+            if !tier.is_valid() {
+                return Err(ScheduleError::InvalidRule(name.clone()));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Maximum number of adjustments due at once for an account.
+    pub(crate) fn max_periods(&self) -> u64 {
+        self.max_periods.map_or(MAX_PERIODS, NonZeroU64::get)
+    }
+
+    /// The adjustments of a client, if any. The house account has none.
+    pub(crate) fn tier(&self, client: Client) -> Option<&TierAdjustments> {
+        if client == self.house {
+            return None;
+        }
+
+        self.clients
+            .get(&client)
+            .or(self.default_tier.as_ref())
+            .and_then(|name| self.tiers.get(name))
+    }
+}
+
+/// The kind of a scheduled adjustment.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum AdjustmentKind {
+    Interest,
+    Fee,
+}
+
+impl AdjustmentKind {
+    /// The name of the kind, as serialized.
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            Self::Interest => "interest",
+            Self::Fee => "fee",
+        }
+    }
+}
+
+/// Record of a scheduled adjustment applied to an account, kept in the ledger.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+pub(crate) struct Adjustment {
+    /// The client whose account was adjusted.
+    pub(crate) client: Client,
+    pub(crate) kind: AdjustmentKind,
+    /// The interest paid, or the fee charged.
+    pub(crate) amount: Funds,
+    /// When the adjustment was due.
+    pub(crate) timestamp: Timestamp,
+    /// The engine sequence when the adjustment was applied.
+    pub(crate) seq: u64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::snapshot::AccountSnapshot;
+    use rust_decimal::Decimal;
+    use tempfile::tempdir;
+
+    fn funds(amount: f32) -> Decimal {
+        Decimal::from_f32_retain(amount).unwrap()
+    }
+
+    #[test]
+    fn test_interest_is_rounded_with_the_strategy() {
+        let account = Account::from_snapshot(AccountSnapshot {
+            client: 1,
+            available: Decimal::new(125, 2),
+            held: funds(2.0),
+            total: Decimal::new(325, 2),
+            locked: false,
+            fees: Funds::ZERO,
+            lock_reason: None,
+            disputed_transactions: Vec::new(),
+            authorizations: Vec::new(),
+            deposits: 0,
+            disputes: 0,
+            charged_back: Funds::ZERO,
+        });
+        let rule = |percent, on| InterestRule { percent, on };
+
+        let half_even = RoundingStrategy::HalfEven;
+
+        // 0.000125 and 0.000325 are both halfway between two ten-thousandths.
+        assert_eq!(
+            rule(Decimal::new(1, 2), Balance::Available).interest(&account, half_even),
+            Some(Decimal::new(1, 4))
+        );
+        assert_eq!(
+            rule(Decimal::new(1, 2), Balance::Total).interest(&account, half_even),
+            Some(Decimal::new(3, 4))
+        );
+        assert_eq!(
+            rule(funds(1.0), Balance::Held).interest(&account, half_even),
+            Some(Decimal::new(2, 2))
+        );
+        assert_eq!(
+            rule(Decimal::new(1, 2), Balance::Available).interest(&account, RoundingStrategy::Up),
+            Some(Decimal::new(2, 4))
+        );
+    }
+
+    #[test]
+    fn test_adjustments_are_due_at_each_interval() {
+        let tier = TierAdjustments {
+            every: 100,
+            ..Default::default()
+        };
+
+        let (due, skipped) = tier.due(150, 420, 10);
+        assert_eq!((due.collect::<Vec<_>>(), skipped), (vec![200, 300, 400], 0));
+        let (due, skipped) = tier.due(200, 299, 10);
+        assert_eq!((due.count(), skipped), (0, 0));
+    }
+
+    #[test]
+    fn test_only_the_latest_adjustments_are_due_after_a_jump() {
+        let tier = TierAdjustments {
+            every: 100,
+            ..Default::default()
+        };
+
+        let (due, skipped) = tier.due(0, 1_000_000_000, 2);
+        assert_eq!(due.collect::<Vec<_>>(), vec![999_999_900, 1_000_000_000]);
+        assert_eq!(skipped, 9_999_998);
+    }
+
+    #[test]
+    fn test_load_rejects_invalid_schedules() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("adjustments.json");
+
+        fs::write(&path, r#"{"house":0,"clients":{"1":"gold"},"tiers":{}}"#).unwrap();
+        assert!(matches!(
+            AdjustmentSchedule::load(&path),
+            Err(Error::Schedule(ScheduleError::UnknownTier(tier))) if tier == "gold"
+        ));
+
+        fs::write(&path, r#"{"house":0,"tiers":{"a":{"every":0,"fee":"1"}}}"#).unwrap();
+        assert!(matches!(
+            AdjustmentSchedule::load(&path),
+            Err(Error::Schedule(ScheduleError::InvalidRule(tier))) if tier == "a"
+        ));
+
+        fs::write(
+            &path,
+            r#"{"house":0,"default_tier":"a","tiers":{"a":{"every":60}}}"#,
+        )
+        .unwrap();
+        let schedule = AdjustmentSchedule::load(&path).unwrap();
+        assert!(schedule.tier(1).is_some());
+        assert!(schedule.tier(0).is_none());
+    }
+}
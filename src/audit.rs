@@ -0,0 +1,324 @@
+//! This module defines the audit trail of the accounts: an append-only log with an entry for every
+//! operation applied to, or rejected on, an account, with the balances of the account before and
+//! after it.
+//!
+//! The operations are the transactions, the disputes and authorizations expired by the engine, the
+//! scheduled adjustments, and the unlocks by an operator. An operation touching several accounts
+//! (e.g. a transfer, or a fee going to the house account) has an entry for each of them. The log
+//! is a file of JSON lines, so it survives between runs and can be read back to print the history
+//! of a client.
+//!
+//! With a write-ahead log, the audit log goes to disk before each sync of the write-ahead log, and
+//! the recovery audits the rows it replays that the interrupted run did not get to audit (see
+//! [`AuditLog::start_replay`]). A partially written last line, left by a crash, is cut from the
+//! file when it is opened again.
+
+use crate::{
+    accounts::{Account, Accounts},
+    error::Error,
+    primitives::{Client, Funds, Timestamp, Tx},
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs,
+    io::{self, BufRead, BufWriter, Read, Seek, SeekFrom, Write},
+    path::Path,
+};
+
+/// Number of bytes read from the end of the log to find its last entry.
+const TAIL_LEN: u64 = 64 * 1024;
+
+/// The balances of an account at some point.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, Serialize)]
+pub(crate) struct Balances {
+    pub(crate) available: Funds,
+    pub(crate) held: Funds,
+    pub(crate) total: Funds,
+    pub(crate) locked: bool,
+}
+
+impl Balances {
+    /// The balances of an account, or the ones of a new account if there is none yet.
+    pub(crate) fn of(account: Option<&Account>) -> Self {
+        account.map_or_else(Self::default, |account| Self {
+            available: account.available(),
+            held: account.held(),
+            total: account.total(),
+            locked: account.is_locked(),
+        })
+    }
+}
+
+/// An operation applied to, or rejected on, an account.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub(crate) struct AuditEntry {
+    pub(crate) client: Client,
+    /// The engine sequence after the operation.
+    pub(crate) seq: u64,
+    /// The engine clock after the operation, if set.
+    pub(crate) timestamp: Option<Timestamp>,
+    /// The transaction type (e.g. `deposit`), `expired_dispute`, `expired_authorization`,
+    /// `interest` and `fee` for the operations of the engine itself, or `unlock`.
+    pub(crate) operation: String,
+    /// The transaction the operation applies or refers to, if any.
+    pub(crate) tx: Option<Tx>,
+    /// `accepted`, or the code of the error the operation was rejected with.
+    pub(crate) result: String,
+    /// The message of the error, if rejected.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) error: Option<String>,
+    pub(crate) before: Balances,
+    pub(crate) after: Balances,
+}
+
+/// The balances of some accounts before an operation, to audit it once applied.
+#[derive(Debug)]
+pub(crate) struct Before {
+    /// The account the operation is on comes first, the others are only audited if they changed.
+    balances: Vec<(Client, Balances)>,
+}
+
+impl Before {
+    /// Takes the balances of the accounts of the given clients, the one the operation is on first.
+    pub(crate) fn take(accounts: &Accounts, clients: impl IntoIterator<Item = Client>) -> Self {
+        let mut balances: Vec<(Client, Balances)> = Vec::new();
+        for client in clients {
+            if balances.iter().all(|(known, _)| *known != client) {
+                balances.push((client, Balances::of(accounts.get(client))));
+            }
+        }
+        Self { balances }
+    }
+
+    /// The entries of an operation, now that it was applied or rejected, at the given engine
+    /// sequence and clock.
+    pub(crate) fn entries<'a>(
+        self,
+        accounts: &'a Accounts,
+        operation: &'a str,
+        tx: Option<Tx>,
+        error: Option<&'a Error>,
+        (seq, timestamp): (u64, Option<Timestamp>),
+    ) -> impl Iterator<Item = AuditEntry> + 'a {
+        self.balances
+            .into_iter()
+            .enumerate()
+            .filter_map(move |(i, (client, before))| {
+                let after = Balances::of(accounts.get(client));
+                (i == 0 || after != before).then(|| AuditEntry {
+                    client,
+                    seq,
+                    timestamp,
+                    operation: operation.to_string(),
+                    tx,
+                    result: error.map_or("accepted", Error::code).to_string(),
+                    error: error.map(ToString::to_string),
+                    before,
+                    after,
+                })
+            })
+    }
+}
+
+/// The append-only audit log, written as JSON lines.
+#[derive(Debug)]
+pub(crate) struct AuditLog {
+    writer: BufWriter<fs::File>,
+    /// The first write error, reported by [`AuditLog::flush`].
+    error: Option<io::Error>,
+    /// The sequence of the last entry previous runs left in the log, if any.
+    last_seq: Option<u64>,
+    /// Entries up to this sequence are already in the log, and are skipped.
+    skip_through: Option<u64>,
+}
+
+impl AuditLog {
+    /// Opens the log at the given path, appending to what previous runs left in it. A partially
+    /// written last line is cut from the file.
+    pub(crate) fn open(path: &Path) -> Result<Self, Error> {
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(path)?;
+        let len = file.metadata()?.len();
+        let (complete_len, last_seq) = tail(&mut file, len)?;
+        if complete_len < len {
+            tracing::warn!(
+                "Discarding {} bytes of a partially written audit log entry",
+                len - complete_len
+            );
+            file.set_len(complete_len)?;
+        }
+        Ok(Self {
+            writer: BufWriter::new(file),
+            error: None,
+            last_seq,
+            skip_through: None,
+        })
+    }
+
+    /// Skips, until [`AuditLog::end_replay`], the entries that previous runs left in the log, i.e.
+    /// the ones up to the sequence of its last entry. This is for replaying the write-ahead log of
+    /// an interrupted run, which audited its rows up to some point.
+    pub(crate) fn start_replay(&mut self) {
+        self.skip_through = self.last_seq;
+    }
+
+    /// Appends every entry again, once the write-ahead log is replayed.
+    pub(crate) fn end_replay(&mut self) {
+        self.skip_through = None;
+    }
+
+    /// Appends an entry. Write errors do not stop the engine, they are reported when flushing.
+    pub(crate) fn append(&mut self, entry: &AuditEntry) {
+        if self.error.is_some() || self.skip_through.is_some_and(|seq| entry.seq <= seq) {
+            return;
+        }
+
+        let result = serde_json::to_writer(&mut self.writer, entry)
+            .map_err(io::Error::from)
+            .and_then(|_| self.writer.write_all(b"\n"));
+        if let Err(e) = result {
+            tracing::error!("Cannot write to the audit log: {}", e);
+            self.error = Some(e);
+        }
+    }
+
+    /// Writes the buffered entries to disk, and syncs them, failing if any entry could not be
+    /// written.
+    pub(crate) fn flush(&mut self) -> Result<(), Error> {
+        if let Some(e) = self.error.take() {
+            return Err(e.into());
+        }
+        self.writer.flush()?;
+        self.writer.get_ref().sync_data()?;
+        Ok(())
+    }
+}
+
+/// Reads the end of the log of the given length: the length of its complete lines, and the
+/// sequence of its last entry.
+fn tail(file: &mut fs::File, len: u64) -> io::Result<(u64, Option<u64>)> {
+    let start = len.saturating_sub(TAIL_LEN);
+    let mut tail = Vec::new();
+    file.seek(SeekFrom::Start(start))?;
+    file.read_to_end(&mut tail)?;
+
+    // Only lines terminated by a newline were completely written.
+    let Some(end) = tail.iter().rposition(|b| *b == b'\n') else {
+        // A single line longer than what is read is not a partial entry.
+        return Ok((if start == 0 { 0 } else { len }, None));
+    };
+    // The first line read may be cut at the start, it is then skipped as unreadable.
+    let last_seq = tail[..end]
+        .split(|b| *b == b'\n')
+        .rev()
+        .find_map(|line| serde_json::from_slice::<AuditEntry>(line).ok())
+        .map(|entry| entry.seq);
+    Ok((start + end as u64 + 1, last_seq))
+}
+
+/// The entries of the audit log at the given path about a client, oldest first. A partially
+/// written last line is skipped.
+pub(crate) fn history(path: &Path, client: Client) -> Result<Vec<AuditEntry>, Error> {
+    let mut reader = io::BufReader::new(fs::File::open(path)?);
+    let mut entries = Vec::new();
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        if reader.read_until(b'\n', &mut line)? == 0 {
+            break;
+        }
+        if line.last() != Some(&b'\n') {
+            tracing::warn!("Skipping a partially written audit log entry");
+            break;
+        }
+        if line.trim_ascii().is_empty() {
+            continue;
+        }
+        let entry: AuditEntry = serde_json::from_slice(&line).map_err(io::Error::from)?;
+        if entry.client == client {
+            entries.push(entry);
+        }
+    }
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::AccountError;
+    use rust_decimal::Decimal;
+    use tempfile::tempdir;
+
+    fn funds(amount: f32) -> Decimal {
+        Decimal::from_f32_retain(amount).unwrap()
+    }
+
+    #[test]
+    fn test_only_the_first_account_is_audited_when_unchanged() {
+        let mut accounts = Accounts::new();
+        accounts.get_mut(1).credit(funds(10.0)).unwrap();
+
+        let before = Before::take(&accounts, [1, 2, 1]);
+        let error = AccountError::InsufficientFunds(1, funds(11.0), funds(10.0)).into();
+        let entries: Vec<AuditEntry> = before
+            .entries(&accounts, "withdrawal", Some(3), Some(&error), (0, None))
+            .collect();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].result, "account.insufficient_funds");
+        assert_eq!(entries[0].before, entries[0].after);
+
+        let before = Before::take(&accounts, [1, 2]);
+        let [from, to] = accounts.get_pair_mut(1, 2);
+        from.debit(funds(4.0)).unwrap();
+        to.credit(funds(4.0)).unwrap();
+        let entries: Vec<AuditEntry> = before
+            .entries(&accounts, "transfer", Some(4), None, (1, None))
+            .collect();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[1].client, 2);
+        assert_eq!(entries[1].before, Balances::default());
+        assert_eq!(entries[1].after.total, funds(4.0));
+    }
+
+    #[test]
+    fn test_history_reads_back_the_entries_of_a_client() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("audit.log");
+        let entry = |client, seq| AuditEntry {
+            client,
+            seq,
+            timestamp: None,
+            operation: "deposit".to_string(),
+            tx: Some(seq as Tx),
+            result: "accepted".to_string(),
+            error: None,
+            before: Balances::default(),
+            after: Balances::default(),
+        };
+
+        // The log is appended to, run after run.
+        for seq in 1..=3 {
+            let mut log = AuditLog::open(&path).unwrap();
+            log.append(&entry(seq as Client % 2, seq));
+            log.flush().unwrap();
+        }
+
+        let history = history(&path, 1).unwrap();
+        assert_eq!(history, vec![entry(1, 1), entry(1, 3)]);
+
+        // A crash in the middle of an append leaves a partial line, which is skipped.
+        let mut file = fs::OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(br#"{"client":1,"seq":4,"#).unwrap();
+        assert_eq!(crate::audit::history(&path, 1).unwrap().len(), 2);
+
+        // And cut when the log is opened again.
+        let mut log = AuditLog::open(&path).unwrap();
+        log.append(&entry(1, 5));
+        log.flush().unwrap();
+        let history = crate::audit::history(&path, 1).unwrap();
+        assert_eq!(history, vec![entry(1, 1), entry(1, 3), entry(1, 5)]);
+    }
+}
@@ -0,0 +1,114 @@
+//! This module defines how the rows of the input are grouped into batches: consecutive rows with
+//! the same `batch_id` are the legs of one batch, which the [`Engine`] applies atomically (see
+//! [`Engine::apply_batch`]).
+//!
+//! The legs of a batch must be consecutive: a batch interrupted by another row is split in two,
+//! each applied on its own.
+//!
+//! [`Engine`]: crate::engine::Engine
+//! [`Engine::apply_batch`]: crate::engine::Engine::apply_batch
+
+use crate::primitives::BatchId;
+use std::iter::Peekable;
+
+/// Groups the items of an iterator into batches. Items outside of any batch come alone, and an
+/// error fails the batch it interrupts as a whole: the legs after it are dropped too.
+pub(crate) struct Batches<I: Iterator> {
+    items: Peekable<I>,
+    batch_id: fn(&I::Item) -> Option<BatchId>,
+}
+
+impl<I: Iterator> Batches<I> {
+    /// Groups the items by the batch given by `batch_id`.
+    pub(crate) fn new(
+        items: impl IntoIterator<IntoIter = I>,
+        batch_id: fn(&I::Item) -> Option<BatchId>,
+    ) -> Self {
+        Self {
+            items: items.into_iter().peekable(),
+            batch_id,
+        }
+    }
+}
+
+impl<I, T, E> Iterator for Batches<I>
+where
+    I: Iterator<Item = Result<T, E>>,
+{
+    type Item = Result<Vec<T>, E>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let first = self.items.next()?;
+        let batch = (self.batch_id)(&first);
+        let mut legs = vec![match first {
+            Ok(leg) => leg,
+            Err(e) => return Some(Err(e)),
+        }];
+        let Some(batch) = batch else {
+            return Some(Ok(legs));
+        };
+
+        let batch_id = self.batch_id;
+        let mut error = None;
+        while let Some(item) = self
+            .items
+            .next_if(|item| item.is_err() || batch_id(item) == Some(batch))
+        {
+            match item {
+                Ok(leg) => legs.push(leg),
+                // The rest of the batch is consumed, so none of its legs is applied on its own.
+                Err(e) => {
+                    error.get_or_insert(e);
+                }
+            }
+        }
+        match error {
+            Some(e) => Some(Err(e)),
+            None => Some(Ok(legs)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn batch_id(item: &Result<(u32, Option<BatchId>), &str>) -> Option<BatchId> {
+        item.as_ref().ok().and_then(|(_, batch)| *batch)
+    }
+
+    #[test]
+    fn test_consecutive_rows_of_a_batch_are_grouped() {
+        let rows = vec![
+            Ok((1, None)),
+            Ok((2, Some(7))),
+            Ok((3, Some(7))),
+            Ok((4, None)),
+            Ok((5, Some(7))),
+            Ok((6, Some(8))),
+        ];
+        let batches: Vec<Vec<u32>> = Batches::new(rows, batch_id)
+            .map(|batch| batch.unwrap().into_iter().map(|(row, _)| row).collect())
+            .collect();
+
+        assert_eq!(
+            batches,
+            vec![vec![1], vec![2, 3], vec![4], vec![5], vec![6]]
+        );
+    }
+
+    #[test]
+    fn test_error_drops_the_batch_it_interrupts() {
+        let rows = vec![
+            Ok((1, Some(7))),
+            Err("bad row"),
+            Ok((2, Some(7))),
+            Ok((3, None)),
+        ];
+        let mut batches = Batches::new(rows, batch_id);
+
+        assert_eq!(batches.next(), Some(Err("bad row")));
+        assert_eq!(batches.next(), Some(Ok(vec![(3, None)])));
+        assert_eq!(batches.next(), None);
+    }
+}
@@ -68,7 +68,9 @@
 //!
 //! Using the `futures` crate and tokio for the runtime.
 
-use crate::{accounts::Accounts, engine::Engine, error::Error, transactions::Transaction};
+use crate::{
+    accounts::Accounts, batches::Batches, engine::Engine, error::Error, transactions::Transaction,
+};
 
 /// Behavior expected from an entity providing [`Transaction`]s in a synchronous manner.
 pub(crate) trait TransactionSource {
@@ -124,13 +126,24 @@ impl TransactionProcessor for Engine {
     where
         I: IntoIterator<Item = Result<Transaction, csv::Error>>,
     {
-        for record in transactions {
-            let transaction: Transaction = record?;
-            let account = accounts.get_mut(transaction.client);
-            match self.process(account, transaction) {
+        let batch_id = |record: &Result<Transaction, csv::Error>| {
+            record
+                .as_ref()
+                .ok()
+                .and_then(|transaction| transaction.batch_id)
+        };
+        for rows in Batches::new(transactions, batch_id) {
+            let rows: Vec<Transaction> = match rows {
+                Ok(rows) => rows,
+                Err(e) => {
+                    self.malformed(e)?;
+                    continue;
+                }
+            };
+            match self.apply_group(accounts, rows) {
                 Ok(_) => {}
                 Err(e) => {
-                    tracing::error!("{}", e);
+                    tracing::error!(code = e.code(), category = ?e.category(), "{}", e);
                 }
             }
         }
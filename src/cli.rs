@@ -0,0 +1,622 @@
+//! This module defines the command line interface of the application.
+//!
+//! The CLI has grown past a single positional argument, so it uses the `clap` crate (as the
+//! README suggested) instead of reading `std::env::args` by hand.
+
+use crate::{
+    accounts::RoundingStrategy,
+    adjustments::Balance,
+    config::ConfigFile,
+    engine::EngineConfig,
+    error::Error,
+    io::OutputFormat,
+    ledger::{Retention, TxState},
+    primitives::{Client, Funds, Timestamp, Tx},
+    replay::Point,
+    telemetry::LogLevel,
+    transactions::ExcessPrecision,
+};
+use clap::{Args, Parser, Subcommand};
+use rust_decimal::Decimal;
+use std::path::PathBuf;
+
+/// Ingests a CSV file of transactions and outputs the resulting state of the accounts.
+#[derive(Debug, Parser)]
+#[command(
+    version,
+    about,
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
+)]
+pub(crate) struct Cli {
+    #[command(subcommand)]
+    pub(crate) command: Option<Command>,
+    /// Path of the CSV file containing the transactions.
+    #[arg(required = true)]
+    pub(crate) file: Option<String>,
+    /// Load the engine state from this snapshot before processing the transactions.
+    #[arg(long, value_name = "PATH")]
+    pub(crate) snapshot: Option<PathBuf>,
+    /// Dump the engine state to this snapshot after processing the transactions.
+    #[arg(long, value_name = "PATH")]
+    pub(crate) save_snapshot: Option<PathBuf>,
+    /// Log every accepted transaction to this write-ahead log, and recover from it on startup.
+    ///
+    /// The log is emptied once the state is saved, hence it requires `--save-snapshot`.
+    #[arg(long, value_name = "PATH", requires = "save_snapshot")]
+    pub(crate) wal: Option<PathBuf>,
+    /// Number of write-ahead log entries written between two syncs to disk.
+    #[arg(long, value_name = "N", default_value_t = 1000)]
+    pub(crate) wal_sync_every: usize,
+    #[command(flatten)]
+    pub(crate) engine: EngineArgs,
+    /// Write the transactions held for review by the risk rules to this CSV file.
+    #[arg(long, value_name = "PATH")]
+    pub(crate) held_output: Option<PathBuf>,
+    /// Operator action: unlock the account of this client before processing. Can be repeated.
+    #[arg(long, value_name = "CLIENT")]
+    pub(crate) unlock: Vec<Client>,
+    /// Log the events up to this level. Nothing is logged by default.
+    #[arg(long, value_enum, default_value_t = LogLevel::Off)]
+    pub(crate) log_level: LogLevel,
+    /// Write the logs to this file instead of stderr.
+    #[arg(long, value_name = "PATH")]
+    pub(crate) log_file: Option<PathBuf>,
+    /// Write the metrics of the run to this file, in the Prometheus text format.
+    #[arg(long, value_name = "PATH")]
+    pub(crate) metrics: Option<PathBuf>,
+    /// Print a summary of the metrics of the run to stderr.
+    #[arg(long)]
+    pub(crate) metrics_summary: bool,
+    /// Write the reconciliation report of the run to this file, as JSON.
+    #[arg(long, value_name = "PATH")]
+    pub(crate) report: Option<PathBuf>,
+    /// Append every operation applied to, or rejected on, an account to this audit log.
+    #[arg(long, value_name = "PATH")]
+    pub(crate) audit_log: Option<PathBuf>,
+    /// Check the transactions without keeping anything, and print the rows that would be rejected
+    /// and the balances the run would end with, as JSON, instead of the accounts. No file is
+    /// written, so it cannot be combined with the options writing one.
+    #[arg(
+        long,
+        conflicts_with_all = [
+            "save_snapshot",
+            "audit_log",
+            "ledger_spill",
+            "held_output",
+            "metrics",
+            "report",
+            "log_file",
+        ]
+    )]
+    pub(crate) dry_run: bool,
+}
+
+/// The options setting the behavior of the engine, shared by the commands running one. They
+/// override the ones of the configuration file.
+#[derive(Debug, Clone, Args)]
+pub(crate) struct EngineArgs {
+    /// Read the options of the engine from this TOML file.
+    #[arg(long, value_name = "PATH")]
+    pub(crate) config: Option<PathBuf>,
+    /// Which transactions are kept in the ledger to be disputed later. All of them by default.
+    #[arg(long, value_enum)]
+    pub(crate) ledger_retention: Option<Retention>,
+    /// Number of transactions that can be applied after a transaction while its ledger record is
+    /// kept, i.e. while it can still be disputed. Unlimited by default.
+    #[arg(long, value_name = "N")]
+    pub(crate) ledger_window: Option<u64>,
+    /// Move the oldest ledger records to this file, instead of keeping them all in memory.
+    #[arg(long, value_name = "PATH")]
+    pub(crate) ledger_spill: Option<PathBuf>,
+    /// Number of ledger records kept in memory when spilling to a file. 1000000 by default.
+    #[arg(long, value_name = "N")]
+    pub(crate) ledger_memory_records: Option<usize>,
+    /// Maximum number of seconds between a transaction and a dispute on it. Only enforced on
+    /// transactions with a timestamp.
+    #[arg(long, value_name = "SECS")]
+    pub(crate) dispute_window: Option<u64>,
+    /// Maximum number of seconds a dispute stays open. Later resolutions and chargebacks are
+    /// rejected, and stale disputes are resolved automatically.
+    #[arg(long, value_name = "SECS")]
+    pub(crate) resolve_window: Option<u64>,
+    /// Void authorizations still holding funds this many seconds after they were applied. Only
+    /// enforced on authorizations with a timestamp.
+    #[arg(long, value_name = "SECS")]
+    pub(crate) auth_expiry: Option<u64>,
+    /// Hold transactions for this many seconds, to apply them in timestamp order.
+    #[arg(long, value_name = "SECS")]
+    pub(crate) reorder_watermark: Option<u64>,
+    /// Let up to this many disputes, resolutions, chargebacks, captures and voids wait for the
+    /// transaction they reference to arrive.
+    #[arg(long, value_name = "N")]
+    pub(crate) pending_max_rows: Option<usize>,
+    /// Maximum number of seconds a transaction waits for the one it references. Requires a limit
+    /// on the number of rows waiting.
+    #[arg(long, value_name = "SECS")]
+    pub(crate) pending_max_wait: Option<u64>,
+    /// Charge the fees of the schedule in this JSON file, posting them to its house account.
+    #[arg(long, value_name = "PATH")]
+    pub(crate) fees: Option<PathBuf>,
+    /// Pay the interest and charge the maintenance fees of the schedule in this JSON file, at the
+    /// intervals it sets.
+    #[arg(long, value_name = "PATH")]
+    pub(crate) adjustments: Option<PathBuf>,
+    /// Enforce the per-client limits of the schedule in this JSON file.
+    #[arg(long, value_name = "PATH")]
+    pub(crate) limits: Option<PathBuf>,
+    /// Run the built-in risk rules enabled in this JSON file before applying each transaction.
+    #[arg(long, value_name = "PATH")]
+    pub(crate) risk_rules: Option<PathBuf>,
+    /// Lock accounts with this many disputes open at once.
+    #[arg(long, value_name = "N")]
+    pub(crate) lock_open_disputes: Option<usize>,
+    /// Lock accounts whose number of disputes divided by their number of deposits goes above this.
+    #[arg(long, value_name = "RATIO")]
+    pub(crate) lock_dispute_ratio: Option<Decimal>,
+    /// Lock accounts once the total they had charged back goes above this amount. Zero by default.
+    #[arg(long, value_name = "AMOUNT")]
+    pub(crate) lock_chargebacks: Option<Decimal>,
+    /// Let disputes leave the available funds below zero, and lock those accounts, instead of
+    /// rejecting the disputes.
+    #[arg(long)]
+    pub(crate) lock_negative_balance: bool,
+    /// Log and skip the rows of the input that cannot be read, instead of stopping the run.
+    #[arg(long)]
+    pub(crate) skip_malformed: bool,
+    /// How the accounts are written out. CSV by default.
+    #[arg(long, value_enum)]
+    pub(crate) output_format: Option<OutputFormat>,
+    /// Number of decimal places of the funds written out. 4 by default.
+    #[arg(long, value_name = "N")]
+    pub(crate) precision: Option<u32>,
+    /// How the funds written out, the interest and the fees are rounded. Half to even by default.
+    #[arg(long, value_enum)]
+    pub(crate) rounding: Option<RoundingStrategy>,
+    /// Maximum number of decimal places of the amounts of the input. Unlimited by default.
+    #[arg(long, value_name = "N")]
+    pub(crate) input_precision: Option<u32>,
+    /// What happens to the amounts with more decimal places than the input precision. They are
+    /// rejected by default.
+    #[arg(long, value_enum)]
+    pub(crate) excess_precision: Option<ExcessPrecision>,
+}
+
+/// Commands other than processing a file, mostly looking at the outcome of previous runs.
+#[derive(Debug, Subcommand)]
+pub(crate) enum Command {
+    /// Print the history of a client from an audit log, oldest first, as JSON lines.
+    History {
+        /// The client whose history is printed.
+        client: Client,
+        /// The audit log written with `--audit-log`.
+        #[arg(long, value_name = "PATH")]
+        audit_log: PathBuf,
+    },
+    /// Print the balances of a client at a past point, as JSON, by replaying the transactions from
+    /// a checkpoint.
+    BalancesAt(Box<BalancesAtArgs>),
+    /// Replay two runs side by side (two files, or the same file with other options) and print the
+    /// accounts that differ between them, with where they diverged, as JSON lines.
+    Replay(Box<ReplayArgs>),
+    /// Look up transactions and accounts in a snapshot, printing them as JSON lines.
+    Query {
+        /// The snapshot written with `--save-snapshot`.
+        #[arg(long, value_name = "PATH")]
+        snapshot: PathBuf,
+        /// The configuration file of the run that wrote the snapshot.
+        #[arg(long, value_name = "PATH")]
+        config: Option<PathBuf>,
+        #[command(subcommand)]
+        query: QueryCommand,
+    },
+    /// Work with configuration files.
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommand,
+    },
+}
+
+/// The actions of the `config` command.
+#[derive(Debug, Subcommand)]
+pub(crate) enum ConfigCommand {
+    /// Validate a configuration file, with the schedules it refers to, and print the resulting
+    /// configuration with every default filled in.
+    Check {
+        /// The TOML file to check.
+        path: PathBuf,
+    },
+}
+
+/// A run replayed: its input, its checkpoint and its engine options.
+#[derive(Debug, Parser)]
+#[command(no_binary_name = true)]
+pub(crate) struct RunArgs {
+    /// Path of the CSV file containing the transactions after the checkpoint.
+    pub(crate) file: String,
+    /// Start from the state in this snapshot, instead of an empty one.
+    #[arg(long, value_name = "PATH")]
+    pub(crate) snapshot: Option<PathBuf>,
+    /// The options of the run replayed.
+    #[command(flatten)]
+    pub(crate) engine: EngineArgs,
+}
+
+/// The arguments of the `balances-at` command.
+#[derive(Debug, Args)]
+pub(crate) struct BalancesAtArgs {
+    /// The client whose balances are printed.
+    pub(crate) client: Client,
+    #[command(flatten)]
+    pub(crate) run: RunArgs,
+    #[command(flatten)]
+    pub(crate) point: PointArgs,
+}
+
+/// The arguments of the `replay` command.
+#[derive(Debug, Args)]
+pub(crate) struct ReplayArgs {
+    #[command(flatten)]
+    pub(crate) left: RunArgs,
+    /// The file and options of the other run, e.g. `-- tx.csv --fees fees.json`.
+    #[arg(last = true, required = true, value_name = "RIGHT")]
+    pub(crate) right: Vec<String>,
+}
+
+impl ReplayArgs {
+    /// The other run, parsed from the arguments after `--`.
+    pub(crate) fn right(&self) -> Result<RunArgs, clap::Error> {
+        RunArgs::try_parse_from(&self.right)
+    }
+}
+
+/// The point of the `balances-at` command.
+#[derive(Debug, Args)]
+#[group(required = true, multiple = false)]
+pub(crate) struct PointArgs {
+    /// Right after the transaction with this id.
+    #[arg(long, value_name = "TX")]
+    pub(crate) tx: Option<Tx>,
+    /// Right before a transaction moves the clock past this timestamp.
+    #[arg(long, value_name = "SECS")]
+    pub(crate) timestamp: Option<Timestamp>,
+}
+
+impl From<&PointArgs> for Point {
+    fn from(args: &PointArgs) -> Self {
+        match (args.tx, args.timestamp) {
+            (Some(tx), _) => Point::Tx(tx),
+            // Safe to unwrap since one of them is required.
+            (None, timestamp) => Point::Timestamp(timestamp.unwrap()),
+        }
+    }
+}
+
+/// The lookups of the `query` command.
+#[derive(Debug, Subcommand)]
+pub(crate) enum QueryCommand {
+    /// A recorded transaction, with its dispute state.
+    Transaction { tx: Tx },
+    /// The recorded transactions sending from, or receiving to, the account of a client.
+    Client { client: Client },
+    /// The recorded transactions in a dispute state.
+    State {
+        #[arg(value_enum)]
+        state: TxState,
+    },
+    /// The transactions under an open dispute.
+    OpenDisputes,
+    /// The locked accounts.
+    Locked,
+    /// The accounts whose balance is above and/or below the given amounts (strictly).
+    Balance {
+        /// The balance compared.
+        #[arg(long, value_enum, default_value_t = Balance::Total)]
+        on: Balance,
+        #[arg(long, value_name = "AMOUNT")]
+        above: Option<Funds>,
+        #[arg(long, value_name = "AMOUNT")]
+        below: Option<Funds>,
+    },
+}
+
+impl EngineArgs {
+    /// The configuration file, if given, with the options of the command line applied over it.
+    pub(crate) fn config_file(&self) -> Result<ConfigFile, Error> {
+        let mut config = match &self.config {
+            Some(path) => ConfigFile::load(path)?,
+            None => ConfigFile::default(),
+        };
+
+        let ledger = &mut config.ledger;
+        if let Some(retention) = self.ledger_retention {
+            ledger.retention = retention;
+        }
+        ledger.window = self.ledger_window.or(ledger.window);
+        ledger.spill = self.ledger_spill.clone().or(ledger.spill.take());
+        if let Some(records) = self.ledger_memory_records {
+            ledger.memory_records = records;
+        }
+
+        let disputes = &mut config.disputes;
+        disputes.window = self.dispute_window.or(disputes.window);
+        disputes.resolve_window = self.resolve_window.or(disputes.resolve_window);
+        config.authorizations.expiry = self.auth_expiry.or(config.authorizations.expiry);
+
+        let sequencing = &mut config.sequencing;
+        sequencing.reorder_watermark = self.reorder_watermark.or(sequencing.reorder_watermark);
+        sequencing.pending_max_rows = self.pending_max_rows.or(sequencing.pending_max_rows);
+        sequencing.pending_max_wait = self.pending_max_wait.or(sequencing.pending_max_wait);
+
+        let schedules = &mut config.schedules;
+        for (option, path) in [
+            (&self.fees, &mut schedules.fees),
+            (&self.adjustments, &mut schedules.adjustments),
+            (&self.limits, &mut schedules.limits),
+            (&self.risk_rules, &mut schedules.risk_rules),
+        ] {
+            if option.is_some() {
+                path.clone_from(option);
+            }
+        }
+
+        let locks = &mut config.locks;
+        locks.open_disputes = self.lock_open_disputes.or(locks.open_disputes);
+        locks.dispute_ratio = self.lock_dispute_ratio.or(locks.dispute_ratio);
+        if let Some(chargebacks) = self.lock_chargebacks {
+            locks.chargebacks = chargebacks;
+        }
+        locks.negative_balance |= self.lock_negative_balance;
+
+        let input = &mut config.input;
+        input.skip_malformed |= self.skip_malformed;
+        input.precision = self.input_precision.or(input.precision);
+        if let Some(excess) = self.excess_precision {
+            input.excess_precision = excess;
+        }
+
+        let output = &mut config.output;
+        if let Some(format) = self.output_format {
+            output.format = format;
+        }
+        if let Some(precision) = self.precision {
+            output.precision = precision;
+        }
+        if let Some(rounding) = self.rounding {
+            output.rounding = rounding;
+        }
+
+        Ok(config)
+    }
+
+    /// The configuration of the engine, as given in the configuration file and the command line.
+    /// Fails if the configuration is invalid, or if the fee, limit or adjustment schedules, or the
+    /// risk rules, cannot be read.
+    pub(crate) fn engine_config(&self) -> Result<EngineConfig, Error> {
+        self.config_file()?.engine_config()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sequencer::{PendingLimits, SequencerOptions};
+    use std::path::Path;
+
+    #[test]
+    fn test_cli_errors_when_missing_file() {
+        let result = Cli::try_parse_from(["payments_engine"]);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_cli_parses_file_and_snapshots() {
+        let cli = Cli::try_parse_from([
+            "payments_engine",
+            "transactions.csv",
+            "--snapshot",
+            "yesterday.json",
+            "--save-snapshot",
+            "today.json",
+        ])
+        .unwrap();
+
+        assert_eq!(cli.file.as_deref(), Some("transactions.csv"));
+        assert_eq!(cli.snapshot, Some(PathBuf::from("yesterday.json")));
+        assert_eq!(cli.save_snapshot, Some(PathBuf::from("today.json")));
+    }
+
+    #[test]
+    fn test_cli_engine_config() {
+        let cli = Cli::try_parse_from([
+            "payments_engine",
+            "tx.csv",
+            "--ledger-retention",
+            "disputable",
+            "--ledger-window",
+            "100",
+            "--ledger-spill",
+            "ledger.spill",
+            "--dispute-window",
+            "3600",
+        ])
+        .unwrap();
+
+        let config = cli.engine.engine_config().unwrap();
+        assert_eq!(config.ledger.retention, Retention::Disputable);
+        assert_eq!(config.ledger.window, Some(100));
+        assert_eq!(
+            config.ledger.spill,
+            Some((PathBuf::from("ledger.spill"), 1_000_000))
+        );
+        assert_eq!(config.windows.dispute, Some(3600));
+        assert_eq!(config.windows.resolve, None);
+        assert_eq!(config.sequencing, SequencerOptions::default());
+    }
+
+    #[test]
+    fn test_cli_options_override_the_config_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("engine.toml");
+        std::fs::write(
+            &path,
+            "[ledger]\nwindow = 10\n[locks]\nopen_disputes = 3\n[output]\nprecision = 2",
+        )
+        .unwrap();
+        let cli = Cli::try_parse_from([
+            "payments_engine",
+            "tx.csv",
+            "--config",
+            path.to_str().unwrap(),
+            "--lock-open-disputes",
+            "5",
+            "--output-format",
+            "json-lines",
+            "--rounding",
+            "half-up",
+            "--input-precision",
+            "4",
+        ])
+        .unwrap();
+
+        let config = cli.engine.engine_config().unwrap();
+        assert_eq!(config.ledger.window, Some(10));
+        assert_eq!(config.locks.max_open_disputes, Some(5));
+        assert_eq!(config.rounding.total, 2);
+        assert_eq!(config.rounding.strategy, RoundingStrategy::HalfUp);
+        assert_eq!(config.input_precision.places, Some(4));
+        assert_eq!(config.input_precision.excess, ExcessPrecision::Reject);
+        assert_eq!(config.output, OutputFormat::JsonLines);
+
+        let cli = Cli::try_parse_from(["payments_engine", "config", "check", "engine.toml"]);
+        assert!(matches!(
+            cli.unwrap().command,
+            Some(Command::Config { command: ConfigCommand::Check { path } }) if path == Path::new("engine.toml")
+        ));
+    }
+
+    #[test]
+    fn test_cli_sequencing() {
+        let cli = Cli::try_parse_from([
+            "payments_engine",
+            "tx.csv",
+            "--reorder-watermark",
+            "30",
+            "--pending-max-rows",
+            "1000",
+            "--pending-max-wait",
+            "600",
+        ])
+        .unwrap();
+
+        assert_eq!(
+            cli.engine.engine_config().unwrap().sequencing,
+            SequencerOptions {
+                watermark: Some(30),
+                pending: Some(PendingLimits {
+                    max_rows: 1000,
+                    max_wait: Some(600),
+                }),
+            }
+        );
+    }
+
+    #[test]
+    fn test_cli_history_command() {
+        let cli = Cli::try_parse_from([
+            "payments_engine",
+            "history",
+            "42",
+            "--audit-log",
+            "audit.log",
+        ])
+        .unwrap();
+
+        assert!(cli.file.is_none());
+        assert!(matches!(
+            cli.command,
+            Some(Command::History { client: 42, audit_log }) if audit_log == Path::new("audit.log")
+        ));
+    }
+
+    #[test]
+    fn test_cli_balances_at_command() {
+        let parse = |args: &[&str]| {
+            Cli::try_parse_from(
+                ["payments_engine", "balances-at", "7", "tx.csv"]
+                    .iter()
+                    .chain(args),
+            )
+        };
+
+        let cli = parse(&["--timestamp", "1700000000", "--resolve-window", "60"]).unwrap();
+        let Some(Command::BalancesAt(args)) = cli.command else {
+            panic!("not the balances-at command");
+        };
+        assert_eq!(args.client, 7);
+        assert_eq!(Point::from(&args.point), Point::Timestamp(1_700_000_000));
+        assert_eq!(
+            args.run.engine.engine_config().unwrap().windows.resolve,
+            Some(60)
+        );
+
+        assert!(parse(&[]).is_err());
+        assert!(parse(&["--tx", "1", "--timestamp", "1"]).is_err());
+    }
+
+    #[test]
+    fn test_cli_replay_command() {
+        let cli = Cli::try_parse_from([
+            "payments_engine",
+            "replay",
+            "tx.csv",
+            "--",
+            "tx.csv",
+            "--lock-open-disputes",
+            "2",
+        ])
+        .unwrap();
+        let Some(Command::Replay(args)) = cli.command else {
+            panic!("not the replay command");
+        };
+        let right = args.right().unwrap();
+        assert_eq!(args.left.file, right.file);
+        assert_eq!(args.left.engine.lock_open_disputes, None);
+        assert_eq!(right.engine.lock_open_disputes, Some(2));
+
+        assert!(Cli::try_parse_from(["payments_engine", "replay", "tx.csv"]).is_err());
+    }
+
+    #[test]
+    fn test_cli_wal_requires_save_snapshot() {
+        let result = Cli::try_parse_from(["payments_engine", "tx.csv", "--wal", "wal.log"]);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_cli_dry_run_keeps_nothing() {
+        let cli = Cli::try_parse_from(["payments_engine", "tx.csv", "--dry-run"]).unwrap();
+        assert!(cli.dry_run);
+
+        let result = Cli::try_parse_from([
+            "payments_engine",
+            "tx.csv",
+            "--dry-run",
+            "--save-snapshot",
+            "today.json",
+        ]);
+        assert!(result.is_err());
+
+        for option in [
+            "--ledger-spill",
+            "--held-output",
+            "--metrics",
+            "--report",
+            "--log-file",
+        ] {
+            let result =
+                Cli::try_parse_from(["payments_engine", "tx.csv", "--dry-run", option, "out"]);
+            assert!(result.is_err(), "{option} is accepted");
+        }
+    }
+}
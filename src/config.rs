@@ -0,0 +1,423 @@
+//! This module defines the configuration file: every behavior choice of the engine in one TOML
+//! file, loaded at startup into an [`EngineConfig`], e.g.:
+//!
+//! ```toml
+//! [ledger]
+//! retention = "disputable"
+//! window = 1000000
+//!
+//! [disputes]
+//! window = 2592000
+//!
+//! [locks]
+//! open_disputes = 3
+//! chargebacks = "100"
+//!
+//! [schedules]
+//! fees = "fees.json"
+//!
+//! [input]
+//! skip_malformed = true
+//! precision = 4
+//! excess_precision = "round"
+//!
+//! [output]
+//! format = "json_lines"
+//! precision = 2
+//! rounding = "half_up"
+//!
+//! [output.field_precision]
+//! fees = 4
+//! ```
+//!
+//! Every section and setting is optional, and defaults to the behavior of the engine without a
+//! configuration file. The options given in the command line override the ones of the file. The
+//! paths of the schedules are relative to the file.
+
+use crate::{
+    accounts::{Rounding, RoundingStrategy},
+    adjustments::AdjustmentSchedule,
+    engine::{DisputeWindows, EngineConfig},
+    error::{ConfigError, Error},
+    fees::FeeSchedule,
+    io::OutputFormat,
+    ledger::{LedgerOptions, Retention},
+    limits::LimitSchedule,
+    locks::LockPolicies,
+    primitives::Funds,
+    sequencer::{PendingLimits, SequencerOptions},
+    transactions::{ExcessPrecision, InputPrecision},
+};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+/// How the ledger is kept.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct LedgerSection {
+    /// Which transactions are kept to be disputed later.
+    pub(crate) retention: Retention,
+    /// Number of transactions that can be applied after a transaction while its record is kept.
+    pub(crate) window: Option<u64>,
+    /// File where the oldest records are moved to, instead of keeping them all in memory.
+    pub(crate) spill: Option<PathBuf>,
+    /// Number of records kept in memory when spilling to a file.
+    pub(crate) memory_records: usize,
+}
+
+impl Default for LedgerSection {
+    fn default() -> Self {
+        Self {
+            retention: Retention::All,
+            window: None,
+            spill: None,
+            memory_records: 1_000_000,
+        }
+    }
+}
+
+/// Time limits on disputes, in seconds.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct DisputesSection {
+    /// Maximum time between a transaction and a dispute on it.
+    pub(crate) window: Option<u64>,
+    /// Maximum time a dispute stays open.
+    pub(crate) resolve_window: Option<u64>,
+}
+
+/// How long authorizations hold funds.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct AuthorizationsSection {
+    /// Number of seconds before an authorization still holding funds is voided.
+    pub(crate) expiry: Option<u64>,
+}
+
+/// How the transactions are put back in order.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct SequencingSection {
+    /// Number of seconds transactions are held, to apply them in timestamp order.
+    pub(crate) reorder_watermark: Option<u64>,
+    /// Number of transactions that can wait for the one they reference.
+    pub(crate) pending_max_rows: Option<usize>,
+    /// Maximum number of seconds a transaction waits for the one it references.
+    pub(crate) pending_max_wait: Option<u64>,
+}
+
+/// When accounts get locked.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct LocksSection {
+    /// Lock with this many disputes open at once.
+    pub(crate) open_disputes: Option<usize>,
+    /// Lock when the number of disputes divided by the number of deposits goes above this.
+    pub(crate) dispute_ratio: Option<Decimal>,
+    /// Lock once the total charged back goes above this amount.
+    pub(crate) chargebacks: Funds,
+    /// Let disputes leave the available funds below zero, and lock those accounts.
+    pub(crate) negative_balance: bool,
+}
+
+impl Default for LocksSection {
+    /// Locks on any chargeback, as the engine always did.
+    fn default() -> Self {
+        Self {
+            open_disputes: None,
+            dispute_ratio: None,
+            chargebacks: Funds::ZERO,
+            negative_balance: false,
+        }
+    }
+}
+
+/// The JSON files of the schedules and rules, if any.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct SchedulesSection {
+    pub(crate) fees: Option<PathBuf>,
+    pub(crate) adjustments: Option<PathBuf>,
+    pub(crate) limits: Option<PathBuf>,
+    pub(crate) risk_rules: Option<PathBuf>,
+}
+
+/// How the input is read.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct InputSection {
+    /// Log and skip the rows that cannot be read, instead of stopping the run.
+    pub(crate) skip_malformed: bool,
+    /// Maximum number of decimal places of the amounts. Unlimited if unset.
+    pub(crate) precision: Option<u32>,
+    /// What happens to the amounts with more decimal places.
+    pub(crate) excess_precision: ExcessPrecision,
+    /// How those amounts are rounded, when rounding them.
+    pub(crate) rounding: RoundingStrategy,
+}
+
+/// Number of decimal places of some fields of the output, instead of the precision of the
+/// output.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct FieldPrecision {
+    pub(crate) available: Option<u32>,
+    pub(crate) held: Option<u32>,
+    pub(crate) total: Option<u32>,
+    pub(crate) fees: Option<u32>,
+}
+
+/// How the accounts are written out.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct OutputSection {
+    pub(crate) format: OutputFormat,
+    /// Number of decimal places of the funds.
+    pub(crate) precision: u32,
+    /// How the funds are rounded, and the interest and fees computed.
+    pub(crate) rounding: RoundingStrategy,
+    pub(crate) field_precision: FieldPrecision,
+}
+
+impl Default for OutputSection {
+    fn default() -> Self {
+        let rounding = Rounding::default();
+        Self {
+            format: OutputFormat::Csv,
+            precision: rounding.total,
+            rounding: rounding.strategy,
+            field_precision: FieldPrecision::default(),
+        }
+    }
+}
+
+impl OutputSection {
+    /// The rounding of the accounts written out.
+    pub(crate) fn rounding(&self) -> Rounding {
+        let fields = &self.field_precision;
+        let precision = self.precision;
+        Rounding {
+            strategy: self.rounding,
+            available: fields.available.unwrap_or(precision),
+            held: fields.held.unwrap_or(precision),
+            total: fields.total.unwrap_or(precision),
+            fees: fields.fees.unwrap_or(precision),
+        }
+    }
+}
+
+/// The configuration file. See the module documentation.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct ConfigFile {
+    pub(crate) ledger: LedgerSection,
+    pub(crate) disputes: DisputesSection,
+    pub(crate) authorizations: AuthorizationsSection,
+    pub(crate) sequencing: SequencingSection,
+    pub(crate) locks: LocksSection,
+    pub(crate) schedules: SchedulesSection,
+    pub(crate) input: InputSection,
+    pub(crate) output: OutputSection,
+}
+
+impl ConfigFile {
+    /// Reads a configuration file, resolving the paths it holds against its directory. The file
+    /// is validated once the command line options are applied, see [`ConfigFile::validate`].
+    pub(crate) fn load(path: &Path) -> Result<Self, Error> {
+        let content = fs::read_to_string(path)?;
+        let mut config: Self = toml::from_str(&content).map_err(ConfigError::Malformed)?;
+
+        let dir = path.parent().unwrap_or(Path::new(""));
+        let schedules = &mut config.schedules;
+        for path in [
+            &mut config.ledger.spill,
+            &mut schedules.fees,
+            &mut schedules.adjustments,
+            &mut schedules.limits,
+            &mut schedules.risk_rules,
+        ]
+        .into_iter()
+        .flatten()
+        {
+            *path = dir.join(&*path);
+        }
+        Ok(config)
+    }
+
+    /// Checks that every setting makes sense on its own and with the others.
+    pub(crate) fn validate(&self) -> Result<(), ConfigError> {
+        let invalid = |setting, reason| Err(ConfigError::Invalid(setting, reason));
+        if self.ledger.memory_records == 0 {
+            return invalid("ledger.memory_records", "must be positive");
+        }
+        if self.sequencing.pending_max_rows == Some(0) {
+            return invalid("sequencing.pending_max_rows", "must be positive");
+        }
+        if self.sequencing.pending_max_wait.is_some() && self.sequencing.pending_max_rows.is_none()
+        {
+            return invalid(
+                "sequencing.pending_max_wait",
+                "requires sequencing.pending_max_rows",
+            );
+        }
+        if self.locks.open_disputes == Some(0) {
+            return invalid("locks.open_disputes", "must be positive");
+        }
+        if self
+            .locks
+            .dispute_ratio
+            .is_some_and(|ratio| ratio.is_sign_negative())
+        {
+            return invalid("locks.dispute_ratio", "must not be negative");
+        }
+        if self.locks.chargebacks.is_sign_negative() {
+            return invalid("locks.chargebacks", "must not be negative");
+        }
+        if self.input.precision > Some(Decimal::MAX_SCALE) {
+            return invalid("input.precision", "must be at most 28");
+        }
+        let rounding = self.output.rounding();
+        if [
+            rounding.available,
+            rounding.held,
+            rounding.total,
+            rounding.fees,
+        ]
+        .into_iter()
+        .any(|places| places > Decimal::MAX_SCALE)
+        {
+            return invalid("output.precision", "must be at most 28, for every field");
+        }
+
+        Ok(())
+    }
+
+    /// The configuration of the engine, once validated. Fails if the fee, limit or adjustment
+    /// schedules, or the risk rules, cannot be read.
+    pub(crate) fn engine_config(&self) -> Result<EngineConfig, Error> {
+        self.validate()?;
+        let schedules = &self.schedules;
+        Ok(EngineConfig {
+            ledger: LedgerOptions {
+                retention: self.ledger.retention,
+                window: self.ledger.window,
+                spill: self
+                    .ledger
+                    .spill
+                    .clone()
+                    .map(|path| (path, self.ledger.memory_records)),
+            },
+            windows: DisputeWindows {
+                dispute: self.disputes.window,
+                resolve: self.disputes.resolve_window,
+            },
+            sequencing: SequencerOptions {
+                watermark: self.sequencing.reorder_watermark,
+                pending: self
+                    .sequencing
+                    .pending_max_rows
+                    .map(|max_rows| PendingLimits {
+                        max_rows,
+                        max_wait: self.sequencing.pending_max_wait,
+                    }),
+            },
+            fees: schedules
+                .fees
+                .as_deref()
+                .map(FeeSchedule::load)
+                .transpose()?,
+            limits: schedules
+                .limits
+                .as_deref()
+                .map(LimitSchedule::load)
+                .transpose()?,
+            risk: match &schedules.risk_rules {
+                Some(path) => crate::risk::load(path)?,
+                None => Vec::new(),
+            },
+            locks: LockPolicies {
+                max_open_disputes: self.locks.open_disputes,
+                max_dispute_ratio: self.locks.dispute_ratio,
+                max_charged_back: Some(self.locks.chargebacks),
+                negative_balance: self.locks.negative_balance,
+            },
+            auth_expiry: self.authorizations.expiry,
+            adjustments: schedules
+                .adjustments
+                .as_deref()
+                .map(AdjustmentSchedule::load)
+                .transpose()?,
+            skip_malformed: self.input.skip_malformed,
+            input_precision: InputPrecision {
+                places: self.input.precision,
+                excess: self.input.excess_precision,
+                strategy: self.input.rounding,
+            },
+            rounding: self.output.rounding(),
+            output: self.output.format,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_defaults_match_the_engine_without_a_file() {
+        let config = toml::from_str::<ConfigFile>("").unwrap().engine_config();
+        let config = config.unwrap();
+        let default = EngineConfig::default();
+        assert_eq!(config.ledger.retention, default.ledger.retention);
+        assert_eq!(config.sequencing, default.sequencing);
+        assert_eq!(config.locks, LockPolicies::default());
+        assert_eq!(config.rounding, Rounding::default());
+        assert_eq!(config.output, OutputFormat::Csv);
+        assert!(!config.skip_malformed);
+    }
+
+    #[test]
+    fn test_load_resolves_paths_against_the_file() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("engine.toml");
+        let mut file = fs::File::create(&path).unwrap();
+        writeln!(
+            file,
+            "[ledger]\nretention = \"disputable\"\nspill = \"ledger.spill\"\n\
+             [locks]\nopen_disputes = 3\nchargebacks = \"100\"\n\
+             [output]\nformat = \"json_lines\"\nprecision = 2"
+        )
+        .unwrap();
+
+        let config = ConfigFile::load(&path).unwrap();
+        assert_eq!(config.ledger.retention, Retention::Disputable);
+        assert_eq!(config.ledger.spill, Some(dir.path().join("ledger.spill")));
+        assert_eq!(config.locks.open_disputes, Some(3));
+        assert_eq!(config.locks.chargebacks, Decimal::ONE_HUNDRED);
+        assert_eq!(config.output.format, OutputFormat::JsonLines);
+        assert_eq!(config.engine_config().unwrap().rounding.total, 2);
+    }
+
+    #[test]
+    fn test_unknown_and_invalid_settings_are_rejected() {
+        let result = toml::from_str::<ConfigFile>("[locks]\nopen_dispute = 3");
+        assert!(result.is_err());
+
+        let config: ConfigFile = toml::from_str("[sequencing]\npending_max_wait = 10").unwrap();
+        let error: Error = config.engine_config().unwrap_err();
+        assert_eq!(error.code(), "config.invalid");
+        assert_eq!(
+            error.report().context.setting.as_deref(),
+            Some("sequencing.pending_max_wait")
+        );
+
+        let config: ConfigFile = toml::from_str("[output]\nprecision = 30").unwrap();
+        assert!(config.validate().is_err());
+    }
+}
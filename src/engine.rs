@@ -2,124 +2,1259 @@
 //! transaction onto a collection of accounts.
 
 use crate::{
-    accounts::Account,
-    error::{Error, TransactionError},
-    primitives::Tx,
-    transactions::{Transaction, TxType},
+    accounts::{Account, Accounts, Rounding, RoundingStrategy},
+    adjustments::{Adjustment, AdjustmentKind, AdjustmentSchedule},
+    audit::{AuditEntry, AuditLog, Before},
+    error::{AccountError, BatchError, Error, TransactionError},
+    fees::FeeSchedule,
+    io::OutputFormat,
+    ledger::{Ledger, LedgerEntry, LedgerOptions, TxState},
+    limits::{LimitSchedule, Usage},
+    locks::{Exposure, LockPolicies},
+    primitives::{Client, Funds, Timestamp, Tx},
+    report::Totals,
+    risk::{HeldTransaction, RiskContext, RiskRule, RuleConfig, Verdict},
+    sequencer::SequencerOptions,
+    telemetry::Metrics,
+    transactions::{InputPrecision, Transaction, TxType},
 };
-use std::collections::HashMap;
+use std::{
+    collections::{HashMap, VecDeque},
+    time::Instant,
+};
+
+/// Time limits on disputes, in seconds. They are only enforced on transactions with a timestamp.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct DisputeWindows {
+    /// Maximum time between a deposit and a dispute on it.
+    pub(crate) dispute: Option<u64>,
+    /// Maximum time between a dispute and its resolution or chargeback. Disputes left open for
+    /// longer are resolved automatically.
+    pub(crate) resolve: Option<u64>,
+}
+
+/// The behavior choices of the [`Engine`].
+#[derive(Debug, Clone, Default)]
+pub(crate) struct EngineConfig {
+    /// How the ledger is kept.
+    pub(crate) ledger: LedgerOptions,
+    /// Time limits on disputes.
+    pub(crate) windows: DisputeWindows,
+    /// How the transactions are put back in order before reaching the engine.
+    pub(crate) sequencing: SequencerOptions,
+    /// Fees charged on the transactions, if any.
+    pub(crate) fees: Option<FeeSchedule>,
+    /// Limits on what each client can move, if any.
+    pub(crate) limits: Option<LimitSchedule>,
+    /// Built-in risk rules run before applying each transaction.
+    pub(crate) risk: Vec<RuleConfig>,
+    /// When accounts get locked.
+    pub(crate) locks: LockPolicies,
+    /// Number of seconds an authorization holds funds before it is voided automatically. Only
+    /// enforced on authorizations applied with a timestamp.
+    pub(crate) auth_expiry: Option<u64>,
+    /// Interest paid and maintenance fees charged at regular intervals, if any.
+    pub(crate) adjustments: Option<AdjustmentSchedule>,
+    /// Log and skip the rows that cannot be read, instead of stopping the run at the first one.
+    pub(crate) skip_malformed: bool,
+    /// How precise the amounts of the input can be.
+    pub(crate) input_precision: InputPrecision,
+    /// How the funds of the accounts are rounded when written out. The interest and the fees are
+    /// computed to four decimal places with its strategy.
+    pub(crate) rounding: Rounding,
+    /// How the accounts are written out.
+    pub(crate) output: OutputFormat,
+}
 
 /// Engine in charge of applying transactions.
 pub(crate) struct Engine {
-    ledger: HashMap<Tx, Transaction>,
+    config: EngineConfig,
+    ledger: Ledger,
+    /// Number of transactions successfully applied over the lifetime of the engine.
+    sequence: u64,
+    /// Latest timestamp of the applied transactions, i.e. the current time for the engine.
+    clock: Option<Timestamp>,
+    /// Open disputes with a known opening time, oldest first.
+    open_disputes: VecDeque<(Timestamp, Tx)>,
+    /// Open authorizations with a known time, oldest first.
+    open_authorizations: VecDeque<(Timestamp, Tx)>,
+    /// What each client already withdrew, to enforce the limits.
+    usage: HashMap<Client, Usage>,
+    /// Checks run before applying each transaction.
+    rules: Vec<Box<dyn RiskRule>>,
+    /// Transactions held for review by the risk rules, in arrival order.
+    held: Vec<HeldTransaction>,
+    /// While applying a batch, the legs the risk rules learn from once the batch is accepted.
+    deferred: Option<Vec<(Transaction, Option<Timestamp>)>>,
+    /// Rows applied and time taken, since the engine was created.
+    metrics: Metrics,
+    /// Funds that entered and left the accounts, since the engine was created.
+    totals: Totals,
+    /// Where the operations on the accounts are audited, if anywhere.
+    audit: Option<AuditLog>,
 }
 
-impl Engine {
-    pub(crate) fn new() -> Self {
+/// The state a batch changes, restored when the batch is rejected.
+struct Checkpoint {
+    /// The accounts the legs touch, including the house account.
+    accounts: Vec<Account>,
+    /// What the clients of the legs already withdrew.
+    usage: Vec<(Client, Option<Usage>)>,
+    /// The transaction ids of the legs not seen before.
+    fresh: Vec<Tx>,
+    sequence: u64,
+    clock: Option<Timestamp>,
+    held: usize,
+    totals: Totals,
+}
+
+impl Default for Engine {
+    /// An engine with the default configuration, keeping its whole ledger in memory.
+    fn default() -> Self {
         Self {
-            ledger: HashMap::new(),
+            config: EngineConfig::default(),
+            ledger: Ledger::new(),
+            sequence: 0,
+            clock: None,
+            open_disputes: VecDeque::new(),
+            open_authorizations: VecDeque::new(),
+            usage: HashMap::new(),
+            rules: Vec::new(),
+            held: Vec::new(),
+            deferred: None,
+            metrics: Metrics::default(),
+            totals: Totals::default(),
+            audit: None,
+        }
+    }
+}
+
+impl Engine {
+    /// Creates an empty engine with the given configuration.
+    pub(crate) fn new(config: EngineConfig) -> Result<Self, Error> {
+        let ledger = Ledger::with_options(&config.ledger)?;
+        Self::restore(config, ledger, 0, None, HashMap::new())
+    }
+
+    /// Creates an engine from a previously populated [`Ledger`], its engine sequence, its clock
+    /// and the usage of the limits, e.g. when restoring from a snapshot. The risk rules start
+    /// without any history, which is not part of a snapshot.
+    pub(crate) fn restore(
+        config: EngineConfig,
+        mut ledger: Ledger,
+        sequence: u64,
+        clock: Option<Timestamp>,
+        usage: HashMap<Client, Usage>,
+    ) -> Result<Self, Error> {
+        if let Some(fees) = &config.fees {
+            fees.validate()?;
+        }
+        if let Some(limits) = &config.limits {
+            limits.validate()?;
+        }
+        if let Some(adjustments) = &config.adjustments {
+            adjustments.validate()?;
+        }
+
+        let entries = ledger.entries()?;
+        let mut open_disputes: Vec<(Timestamp, Tx)> = entries
+            .iter()
+            .filter(|(_, entry)| entry.state == TxState::Disputed)
+            .filter_map(|(tx, entry)| Some((entry.disputed_at?, *tx)))
+            .collect();
+        open_disputes.sort_unstable();
+        let mut open_authorizations: Vec<(Timestamp, Tx)> = entries
+            .iter()
+            .filter(|(_, entry)| {
+                entry.variant == TxType::Authorize && entry.state == TxState::Processed
+            })
+            .filter_map(|(tx, entry)| Some((entry.timestamp?, *tx)))
+            .collect();
+        open_authorizations.sort_unstable();
+
+        let rules: Vec<Box<dyn RiskRule>> = config.risk.iter().map(RuleConfig::build).collect();
+        let mut engine = Self {
+            config,
+            ledger,
+            sequence,
+            clock,
+            open_disputes: open_disputes.into(),
+            open_authorizations: open_authorizations.into(),
+            usage,
+            rules: Vec::new(),
+            held: Vec::new(),
+            deferred: None,
+            metrics: Metrics::default(),
+            totals: Totals::default(),
+            audit: None,
+        };
+        for rule in rules {
+            engine.add_rule(rule);
+        }
+        Ok(engine)
+    }
+
+    /// Adds a risk rule, run before applying each transaction after the ones already added.
+    pub(crate) fn add_rule(&mut self, rule: Box<dyn RiskRule>) {
+        self.rules.push(rule);
+    }
+
+    /// Audits every operation applied to, or rejected on, an account from now on.
+    pub(crate) fn set_audit_log(&mut self, log: AuditLog) {
+        self.audit = Some(log);
+    }
+
+    /// The log the operations are audited to, if any.
+    pub(crate) fn audit_log_mut(&mut self) -> Option<&mut AuditLog> {
+        self.audit.as_mut()
+    }
+
+    /// Writes the audited operations to disk, if audited at all.
+    pub(crate) fn flush_audit_log(&mut self) -> Result<(), Error> {
+        match &mut self.audit {
+            Some(log) => log.flush(),
+            None => Ok(()),
+        }
+    }
+
+    /// Transactions held for review by the risk rules so far.
+    pub(crate) fn held(&self) -> &[HeldTransaction] {
+        &self.held
+    }
+
+    /// Number of transactions successfully applied so far. Every accepted transaction increases
+    /// it by one, so it identifies a position in the write-ahead log.
+    pub(crate) fn sequence(&self) -> u64 {
+        self.sequence
+    }
+
+    /// Deals with a row of the input that cannot be read: stops the run, unless the configuration
+    /// skips those rows, which are then counted in the metrics.
+    pub(crate) fn malformed(&mut self, error: csv::Error) -> Result<(), Error> {
+        let error = Error::from(error);
+        if !self.config.skip_malformed {
+            return Err(error);
         }
+        self.metrics.count_malformed();
+        tracing::error!(code = error.code(), category = ?error.category(), "{}", error);
+        Ok(())
+    }
+
+    /// Latest timestamp of the applied transactions, if any had one.
+    pub(crate) fn clock(&self) -> Option<Timestamp> {
+        self.clock
+    }
+
+    /// What each client already withdrew, in ascending client id.
+    pub(crate) fn usage(&self) -> Vec<(Client, Usage)> {
+        let mut usage: Vec<(Client, Usage)> = self
+            .usage
+            .iter()
+            .map(|(client, usage)| (*client, usage.clone()))
+            .collect();
+        usage.sort_by_key(|(client, _)| *client);
+        usage
+    }
+
+    /// Rows applied and time taken so far.
+    pub(crate) fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    /// Funds that entered and left the accounts so far.
+    pub(crate) fn totals(&self) -> &Totals {
+        &self.totals
+    }
+
+    /// The historical records of the engine.
+    pub(crate) fn ledger(&self) -> &Ledger {
+        &self.ledger
+    }
+
+    /// The historical records of the engine, mutably.
+    pub(crate) fn ledger_mut(&mut self) -> &mut Ledger {
+        &mut self.ledger
     }
 
     /// Get a transaction from the ledger/historical records.
-    fn get_transaction(&self, tx: Tx) -> Result<&Transaction, Error> {
-        self.ledger
-            .get(&tx)
-            .ok_or(TransactionError::MissingDispute(tx).into())
+    fn get_transaction(&mut self, tx: Tx) -> Result<LedgerEntry, Error> {
+        match self.ledger.get(tx, self.sequence)? {
+            Some(entry) => Ok(entry),
+            None if self.ledger.contains(tx) => Err(TransactionError::NotRetained(tx).into()),
+            None => Err(TransactionError::MissingDispute(tx).into()),
+        }
+    }
+
+    /// The time at which a [`Transaction`] is applied: its own timestamp, unless the engine clock
+    /// is already past it.
+    fn now(&self, transaction: &Transaction) -> Option<Timestamp> {
+        match (self.clock, transaction.timestamp) {
+            (Some(clock), Some(timestamp)) => Some(clock.max(timestamp)),
+            (clock, timestamp) => clock.or(timestamp),
+        }
     }
 
-    /// Process the [`Transaction`] onto the corresponding [`Account`] b
-    pub(crate) fn process(
+    /// Applies the [`Transaction`] onto the corresponding account of the [`Accounts`] (and the
+    /// account of the other client, for transfers), locks the accounts breaking a lock policy,
+    /// posts the fee to the house account, and then resolves the disputes left open for longer than
+    /// allowed, voids the expired authorizations and applies the scheduled adjustments now due.
+    ///
+    /// Only accepted transactions move the clock, so replaying them (e.g. from the write-ahead log)
+    /// expires and adjusts exactly the same at the same points.
+    pub(crate) fn apply(
         &mut self,
-        account: &mut Account,
+        accounts: &mut Accounts,
         transaction: Transaction,
     ) -> Result<(), Error> {
-        transaction.is_valid()?;
+        let started = Instant::now();
+        let variant = transaction.variant;
+        let since = self.clock;
+        let tx = transaction.tx;
+        let before = self.before_transaction(accounts, &transaction);
+        let result = self.apply_one(accounts, transaction);
+        self.metrics.count(variant, result.as_ref().err());
+        self.metrics.observe(started.elapsed());
+        self.audit(
+            accounts,
+            before,
+            variant.name(),
+            Some(tx),
+            result.as_ref().err(),
+        );
+        result?;
 
-        // TODO: check all possible transactions? or only a subset?
-        if self.ledger.contains_key(&transaction.tx) {
-            return Err(TransactionError::DuplicateFound(transaction.tx).into());
+        self.expire_disputes(accounts)?;
+        self.expire_authorizations(accounts)?;
+        self.adjust(accounts, since);
+        Ok(())
+    }
+
+    /// Moves the clock to a timestamp, as a [`TxType::Tick`] does, but without applying a row: the
+    /// stale disputes and authorizations are expired and the scheduled adjustments due are applied.
+    pub(crate) fn advance_clock(
+        &mut self,
+        accounts: &mut Accounts,
+        timestamp: Timestamp,
+    ) -> Result<(), Error> {
+        let since = self.clock;
+        self.clock = Some(since.map_or(timestamp, |clock| clock.max(timestamp)));
+        self.expire_disputes(accounts)?;
+        self.expire_authorizations(accounts)?;
+        self.adjust(accounts, since);
+        Ok(())
+    }
+
+    /// Unlocks an account on behalf of an operator. The account is only locked again by a
+    /// transaction taking it further past a lock policy.
+    pub(crate) fn unlock(&mut self, accounts: &mut Accounts, client: Client) {
+        let before = self.before(accounts, [client]);
+        accounts.get_mut(client).unlock();
+        tracing::info!("Account {} was unlocked by the operator", client);
+        self.audit(accounts, before, "unlock", None, None);
+    }
+
+    /// Applies the legs of a batch atomically: either they are all accepted, or the accounts and
+    /// the engine are left as they were and the error lists every leg that failed.
+    ///
+    /// Only deposits, withdrawals and transfers can be part of a batch. The risk rules learn from
+    /// the legs once the batch is accepted, and the stale disputes and authorizations are expired,
+    /// and the accounts adjusted, after the whole batch. Ledger records dropped by the window while
+    /// applying a rejected batch are not brought back.
+    pub(crate) fn apply_batch(
+        &mut self,
+        accounts: &mut Accounts,
+        legs: Vec<Transaction>,
+    ) -> Result<(), Error> {
+        let _span =
+            tracing::debug_span!("batch", batch = ?legs.iter().find_map(|leg| leg.batch_id))
+                .entered();
+        let started = Instant::now();
+        let batch = legs.iter().find_map(|leg| leg.batch_id).unwrap_or_default();
+        let since = self.clock;
+        let checkpoint = self.checkpoint(accounts, &legs);
+        self.deferred = Some(Vec::new());
+
+        let mut failed = Vec::new();
+        let mut applied = Vec::with_capacity(legs.len());
+        // The legs are only audited as applied once the whole batch is accepted.
+        let mut audited: Vec<AuditEntry> = Vec::new();
+        for leg in legs {
+            let (variant, tx) = (leg.variant, leg.tx);
+            applied.push((variant, tx, leg.client));
+            let before = self.before_transaction(accounts, &leg);
+            let result = match variant {
+                TxType::Deposit | TxType::Withdrawal | TxType::Transfer => {
+                    self.apply_one(accounts, leg)
+                }
+                _ => Err(TransactionError::NotBatchable(tx).into()),
+            };
+            if let Some(before) = before {
+                let at = (self.sequence, self.clock);
+                audited.extend(before.entries(accounts, variant.name(), Some(tx), None, at));
+            }
+            // The other legs are still applied, to report all the failures at once.
+            if let Err(e) = result {
+                failed.push((tx, e));
+            }
+        }
+
+        let deferred = self.deferred.take().unwrap_or_default();
+        if failed.is_empty() {
+            for (leg, now) in &deferred {
+                for rule in &mut self.rules {
+                    rule.observe(leg, *now);
+                }
+            }
+        } else {
+            self.rollback(accounts, checkpoint)?;
+        }
+        let result = match failed.is_empty() {
+            true => Ok(()),
+            false => Err(BatchError::Rejected(batch, failed).into()),
+        };
+
+        // Each leg counts with its own error, or with the batch error when only other legs failed.
+        // The legs of a rejected batch left the accounts as they were.
+        if let (Some(log), Ok(())) = (&mut self.audit, &result) {
+            for entry in &audited {
+                log.append(entry);
+            }
+        }
+        for (variant, tx, client) in applied {
+            let error = result.as_ref().err().map(|e: &Error| e.for_leg(tx));
+            self.metrics.count(variant, error);
+            if error.is_some() {
+                let before = self.before(accounts, [client]);
+                self.audit(accounts, before, variant.name(), Some(tx), error);
+            }
+        }
+        self.metrics.observe(started.elapsed());
+
+        self.expire_disputes(accounts)?;
+        self.expire_authorizations(accounts)?;
+        self.adjust(accounts, since);
+        result
+    }
+
+    /// Applies a group of rows given by [`Batches`]: the legs of a batch, or a single transaction.
+    ///
+    /// [`Batches`]: crate::batches::Batches
+    pub(crate) fn apply_group(
+        &mut self,
+        accounts: &mut Accounts,
+        mut rows: Vec<Transaction>,
+    ) -> Result<(), Error> {
+        if rows.len() == 1 && rows[0].batch_id.is_none() {
+            self.apply(accounts, rows.remove(0))
+        } else {
+            self.apply_batch(accounts, rows)
         }
+    }
+
+    /// Captures the state the legs of a batch can change.
+    fn checkpoint(&mut self, accounts: &mut Accounts, legs: &[Transaction]) -> Checkpoint {
+        let mut clients: Vec<Client> = legs
+            .iter()
+            .flat_map(|leg| std::iter::once(leg.client).chain(leg.to))
+            .chain(self.config.fees.as_ref().map(|fees| fees.house))
+            .collect();
+        clients.sort_unstable();
+        clients.dedup();
+
+        let mut fresh: Vec<Tx> = legs
+            .iter()
+            .map(|leg| leg.tx)
+            .filter(|tx| !self.ledger.contains(*tx))
+            .collect();
+        fresh.sort_unstable();
+        fresh.dedup();
+
+        Checkpoint {
+            accounts: clients
+                .iter()
+                .map(|client| accounts.get_mut(*client).clone())
+                .collect(),
+            usage: clients
+                .iter()
+                .map(|client| (*client, self.usage.get(client).cloned()))
+                .collect(),
+            fresh,
+            sequence: self.sequence,
+            clock: self.clock,
+            held: self.held.len(),
+            totals: self.totals.clone(),
+        }
+    }
+
+    /// Brings the accounts and the engine back to a [`Checkpoint`].
+    fn rollback(&mut self, accounts: &mut Accounts, checkpoint: Checkpoint) -> Result<(), Error> {
+        for account in checkpoint.accounts {
+            accounts.insert(account);
+        }
+        for (client, usage) in checkpoint.usage {
+            match usage {
+                Some(usage) => self.usage.insert(client, usage),
+                None => self.usage.remove(&client),
+            };
+        }
+        for tx in checkpoint.fresh {
+            self.ledger.forget(tx)?;
+        }
+        self.sequence = checkpoint.sequence;
+        self.clock = checkpoint.clock;
+        self.held.truncate(checkpoint.held);
+        self.totals = checkpoint.totals;
+        Ok(())
+    }
+
+    /// The balances of the given accounts before an operation, if the operations are audited.
+    fn before(
+        &self,
+        accounts: &Accounts,
+        clients: impl IntoIterator<Item = Client>,
+    ) -> Option<Before> {
+        self.audit.as_ref().map(|_| Before::take(accounts, clients))
+    }
 
+    /// The balances of the accounts a transaction may touch before applying it, if the operations
+    /// are audited: its client's, the other client's, and the house account's.
+    fn before_transaction(
+        &mut self,
+        accounts: &Accounts,
+        transaction: &Transaction,
+    ) -> Option<Before> {
+        if self.audit.is_none() || transaction.variant == TxType::Tick {
+            return None;
+        }
+
+        let counterparty = self.counterparty(transaction);
+        let house = self.config.fees.as_ref().map(|fees| fees.house);
+        self.before(
+            accounts,
+            std::iter::once(transaction.client)
+                .chain(counterparty)
+                .chain(house),
+        )
+    }
+
+    /// The other client a transaction may touch, before applying it: the receiver of a transfer,
+    /// or of the transfer it disputes, resolves or charges back.
+    fn counterparty(&mut self, transaction: &Transaction) -> Option<Client> {
         match transaction.variant {
-            TxType::Deposit => self.process_deposit(account, transaction)?,
-            TxType::Withdrawal => self.process_withdrawal(account, transaction)?,
-            TxType::Dispute => self.process_dispute(account, transaction)?,
-            TxType::Resolve => self.process_resolution(account, transaction)?,
-            TxType::Chargeback => self.process_chargeback(account, transaction)?,
+            TxType::Dispute | TxType::Resolve | TxType::Chargeback => self
+                .ledger
+                .get(transaction.tx, self.sequence)
+                .ok()
+                .flatten()
+                .and_then(|entry| entry.to),
+            _ => transaction.to,
+        }
+    }
+
+    /// Appends the entries of an operation to the audit log, if the operations are audited.
+    fn audit(
+        &mut self,
+        accounts: &Accounts,
+        before: Option<Before>,
+        operation: &str,
+        tx: Option<Tx>,
+        error: Option<&Error>,
+    ) {
+        let (Some(log), Some(before)) = (&mut self.audit, before) else {
+            return;
+        };
+        let at = (self.sequence, self.clock);
+        for entry in before.entries(accounts, operation, tx, error, at) {
+            log.append(&entry);
+        }
+    }
+
+    /// Applies a single [`Transaction`], without expiring anything.
+    fn apply_one(
+        &mut self,
+        accounts: &mut Accounts,
+        transaction: Transaction,
+    ) -> Result<(), Error> {
+        if transaction.variant == TxType::Tick {
+            return self.tick(transaction);
+        }
+
+        // The policies only lock the accounts this transaction takes further past them.
+        let exposures: Vec<(Client, Exposure)> = std::iter::once(transaction.client)
+            .chain(self.counterparty(&transaction))
+            .map(|client| {
+                let exposure = accounts.get(client).map(Exposure::of).unwrap_or_default();
+                (client, exposure)
+            })
+            .collect();
+        let client = transaction.client;
+        let (fee, counterparty) = self.process(accounts, transaction)?;
+        for client in std::iter::once(client).chain(counterparty) {
+            let before = exposures
+                .iter()
+                .find(|(known, _)| *known == client)
+                .map(|(_, exposure)| *exposure)
+                .unwrap_or_default();
+            let account = accounts.get_mut(client);
+            if !account.is_locked()
+                && let Some(reason) = self.config.locks.check(&before, account)
+            {
+                account.lock(reason);
+                tracing::warn!("Account {} was locked: {:?}", account.client(), reason);
+            }
+        }
+        // Checked by the transaction before changing any account.
+        if let Some(fees) = &self.config.fees
+            && !fee.is_zero()
+        {
+            accounts.get_mut(fees.house).collect_fee(fee)?;
+        }
+        Ok(())
+    }
+
+    /// Moves the clock to the timestamp of a [`TxType::Tick`], without touching any account.
+    fn tick(&mut self, transaction: Transaction) -> Result<(), Error> {
+        transaction.is_valid()?;
+
+        self.sequence += 1;
+        if let Some(timestamp) = transaction.timestamp {
+            self.clock = Some(self.clock.map_or(timestamp, |clock| clock.max(timestamp)));
+        }
+        Ok(())
+    }
+
+    /// Applies the scheduled adjustments due after `since` and up to the clock, oldest first, to
+    /// the accounts known at this point. Nothing is due before the clock is first set.
+    fn adjust(&mut self, accounts: &mut Accounts, since: Option<Timestamp>) {
+        let (Some(schedule), Some(since), Some(now)) =
+            (&self.config.adjustments, since, self.clock)
+        else {
+            return;
+        };
+        if now <= since {
+            return;
+        }
+
+        let mut due = Vec::new();
+        let max = schedule.max_periods();
+        for account in accounts.iter() {
+            if let Some(tier) = schedule.tier(account.client()) {
+                let (periods, skipped) = tier.due(since, now, max);
+                if skipped > 0 {
+                    tracing::warn!(
+                        "{} adjustments of account {} are skipped: the clock jumped past more than {}",
+                        skipped,
+                        account.client(),
+                        max
+                    );
+                }
+                due.extend(periods.map(|at| (at, account.client(), tier.clone())));
+            }
+        }
+        due.sort_unstable_by_key(|(at, client, _)| (*at, *client));
+
+        let house = schedule.house;
+        for (at, client, tier) in due {
+            if let Some(rule) = &tier.interest {
+                let interest =
+                    rule.interest(accounts.get_mut(client), self.config.rounding.strategy);
+                self.post_adjustment(
+                    accounts,
+                    house,
+                    client,
+                    AdjustmentKind::Interest,
+                    interest,
+                    at,
+                );
+            }
+            if let Some(fee) = tier.fee {
+                // The fee never takes the account below zero.
+                let fee = fee.min(accounts.get_mut(client).available().max(Funds::ZERO));
+                self.post_adjustment(accounts, house, client, AdjustmentKind::Fee, Some(fee), at);
+            }
+        }
+    }
+
+    /// Moves a scheduled adjustment between the house account and the account of a client, and
+    /// records it in the ledger. Adjustments that cannot be applied (i.e. on overflow) are skipped,
+    /// leaving both accounts as they were.
+    fn post_adjustment(
+        &mut self,
+        accounts: &mut Accounts,
+        house: Client,
+        client: Client,
+        kind: AdjustmentKind,
+        amount: Option<Funds>,
+        at: Timestamp,
+    ) {
+        if amount.is_some_and(|amount| amount.is_zero()) {
+            return;
+        }
+
+        let saved = [
+            accounts.get_mut(house).clone(),
+            accounts.get_mut(client).clone(),
+        ];
+        let before = self.before(accounts, [client, house]);
+        let result = amount
+            .ok_or_else(|| AccountError::Overflow(client).into())
+            .and_then(|amount| {
+                let [house, account] = accounts.get_pair_mut(house, client);
+                match kind {
+                    AdjustmentKind::Interest => {
+                        house.collect_fee(-amount)?;
+                        account.adjust(amount)?;
+                    }
+                    AdjustmentKind::Fee => {
+                        account.adjust(-amount)?;
+                        account.pay_fee(amount)?;
+                        house.collect_fee(amount)?;
+                    }
+                }
+                Ok::<_, Error>(amount)
+            });
+
+        match result {
+            Ok(amount) => {
+                self.ledger.record_adjustment(Adjustment {
+                    client,
+                    kind,
+                    amount,
+                    timestamp: at,
+                    seq: self.sequence,
+                });
+                self.audit(accounts, before, kind.name(), None, None);
+            }
+            Err(e) => {
+                for account in saved {
+                    accounts.insert(account);
+                }
+                self.audit(accounts, before, kind.name(), None, Some(&e));
+                tracing::warn!(
+                    "Adjustment of account {} due at {} is skipped: {}",
+                    client,
+                    at,
+                    e
+                );
+            }
+        }
+    }
+
+    /// Resolves the disputes that have been open for longer than the resolve window.
+    fn expire_disputes(&mut self, accounts: &mut Accounts) -> Result<(), Error> {
+        let (Some(window), Some(now)) = (self.config.windows.resolve, self.clock) else {
+            return Ok(());
+        };
+
+        while let Some(&(disputed_at, tx)) = self.open_disputes.front() {
+            if now.saturating_sub(disputed_at) <= window {
+                break;
+            }
+            self.open_disputes.pop_front();
+
+            // The dispute may have been closed in time.
+            let Some(mut entry) = self.ledger.get(tx, self.sequence)? else {
+                continue;
+            };
+            if entry.state != TxState::Disputed || entry.disputed_at != Some(disputed_at) {
+                continue;
+            }
+
+            let before = self.before(accounts, [entry.client]);
+            match accounts.get_mut(entry.client).resolve(tx) {
+                Ok(()) => {
+                    entry.state = TxState::Resolved;
+                    self.ledger.update(tx, entry)?;
+                    self.audit(accounts, before, "expired_dispute", Some(tx), None);
+                    tracing::info!("Stale dispute for transaction {} was resolved", tx);
+                }
+                Err(e) => {
+                    self.audit(accounts, before, "expired_dispute", Some(tx), Some(&e));
+                    tracing::warn!("Stale dispute for transaction {} is kept: {}", tx, e);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Voids the authorizations that have been holding funds for longer than the expiry.
+    fn expire_authorizations(&mut self, accounts: &mut Accounts) -> Result<(), Error> {
+        let (Some(expiry), Some(now)) = (self.config.auth_expiry, self.clock) else {
+            return Ok(());
+        };
+
+        while let Some(&(authorized_at, tx)) = self.open_authorizations.front() {
+            if now.saturating_sub(authorized_at) <= expiry {
+                break;
+            }
+            self.open_authorizations.pop_front();
+
+            // The authorization may have been captured or voided in time.
+            let Some(mut entry) = self.ledger.get(tx, self.sequence)? else {
+                continue;
+            };
+            if entry.state != TxState::Processed {
+                continue;
+            }
+
+            let before = self.before(accounts, [entry.client]);
+            match accounts.get_mut(entry.client).void(tx) {
+                Ok(()) => {
+                    entry.state = TxState::Voided;
+                    self.ledger.update(tx, entry)?;
+                    self.audit(accounts, before, "expired_authorization", Some(tx), None);
+                    tracing::info!("Expired authorization {} was voided", tx);
+                }
+                Err(e) => {
+                    self.audit(
+                        accounts,
+                        before,
+                        "expired_authorization",
+                        Some(tx),
+                        Some(&e),
+                    );
+                    tracing::warn!("Expired authorization {} is kept: {}", tx, e);
+                }
+            }
         }
 
         Ok(())
     }
 
+    /// Checks that a resolution or chargeback happens within the resolve window of its dispute.
+    fn check_resolve_window(
+        &self,
+        past_transaction: &LedgerEntry,
+        transaction: &Transaction,
+    ) -> Result<(), Error> {
+        if let (Some(window), Some(now), Some(disputed_at)) = (
+            self.config.windows.resolve,
+            self.now(transaction),
+            past_transaction.disputed_at,
+        ) && now.saturating_sub(disputed_at) > window
+        {
+            return Err(TransactionError::ResolveWindowExpired(transaction.tx).into());
+        }
+
+        Ok(())
+    }
+
+    /// The fee charged on a [`Transaction`] with the given amount.
+    fn fee(&self, transaction: &Transaction, amount: Funds) -> Result<Funds, Error> {
+        let Some(fees) = &self.config.fees else {
+            return Ok(Funds::ZERO);
+        };
+        let strategy = self.config.rounding.strategy;
+        fees.fee(transaction.client, transaction.variant, amount, strategy)
+            .ok_or(AccountError::Overflow(transaction.client).into())
+    }
+
+    /// Checks that the house account can collect the given fee. This is done before the
+    /// transaction charging it changes any account, so that it is not left half applied.
+    fn check_house(&self, accounts: &Accounts, fee: Funds) -> Result<(), Error> {
+        match (&self.config.fees, fee.is_zero()) {
+            (Some(fees), false) => accounts
+                .get(fees.house)
+                .map_or(Ok(()), |house| house.can_collect_fee(fee)),
+            _ => Ok(()),
+        }
+    }
+
+    /// Checks that a transaction is within the limits of its client.
+    fn check_limits(&self, transaction: &Transaction) -> Result<(), Error> {
+        let (Some(schedule), Some(amount)) = (&self.config.limits, transaction.amount) else {
+            return Ok(());
+        };
+        let Some(limits) = schedule.limits(transaction.client) else {
+            return Ok(());
+        };
+
+        let unused = Usage::default();
+        let usage = self.usage.get(&transaction.client).unwrap_or(&unused);
+        let now = self.now(transaction).unwrap_or_default();
+        limits.check(usage, transaction, amount, now)?;
+        Ok(())
+    }
+
+    /// Takes an accepted withdrawal, transfer or capture into account for the limits of its client.
+    fn record_withdrawal(&mut self, client: Client, amount: Funds, now: Option<Timestamp>) {
+        let Some(limits) = self
+            .config
+            .limits
+            .as_ref()
+            .and_then(|schedule| schedule.limits(client))
+        else {
+            return;
+        };
+
+        self.usage
+            .entry(client)
+            .or_default()
+            .record(limits, amount, now.unwrap_or_default());
+    }
+
+    /// Runs the risk rules on a [`Transaction`] about to be applied. If any rule rejects it, or
+    /// holds it for review (which is then kept in [`Engine::held`]), the transaction fails.
+    fn check_risk(
+        &mut self,
+        account: &Account,
+        transaction: &Transaction,
+        now: Option<Timestamp>,
+    ) -> Result<(), Error> {
+        let context = RiskContext {
+            account,
+            ledger: &self.ledger,
+            now,
+        };
+
+        // The strictest verdict wins.
+        let mut flagged: Option<(Verdict, &str)> = None;
+        for rule in &self.rules {
+            match rule.evaluate(transaction, &context) {
+                Verdict::Allow => {}
+                Verdict::Hold if flagged.is_some() => {}
+                verdict @ Verdict::Hold => flagged = Some((verdict, rule.name())),
+                verdict @ Verdict::Reject => {
+                    flagged = Some((verdict, rule.name()));
+                    break;
+                }
+            }
+        }
+
+        match flagged {
+            None | Some((Verdict::Allow, _)) => Ok(()),
+            Some((Verdict::Hold, rule)) => {
+                let rule = rule.to_string();
+                self.held
+                    .push(HeldTransaction::new(transaction, rule.clone()));
+                Err(TransactionError::HeldForReview(transaction.tx, rule).into())
+            }
+            Some((Verdict::Reject, rule)) => {
+                Err(TransactionError::RejectedByRule(transaction.tx, rule.to_string()).into())
+            }
+        }
+    }
+
+    /// Process the [`Transaction`] onto the corresponding [`Account`], returning the fee to post to
+    /// the house account (negative when fees are reversed), and the other client whose account it
+    /// touched, if any.
+    fn process(
+        &mut self,
+        accounts: &mut Accounts,
+        mut transaction: Transaction,
+    ) -> Result<(Funds, Option<Client>), Error> {
+        let _span = tracing::debug_span!(
+            "process",
+            tx = transaction.tx,
+            client = transaction.client,
+            r#type = transaction.variant.name()
+        )
+        .entered();
+        transaction.normalize(&self.config.input_precision)?;
+        transaction.is_valid()?;
+
+        // Disputes, resolutions, chargebacks, captures, voids and refunds refer to a previous
+        // transaction id, so only the transactions creating a record can be duplicates.
+        if transaction.variant.creates_record() && self.ledger.contains(transaction.tx) {
+            return Err(TransactionError::DuplicateFound(transaction.tx).into());
+        }
+
+        // Limits are checked before touching the account.
+        self.check_limits(&transaction)?;
+        let now = self.now(&transaction);
+        self.check_risk(accounts.get_mut(transaction.client), &transaction, now)?;
+
+        // Disputes, resolutions and chargebacks act on the account credited by the transaction
+        // they refer to, which is another client's for transfers.
+        let disputed = match transaction.variant {
+            TxType::Dispute | TxType::Resolve | TxType::Chargeback => {
+                Some(self.get_disputed(&transaction)?)
+            }
+            _ => None,
+        };
+
+        let applied = transaction.clone();
+        let account = accounts.get_mut(transaction.client);
+        let fee = match (transaction.variant, disputed) {
+            (TxType::Deposit, _) => self.process_deposit(accounts, transaction)?,
+            (TxType::Withdrawal, _) => self.process_withdrawal(accounts, transaction)?,
+            (TxType::Transfer, _) => self.process_transfer(accounts, transaction)?,
+            (TxType::Dispute, Some(past_transaction)) => {
+                self.process_dispute(accounts, transaction, past_transaction)?
+            }
+            (TxType::Resolve, Some(past_transaction)) => {
+                self.process_resolution(accounts, transaction, past_transaction)?
+            }
+            (TxType::Chargeback, Some(past_transaction)) => {
+                self.process_chargeback(accounts, transaction, past_transaction)?
+            }
+            (TxType::Authorize, _) => self.process_authorization(account, transaction)?,
+            (TxType::Capture, _) => self.process_capture(account, transaction)?,
+            (TxType::Void, _) => self.process_void(account, transaction)?,
+            (TxType::Refund, _) => self.process_refund(account, transaction)?,
+            (TxType::Dispute | TxType::Resolve | TxType::Chargeback, None) => {
+                unreachable!("disputed transactions are looked up above")
+            }
+            (TxType::Tick, _) => unreachable!("ticks do not reach any account"),
+        };
+        let counterparty = applied.to.or(disputed.and_then(|entry| entry.to));
+
+        if let (TxType::Withdrawal | TxType::Transfer, Some(amount)) =
+            (applied.variant, applied.amount)
+        {
+            self.record_withdrawal(applied.client, amount, now);
+        }
+        match &mut self.deferred {
+            Some(deferred) => deferred.push((applied.clone(), now)),
+            None => {
+                for rule in &mut self.rules {
+                    rule.observe(&applied, now);
+                }
+            }
+        }
+        self.sequence += 1;
+        if let Some(timestamp) = applied.timestamp {
+            self.clock = Some(self.clock.map_or(timestamp, |clock| clock.max(timestamp)));
+        }
+        Ok((fee, counterparty))
+    }
+
     /// All the actions necessary for a [`TxType::Deposit`].
     fn process_deposit(
         &mut self,
-        account: &mut Account,
+        accounts: &mut Accounts,
         transaction: Transaction,
-    ) -> Result<(), Error> {
+    ) -> Result<Funds, Error> {
         // Safe to unwrap since there's a check for valid transactions earlier.
-        account.credit(transaction.amount.unwrap())?;
+        let amount = transaction.amount.unwrap();
+        // The fee is taken from the deposit itself, so it cannot be above it.
+        let fee = self.fee(&transaction, amount)?.min(amount);
+        self.check_house(accounts, fee)?;
+        let account = accounts.get_mut(transaction.client);
+        account.credit(amount - fee)?;
+        account.pay_fee(fee)?;
+        account.count_deposit();
 
         // Record the deposit in the history.
-        self.ledger.insert(transaction.tx, transaction);
-        Ok(())
+        self.ledger.record(&transaction, fee, self.sequence)?;
+        self.totals.deposited += amount;
+        Ok(fee)
     }
 
     /// All the actions involved in a [`TxType::Withdrawal`].
     fn process_withdrawal(
         &mut self,
-        account: &mut Account,
+        accounts: &mut Accounts,
         transaction: Transaction,
-    ) -> Result<(), Error> {
+    ) -> Result<Funds, Error> {
         // Safe to unwrap since there's a check for valid transactions earlier.
-        account.debit(transaction.amount.unwrap())?;
+        let amount = transaction.amount.unwrap();
+        // The fee is debited on top of the amount withdrawn.
+        let fee = self.fee(&transaction, amount)?;
+        self.check_house(accounts, fee)?;
+        let account = accounts.get_mut(transaction.client);
+        let debited = amount
+            .checked_add(fee)
+            .ok_or(AccountError::Overflow(transaction.client))?;
+        account.debit(debited)?;
+        account.pay_fee(fee)?;
 
         // Record the withdrawal in the history.
-        self.ledger.insert(transaction.tx, transaction);
-        Ok(())
+        self.ledger.record(&transaction, fee, self.sequence)?;
+        // The fee stays in the accounts, on the house account.
+        self.totals.withdrawn += amount;
+        Ok(fee)
     }
 
-    // TODO: the three functions below share some common functionality that can be refactored into
-    // a new function, so there's less boilerplate.
-
-    /// All the actions involved in a [`TxType::Dispute`].
-    fn process_dispute(
+    /// All the actions involved in a [`TxType::Transfer`]. Both accounts are changed, or none.
+    fn process_transfer(
         &mut self,
-        account: &mut Account,
+        accounts: &mut Accounts,
         transaction: Transaction,
-    ) -> Result<(), Error> {
+    ) -> Result<Funds, Error> {
+        // Safe to unwrap since there's a check for valid transactions earlier.
+        let amount = transaction.amount.unwrap();
+        let to = transaction.to.unwrap();
+        let [from, to] = accounts.get_pair_mut(transaction.client, to);
+        from.debit(amount)?;
+        if let Err(e) = to.credit(amount) {
+            // Undoes the debit, which cannot fail since it just succeeded.
+            from.credit(amount)?;
+            return Err(e);
+        }
+
+        // Record the transfer in the history.
+        self.ledger
+            .record(&transaction, Funds::ZERO, self.sequence)?;
+        Ok(Funds::ZERO)
+    }
+
+    /// Gets the transaction a dispute, resolution or chargeback refers to.
+    fn get_disputed(&mut self, transaction: &Transaction) -> Result<LedgerEntry, Error> {
         // If there exists a previous transaction.
         let past_transaction = self.get_transaction(transaction.tx)?;
-        // And it was a deposit.
-        if past_transaction.variant == TxType::Deposit {
+        // And it was a deposit or a transfer.
+        if transaction.variant == TxType::Dispute
+            && !matches!(past_transaction.variant, TxType::Deposit | TxType::Transfer)
+        {
             return Err(TransactionError::OnlyDepositsCanBeDisputed(transaction.tx).into());
         }
 
-        // If the dispute matches the previous transaction client.
+        // And has the same client.
         if past_transaction.client != transaction.client {
             return Err(TransactionError::WrongClient(
                 transaction.tx,
                 past_transaction.client,
                 transaction.client,
+                transaction.variant,
             )
             .into());
         }
 
-        // Safe to unwrap since there's a check for valid transactions earlier.
-        account.dispute(transaction.amount.unwrap(), transaction.tx)?;
+        Ok(past_transaction)
+    }
 
-        Ok(())
+    /// All the actions involved in a [`TxType::Dispute`].
+    fn process_dispute(
+        &mut self,
+        accounts: &mut Accounts,
+        transaction: Transaction,
+        past_transaction: LedgerEntry,
+    ) -> Result<Funds, Error> {
+        // If it is not too late to dispute it.
+        let now = self.now(&transaction);
+        if let (Some(window), Some(now), Some(timestamp)) =
+            (self.config.windows.dispute, now, past_transaction.timestamp)
+            && now.saturating_sub(timestamp) > window
+        {
+            return Err(TransactionError::DisputeWindowExpired(transaction.tx).into());
+        }
+
+        // The dispute itself has no amount, it holds the amount credited by the disputed
+        // transaction, i.e. without its fee and what was already refunded.
+        let held = past_transaction.remaining();
+        let account = accounts.get_mut(past_transaction.credited());
+        if self.config.locks.negative_balance {
+            account.dispute_overdrawn(held, transaction.tx)?;
+        } else {
+            account.dispute(held, transaction.tx)?;
+        }
+
+        let mut entry = past_transaction;
+        entry.state = TxState::Disputed;
+        entry.disputed_at = now;
+        self.ledger.update(transaction.tx, entry)?;
+        if let Some(now) = now {
+            self.open_disputes.push_back((now, transaction.tx));
+        }
+
+        Ok(Funds::ZERO)
     }
 
     /// All the actions involed in a resolution ([`TxType::Resolve`]).
     fn process_resolution(
+        &mut self,
+        accounts: &mut Accounts,
+        transaction: Transaction,
+        past_transaction: LedgerEntry,
+    ) -> Result<Funds, Error> {
+        self.check_resolve_window(&past_transaction, &transaction)?;
+        let account = accounts.get_mut(past_transaction.credited());
+        match past_transaction.to {
+            Some(_) => account.resolve_received(transaction.tx)?,
+            None => account.resolve(transaction.tx)?,
+        }
+
+        self.ledger.set_state(transaction.tx, TxState::Resolved)?;
+        Ok(Funds::ZERO)
+    }
+
+    /// All the actions involved in a [`TxType::Chargeback`].
+    fn process_chargeback(
+        &mut self,
+        accounts: &mut Accounts,
+        transaction: Transaction,
+        past_transaction: LedgerEntry,
+    ) -> Result<Funds, Error> {
+        self.check_resolve_window(&past_transaction, &transaction)?;
+
+        // Charging back a transfer gives the funds back to the sending client, even if locked, so
+        // it is credited first: if that fails, nothing was changed.
+        let refunded = past_transaction.amount - past_transaction.fee;
+        if let Some(to) = past_transaction.to {
+            let [from, to] = accounts.get_pair_mut(transaction.client, to);
+            from.reimburse(refunded)?;
+            if let Err(e) = to.chargeback(transaction.tx) {
+                // Undoes the credit, which cannot fail since it just succeeded.
+                from.reimburse(-refunded)?;
+                return Err(e);
+            }
+            self.ledger
+                .set_state(transaction.tx, TxState::ChargedBack)?;
+            return Ok(Funds::ZERO);
+        }
+
+        // The fee reversed is at most the whole fee of the transaction.
+        self.check_house(accounts, -past_transaction.fee)?;
+        let account = accounts.get_mut(transaction.client);
+        let charged_back = account.chargeback(transaction.tx)?;
+
+        // The fee goes back out with the funds charged back, in proportion to them.
+        let fee = proportional_fee(
+            &past_transaction,
+            charged_back,
+            self.config.rounding.strategy,
+        );
+        account.reverse_fee(fee)?;
+
+        self.ledger
+            .set_state(transaction.tx, TxState::ChargedBack)?;
+        self.totals.charged_back += charged_back + fee;
+        Ok(-fee)
+    }
+
+    /// All the actions involved in a [`TxType::Authorize`].
+    fn process_authorization(
         &mut self,
         account: &mut Account,
         transaction: Transaction,
-    ) -> Result<(), Error> {
+    ) -> Result<Funds, Error> {
+        // Safe to unwrap since there's a check for valid transactions earlier.
+        let amount = transaction.amount.unwrap();
+        account.authorize(amount, transaction.tx)?;
+
+        // The authorization holds the funds from the time it is applied, which keeps the open
+        // authorizations in order of expiry.
+        let now = self.now(&transaction);
+        let authorization = Transaction {
+            timestamp: now,
+            ..transaction
+        };
+        self.ledger
+            .record(&authorization, Funds::ZERO, self.sequence)?;
+        if let Some(now) = now {
+            self.open_authorizations.push_back((now, authorization.tx));
+        }
+
+        Ok(Funds::ZERO)
+    }
+
+    /// Gets the open authorization a capture or a void refers to.
+    fn get_authorization(&mut self, transaction: &Transaction) -> Result<LedgerEntry, Error> {
         // If there exists a previous transaction.
-        let past_transaction = self.get_transaction(transaction.tx)?;
+        let past_transaction = match self.get_transaction(transaction.tx) {
+            Err(Error::Transaction(TransactionError::MissingDispute(tx))) => {
+                return Err(TransactionError::MissingAuthorization(tx).into());
+            }
+            past_transaction => past_transaction?,
+        };
+        // And it is an authorization still holding funds.
+        if past_transaction.variant != TxType::Authorize
+            || past_transaction.state != TxState::Processed
+        {
+            return Err(TransactionError::MissingAuthorization(transaction.tx).into());
+        }
 
         // And has the same client.
         if past_transaction.client != transaction.client {
@@ -127,23 +1262,78 @@ impl Engine {
                 transaction.tx,
                 past_transaction.client,
                 transaction.client,
+                transaction.variant,
             )
             .into());
         }
 
-        account.resolve(transaction.tx)?;
+        Ok(past_transaction)
+    }
 
-        Ok(())
+    /// All the actions involved in a [`TxType::Capture`].
+    fn process_capture(
+        &mut self,
+        account: &mut Account,
+        transaction: Transaction,
+    ) -> Result<Funds, Error> {
+        let authorization = self.get_authorization(&transaction)?;
+
+        // And it did not expire yet.
+        if let (Some(expiry), Some(now), Some(authorized_at)) = (
+            self.config.auth_expiry,
+            self.now(&transaction),
+            authorization.timestamp,
+        ) && now.saturating_sub(authorized_at) > expiry
+        {
+            return Err(TransactionError::AuthorizationExpired(transaction.tx).into());
+        }
+
+        let (captured, left) = account.capture(transaction.tx, transaction.amount)?;
+        if left.is_zero() {
+            self.ledger.set_state(transaction.tx, TxState::Captured)?;
+        }
+        self.totals.captured += captured;
+        // The authorization was checked against the limits, the funds leave the account now.
+        let now = self.now(&transaction);
+        self.record_withdrawal(transaction.client, captured, now);
+        Ok(Funds::ZERO)
     }
 
-    /// All the actions involved in a [`TxType::Chargeback`].
-    fn process_chargeback(
+    /// All the actions involved in a [`TxType::Void`].
+    fn process_void(
         &mut self,
         account: &mut Account,
         transaction: Transaction,
-    ) -> Result<(), Error> {
+    ) -> Result<Funds, Error> {
+        self.get_authorization(&transaction)?;
+        account.void(transaction.tx)?;
+
+        self.ledger.set_state(transaction.tx, TxState::Voided)?;
+        Ok(Funds::ZERO)
+    }
+
+    /// All the actions involved in a [`TxType::Refund`].
+    fn process_refund(
+        &mut self,
+        account: &mut Account,
+        transaction: Transaction,
+    ) -> Result<Funds, Error> {
         // If there exists a previous transaction.
-        let past_transaction = self.get_transaction(transaction.tx)?;
+        let past_transaction = match self.get_transaction(transaction.tx) {
+            Err(Error::Transaction(TransactionError::MissingDispute(tx))) => {
+                return Err(TransactionError::NotRefundable(tx).into());
+            }
+            past_transaction => past_transaction?,
+        };
+        // And it is a deposit whose funds are not held, nor gone, because of a dispute.
+        if past_transaction.variant != TxType::Deposit
+            || matches!(
+                past_transaction.state,
+                TxState::Disputed | TxState::ChargedBack
+            )
+        {
+            return Err(TransactionError::NotRefundable(transaction.tx).into());
+        }
 
         // And has the same client.
         if past_transaction.client != transaction.client {
@@ -151,12 +1341,1325 @@ impl Engine {
                 transaction.tx,
                 past_transaction.client,
                 transaction.client,
+                transaction.variant,
             )
             .into());
         }
 
-        account.chargeback(transaction.tx)?;
+        // And there is enough left to refund. The fee of the deposit is not refunded.
+        let left = past_transaction.remaining();
+        let refunded = transaction.amount.unwrap_or(left);
+        if refunded > left || refunded.is_zero() {
+            return Err(TransactionError::RefundExceedsDeposit(transaction.tx).into());
+        }
+        account.debit(refunded)?;
 
-        Ok(())
+        let mut entry = past_transaction;
+        entry.refunded += refunded;
+        self.ledger.update(transaction.tx, entry)?;
+        self.totals.refunded += refunded;
+        Ok(Funds::ZERO)
+    }
+}
+
+/// The part of the fee of a recorded transaction matching the given part of the amount it
+/// credited, rounded to four decimal places with the given strategy.
+fn proportional_fee(entry: &LedgerEntry, credited: Funds, strategy: RoundingStrategy) -> Funds {
+    let net = entry.amount - entry.fee;
+    if net.is_zero() {
+        return entry.fee;
+    }
+
+    entry
+        .fee
+        .checked_mul(credited)
+        .and_then(|fee| fee.checked_div(net))
+        .map_or(entry.fee, |fee| strategy.round(fee, 4).min(entry.fee))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        ledger::Retention,
+        locks::LockReason,
+        primitives::{BatchId, Client},
+        snapshot::AccountSnapshot,
+    };
+    use rust_decimal::Decimal;
+
+    fn funds(amount: f32) -> Decimal {
+        Decimal::from_f32_retain(amount).unwrap()
+    }
+
+    fn transaction(variant: TxType, client: Client, tx: Tx, amount: Option<f32>) -> Transaction {
+        Transaction {
+            variant,
+            client,
+            tx,
+            amount: amount.map(funds),
+            to: None,
+            batch_id: None,
+            timestamp: None,
+        }
+    }
+
+    fn apply(
+        engine: &mut Engine,
+        accounts: &mut Accounts,
+        transaction: Transaction,
+    ) -> Result<(), Error> {
+        engine.apply(accounts, transaction)
+    }
+
+    fn state(accounts: &mut Accounts, client: Client) -> AccountSnapshot {
+        accounts.get_mut(client).snapshot()
+    }
+
+    #[test]
+    fn test_dispute_holds_the_deposited_amount() {
+        let mut engine = Engine::default();
+        let mut accounts = Accounts::new();
+        apply(
+            &mut engine,
+            &mut accounts,
+            transaction(TxType::Deposit, 1, 1, Some(10.0)),
+        )
+        .unwrap();
+        apply(
+            &mut engine,
+            &mut accounts,
+            transaction(TxType::Dispute, 1, 1, None),
+        )
+        .unwrap();
+
+        let account = state(&mut accounts, 1);
+        assert_eq!(account.available, Funds::ZERO);
+        assert_eq!(account.held, funds(10.0));
+        let now = engine.sequence();
+        let entry = engine.ledger_mut().get(1, now).unwrap().unwrap();
+        assert_eq!(entry.state, TxState::Disputed);
+    }
+
+    #[test]
+    fn test_withdrawals_cannot_be_disputed() {
+        let mut engine = Engine::default();
+        let mut accounts = Accounts::new();
+        apply(
+            &mut engine,
+            &mut accounts,
+            transaction(TxType::Deposit, 1, 1, Some(10.0)),
+        )
+        .unwrap();
+        apply(
+            &mut engine,
+            &mut accounts,
+            transaction(TxType::Withdrawal, 1, 2, Some(1.0)),
+        )
+        .unwrap();
+
+        let result = apply(
+            &mut engine,
+            &mut accounts,
+            transaction(TxType::Dispute, 1, 2, None),
+        );
+        assert!(matches!(
+            result,
+            Err(Error::Transaction(
+                TransactionError::OnlyDepositsCanBeDisputed(2)
+            ))
+        ));
+    }
+
+    #[test]
+    fn test_duplicated_deposits_are_rejected() {
+        let mut engine = Engine::default();
+        let mut accounts = Accounts::new();
+        apply(
+            &mut engine,
+            &mut accounts,
+            transaction(TxType::Deposit, 1, 1, Some(10.0)),
+        )
+        .unwrap();
+
+        let result = apply(
+            &mut engine,
+            &mut accounts,
+            transaction(TxType::Deposit, 1, 1, Some(10.0)),
+        );
+        assert!(matches!(
+            result,
+            Err(Error::Transaction(TransactionError::DuplicateFound(1)))
+        ));
+        assert_eq!(engine.sequence(), 1);
+    }
+
+    #[test]
+    fn test_dispute_out_of_ledger_window_is_rejected() {
+        let mut engine = Engine::new(EngineConfig {
+            ledger: LedgerOptions {
+                retention: Retention::Disputable,
+                window: Some(1),
+                spill: None,
+            },
+            ..Default::default()
+        })
+        .unwrap();
+        let mut accounts = Accounts::new();
+        apply(
+            &mut engine,
+            &mut accounts,
+            transaction(TxType::Deposit, 1, 1, Some(10.0)),
+        )
+        .unwrap();
+        apply(
+            &mut engine,
+            &mut accounts,
+            transaction(TxType::Deposit, 1, 2, Some(1.0)),
+        )
+        .unwrap();
+        apply(
+            &mut engine,
+            &mut accounts,
+            transaction(TxType::Deposit, 1, 3, Some(1.0)),
+        )
+        .unwrap();
+
+        let result = apply(
+            &mut engine,
+            &mut accounts,
+            transaction(TxType::Dispute, 1, 1, None),
+        );
+        assert!(matches!(
+            result,
+            Err(Error::Transaction(TransactionError::NotRetained(1)))
+        ));
+        apply(
+            &mut engine,
+            &mut accounts,
+            transaction(TxType::Dispute, 1, 3, None),
+        )
+        .unwrap();
+    }
+
+    fn at(mut transaction: Transaction, timestamp: Timestamp) -> Transaction {
+        transaction.timestamp = Some(timestamp);
+        transaction
+    }
+
+    fn windowed_engine(dispute: u64, resolve: u64) -> Engine {
+        Engine::new(EngineConfig {
+            windows: DisputeWindows {
+                dispute: Some(dispute),
+                resolve: Some(resolve),
+            },
+            ..Default::default()
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_dispute_after_window_is_rejected() {
+        let mut engine = windowed_engine(100, 100);
+        let mut accounts = Accounts::new();
+        let deposit = transaction(TxType::Deposit, 1, 1, Some(10.0));
+        apply(&mut engine, &mut accounts, at(deposit, 1_000)).unwrap();
+
+        let dispute = transaction(TxType::Dispute, 1, 1, None);
+        let result = apply(&mut engine, &mut accounts, at(dispute.clone(), 1_101));
+        assert!(matches!(
+            result,
+            Err(Error::Transaction(TransactionError::DisputeWindowExpired(
+                1
+            )))
+        ));
+        apply(&mut engine, &mut accounts, at(dispute, 1_100)).unwrap();
+    }
+
+    #[test]
+    fn test_resolve_after_window_is_rejected() {
+        let mut engine = windowed_engine(100, 50);
+        let mut accounts = Accounts::new();
+        let deposit = transaction(TxType::Deposit, 1, 1, Some(10.0));
+        let dispute = transaction(TxType::Dispute, 1, 1, None);
+        apply(&mut engine, &mut accounts, at(deposit, 1_000)).unwrap();
+        apply(&mut engine, &mut accounts, at(dispute, 1_010)).unwrap();
+
+        let chargeback = transaction(TxType::Chargeback, 1, 1, None);
+        let result = apply(&mut engine, &mut accounts, at(chargeback, 1_061));
+        assert!(matches!(
+            result,
+            Err(Error::Transaction(TransactionError::ResolveWindowExpired(
+                1
+            )))
+        ));
+        assert!(!state(&mut accounts, 1).locked);
+    }
+
+    #[test]
+    fn test_stale_disputes_are_resolved_automatically() {
+        let mut engine = windowed_engine(100, 50);
+        let mut accounts = Accounts::new();
+        let deposit = transaction(TxType::Deposit, 1, 1, Some(10.0));
+        let dispute = transaction(TxType::Dispute, 1, 1, None);
+        apply(&mut engine, &mut accounts, at(deposit, 1_000)).unwrap();
+        apply(&mut engine, &mut accounts, at(dispute, 1_010)).unwrap();
+        assert_eq!(state(&mut accounts, 1).held, funds(10.0));
+
+        // Any later transaction, even for another client, moves the clock.
+        let other = transaction(TxType::Deposit, 2, 2, Some(1.0));
+        apply(&mut engine, &mut accounts, at(other, 1_061)).unwrap();
+
+        let account = state(&mut accounts, 1);
+        assert_eq!(account.held, Funds::ZERO);
+        assert_eq!(account.available, funds(10.0));
+        let now = engine.sequence();
+        let entry = engine.ledger_mut().get(1, now).unwrap().unwrap();
+        assert_eq!(entry.state, TxState::Resolved);
+        assert_eq!(engine.clock(), Some(1_061));
+    }
+
+    #[test]
+    fn test_windows_are_not_enforced_without_timestamps() {
+        let mut engine = windowed_engine(0, 0);
+        let mut accounts = Accounts::new();
+        let deposit = transaction(TxType::Deposit, 1, 1, Some(10.0));
+        apply(&mut engine, &mut accounts, deposit).unwrap();
+        let dispute = transaction(TxType::Dispute, 1, 1, None);
+        apply(&mut engine, &mut accounts, at(dispute, 1_000)).unwrap();
+    }
+
+    fn engine_with_fees() -> Engine {
+        let fees = serde_json::from_str(
+            r#"{
+                "house": 0,
+                "default_tier": "standard",
+                "tiers": {
+                    "standard": {
+                        "deposit": { "percent": "1" },
+                        "withdrawal": { "flat": "0.5" }
+                    }
+                }
+            }"#,
+        )
+        .unwrap();
+        Engine::new(EngineConfig {
+            fees: Some(fees),
+            ..Default::default()
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_scheduled_adjustments_follow_the_clock() {
+        let adjustments = serde_json::from_str(
+            r#"{
+                "house": 0,
+                "clients": { "1": "savings", "2": "basic" },
+                "tiers": {
+                    "savings": { "every": 100, "interest": { "percent": "1" } },
+                    "basic": { "every": 100, "fee": "3" }
+                }
+            }"#,
+        )
+        .unwrap();
+        let mut engine = Engine::new(EngineConfig {
+            adjustments: Some(adjustments),
+            ..Default::default()
+        })
+        .unwrap();
+        let mut accounts = Accounts::new();
+        let deposit = transaction(TxType::Deposit, 1, 1, Some(100.0));
+        apply(&mut engine, &mut accounts, at(deposit, 50)).unwrap();
+        let deposit = transaction(TxType::Deposit, 2, 2, Some(5.0));
+        apply(&mut engine, &mut accounts, at(deposit, 60)).unwrap();
+
+        // A tick needs a timestamp to move the clock to.
+        let tick = transaction(TxType::Tick, 0, 0, None);
+        assert!(apply(&mut engine, &mut accounts, tick.clone()).is_err());
+        apply(&mut engine, &mut accounts, at(tick, 250)).unwrap();
+
+        assert_eq!(state(&mut accounts, 1).available, Decimal::new(10201, 2));
+        let account = state(&mut accounts, 2);
+        assert_eq!(account.available, Funds::ZERO);
+        assert_eq!(account.fees, funds(5.0));
+        assert_eq!(state(&mut accounts, 0).available, Decimal::new(299, 2));
+        assert_eq!(engine.sequence(), 3);
+
+        let adjustments: Vec<_> = engine
+            .ledger()
+            .adjustments()
+            .iter()
+            .map(|a| (a.timestamp, a.client, a.kind, a.amount))
+            .collect();
+        assert_eq!(
+            adjustments,
+            vec![
+                (100, 1, AdjustmentKind::Interest, funds(1.0)),
+                (100, 2, AdjustmentKind::Fee, funds(3.0)),
+                (200, 1, AdjustmentKind::Interest, Decimal::new(101, 2)),
+                (200, 2, AdjustmentKind::Fee, funds(2.0)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_fees_are_posted_to_the_house_account() {
+        let mut engine = engine_with_fees();
+        let mut accounts = Accounts::new();
+        let deposit = transaction(TxType::Deposit, 1, 1, Some(100.0));
+        apply(&mut engine, &mut accounts, deposit).unwrap();
+        let withdrawal = transaction(TxType::Withdrawal, 1, 2, Some(10.0));
+        apply(&mut engine, &mut accounts, withdrawal).unwrap();
+
+        let account = state(&mut accounts, 1);
+        assert_eq!(account.available, funds(88.5));
+        assert_eq!(account.fees, funds(1.5));
+        assert_eq!(state(&mut accounts, 0).available, funds(1.5));
+
+        // The fee is debited on top of the withdrawal.
+        let withdrawal = transaction(TxType::Withdrawal, 1, 3, Some(88.5));
+        let result = apply(&mut engine, &mut accounts, withdrawal);
+        assert!(matches!(
+            result,
+            Err(Error::Account(AccountError::InsufficientFunds(1, ..)))
+        ));
+    }
+
+    #[test]
+    fn test_fee_the_house_account_cannot_collect_changes_nothing() {
+        let mut engine = engine_with_fees();
+        let mut accounts = Accounts::new();
+        accounts.get_mut(0).credit(Decimal::MAX).unwrap();
+
+        let deposit = transaction(TxType::Deposit, 1, 1, Some(100.0));
+        let result = apply(&mut engine, &mut accounts, deposit);
+        assert!(matches!(
+            result,
+            Err(Error::Account(AccountError::Overflow(0)))
+        ));
+        assert_eq!(state(&mut accounts, 1).total, Funds::ZERO);
+        assert_eq!(state(&mut accounts, 0).available, Decimal::MAX);
+        assert_eq!(engine.sequence(), 0);
+        assert!(!engine.ledger().contains(1));
+    }
+
+    #[test]
+    fn test_chargeback_reverses_the_fee() {
+        let mut engine = engine_with_fees();
+        let mut accounts = Accounts::new();
+        let deposit = transaction(TxType::Deposit, 1, 1, Some(100.0));
+        apply(&mut engine, &mut accounts, deposit).unwrap();
+        let dispute = transaction(TxType::Dispute, 1, 1, None);
+        apply(&mut engine, &mut accounts, dispute).unwrap();
+        assert_eq!(state(&mut accounts, 1).held, funds(99.0));
+
+        let chargeback = transaction(TxType::Chargeback, 1, 1, None);
+        apply(&mut engine, &mut accounts, chargeback).unwrap();
+
+        let account = state(&mut accounts, 1);
+        assert_eq!(account.available, Funds::ZERO);
+        assert_eq!(account.held, Funds::ZERO);
+        assert_eq!(account.fees, Funds::ZERO);
+        assert_eq!(state(&mut accounts, 0).available, Funds::ZERO);
+    }
+
+    #[test]
+    fn test_proportional_fee() {
+        let entry = LedgerEntry {
+            variant: TxType::Deposit,
+            client: 1,
+            amount: funds(101.0),
+            state: TxState::Disputed,
+            seq: 0,
+            to: None,
+            timestamp: None,
+            disputed_at: None,
+            fee: funds(1.0),
+            refunded: Funds::ZERO,
+        };
+        let half_even = RoundingStrategy::HalfEven;
+
+        assert_eq!(
+            proportional_fee(&entry, funds(100.0), half_even),
+            funds(1.0)
+        );
+        assert_eq!(
+            proportional_fee(&entry, funds(25.0), half_even),
+            funds(0.25)
+        );
+        assert_eq!(
+            proportional_fee(&entry, Funds::ZERO, half_even),
+            Funds::ZERO
+        );
+    }
+
+    #[test]
+    fn test_withdrawals_above_the_limits_are_rejected() {
+        let limits = serde_json::from_str(
+            r#"{ "clients": { "1": { "max_transaction": "50", "daily_withdrawal": "60" } } }"#,
+        )
+        .unwrap();
+        let mut engine = Engine::new(EngineConfig {
+            limits: Some(limits),
+            ..Default::default()
+        })
+        .unwrap();
+        let mut accounts = Accounts::new();
+        apply(
+            &mut engine,
+            &mut accounts,
+            transaction(TxType::Deposit, 1, 1, Some(40.0)),
+        )
+        .unwrap();
+        apply(
+            &mut engine,
+            &mut accounts,
+            transaction(TxType::Deposit, 1, 2, Some(40.0)),
+        )
+        .unwrap();
+
+        let result = apply(
+            &mut engine,
+            &mut accounts,
+            transaction(TxType::Deposit, 1, 3, Some(51.0)),
+        );
+        assert!(matches!(
+            result,
+            Err(Error::Account(AccountError::TransactionLimitExceeded(1, limit, _))) if limit == funds(50.0)
+        ));
+
+        let withdrawal = at(transaction(TxType::Withdrawal, 1, 4, Some(40.0)), 1_000);
+        apply(&mut engine, &mut accounts, withdrawal).unwrap();
+        let withdrawal = at(transaction(TxType::Withdrawal, 1, 5, Some(30.0)), 2_000);
+        let result = apply(&mut engine, &mut accounts, withdrawal);
+        assert!(matches!(
+            result,
+            Err(Error::Account(AccountError::DailyLimitExceeded(1, limit, _))) if limit == funds(60.0)
+        ));
+        assert_eq!(state(&mut accounts, 1).available, funds(40.0));
+    }
+
+    #[test]
+    fn test_transfers_count_towards_the_withdrawal_limits() {
+        let limits =
+            serde_json::from_str(r#"{ "clients": { "1": { "daily_withdrawal": "60" } } }"#)
+                .unwrap();
+        let mut engine = Engine::new(EngineConfig {
+            limits: Some(limits),
+            ..Default::default()
+        })
+        .unwrap();
+        let mut accounts = Accounts::new();
+        apply(
+            &mut engine,
+            &mut accounts,
+            transaction(TxType::Deposit, 1, 1, Some(100.0)),
+        )
+        .unwrap();
+
+        apply(
+            &mut engine,
+            &mut accounts,
+            at(transfer(1, 2, 2, 40.0), 1_000),
+        )
+        .unwrap();
+        let withdrawal = at(transaction(TxType::Withdrawal, 1, 3, Some(30.0)), 2_000);
+        let result = apply(&mut engine, &mut accounts, withdrawal);
+        assert!(matches!(
+            result,
+            Err(Error::Account(AccountError::DailyLimitExceeded(1, limit, _))) if limit == funds(60.0)
+        ));
+        let result = apply(
+            &mut engine,
+            &mut accounts,
+            at(transfer(1, 2, 4, 30.0), 3_000),
+        );
+        assert!(matches!(
+            result,
+            Err(Error::Account(AccountError::DailyLimitExceeded(1, limit, _))) if limit == funds(60.0)
+        ));
+        assert_eq!(state(&mut accounts, 1).available, funds(60.0));
+    }
+
+    #[test]
+    fn test_authorizations_are_checked_and_captures_count_towards_the_limits() {
+        let limits =
+            serde_json::from_str(r#"{ "clients": { "1": { "daily_withdrawal": "60" } } }"#)
+                .unwrap();
+        let mut engine = Engine::new(EngineConfig {
+            limits: Some(limits),
+            ..Default::default()
+        })
+        .unwrap();
+        let mut accounts = Accounts::new();
+        apply(
+            &mut engine,
+            &mut accounts,
+            transaction(TxType::Deposit, 1, 1, Some(100.0)),
+        )
+        .unwrap();
+
+        let authorize = at(transaction(TxType::Authorize, 1, 2, Some(70.0)), 1_000);
+        let result = apply(&mut engine, &mut accounts, authorize);
+        assert!(matches!(
+            result,
+            Err(Error::Account(AccountError::DailyLimitExceeded(1, limit, _))) if limit == funds(60.0)
+        ));
+
+        let authorize = at(transaction(TxType::Authorize, 1, 3, Some(50.0)), 2_000);
+        apply(&mut engine, &mut accounts, authorize).unwrap();
+        let capture = at(transaction(TxType::Capture, 1, 3, None), 3_000);
+        apply(&mut engine, &mut accounts, capture).unwrap();
+        let withdrawal = at(transaction(TxType::Withdrawal, 1, 4, Some(20.0)), 4_000);
+        let result = apply(&mut engine, &mut accounts, withdrawal);
+        assert!(matches!(
+            result,
+            Err(Error::Account(AccountError::DailyLimitExceeded(1, limit, _))) if limit == funds(60.0)
+        ));
+        assert_eq!(state(&mut accounts, 1).total, funds(50.0));
+    }
+
+    /// Holds every withdrawal.
+    struct HoldWithdrawals;
+
+    impl RiskRule for HoldWithdrawals {
+        fn name(&self) -> &str {
+            "hold_withdrawals"
+        }
+
+        fn evaluate(&self, transaction: &Transaction, _context: &RiskContext) -> Verdict {
+            if transaction.variant == TxType::Withdrawal {
+                Verdict::Hold
+            } else {
+                Verdict::Allow
+            }
+        }
+    }
+
+    #[test]
+    fn test_risk_rules_hold_and_reject_transactions() {
+        let risk = serde_json::from_str(
+            r#"[{"rule":"repeated_disputes","max_disputes":0,"window":60,"action":"reject"}]"#,
+        )
+        .unwrap();
+        let mut engine = Engine::new(EngineConfig {
+            risk,
+            ..Default::default()
+        })
+        .unwrap();
+        engine.add_rule(Box::new(HoldWithdrawals));
+        let mut accounts = Accounts::new();
+        apply(
+            &mut engine,
+            &mut accounts,
+            transaction(TxType::Deposit, 1, 1, Some(10.0)),
+        )
+        .unwrap();
+
+        let result = apply(
+            &mut engine,
+            &mut accounts,
+            transaction(TxType::Withdrawal, 1, 2, Some(5.0)),
+        );
+        assert!(matches!(
+            result,
+            Err(Error::Transaction(TransactionError::HeldForReview(2, _)))
+        ));
+        let result = apply(
+            &mut engine,
+            &mut accounts,
+            transaction(TxType::Dispute, 1, 1, None),
+        );
+        assert!(matches!(
+            result,
+            Err(Error::Transaction(TransactionError::RejectedByRule(1, rule))) if rule == "repeated_disputes"
+        ));
+
+        assert_eq!(state(&mut accounts, 1).available, funds(10.0));
+        assert_eq!(engine.held().len(), 1);
+        assert_eq!(engine.held()[0].tx, 2);
+        assert_eq!(engine.held()[0].rule, "hold_withdrawals");
+    }
+
+    #[test]
+    fn test_lock_policies_record_the_reason() {
+        let mut engine = Engine::new(EngineConfig {
+            locks: LockPolicies {
+                negative_balance: true,
+                ..Default::default()
+            },
+            ..Default::default()
+        })
+        .unwrap();
+        let mut accounts = Accounts::new();
+        apply(
+            &mut engine,
+            &mut accounts,
+            transaction(TxType::Deposit, 1, 1, Some(10.0)),
+        )
+        .unwrap();
+        apply(
+            &mut engine,
+            &mut accounts,
+            transaction(TxType::Withdrawal, 1, 2, Some(8.0)),
+        )
+        .unwrap();
+        apply(
+            &mut engine,
+            &mut accounts,
+            transaction(TxType::Dispute, 1, 1, None),
+        )
+        .unwrap();
+
+        let account = state(&mut accounts, 1);
+        assert_eq!(account.available, funds(-8.0));
+        assert!(account.locked);
+        assert_eq!(account.lock_reason, Some(LockReason::NegativeBalance));
+    }
+
+    #[test]
+    fn test_unlocked_account_stays_unlocked_until_a_new_chargeback() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("audit.log");
+        let mut engine = Engine::default();
+        engine.set_audit_log(AuditLog::open(&path).unwrap());
+        let mut accounts = Accounts::new();
+        for (variant, tx, amount) in [
+            (TxType::Deposit, 1, Some(10.0)),
+            (TxType::Deposit, 2, Some(5.0)),
+            (TxType::Dispute, 1, None),
+            (TxType::Chargeback, 1, None),
+        ] {
+            apply(
+                &mut engine,
+                &mut accounts,
+                transaction(variant, 1, tx, amount),
+            )
+            .unwrap();
+        }
+        assert!(state(&mut accounts, 1).locked);
+
+        engine.unlock(&mut accounts, 1);
+        apply(
+            &mut engine,
+            &mut accounts,
+            transaction(TxType::Deposit, 1, 3, Some(1.0)),
+        )
+        .unwrap();
+        assert!(!state(&mut accounts, 1).locked);
+
+        apply(
+            &mut engine,
+            &mut accounts,
+            transaction(TxType::Dispute, 1, 2, None),
+        )
+        .unwrap();
+        apply(
+            &mut engine,
+            &mut accounts,
+            transaction(TxType::Chargeback, 1, 2, None),
+        )
+        .unwrap();
+        assert!(state(&mut accounts, 1).locked);
+
+        engine.flush_audit_log().unwrap();
+        let history = crate::audit::history(&path, 1).unwrap();
+        let unlock = history
+            .iter()
+            .find(|entry| entry.operation == "unlock")
+            .unwrap();
+        assert!(unlock.before.locked);
+        assert!(!unlock.after.locked);
+    }
+
+    #[test]
+    fn test_authorization_is_captured_in_parts() {
+        let mut engine = Engine::default();
+        let mut accounts = Accounts::new();
+        apply(
+            &mut engine,
+            &mut accounts,
+            transaction(TxType::Deposit, 1, 1, Some(10.0)),
+        )
+        .unwrap();
+        apply(
+            &mut engine,
+            &mut accounts,
+            transaction(TxType::Authorize, 1, 2, Some(6.0)),
+        )
+        .unwrap();
+        let account = state(&mut accounts, 1);
+        assert_eq!(account.available, funds(4.0));
+        assert_eq!(account.held, funds(6.0));
+
+        apply(
+            &mut engine,
+            &mut accounts,
+            transaction(TxType::Capture, 1, 2, Some(2.0)),
+        )
+        .unwrap();
+        let result = apply(
+            &mut engine,
+            &mut accounts,
+            transaction(TxType::Capture, 1, 2, Some(5.0)),
+        );
+        assert!(matches!(
+            result,
+            Err(Error::Transaction(
+                TransactionError::CaptureExceedsAuthorization(2)
+            ))
+        ));
+        apply(
+            &mut engine,
+            &mut accounts,
+            transaction(TxType::Capture, 1, 2, None),
+        )
+        .unwrap();
+
+        let account = state(&mut accounts, 1);
+        assert_eq!(account.available, funds(4.0));
+        assert_eq!(account.held, Funds::ZERO);
+        assert_eq!(account.total, funds(4.0));
+        let now = engine.sequence();
+        let entry = engine.ledger_mut().get(2, now).unwrap().unwrap();
+        assert_eq!(entry.state, TxState::Captured);
+
+        let result = apply(
+            &mut engine,
+            &mut accounts,
+            transaction(TxType::Void, 1, 2, None),
+        );
+        assert!(matches!(
+            result,
+            Err(Error::Transaction(TransactionError::MissingAuthorization(
+                2
+            )))
+        ));
+    }
+
+    #[test]
+    fn test_void_releases_the_authorization() {
+        let mut engine = Engine::default();
+        let mut accounts = Accounts::new();
+        apply(
+            &mut engine,
+            &mut accounts,
+            transaction(TxType::Deposit, 1, 1, Some(10.0)),
+        )
+        .unwrap();
+        apply(
+            &mut engine,
+            &mut accounts,
+            transaction(TxType::Authorize, 1, 2, Some(6.0)),
+        )
+        .unwrap();
+
+        let result = apply(
+            &mut engine,
+            &mut accounts,
+            transaction(TxType::Void, 2, 2, None),
+        );
+        assert!(matches!(
+            result,
+            Err(Error::Transaction(TransactionError::WrongClient(
+                2,
+                1,
+                2,
+                TxType::Void
+            )))
+        ));
+        assert!(result.unwrap_err().to_string().contains("in a void"));
+        let result = apply(
+            &mut engine,
+            &mut accounts,
+            transaction(TxType::Capture, 1, 1, None),
+        );
+        assert!(matches!(
+            result,
+            Err(Error::Transaction(TransactionError::MissingAuthorization(
+                1
+            )))
+        ));
+
+        apply(
+            &mut engine,
+            &mut accounts,
+            transaction(TxType::Void, 1, 2, None),
+        )
+        .unwrap();
+        let account = state(&mut accounts, 1);
+        assert_eq!(account.available, funds(10.0));
+        assert_eq!(account.held, Funds::ZERO);
+        assert!(account.authorizations.is_empty());
+    }
+
+    #[test]
+    fn test_expired_authorizations_are_voided() {
+        let mut engine = Engine::new(EngineConfig {
+            auth_expiry: Some(60),
+            ..Default::default()
+        })
+        .unwrap();
+        let mut accounts = Accounts::new();
+        let deposit = transaction(TxType::Deposit, 1, 1, Some(10.0));
+        let authorize = transaction(TxType::Authorize, 1, 2, Some(6.0));
+        apply(&mut engine, &mut accounts, at(deposit, 1_000)).unwrap();
+        apply(&mut engine, &mut accounts, at(authorize, 1_000)).unwrap();
+
+        let capture = transaction(TxType::Capture, 1, 2, None);
+        let result = apply(&mut engine, &mut accounts, at(capture, 1_061));
+        assert!(matches!(
+            result,
+            Err(Error::Transaction(TransactionError::AuthorizationExpired(
+                2
+            )))
+        ));
+        assert_eq!(state(&mut accounts, 1).held, funds(6.0));
+
+        // Any later transaction moves the clock past the expiry.
+        let other = transaction(TxType::Deposit, 2, 3, Some(1.0));
+        apply(&mut engine, &mut accounts, at(other, 1_061)).unwrap();
+
+        let account = state(&mut accounts, 1);
+        assert_eq!(account.available, funds(10.0));
+        assert_eq!(account.held, Funds::ZERO);
+        let now = engine.sequence();
+        let entry = engine.ledger_mut().get(2, now).unwrap().unwrap();
+        assert_eq!(entry.state, TxState::Voided);
+    }
+
+    #[test]
+    fn test_deposit_is_refunded_in_parts() {
+        let mut engine = Engine::default();
+        let mut accounts = Accounts::new();
+        apply(
+            &mut engine,
+            &mut accounts,
+            transaction(TxType::Deposit, 1, 1, Some(10.0)),
+        )
+        .unwrap();
+        apply(
+            &mut engine,
+            &mut accounts,
+            transaction(TxType::Deposit, 1, 2, Some(5.0)),
+        )
+        .unwrap();
+
+        apply(
+            &mut engine,
+            &mut accounts,
+            transaction(TxType::Refund, 1, 1, Some(4.0)),
+        )
+        .unwrap();
+        let result = apply(
+            &mut engine,
+            &mut accounts,
+            transaction(TxType::Refund, 1, 1, Some(7.0)),
+        );
+        assert!(matches!(
+            result,
+            Err(Error::Transaction(TransactionError::RefundExceedsDeposit(
+                1
+            )))
+        ));
+        apply(
+            &mut engine,
+            &mut accounts,
+            transaction(TxType::Refund, 1, 1, None),
+        )
+        .unwrap();
+
+        let account = state(&mut accounts, 1);
+        assert_eq!(account.available, funds(5.0));
+        assert_eq!(account.total, funds(5.0));
+        assert!(!account.locked);
+        let now = engine.sequence();
+        let entry = engine.ledger_mut().get(1, now).unwrap().unwrap();
+        assert_eq!(entry.refunded, funds(10.0));
+
+        let result = apply(
+            &mut engine,
+            &mut accounts,
+            transaction(TxType::Refund, 1, 1, Some(1.0)),
+        );
+        assert!(matches!(
+            result,
+            Err(Error::Transaction(TransactionError::RefundExceedsDeposit(
+                1
+            )))
+        ));
+    }
+
+    #[test]
+    fn test_only_undisputed_deposits_are_refunded() {
+        let mut engine = Engine::default();
+        let mut accounts = Accounts::new();
+        apply(
+            &mut engine,
+            &mut accounts,
+            transaction(TxType::Deposit, 1, 1, Some(10.0)),
+        )
+        .unwrap();
+        apply(
+            &mut engine,
+            &mut accounts,
+            transaction(TxType::Withdrawal, 1, 2, Some(1.0)),
+        )
+        .unwrap();
+
+        for (client, tx, error) in [
+            (1, 2, TransactionError::NotRefundable(2)),
+            (1, 3, TransactionError::NotRefundable(3)),
+            (2, 1, TransactionError::WrongClient(1, 1, 2, TxType::Refund)),
+        ] {
+            let result = apply(
+                &mut engine,
+                &mut accounts,
+                transaction(TxType::Refund, client, tx, Some(1.0)),
+            );
+            assert!(matches!(result, Err(Error::Transaction(e)) if e == error));
+        }
+
+        // A partly refunded deposit only holds what is left of it when disputed.
+        apply(
+            &mut engine,
+            &mut accounts,
+            transaction(TxType::Refund, 1, 1, Some(4.0)),
+        )
+        .unwrap();
+        apply(
+            &mut engine,
+            &mut accounts,
+            transaction(TxType::Deposit, 1, 4, Some(5.0)),
+        )
+        .unwrap();
+        apply(
+            &mut engine,
+            &mut accounts,
+            transaction(TxType::Dispute, 1, 1, None),
+        )
+        .unwrap();
+        let result = apply(
+            &mut engine,
+            &mut accounts,
+            transaction(TxType::Refund, 1, 1, Some(1.0)),
+        );
+        assert!(matches!(
+            result,
+            Err(Error::Transaction(TransactionError::NotRefundable(1)))
+        ));
+
+        let account = state(&mut accounts, 1);
+        assert_eq!(account.available, funds(4.0));
+        assert_eq!(account.held, funds(6.0));
+        assert_eq!(account.total, funds(10.0));
+    }
+
+    #[test]
+    fn test_metrics_count_rows_by_type_and_result() {
+        let mut engine = Engine::default();
+        let mut accounts = Accounts::new();
+        apply(
+            &mut engine,
+            &mut accounts,
+            transaction(TxType::Deposit, 1, 1, Some(10.0)),
+        )
+        .unwrap();
+        apply(
+            &mut engine,
+            &mut accounts,
+            transaction(TxType::Withdrawal, 1, 2, Some(20.0)),
+        )
+        .unwrap_err();
+        let legs = vec![
+            transaction(TxType::Deposit, 2, 3, Some(1.0)),
+            transaction(TxType::Withdrawal, 2, 4, Some(5.0)),
+        ];
+        engine
+            .apply_batch(
+                &mut accounts,
+                legs.into_iter()
+                    .map(|leg| Transaction {
+                        batch_id: Some(1),
+                        ..leg
+                    })
+                    .collect(),
+            )
+            .unwrap_err();
+
+        let text = engine.metrics().prometheus();
+        for line in [
+            "{type=\"deposit\",result=\"accepted\"} 1",
+            "{type=\"deposit\",result=\"batch.rejected\"} 1",
+            "{type=\"withdrawal\",result=\"account.insufficient_funds\"} 2",
+            "payments_engine_apply_seconds_count 3",
+        ] {
+            assert!(text.contains(line), "{}", line);
+        }
+    }
+
+    #[test]
+    fn test_audit_log_records_applied_and_rejected_operations() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("audit.log");
+        let mut engine = Engine::default();
+        engine.set_audit_log(AuditLog::open(&path).unwrap());
+        let mut accounts = Accounts::new();
+
+        apply(
+            &mut engine,
+            &mut accounts,
+            transaction(TxType::Deposit, 1, 1, Some(10.0)),
+        )
+        .unwrap();
+        apply(&mut engine, &mut accounts, transfer(1, 2, 2, 4.0)).unwrap();
+        apply(
+            &mut engine,
+            &mut accounts,
+            transaction(TxType::Withdrawal, 1, 3, Some(20.0)),
+        )
+        .unwrap_err();
+        let legs = vec![
+            leg(transaction(TxType::Deposit, 2, 4, Some(1.0)), 1),
+            leg(transaction(TxType::Withdrawal, 2, 5, Some(50.0)), 1),
+        ];
+        engine.apply_batch(&mut accounts, legs).unwrap_err();
+        engine.flush_audit_log().unwrap();
+
+        let history = crate::audit::history(&path, 1).unwrap();
+        let operations: Vec<(&str, &str)> = history
+            .iter()
+            .map(|entry| (entry.operation.as_str(), entry.result.as_str()))
+            .collect();
+        assert_eq!(
+            operations,
+            vec![
+                ("deposit", "accepted"),
+                ("transfer", "accepted"),
+                ("withdrawal", "account.insufficient_funds"),
+            ]
+        );
+        assert_eq!(history[1].before.available, funds(10.0));
+        assert_eq!(history[1].after.available, funds(6.0));
+        assert_eq!(history[2].before, history[2].after);
+
+        // The receiving side of the transfer, and the legs of the rejected batch.
+        let history = crate::audit::history(&path, 2).unwrap();
+        let results: Vec<&str> = history.iter().map(|entry| entry.result.as_str()).collect();
+        assert_eq!(
+            results,
+            vec!["accepted", "batch.rejected", "account.insufficient_funds"]
+        );
+        assert_eq!(history[0].after.total, funds(4.0));
+        assert!(history[1..].iter().all(|entry| entry.before == entry.after));
+    }
+
+    fn transfer(from: Client, to: Client, tx: Tx, amount: f32) -> Transaction {
+        Transaction {
+            to: Some(to),
+            ..transaction(TxType::Transfer, from, tx, Some(amount))
+        }
+    }
+
+    #[test]
+    fn test_transfer_is_rolled_back_when_a_side_fails() {
+        let mut engine = Engine::default();
+        let mut accounts = Accounts::new();
+        apply(
+            &mut engine,
+            &mut accounts,
+            transaction(TxType::Deposit, 1, 1, Some(10.0)),
+        )
+        .unwrap();
+        apply(&mut engine, &mut accounts, transfer(1, 2, 2, 4.0)).unwrap();
+        assert_eq!(state(&mut accounts, 1).available, funds(6.0));
+        assert_eq!(state(&mut accounts, 2).available, funds(4.0));
+
+        accounts.get_mut(3).lock(LockReason::Chargebacks);
+        let result = apply(&mut engine, &mut accounts, transfer(1, 3, 3, 4.0));
+        assert!(matches!(
+            result,
+            Err(Error::Account(AccountError::AccountLocked(3)))
+        ));
+        let result = apply(&mut engine, &mut accounts, transfer(2, 1, 4, 5.0));
+        assert!(matches!(
+            result,
+            Err(Error::Account(AccountError::InsufficientFunds(2, ..)))
+        ));
+
+        assert_eq!(state(&mut accounts, 1).available, funds(6.0));
+        assert_eq!(state(&mut accounts, 2).available, funds(4.0));
+        assert_eq!(state(&mut accounts, 3).available, Funds::ZERO);
+        assert!(!engine.ledger().contains(3));
+    }
+
+    #[test]
+    fn test_transfer_is_disputed_as_a_unit() {
+        let mut engine = Engine::default();
+        let mut accounts = Accounts::new();
+        apply(
+            &mut engine,
+            &mut accounts,
+            transaction(TxType::Deposit, 1, 1, Some(10.0)),
+        )
+        .unwrap();
+        apply(&mut engine, &mut accounts, transfer(1, 2, 2, 4.0)).unwrap();
+
+        // The sender disputes the transfer, which holds the funds on the receiving side.
+        let result = apply(
+            &mut engine,
+            &mut accounts,
+            transaction(TxType::Dispute, 2, 2, None),
+        );
+        assert!(matches!(
+            result,
+            Err(Error::Transaction(TransactionError::WrongClient(
+                2,
+                1,
+                2,
+                TxType::Dispute
+            )))
+        ));
+        apply(
+            &mut engine,
+            &mut accounts,
+            transaction(TxType::Dispute, 1, 2, None),
+        )
+        .unwrap();
+        let receiver = state(&mut accounts, 2);
+        assert_eq!(receiver.available, Funds::ZERO);
+        assert_eq!(receiver.held, funds(4.0));
+
+        // The chargeback gives the funds back to the sender.
+        apply(
+            &mut engine,
+            &mut accounts,
+            transaction(TxType::Chargeback, 1, 2, None),
+        )
+        .unwrap();
+        let sender = state(&mut accounts, 1);
+        assert_eq!(sender.available, funds(10.0));
+        assert!(!sender.locked);
+        let receiver = state(&mut accounts, 2);
+        assert_eq!(receiver.held, Funds::ZERO);
+        assert_eq!(receiver.lock_reason, Some(LockReason::Chargebacks));
+    }
+
+    #[test]
+    fn test_transfer_dispute_settles_on_locked_accounts() {
+        let mut engine = Engine::default();
+        let mut accounts = Accounts::new();
+        apply(
+            &mut engine,
+            &mut accounts,
+            transaction(TxType::Deposit, 1, 1, Some(10.0)),
+        )
+        .unwrap();
+        apply(&mut engine, &mut accounts, transfer(1, 2, 2, 4.0)).unwrap();
+        apply(&mut engine, &mut accounts, transfer(1, 2, 3, 3.0)).unwrap();
+        for tx in [2, 3] {
+            apply(
+                &mut engine,
+                &mut accounts,
+                transaction(TxType::Dispute, 1, tx, None),
+            )
+            .unwrap();
+        }
+
+        // The receiver has no say in the resolution.
+        accounts.get_mut(2).lock(LockReason::OpenDisputes);
+        apply(
+            &mut engine,
+            &mut accounts,
+            transaction(TxType::Resolve, 1, 2, None),
+        )
+        .unwrap();
+        let receiver = state(&mut accounts, 2);
+        assert_eq!(receiver.available, funds(4.0));
+        assert_eq!(receiver.held, funds(3.0));
+
+        // Nor does the sender in getting the funds back.
+        accounts.get_mut(1).lock(LockReason::OpenDisputes);
+        apply(
+            &mut engine,
+            &mut accounts,
+            transaction(TxType::Chargeback, 1, 3, None),
+        )
+        .unwrap();
+        assert_eq!(state(&mut accounts, 1).available, funds(6.0));
+        assert_eq!(state(&mut accounts, 2).held, Funds::ZERO);
+    }
+
+    fn leg(transaction: Transaction, batch_id: BatchId) -> Transaction {
+        Transaction {
+            batch_id: Some(batch_id),
+            ..transaction
+        }
+    }
+
+    #[test]
+    fn test_batch_is_applied_atomically() {
+        let mut engine = Engine::default();
+        let mut accounts = Accounts::new();
+        apply(
+            &mut engine,
+            &mut accounts,
+            transaction(TxType::Deposit, 1, 1, Some(10.0)),
+        )
+        .unwrap();
+
+        // A payout split across two clients.
+        engine
+            .apply_batch(
+                &mut accounts,
+                vec![
+                    leg(transfer(1, 2, 2, 6.0), 7),
+                    leg(transfer(1, 3, 3, 4.0), 7),
+                ],
+            )
+            .unwrap();
+        assert_eq!(state(&mut accounts, 1).available, Funds::ZERO);
+        assert_eq!(state(&mut accounts, 2).available, funds(6.0));
+        assert_eq!(state(&mut accounts, 3).available, funds(4.0));
+        assert_eq!(engine.sequence(), 3);
+    }
+
+    #[test]
+    fn test_rejected_batch_reports_every_failed_leg() {
+        let mut engine = Engine::default();
+        let mut accounts = Accounts::new();
+        apply(
+            &mut engine,
+            &mut accounts,
+            transaction(TxType::Deposit, 1, 1, Some(10.0)),
+        )
+        .unwrap();
+
+        let result = engine.apply_batch(
+            &mut accounts,
+            vec![
+                leg(transfer(1, 2, 2, 6.0), 7),
+                leg(transfer(1, 3, 3, 6.0), 7),
+                leg(transaction(TxType::Deposit, 4, 4, Some(1.0)), 7),
+                leg(transaction(TxType::Dispute, 1, 1, None), 7),
+            ],
+        );
+        let Err(Error::Batch(BatchError::Rejected(7, failed))) = result else {
+            panic!("the batch should be rejected");
+        };
+        let failed: Vec<Tx> = failed.iter().map(|(tx, _)| *tx).collect();
+        assert_eq!(failed, vec![3, 1]);
+
+        // Nothing was applied, not even the legs that succeeded.
+        assert_eq!(state(&mut accounts, 1).available, funds(10.0));
+        assert_eq!(state(&mut accounts, 2).available, Funds::ZERO);
+        assert_eq!(state(&mut accounts, 4).available, Funds::ZERO);
+        assert_eq!(engine.sequence(), 1);
+        assert!(!engine.ledger().contains(2));
+        assert!(!engine.ledger().contains(4));
+
+        // So the same legs can be sent again.
+        apply(&mut engine, &mut accounts, transfer(1, 2, 2, 6.0)).unwrap();
     }
 }
@@ -3,8 +3,17 @@
 //! I'm aware that most of this stuff can be replaced with the `anyhow` crate or similar. However,
 //! I wanted to implement the errors myself because it helps me find errors in the application and
 //! think about the process a bit more.
+//!
+//! Besides their messages, errors are meant to be consumed by machines: every error has a stable
+//! [`code`](Error::code), a [`Category`] and a [`Severity`], and serializes as an [`ErrorReport`]
+//! with its context (transaction, client, amounts...). Codes are never changed nor reused, only
+//! added.
 
-use crate::primitives::{Client, Tx};
+use crate::{
+    primitives::{BatchId, Client, Funds, Tx},
+    transactions::TxType,
+};
+use serde::Serialize;
 
 // NOTE: this could be used for a broader, friendlier interface for errors. However, I find more concrete errors easier and faster to iterate and prototype with, since I see where and how I fail.
 //
@@ -24,9 +33,262 @@ pub(crate) enum Error {
     Io(std::io::Error),
     /// Error while dealing with CSV files.
     Csv(csv::Error),
+    /// Error while dealing with snapshots.
+    Snapshot(SnapshotError),
+    /// Error while dealing with the write-ahead log.
+    Wal(WalError),
+    /// Error while dealing with the fee or limit schedules.
+    Schedule(ScheduleError),
+    /// Error while dealing with the configuration file.
+    Config(ConfigError),
+    /// Error while applying a batch of transactions.
+    Batch(BatchError),
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Account(error) => Some(error),
+            Error::Transaction(error) => Some(error),
+            Error::Io(error) => Some(error),
+            Error::Csv(error) => Some(error),
+            Error::Snapshot(error) => Some(error),
+            Error::Wal(error) => Some(error),
+            Error::Schedule(error) => Some(error),
+            Error::Config(error) => Some(error),
+            Error::Batch(error) => Some(error),
+        }
+    }
+}
+
+impl Error {
+    /// Stable identifier of the error, e.g. `transaction.duplicate_found`.
+    pub(crate) fn code(&self) -> &'static str {
+        match self {
+            Error::Account(error) => error.code(),
+            Error::Transaction(error) => error.code(),
+            Error::Io(_) => "io",
+            Error::Csv(_) => "csv",
+            Error::Snapshot(SnapshotError::UnsupportedVersion(_)) => "snapshot.unsupported_version",
+            Error::Snapshot(SnapshotError::Malformed(_)) => "snapshot.malformed",
+            Error::Wal(WalError::Corrupted(_)) => "wal.corrupted",
+            Error::Wal(WalError::Gap(..)) => "wal.gap",
+            Error::Wal(WalError::ReplayRejected(..)) => "wal.replay_rejected",
+            Error::Schedule(ScheduleError::Malformed(_)) => "schedule.malformed",
+            Error::Schedule(ScheduleError::UnknownTier(_)) => "schedule.unknown_tier",
+            Error::Schedule(ScheduleError::InvalidRule(_)) => "schedule.invalid_rule",
+            Error::Config(ConfigError::Malformed(_)) => "config.malformed",
+            Error::Config(ConfigError::Invalid(..)) => "config.invalid",
+            Error::Batch(BatchError::Rejected(..)) => "batch.rejected",
+        }
+    }
+
+    /// What the error is about.
+    pub(crate) fn category(&self) -> Category {
+        match self {
+            Error::Account(error) => error.category(),
+            Error::Transaction(error) => error.category(),
+            Error::Io(_) | Error::Csv(_) => Category::Io,
+            Error::Snapshot(_) | Error::Wal(_) => Category::Storage,
+            Error::Schedule(_) | Error::Config(_) => Category::Configuration,
+            Error::Batch(_) => Category::Batch,
+        }
+    }
+
+    /// What the error means for the processing.
+    pub(crate) fn severity(&self) -> Severity {
+        match self {
+            Error::Transaction(TransactionError::HeldForReview(..)) => Severity::Held,
+            Error::Account(_) | Error::Transaction(_) | Error::Batch(_) => Severity::Rejected,
+            Error::Io(_)
+            | Error::Csv(_)
+            | Error::Snapshot(_)
+            | Error::Wal(_)
+            | Error::Schedule(_)
+            | Error::Config(_) => Severity::Fatal,
+        }
+    }
+
+    /// The error a leg of a batch was rejected with: its own, or the batch error when only other
+    /// legs failed. Any other error is the one of every leg.
+    pub(crate) fn for_leg(&self, tx: Tx) -> &Error {
+        match self {
+            Error::Batch(BatchError::Rejected(_, failed)) => failed
+                .iter()
+                .find(|(failed, _)| *failed == tx)
+                .map_or(self, |(_, e)| e),
+            _ => self,
+        }
+    }
+
+    /// The report of the error the given transaction was rejected with (see [`Error::for_leg`]),
+    /// with the transaction in its context.
+    pub(crate) fn report_for(&self, tx: Tx) -> ErrorReport {
+        let mut report = self.for_leg(tx).report();
+        report.context.tx.get_or_insert(tx);
+        report
+    }
+
+    /// The error with its code, category, severity and context, ready to be serialized.
+    pub(crate) fn report(&self) -> ErrorReport {
+        let mut report = ErrorReport {
+            code: self.code(),
+            category: self.category(),
+            severity: self.severity(),
+            message: self.to_string(),
+            context: ErrorContext::default(),
+            legs: Vec::new(),
+            source: None,
+        };
+
+        let context = &mut report.context;
+        match self {
+            Error::Account(error) => *context = error.context(),
+            Error::Transaction(error) => *context = error.context(),
+            Error::Io(_) => {}
+            Error::Csv(error) => context.line = error.position().map(|position| position.line()),
+            Error::Snapshot(SnapshotError::UnsupportedVersion(version)) => {
+                context.version = Some(*version)
+            }
+            Error::Snapshot(SnapshotError::Malformed(_)) => {}
+            Error::Wal(WalError::Corrupted(line)) => context.line = Some(*line),
+            Error::Wal(WalError::Gap(expected, found)) => {
+                context.expected_seq = Some(*expected);
+                context.seq = Some(*found);
+            }
+            Error::Wal(WalError::ReplayRejected(seq, error)) => {
+                context.seq = Some(*seq);
+                report.source = Some(Box::new(error.report()));
+            }
+            Error::Schedule(ScheduleError::Malformed(_)) => {}
+            Error::Schedule(
+                ScheduleError::UnknownTier(tier) | ScheduleError::InvalidRule(tier),
+            ) => context.tier = Some(tier.clone()),
+            Error::Config(ConfigError::Malformed(_)) => {}
+            Error::Config(ConfigError::Invalid(setting, _)) => {
+                context.setting = Some(setting.to_string())
+            }
+            Error::Batch(BatchError::Rejected(batch, legs)) => {
+                context.batch = Some(*batch);
+                report.legs = legs
+                    .iter()
+                    .map(|(tx, error)| error.report_for(*tx))
+                    .collect();
+            }
+        }
+        report
+    }
+}
+
+impl Serialize for Error {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.report().serialize(serializer)
+    }
 }
 
-impl std::error::Error for Error {}
+/// What an error is about.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Category {
+    /// The transaction itself is malformed, or a duplicate.
+    Validation,
+    /// The accounts do not have the funds, or their funds would overflow.
+    Funds,
+    /// The transaction refers to a previous transaction that is missing or in the wrong state.
+    Reference,
+    /// The transaction arrived after a time window closed.
+    Expired,
+    /// A lock, a limit or a risk rule prevents the transaction.
+    Policy,
+    /// Some legs of a batch were rejected.
+    Batch,
+    /// Reading the input or writing the output failed.
+    Io,
+    /// The snapshot or the write-ahead log cannot be used.
+    Storage,
+    /// A schedule or a rule file cannot be used.
+    Configuration,
+}
+
+/// What an error means for the processing.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Severity {
+    /// The transaction was rejected, and the processing goes on.
+    Rejected,
+    /// The transaction was held for review, and the processing goes on.
+    Held,
+    /// The processing stops.
+    Fatal,
+}
+
+/// The values an error refers to. Only the ones relevant to the error are set.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub(crate) struct ErrorContext {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) tx: Option<Tx>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) client: Option<Client>,
+    /// The client of the original transaction, when another client referred to it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) expected_client: Option<Client>,
+    /// The amount of the operation that was rejected.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) amount: Option<Funds>,
+    /// The funds available in the account when the operation was rejected.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) available: Option<Funds>,
+    /// The limit that was hit, as an amount.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) limit: Option<Funds>,
+    /// The limit that was hit, as a number of withdrawals.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) count: Option<u32>,
+    /// The length of the window of the limit, in seconds.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) window: Option<u64>,
+    /// The risk rule that held or rejected the transaction.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) rule: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) batch: Option<BatchId>,
+    /// The sequence of the write-ahead log entry.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) seq: Option<u64>,
+    /// The sequence the write-ahead log should have continued with.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) expected_seq: Option<u64>,
+    /// The line of the write-ahead log, or of the input.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) line: Option<u64>,
+    /// The version of the snapshot.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) version: Option<u32>,
+    /// The tier of the schedule.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) tier: Option<String>,
+    /// The setting of the configuration file, e.g. `ledger.memory_records`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) setting: Option<String>,
+}
+
+/// Machine-readable form of an [`Error`].
+#[derive(Debug, Serialize)]
+pub(crate) struct ErrorReport {
+    pub(crate) code: &'static str,
+    pub(crate) category: Category,
+    pub(crate) severity: Severity,
+    /// The message of the error, for humans.
+    pub(crate) message: String,
+    #[serde(flatten)]
+    pub(crate) context: ErrorContext,
+    /// The errors of the rejected legs, for batches.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub(crate) legs: Vec<ErrorReport>,
+    /// The error that caused this one, if it is an [`Error`] as well.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) source: Option<Box<ErrorReport>>,
+}
 
 impl std::fmt::Display for Error {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -35,6 +297,11 @@ impl std::fmt::Display for Error {
             Error::Transaction(error) => write!(f, "Error while processing transaction: {}", error),
             Error::Io(error) => write!(f, "IO related error: {}", error),
             Error::Csv(error) => write!(f, "CSV related error: {}", error),
+            Error::Snapshot(error) => write!(f, "Snapshot related error: {}", error),
+            Error::Wal(error) => write!(f, "Write-ahead log related error: {}", error),
+            Error::Schedule(error) => write!(f, "Schedule related error: {}", error),
+            Error::Config(error) => write!(f, "Configuration related error: {}", error),
+            Error::Batch(error) => write!(f, "Batch related error: {}", error),
         }
     }
 }
@@ -51,17 +318,210 @@ impl From<csv::Error> for Error {
     }
 }
 
+/// Errors while saving or loading snapshots of the application state.
+#[derive(Debug)]
+pub(crate) enum SnapshotError {
+    /// The snapshot was written with a format version this build cannot read.
+    UnsupportedVersion(u32),
+    /// The snapshot could not be (de)serialized.
+    Malformed(serde_json::Error),
+}
+
+impl From<SnapshotError> for Error {
+    fn from(err: SnapshotError) -> Self {
+        Error::Snapshot(err)
+    }
+}
+
+impl std::error::Error for SnapshotError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            SnapshotError::UnsupportedVersion(_) => None,
+            SnapshotError::Malformed(error) => Some(error),
+        }
+    }
+}
+
+impl std::fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SnapshotError::UnsupportedVersion(v) => {
+                write!(f, "Snapshot version {} is not supported", v)
+            }
+            SnapshotError::Malformed(error) => write!(f, "Snapshot is malformed: {}", error),
+        }
+    }
+}
+
+/// Errors while writing or recovering from the write-ahead log.
+#[derive(Debug)]
+pub(crate) enum WalError {
+    /// The entry at the given line could not be read.
+    Corrupted(u64),
+    /// The log skips sequence numbers: expected the first one, found the second.
+    Gap(u64, u64),
+    /// The entry with the given sequence was accepted originally, but failed when replayed.
+    ReplayRejected(u64, Box<Error>),
+}
+
+impl From<WalError> for Error {
+    fn from(err: WalError) -> Self {
+        Error::Wal(err)
+    }
+}
+
+impl std::error::Error for WalError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            WalError::Corrupted(_) | WalError::Gap(..) => None,
+            WalError::ReplayRejected(_, error) => Some(error.as_ref()),
+        }
+    }
+}
+
+impl std::fmt::Display for WalError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WalError::Corrupted(line) => write!(f, "Entry at line {} is corrupted", line),
+            WalError::Gap(expected, found) => write!(
+                f,
+                "Entries are not contiguous: expected sequence {} and found {}",
+                expected, found
+            ),
+            WalError::ReplayRejected(seq, error) => {
+                write!(f, "Entry {} could not be replayed: {}", seq, error)
+            }
+        }
+    }
+}
+
+/// Errors while loading the fee or limit schedules.
+#[derive(Debug)]
+pub(crate) enum ScheduleError {
+    /// The schedule could not be deserialized.
+    Malformed(serde_json::Error),
+    /// A client or the default tier refers to a tier that is not defined.
+    UnknownTier(String),
+    /// A rule of the given tier (or client) has negative values, or a minimum above its maximum.
+    InvalidRule(String),
+}
+
+impl From<ScheduleError> for Error {
+    fn from(err: ScheduleError) -> Self {
+        Error::Schedule(err)
+    }
+}
+
+impl std::error::Error for ScheduleError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ScheduleError::Malformed(error) => Some(error),
+            ScheduleError::UnknownTier(_) | ScheduleError::InvalidRule(_) => None,
+        }
+    }
+}
+
+impl std::fmt::Display for ScheduleError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ScheduleError::Malformed(error) => write!(f, "Schedule is malformed: {}", error),
+            ScheduleError::UnknownTier(tier) => write!(f, "Tier '{}' is not defined", tier),
+            ScheduleError::InvalidRule(tier) => write!(f, "Tier '{}' has an invalid rule", tier),
+        }
+    }
+}
+
+/// Errors while loading the configuration file.
+#[derive(Debug)]
+pub(crate) enum ConfigError {
+    /// The file could not be deserialized.
+    Malformed(toml::de::Error),
+    /// The given setting has a value that makes no sense, for the given reason.
+    Invalid(&'static str, &'static str),
+}
+
+impl From<ConfigError> for Error {
+    fn from(err: ConfigError) -> Self {
+        Error::Config(err)
+    }
+}
+
+impl std::error::Error for ConfigError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ConfigError::Malformed(error) => Some(error),
+            ConfigError::Invalid(..) => None,
+        }
+    }
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigError::Malformed(error) => write!(f, "Configuration is malformed: {}", error),
+            ConfigError::Invalid(setting, reason) => {
+                write!(f, "Setting '{}' is invalid: {}", setting, reason)
+            }
+        }
+    }
+}
+
+/// Errors while applying a batch of transactions.
+#[derive(Debug)]
+pub(crate) enum BatchError {
+    /// Some legs of the batch failed, so none was applied. Lists the failed legs with their error.
+    Rejected(BatchId, Vec<(Tx, Error)>),
+}
+
+impl From<BatchError> for Error {
+    fn from(err: BatchError) -> Self {
+        Error::Batch(err)
+    }
+}
+
+impl std::error::Error for BatchError {
+    /// The error of the first leg that failed.
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            BatchError::Rejected(_, legs) => legs.first().map(|(_, error)| error as _),
+        }
+    }
+}
+
+impl std::fmt::Display for BatchError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BatchError::Rejected(batch, legs) => {
+                write!(f, "Batch {} was rejected", batch)?;
+                for (tx, error) in legs {
+                    write!(f, "; transaction {}: {}", tx, error)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
 /// Errors while dealing with [`Account`]s.
 #[derive(Debug)]
 pub(crate) enum AccountError {
-    /// There are not enough funds in the client's account.
-    InsufficientFunds(Client),
+    /// There are not enough funds in the client's account: the given amount is needed, and the
+    /// given funds are available.
+    InsufficientFunds(Client, Funds, Funds),
     /// The client's account is locked and cannot perfom operations.
     AccountLocked(Client),
     /// The client's account overflowed.
     Overflow(Client),
     /// The client's account underflowed.
     Underflow(Client),
+    /// The given amount is above the maximum for a single transaction, which is given first.
+    TransactionLimitExceeded(Client, Funds, Funds),
+    /// Withdrawing the given amount would go above the daily limit, which is given first.
+    DailyLimitExceeded(Client, Funds, Funds),
+    /// Withdrawing the given amount would go above the monthly limit, which is given first.
+    MonthlyLimitExceeded(Client, Funds, Funds),
+    /// There were already the given number of withdrawals within the given number of seconds.
+    VelocityLimitExceeded(Client, u32, u64),
 }
 
 impl From<AccountError> for Error {
@@ -70,17 +530,100 @@ impl From<AccountError> for Error {
     }
 }
 
+impl std::error::Error for AccountError {}
+
+impl AccountError {
+    fn code(&self) -> &'static str {
+        match self {
+            AccountError::InsufficientFunds(..) => "account.insufficient_funds",
+            AccountError::AccountLocked(_) => "account.locked",
+            AccountError::Overflow(_) => "account.overflow",
+            AccountError::Underflow(_) => "account.underflow",
+            AccountError::TransactionLimitExceeded(..) => "account.transaction_limit_exceeded",
+            AccountError::DailyLimitExceeded(..) => "account.daily_limit_exceeded",
+            AccountError::MonthlyLimitExceeded(..) => "account.monthly_limit_exceeded",
+            AccountError::VelocityLimitExceeded(..) => "account.velocity_limit_exceeded",
+        }
+    }
+
+    fn category(&self) -> Category {
+        match self {
+            AccountError::InsufficientFunds(..)
+            | AccountError::Overflow(_)
+            | AccountError::Underflow(_) => Category::Funds,
+            AccountError::AccountLocked(_)
+            | AccountError::TransactionLimitExceeded(..)
+            | AccountError::DailyLimitExceeded(..)
+            | AccountError::MonthlyLimitExceeded(..)
+            | AccountError::VelocityLimitExceeded(..) => Category::Policy,
+        }
+    }
+
+    fn context(&self) -> ErrorContext {
+        match self {
+            AccountError::InsufficientFunds(client, amount, available) => ErrorContext {
+                client: Some(*client),
+                amount: Some(*amount),
+                available: Some(*available),
+                ..Default::default()
+            },
+            AccountError::AccountLocked(client)
+            | AccountError::Overflow(client)
+            | AccountError::Underflow(client) => ErrorContext {
+                client: Some(*client),
+                ..Default::default()
+            },
+            AccountError::TransactionLimitExceeded(client, limit, amount)
+            | AccountError::DailyLimitExceeded(client, limit, amount)
+            | AccountError::MonthlyLimitExceeded(client, limit, amount) => ErrorContext {
+                client: Some(*client),
+                amount: Some(*amount),
+                limit: Some(*limit),
+                ..Default::default()
+            },
+            AccountError::VelocityLimitExceeded(client, count, window) => ErrorContext {
+                client: Some(*client),
+                count: Some(*count),
+                window: Some(*window),
+                ..Default::default()
+            },
+        }
+    }
+}
+
 impl std::fmt::Display for AccountError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            AccountError::InsufficientFunds(c) => {
-                write!(f, "Account {} does not have enough funds", c)
-            }
+            AccountError::InsufficientFunds(c, amount, available) => write!(
+                f,
+                "Account {} does not have enough funds: {} needed and {} available",
+                c, amount, available
+            ),
             AccountError::AccountLocked(c) => {
                 write!(f, "Account {} is locked and cannot perform operations", c)
             }
             AccountError::Overflow(c) => write!(f, "Account {} overflowed", c),
             AccountError::Underflow(c) => write!(f, "Account {} underflowed", c),
+            AccountError::TransactionLimitExceeded(c, limit, _) => write!(
+                f,
+                "Account {} cannot move more than {} in a single transaction",
+                c, limit
+            ),
+            AccountError::DailyLimitExceeded(c, limit, _) => {
+                write!(f, "Account {} cannot withdraw more than {} a day", c, limit)
+            }
+            AccountError::MonthlyLimitExceeded(c, limit, _) => {
+                write!(
+                    f,
+                    "Account {} cannot withdraw more than {} a month",
+                    c, limit
+                )
+            }
+            AccountError::VelocityLimitExceeded(c, count, window) => write!(
+                f,
+                "Account {} cannot make more than {} withdrawals in {} seconds",
+                c, count, window
+            ),
         }
     }
 }
@@ -102,8 +645,41 @@ pub(crate) enum TransactionError {
     MissingDispute(Tx),
     /// Only a deposit transaction can be disputed.
     OnlyDepositsCanBeDisputed(Tx),
-    /// The client in the dispute is not the same as the one in the original transaction.
-    WrongClient(Tx, Client, Client),
+    /// The client of the given operation (a dispute, a capture, a refund...) is not the same as
+    /// the one of the original transaction.
+    WrongClient(Tx, Client, Client, TxType),
+    /// The transaction was processed, but its record is no longer kept in the ledger.
+    NotRetained(Tx),
+    /// The dispute arrived after the dispute window of the transaction closed.
+    DisputeWindowExpired(Tx),
+    /// The resolution or chargeback arrived after the resolve window of the dispute closed.
+    ResolveWindowExpired(Tx),
+    /// The given risk rule held the transaction for review.
+    HeldForReview(Tx, String),
+    /// The given risk rule rejected the transaction.
+    RejectedByRule(Tx, String),
+    /// There is no open authorization for the transaction.
+    MissingAuthorization(Tx),
+    /// The capture is above what is left of the authorization.
+    CaptureExceedsAuthorization(Tx),
+    /// The capture arrived after the authorization expired.
+    AuthorizationExpired(Tx),
+    /// The transfer is missing the receiving client.
+    MissingRecipient(Tx),
+    /// The receiving client is the sending one, or the transaction is not a transfer.
+    InvalidRecipient(Tx),
+    /// Only deposits, withdrawals and transfers can be part of a batch.
+    NotBatchable(Tx),
+    /// The refund does not refer to a deposit, or the deposit is under dispute or charged back.
+    NotRefundable(Tx),
+    /// The refund is above what is left to refund of the deposit.
+    RefundExceedsDeposit(Tx),
+    /// The transaction is missing the timestamp field.
+    MissingTimestamp(Tx),
+    /// The transaction is not in the input replayed.
+    NotFound(Tx),
+    /// The amount has more decimal places than the given input precision.
+    ExcessPrecision(Tx, u32),
 }
 
 impl From<TransactionError> for Error {
@@ -112,6 +688,112 @@ impl From<TransactionError> for Error {
     }
 }
 
+impl std::error::Error for TransactionError {}
+
+impl TransactionError {
+    fn code(&self) -> &'static str {
+        match self {
+            TransactionError::MissingAmount(_) => "transaction.missing_amount",
+            TransactionError::AmountPresent(_) => "transaction.amount_present",
+            TransactionError::NonPositiveAmount(_) => "transaction.non_positive_amount",
+            TransactionError::DuplicateFound(_) => "transaction.duplicate_found",
+            TransactionError::ExistingDispute(_) => "transaction.existing_dispute",
+            TransactionError::MissingDispute(_) => "transaction.missing_dispute",
+            TransactionError::OnlyDepositsCanBeDisputed(_) => "transaction.not_disputable",
+            TransactionError::WrongClient(..) => "transaction.wrong_client",
+            TransactionError::NotRetained(_) => "transaction.not_retained",
+            TransactionError::DisputeWindowExpired(_) => "transaction.dispute_window_expired",
+            TransactionError::ResolveWindowExpired(_) => "transaction.resolve_window_expired",
+            TransactionError::HeldForReview(..) => "transaction.held_for_review",
+            TransactionError::RejectedByRule(..) => "transaction.rejected_by_rule",
+            TransactionError::MissingAuthorization(_) => "transaction.missing_authorization",
+            TransactionError::CaptureExceedsAuthorization(_) => {
+                "transaction.capture_exceeds_authorization"
+            }
+            TransactionError::AuthorizationExpired(_) => "transaction.authorization_expired",
+            TransactionError::MissingRecipient(_) => "transaction.missing_recipient",
+            TransactionError::InvalidRecipient(_) => "transaction.invalid_recipient",
+            TransactionError::NotBatchable(_) => "transaction.not_batchable",
+            TransactionError::NotRefundable(_) => "transaction.not_refundable",
+            TransactionError::RefundExceedsDeposit(_) => "transaction.refund_exceeds_deposit",
+            TransactionError::MissingTimestamp(_) => "transaction.missing_timestamp",
+            TransactionError::NotFound(_) => "transaction.not_found",
+            TransactionError::ExcessPrecision(..) => "transaction.excess_precision",
+        }
+    }
+
+    fn category(&self) -> Category {
+        match self {
+            TransactionError::MissingAmount(_)
+            | TransactionError::AmountPresent(_)
+            | TransactionError::NonPositiveAmount(_)
+            | TransactionError::DuplicateFound(_)
+            | TransactionError::MissingRecipient(_)
+            | TransactionError::InvalidRecipient(_)
+            | TransactionError::NotBatchable(_)
+            | TransactionError::MissingTimestamp(_)
+            | TransactionError::ExcessPrecision(..) => Category::Validation,
+            TransactionError::CaptureExceedsAuthorization(_)
+            | TransactionError::RefundExceedsDeposit(_) => Category::Funds,
+            TransactionError::ExistingDispute(_)
+            | TransactionError::MissingDispute(_)
+            | TransactionError::OnlyDepositsCanBeDisputed(_)
+            | TransactionError::WrongClient(..)
+            | TransactionError::NotRetained(_)
+            | TransactionError::MissingAuthorization(_)
+            | TransactionError::NotRefundable(_)
+            | TransactionError::NotFound(_) => Category::Reference,
+            TransactionError::DisputeWindowExpired(_)
+            | TransactionError::ResolveWindowExpired(_)
+            | TransactionError::AuthorizationExpired(_) => Category::Expired,
+            TransactionError::HeldForReview(..) | TransactionError::RejectedByRule(..) => {
+                Category::Policy
+            }
+        }
+    }
+
+    fn context(&self) -> ErrorContext {
+        match self {
+            TransactionError::WrongClient(tx, expected, client, _) => ErrorContext {
+                tx: Some(*tx),
+                client: Some(*client),
+                expected_client: Some(*expected),
+                ..Default::default()
+            },
+            TransactionError::HeldForReview(tx, rule)
+            | TransactionError::RejectedByRule(tx, rule) => ErrorContext {
+                tx: Some(*tx),
+                rule: Some(rule.clone()),
+                ..Default::default()
+            },
+            TransactionError::MissingAmount(tx)
+            | TransactionError::AmountPresent(tx)
+            | TransactionError::NonPositiveAmount(tx)
+            | TransactionError::DuplicateFound(tx)
+            | TransactionError::ExistingDispute(tx)
+            | TransactionError::MissingDispute(tx)
+            | TransactionError::OnlyDepositsCanBeDisputed(tx)
+            | TransactionError::NotRetained(tx)
+            | TransactionError::DisputeWindowExpired(tx)
+            | TransactionError::ResolveWindowExpired(tx)
+            | TransactionError::MissingAuthorization(tx)
+            | TransactionError::CaptureExceedsAuthorization(tx)
+            | TransactionError::AuthorizationExpired(tx)
+            | TransactionError::MissingRecipient(tx)
+            | TransactionError::InvalidRecipient(tx)
+            | TransactionError::NotBatchable(tx)
+            | TransactionError::NotRefundable(tx)
+            | TransactionError::RefundExceedsDeposit(tx)
+            | TransactionError::MissingTimestamp(tx)
+            | TransactionError::NotFound(tx)
+            | TransactionError::ExcessPrecision(tx, _) => ErrorContext {
+                tx: Some(*tx),
+                ..Default::default()
+            },
+        }
+    }
+}
+
 impl std::fmt::Display for TransactionError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -126,7 +808,11 @@ impl std::fmt::Display for TransactionError {
                 )
             }
             TransactionError::NonPositiveAmount(t) => {
-                write!(f, "Transaction {} has a negative amount.", t)
+                write!(
+                    f,
+                    "Transaction {} has an amount that is zero or negative.",
+                    t
+                )
             }
             TransactionError::DuplicateFound(t) => {
                 write!(f, "Transaction {} is duplicated.", t)
@@ -137,34 +823,167 @@ impl std::fmt::Display for TransactionError {
             TransactionError::MissingDispute(t) => {
                 write!(f, "There is no dispute for transaction {}", t)
             }
-            TransactionError::WrongClient(t, old_client, new_client) => write!(
+            TransactionError::WrongClient(t, old_client, new_client, operation) => write!(
                 f,
-                "Client mismatch for transaction {} while opening a dispute: original is {} and found {}",
-                t, old_client, new_client
+                "Client mismatch for transaction {} in a {}: original is {} and found {}",
+                t,
+                operation.name(),
+                old_client,
+                new_client
             ),
             TransactionError::OnlyDepositsCanBeDisputed(t) => write!(
                 f,
                 "Transaction {} is a dispute that refers to a past transaction that is not a deposit.",
                 t,
             ),
+            TransactionError::NotRetained(t) => write!(
+                f,
+                "Transaction {} is no longer kept in the ledger: it is out of the dispute window or cannot be disputed.",
+                t,
+            ),
+            TransactionError::DisputeWindowExpired(t) => {
+                write!(f, "Transaction {} can no longer be disputed.", t)
+            }
+            TransactionError::ResolveWindowExpired(t) => write!(
+                f,
+                "The dispute for transaction {} can no longer be resolved or charged back.",
+                t
+            ),
+            TransactionError::HeldForReview(t, rule) => {
+                write!(
+                    f,
+                    "Transaction {} was held for review by rule '{}'.",
+                    t, rule
+                )
+            }
+            TransactionError::RejectedByRule(t, rule) => {
+                write!(f, "Transaction {} was rejected by rule '{}'.", t, rule)
+            }
+            TransactionError::MissingAuthorization(t) => {
+                write!(f, "There is no open authorization for transaction {}", t)
+            }
+            TransactionError::CaptureExceedsAuthorization(t) => write!(
+                f,
+                "The capture for transaction {} is above what is left of the authorization.",
+                t
+            ),
+            TransactionError::AuthorizationExpired(t) => {
+                write!(f, "The authorization {} expired.", t)
+            }
+            TransactionError::MissingRecipient(t) => {
+                write!(f, "Transfer {} is missing 'to' and is required.", t)
+            }
+            TransactionError::InvalidRecipient(t) => write!(
+                f,
+                "Transaction {} has 'to' present, but it is not a transfer to another client.",
+                t
+            ),
+            TransactionError::NotBatchable(t) => write!(
+                f,
+                "Transaction {} cannot be part of a batch: only deposits, withdrawals and transfers can.",
+                t
+            ),
+            TransactionError::NotRefundable(t) => write!(
+                f,
+                "Transaction {} cannot be refunded: it is not a deposit, or it is under dispute or charged back.",
+                t
+            ),
+            TransactionError::RefundExceedsDeposit(t) => write!(
+                f,
+                "The refund for transaction {} is above what is left to refund of the deposit.",
+                t
+            ),
+            TransactionError::MissingTimestamp(t) => write!(
+                f,
+                "Transaction {} is missing 'timestamp' and is required.",
+                t
+            ),
+            TransactionError::NotFound(t) => {
+                write!(f, "Transaction {} is not in the input.", t)
+            }
+            TransactionError::ExcessPrecision(t, places) => write!(
+                f,
+                "Transaction {} has an amount with more than {} decimal places.",
+                t, places
+            ),
         }
     }
 }
 
-// NOTE: this produces some output in a log file for errors that arise while executing,
-// but since it was not specified if other files could be produced, it is commented out.
-#[allow(dead_code)]
-/// Set up tracing and the file to write to.
-fn errors_to_file() -> Result<(), crate::error::Error> {
-    // The file to log to
-    let log_file = std::sync::Mutex::new(std::fs::File::create("error.log")?);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::error::Error as _;
+
+    #[test]
+    fn test_errors_serialize_with_their_context() {
+        let error: Error =
+            AccountError::DailyLimitExceeded(3, Funds::new(250, 0), Funds::new(40, 0)).into();
+        assert_eq!(error.code(), "account.daily_limit_exceeded");
+        assert_eq!(
+            serde_json::to_value(&error).unwrap(),
+            serde_json::json!({
+                "code": "account.daily_limit_exceeded",
+                "category": "policy",
+                "severity": "rejected",
+                "message": error.to_string(),
+                "client": 3,
+                "amount": "40",
+                "limit": "250",
+            })
+        );
 
-    // Set the subscriber for the tracing
-    tracing_subscriber::fmt()
-        .with_max_level(tracing::Level::ERROR)
-        .with_writer(log_file)
-        .compact()
-        .init();
+        let error: Error = TransactionError::HeldForReview(7, "velocity".to_string()).into();
+        let report = error.report();
+        assert_eq!(report.severity, Severity::Held);
+        assert_eq!(report.context.rule.as_deref(), Some("velocity"));
+    }
+
+    #[test]
+    fn test_batch_legs_and_sources_are_reported() {
+        let error: Error = BatchError::Rejected(
+            9,
+            vec![
+                (
+                    1,
+                    AccountError::InsufficientFunds(2, Funds::ONE, Funds::ZERO).into(),
+                ),
+                (2, TransactionError::DuplicateFound(2).into()),
+            ],
+        )
+        .into();
+        let report = error.report();
+        assert_eq!(report.context.batch, Some(9));
+        let legs: Vec<_> = report
+            .legs
+            .iter()
+            .map(|leg| (leg.code, leg.context.tx, leg.context.client))
+            .collect();
+        assert_eq!(
+            legs,
+            vec![
+                ("account.insufficient_funds", Some(1), Some(2)),
+                ("transaction.duplicate_found", Some(2), None),
+            ]
+        );
+        assert_eq!(report.legs[0].context.amount, Some(Funds::ONE));
+        assert_eq!(report.legs[0].context.available, Some(Funds::ZERO));
+        let source = error.source().and_then(|batch| batch.source()).unwrap();
+        assert_eq!(
+            source.to_string(),
+            Error::from(AccountError::InsufficientFunds(2, Funds::ONE, Funds::ZERO)).to_string()
+        );
 
-    Ok(())
+        let error: Error =
+            WalError::ReplayRejected(4, Box::new(TransactionError::MissingDispute(5).into()))
+                .into();
+        let report = error.report();
+        assert_eq!(report.severity, Severity::Fatal);
+        assert_eq!(report.source.unwrap().code, "transaction.missing_dispute");
+        let source = error.source().and_then(|wal| wal.source()).unwrap();
+        assert_eq!(
+            source.to_string(),
+            Error::from(TransactionError::MissingDispute(5)).to_string()
+        );
+    }
 }
@@ -0,0 +1,277 @@
+//! This module defines the fee schedule: the fees charged on deposits and withdrawals, depending on
+//! the tier of the client.
+//!
+//! The schedule is read from a JSON file, e.g.:
+//!
+//! ```json
+//! {
+//!   "house": 0,
+//!   "default_tier": "standard",
+//!   "clients": { "7": "premium" },
+//!   "tiers": {
+//!     "standard": {
+//!       "deposit": { "percent": "0.5", "min": "0.1" },
+//!       "withdrawal": { "percent": "1", "flat": "0.25", "max": "20" }
+//!     },
+//!     "premium": {}
+//!   }
+//! }
+//! ```
+//!
+//! Fees are collected by the `house` account, which pays no fees itself. Clients not listed in
+//! `clients` belong to `default_tier`, or pay no fees if there is none.
+
+use crate::{
+    accounts::RoundingStrategy,
+    error::{Error, ScheduleError},
+    primitives::{Client, Funds},
+    transactions::TxType,
+};
+use serde::Deserialize;
+use std::{collections::HashMap, fs, path::Path};
+
+/// How the fee of a transaction is computed from its amount.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct FeeRule {
+    /// Percentage of the amount, e.g. `1.5` charges 1.5%.
+    #[serde(default)]
+    pub(crate) percent: Funds,
+    /// Fixed amount added to the percentage.
+    #[serde(default)]
+    pub(crate) flat: Funds,
+    /// Minimum fee.
+    #[serde(default)]
+    pub(crate) min: Option<Funds>,
+    /// Maximum fee.
+    #[serde(default)]
+    pub(crate) max: Option<Funds>,
+}
+
+impl FeeRule {
+    /// The fee for the given amount, rounded to four decimal places with the given strategy.
+    /// `None` on overflow.
+    pub(crate) fn fee(&self, amount: Funds, strategy: RoundingStrategy) -> Option<Funds> {
+        let mut fee = amount
+            .checked_mul(self.percent)?
+            .checked_div(Funds::ONE_HUNDRED)?
+            .checked_add(self.flat)?;
+        if let Some(min) = self.min {
+            fee = fee.max(min);
+        }
+        if let Some(max) = self.max {
+            fee = fee.min(max);
+        }
+        Some(strategy.round(fee, 4))
+    }
+
+    fn is_valid(&self) -> bool {
+        let non_negative = |funds: Funds| funds >= Funds::ZERO;
+        non_negative(self.percent)
+            && non_negative(self.flat)
+            && self.min.is_none_or(non_negative)
+            && self.max.is_none_or(non_negative)
+            && self.min.zip(self.max).is_none_or(|(min, max)| min <= max)
+    }
+}
+
+/// Fees of a tier, per transaction type. Missing types are free.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct TierFees {
+    #[serde(default)]
+    pub(crate) deposit: Option<FeeRule>,
+    #[serde(default)]
+    pub(crate) withdrawal: Option<FeeRule>,
+}
+
+/// The fees charged to every client, and the account collecting them.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct FeeSchedule {
+    /// The account the fees are posted to.
+    pub(crate) house: Client,
+    /// The tier of the clients not listed in `clients`.
+    #[serde(default)]
+    pub(crate) default_tier: Option<String>,
+    /// The tier of each client.
+    #[serde(default)]
+    pub(crate) clients: HashMap<Client, String>,
+    /// The fees of each tier, by name.
+    pub(crate) tiers: HashMap<String, TierFees>,
+}
+
+impl FeeSchedule {
+    /// Reads and validates a fee schedule from a JSON file.
+    pub(crate) fn load(path: &Path) -> Result<Self, Error> {
+        let content = fs::read_to_string(path)?;
+        let schedule: Self = serde_json::from_str(&content).map_err(ScheduleError::Malformed)?;
+        schedule.validate()?;
+        Ok(schedule)
+    }
+
+    /// Checks that every tier referenced exists, and that every rule makes sense.
+    pub(crate) fn validate(&self) -> Result<(), ScheduleError> {
+        for tier in self.default_tier.iter().chain(self.clients.values()) {
+            if !self.tiers.contains_key(tier) {
+                return Err(ScheduleError::UnknownTier(tier.clone()));
+            }
+        }
+
+        for (name, tier) in &self.tiers {
+            if [&tier.deposit, &tier.withdrawal]
+                .into_iter()
+                .flatten()
+                .any(|rule| !rule.is_valid())
+            {
+                return Err(ScheduleError::InvalidRule(name.clone()));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The fee a client pays for a transaction of the given type and amount, rounded with the given
+    /// strategy. `None` on overflow.
+    pub(crate) fn fee(
+        &self,
+        client: Client,
+        variant: TxType,
+        amount: Funds,
+        strategy: RoundingStrategy,
+    ) -> Option<Funds> {
+        if client == self.house {
+            return Some(Funds::ZERO);
+        }
+
+        let tier = self
+            .clients
+            .get(&client)
+            .or(self.default_tier.as_ref())
+            .and_then(|name| self.tiers.get(name));
+        let rule = tier.and_then(|tier| match variant {
+            TxType::Deposit => tier.deposit.as_ref(),
+            TxType::Withdrawal => tier.withdrawal.as_ref(),
+            _ => None,
+        });
+
+        match rule {
+            Some(rule) => rule.fee(amount, strategy),
+            None => Some(Funds::ZERO),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal::Decimal;
+    use tempfile::tempdir;
+
+    fn funds(amount: f32) -> Decimal {
+        Decimal::from_f32_retain(amount).unwrap()
+    }
+
+    fn schedule() -> FeeSchedule {
+        serde_json::from_str(
+            r#"{
+                "house": 0,
+                "default_tier": "standard",
+                "clients": { "7": "premium" },
+                "tiers": {
+                    "standard": {
+                        "deposit": { "percent": "0.5", "min": "0.1" },
+                        "withdrawal": { "percent": "1", "flat": "0.25", "max": "2" }
+                    },
+                    "premium": {}
+                }
+            }"#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_fee_rules_apply_minimum_and_cap() {
+        let schedule = schedule();
+
+        assert_eq!(
+            schedule.fee(1, TxType::Deposit, funds(100.0), RoundingStrategy::HalfEven),
+            Some(funds(0.5))
+        );
+        assert_eq!(
+            schedule.fee(1, TxType::Deposit, funds(1.0), RoundingStrategy::HalfEven),
+            Some(Decimal::new(1, 1))
+        );
+        assert_eq!(
+            schedule.fee(
+                1,
+                TxType::Withdrawal,
+                funds(10.0),
+                RoundingStrategy::HalfEven
+            ),
+            Some(Decimal::new(35, 2))
+        );
+        assert_eq!(
+            schedule.fee(
+                1,
+                TxType::Withdrawal,
+                funds(1000.0),
+                RoundingStrategy::HalfEven
+            ),
+            Some(funds(2.0))
+        );
+    }
+
+    #[test]
+    fn test_fees_depend_on_the_client_tier() {
+        let schedule = schedule();
+
+        assert_eq!(
+            schedule.fee(
+                7,
+                TxType::Withdrawal,
+                funds(10.0),
+                RoundingStrategy::HalfEven
+            ),
+            Some(Funds::ZERO)
+        );
+        // The house account pays no fees.
+        assert_eq!(
+            schedule.fee(
+                0,
+                TxType::Withdrawal,
+                funds(10.0),
+                RoundingStrategy::HalfEven
+            ),
+            Some(Funds::ZERO)
+        );
+    }
+
+    #[test]
+    fn test_load_rejects_invalid_schedules() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("fees.json");
+
+        fs::write(&path, r#"{"house":0,"default_tier":"gold","tiers":{}}"#).unwrap();
+        assert!(matches!(
+            FeeSchedule::load(&path),
+            Err(Error::Schedule(ScheduleError::UnknownTier(tier))) if tier == "gold"
+        ));
+
+        fs::write(
+            &path,
+            r#"{"house":0,"tiers":{"a":{"deposit":{"min":"2","max":"1"}}}}"#,
+        )
+        .unwrap();
+        assert!(matches!(
+            FeeSchedule::load(&path),
+            Err(Error::Schedule(ScheduleError::InvalidRule(tier))) if tier == "a"
+        ));
+
+        fs::write(&path, r#"{"house":0,"tiers":{"a":{"refund":{}}}}"#).unwrap();
+        assert!(matches!(
+            FeeSchedule::load(&path),
+            Err(Error::Schedule(ScheduleError::Malformed(_)))
+        ));
+    }
+}
@@ -1,17 +1,13 @@
 //! This module defines functions to interact with the input for the application and the output
 //! that is expected from it.
 
-use crate::accounts::Accounts;
-use std::{fs, io};
-
-/// Gets the path of the file containing the transactions, which is given as an argument when
-/// calling the binary.
-pub(crate) fn get_filepath() -> Result<String, io::Error> {
-    std::env::args().nth(1).ok_or(io::Error::new(
-        io::ErrorKind::NotFound,
-        "missing transactions file",
-    ))
-}
+use crate::{accounts::Accounts, error::Error, risk::HeldTransaction};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs,
+    io::{self, Write},
+    path::Path,
+};
 
 /// Create a transaction CSV reader for the given file path.
 pub(crate) fn csv_reader(file_path: &str) -> csv::Result<csv::Reader<fs::File>> {
@@ -25,17 +21,64 @@ pub(crate) fn csv_reader(file_path: &str) -> csv::Result<csv::Reader<fs::File>>
     Ok(rdr)
 }
 
-/// Writes the given collection of [`Accounts`] to std out.
-pub(crate) fn write_csv(accounts: Accounts) -> csv::Result<()> {
+/// How the accounts are written to std out.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, Serialize, clap::ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum OutputFormat {
+    /// A CSV file with a header, as requested.
+    #[default]
+    Csv,
+    /// One JSON object per account and line.
+    JsonLines,
+}
+
+/// Writes the given collection of [`Accounts`] to std out, in the given format.
+pub(crate) fn write_accounts(accounts: &Accounts, format: OutputFormat) -> Result<(), Error> {
+    match format {
+        OutputFormat::Csv => write_csv(accounts)?,
+        OutputFormat::JsonLines => write_json_lines(accounts.rows())?,
+    }
+
+    Ok(())
+}
+
+/// Writes the given collection of [`Accounts`] to std out, as CSV.
+fn write_csv(accounts: &Accounts) -> csv::Result<()> {
     let mut wtr = csv::Writer::from_writer(io::stdout());
 
-    for (_, acc) in accounts.inner() {
-        wtr.serialize(acc)?;
+    for row in accounts.rows() {
+        wtr.serialize(row)?;
     }
 
     Ok(())
 }
 
+/// Writes the given items to std out as JSON, one per line, e.g. the history of a client or the
+/// results of a query.
+pub(crate) fn write_json_lines<T: Serialize>(
+    items: impl IntoIterator<Item = T>,
+) -> Result<(), Error> {
+    let mut out = io::stdout().lock();
+    for item in items {
+        serde_json::to_writer(&mut out, &item).map_err(io::Error::from)?;
+        writeln!(out)?;
+    }
+
+    Ok(())
+}
+
+/// Writes the transactions held for review to a CSV file at the given path.
+pub(crate) fn write_held(path: &Path, held: &[HeldTransaction]) -> csv::Result<()> {
+    let mut wtr = csv::Writer::from_path(path)?;
+
+    for transaction in held {
+        wtr.serialize(transaction)?;
+    }
+
+    wtr.flush()?;
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -48,13 +91,6 @@ mod tests {
         Decimal::from_f32_retain(amount).unwrap()
     }
 
-    #[test]
-    fn test_get_filepath_returns_error_when_missing_argument() {
-        let result = get_filepath();
-        assert!(result.is_err());
-        assert_eq!(result.unwrap_err().kind(), std::io::ErrorKind::NotFound);
-    }
-
     #[test]
     fn test_csv_reader_reads_valid_csv() {
         let mut temp = NamedTempFile::new().unwrap();
@@ -93,8 +129,8 @@ mod tests {
         let mut output = Vec::new();
         {
             let mut writer = csv::Writer::from_writer(&mut output);
-            for (_, acc) in accounts.inner() {
-                writer.serialize(acc).unwrap();
+            for row in accounts.rows() {
+                writer.serialize(row).unwrap();
             }
         }
 
@@ -0,0 +1,844 @@
+//! This module defines the ledger: the historical records the [`Engine`] keeps to detect duplicated
+//! transactions and to look up the transactions referenced by disputes.
+//!
+//! Keeping every transaction forever makes memory grow linearly with the input, so the ledger can
+//! be tuned with [`LedgerOptions`]:
+//! - records are always compact ([`LedgerEntry`]: amount, client and dispute state),
+//! - [`Retention::Disputable`] keeps only the transactions that can be referenced later (deposits,
+//!   transfers and authorizations), and none of the scheduled adjustments,
+//! - a window drops the records, and the adjustments, once too many transactions were applied
+//!   after them,
+//! - a spill file moves the oldest records out of memory, into a [`SpillStore`].
+//!
+//! Duplicate detection does not depend on any of these: every transaction id ever recorded is kept
+//! in [`SeenTxs`], a bitmap whose size is bounded by the range of transaction ids.
+//!
+//! [`Engine`]: crate::engine::Engine
+
+use crate::{
+    adjustments::Adjustment,
+    error::Error,
+    primitives::{Client, Funds, Timestamp, Tx},
+    transactions::{Transaction, TxType},
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    fs,
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::PathBuf,
+};
+
+/// The dispute state of a recorded transaction, or the state of a recorded authorization.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize, Serialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum TxState {
+    /// Applied, and not disputed. For an authorization, still holding funds.
+    Processed,
+    /// Under an open dispute.
+    Disputed,
+    /// A dispute was opened and then resolved.
+    Resolved,
+    /// A dispute was opened and ended in a chargeback.
+    ChargedBack,
+    /// An authorization was captured in full.
+    Captured,
+    /// An authorization was voided, or expired, before being captured in full.
+    Voided,
+}
+
+/// Compact record of a transaction kept in the ledger.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+pub(crate) struct LedgerEntry {
+    /// The type of the original transaction.
+    #[serde(rename = "type")]
+    pub(crate) variant: TxType,
+    /// The client of the original transaction.
+    pub(crate) client: Client,
+    /// The amount of the original transaction.
+    pub(crate) amount: Funds,
+    /// The receiving client of the original transaction, for transfers.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) to: Option<Client>,
+    /// The dispute state of the transaction.
+    pub(crate) state: TxState,
+    /// The engine sequence when the transaction was recorded, used for the window.
+    pub(crate) seq: u64,
+    /// When the original transaction happened, if known.
+    #[serde(default)]
+    pub(crate) timestamp: Option<Timestamp>,
+    /// When the last dispute on the transaction was opened, if known.
+    #[serde(default)]
+    pub(crate) disputed_at: Option<Timestamp>,
+    /// The fee charged on the transaction, already deducted from the amount credited.
+    #[serde(default)]
+    pub(crate) fee: Funds,
+    /// The amount refunded so far, for deposits.
+    #[serde(default)]
+    pub(crate) refunded: Funds,
+}
+
+impl LedgerEntry {
+    /// The client whose account the original transaction credited: the receiving client of a
+    /// transfer, the client of any other transaction.
+    pub(crate) fn credited(&self) -> Client {
+        self.to.unwrap_or(self.client)
+    }
+
+    /// What is left of the amount credited by the original transaction, i.e. without its fee and
+    /// what was refunded of it.
+    pub(crate) fn remaining(&self) -> Funds {
+        self.amount - self.fee - self.refunded
+    }
+}
+
+/// Which transactions are kept in the ledger.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, Serialize, clap::ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Retention {
+    /// Every deposit, withdrawal, transfer and authorization.
+    #[default]
+    All,
+    /// Only the transactions that can be referenced later, i.e. deposits and transfers, which can
+    /// be disputed, and authorizations, which can be captured. Scheduled adjustments are not kept.
+    Disputable,
+}
+
+/// Options to bound the memory used by the [`Ledger`].
+#[derive(Debug, Clone, Default)]
+pub(crate) struct LedgerOptions {
+    /// Which transactions are kept.
+    pub(crate) retention: Retention,
+    /// Number of transactions that can be applied after a transaction while its record is kept.
+    /// Older records are dropped, unless they are under dispute or still authorizing funds.
+    pub(crate) window: Option<u64>,
+    /// File where the oldest records are moved to, together with the maximum number of records
+    /// kept in memory.
+    pub(crate) spill: Option<(PathBuf, usize)>,
+}
+
+/// Behavior expected from the storage of [`LedgerEntry`]s.
+pub(crate) trait LedgerStore {
+    /// Gets the record for a transaction, if any.
+    fn get(&mut self, tx: Tx) -> Result<Option<LedgerEntry>, Error>;
+
+    /// Inserts or replaces the record for a transaction.
+    fn put(&mut self, tx: Tx, entry: LedgerEntry) -> Result<(), Error>;
+
+    /// Removes the record for a transaction, if any.
+    fn remove(&mut self, tx: Tx) -> Result<(), Error>;
+}
+
+/// Stores all the records in memory, using a [`HashMap`] underneath.
+#[derive(Debug, Default)]
+pub(crate) struct MemoryStore(HashMap<Tx, LedgerEntry>);
+
+impl LedgerStore for MemoryStore {
+    fn get(&mut self, tx: Tx) -> Result<Option<LedgerEntry>, Error> {
+        Ok(self.0.get(&tx).copied())
+    }
+
+    fn put(&mut self, tx: Tx, entry: LedgerEntry) -> Result<(), Error> {
+        self.0.insert(tx, entry);
+        Ok(())
+    }
+
+    fn remove(&mut self, tx: Tx) -> Result<(), Error> {
+        self.0.remove(&tx);
+        Ok(())
+    }
+}
+
+/// Keeps the most recent records in memory, and moves the oldest ones to a file.
+///
+/// The file holds fixed-size records addressed by transaction id (i.e. record `tx` lives at
+/// `tx * RECORD_SIZE`), so no index is kept in memory. The file is sparse: only the pages holding
+/// records take space on disk.
+pub(crate) struct SpillStore {
+    hot: HashMap<Tx, LedgerEntry>,
+    /// Insertion order of the records in memory, oldest first. May contain ids that were already
+    /// removed or spilled.
+    order: VecDeque<Tx>,
+    /// Maximum number of records kept in memory.
+    capacity: usize,
+    file: fs::File,
+}
+
+impl SpillStore {
+    /// Size of a record in the spill file.
+    const RECORD_SIZE: u64 = 80;
+
+    /// Creates a store spilling to the given path. Any previous content of the file is discarded.
+    pub(crate) fn new(path: &std::path::Path, capacity: usize) -> Result<Self, Error> {
+        let file = fs::OpenOptions::new()
+            .create(true)
+            .read(true)
+            .write(true)
+            .truncate(true)
+            .open(path)?;
+
+        Ok(Self {
+            hot: HashMap::new(),
+            order: VecDeque::new(),
+            capacity: capacity.max(1),
+            file,
+        })
+    }
+
+    fn encode(entry: Option<&LedgerEntry>) -> [u8; Self::RECORD_SIZE as usize] {
+        let mut record = [0u8; Self::RECORD_SIZE as usize];
+        if let Some(entry) = entry {
+            record[0] = 1;
+            record[1] = match entry.variant {
+                TxType::Deposit => 0,
+                TxType::Withdrawal => 1,
+                TxType::Dispute => 2,
+                TxType::Resolve => 3,
+                TxType::Chargeback => 4,
+                TxType::Authorize => 5,
+                TxType::Capture => 6,
+                TxType::Void => 7,
+                TxType::Transfer => 8,
+                TxType::Refund => 9,
+                TxType::Tick => 10,
+            };
+            record[2] = match entry.state {
+                TxState::Processed => 0,
+                TxState::Disputed => 1,
+                TxState::Resolved => 2,
+                TxState::ChargedBack => 3,
+                TxState::Captured => 4,
+                TxState::Voided => 5,
+            };
+            record[4..6].copy_from_slice(&entry.client.to_le_bytes());
+            if let Some(to) = entry.to {
+                record[3] |= 0b100;
+                record[6..8].copy_from_slice(&to.to_le_bytes());
+            }
+            record[8..16].copy_from_slice(&entry.seq.to_le_bytes());
+            record[16..32].copy_from_slice(&entry.amount.serialize());
+            if let Some(timestamp) = entry.timestamp {
+                record[3] |= 0b01;
+                record[32..40].copy_from_slice(&timestamp.to_le_bytes());
+            }
+            if let Some(disputed_at) = entry.disputed_at {
+                record[3] |= 0b10;
+                record[40..48].copy_from_slice(&disputed_at.to_le_bytes());
+            }
+            record[48..64].copy_from_slice(&entry.fee.serialize());
+            record[64..80].copy_from_slice(&entry.refunded.serialize());
+        }
+        record
+    }
+
+    fn decode(record: &[u8; Self::RECORD_SIZE as usize]) -> Result<Option<LedgerEntry>, Error> {
+        let corrupted = || io::Error::new(io::ErrorKind::InvalidData, "corrupted ledger record");
+
+        if record[0] == 0 {
+            return Ok(None);
+        }
+
+        let variant = match record[1] {
+            0 => TxType::Deposit,
+            1 => TxType::Withdrawal,
+            2 => TxType::Dispute,
+            3 => TxType::Resolve,
+            4 => TxType::Chargeback,
+            5 => TxType::Authorize,
+            6 => TxType::Capture,
+            7 => TxType::Void,
+            8 => TxType::Transfer,
+            9 => TxType::Refund,
+            10 => TxType::Tick,
+            _ => return Err(corrupted().into()),
+        };
+        let state = match record[2] {
+            0 => TxState::Processed,
+            1 => TxState::Disputed,
+            2 => TxState::Resolved,
+            3 => TxState::ChargedBack,
+            4 => TxState::Captured,
+            5 => TxState::Voided,
+            _ => return Err(corrupted().into()),
+        };
+        let mut amount = [0u8; 16];
+        amount.copy_from_slice(&record[16..32]);
+        let mut fee = [0u8; 16];
+        fee.copy_from_slice(&record[48..64]);
+        let mut refunded = [0u8; 16];
+        refunded.copy_from_slice(&record[64..80]);
+        let word = |range: std::ops::Range<usize>| {
+            record[range]
+                .try_into()
+                .map(u64::from_le_bytes)
+                .map_err(|_| corrupted())
+        };
+
+        Ok(Some(LedgerEntry {
+            variant,
+            client: Client::from_le_bytes([record[4], record[5]]),
+            to: (record[3] & 0b100 != 0).then(|| Client::from_le_bytes([record[6], record[7]])),
+            amount: Funds::deserialize(amount),
+            state,
+            seq: word(8..16)?,
+            timestamp: (record[3] & 0b01 != 0).then(|| word(32..40)).transpose()?,
+            disputed_at: (record[3] & 0b10 != 0).then(|| word(40..48)).transpose()?,
+            fee: Funds::deserialize(fee),
+            refunded: Funds::deserialize(refunded),
+        }))
+    }
+
+    fn read_cold(&mut self, tx: Tx) -> Result<Option<LedgerEntry>, Error> {
+        let offset = tx as u64 * Self::RECORD_SIZE;
+        if offset + Self::RECORD_SIZE > self.file.metadata()?.len() {
+            return Ok(None);
+        }
+
+        let mut record = [0u8; Self::RECORD_SIZE as usize];
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.read_exact(&mut record)?;
+        Self::decode(&record)
+    }
+
+    fn write_cold(&mut self, tx: Tx, entry: Option<&LedgerEntry>) -> Result<(), Error> {
+        self.file
+            .seek(SeekFrom::Start(tx as u64 * Self::RECORD_SIZE))?;
+        self.file.write_all(&Self::encode(entry))?;
+        Ok(())
+    }
+
+    /// Moves the oldest records to the file until the memory is within capacity.
+    fn spill(&mut self) -> Result<(), Error> {
+        while self.hot.len() > self.capacity {
+            let Some(tx) = self.order.pop_front() else {
+                break;
+            };
+            if let Some(entry) = self.hot.remove(&tx) {
+                self.write_cold(tx, Some(&entry))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl LedgerStore for SpillStore {
+    fn get(&mut self, tx: Tx) -> Result<Option<LedgerEntry>, Error> {
+        match self.hot.get(&tx) {
+            Some(entry) => Ok(Some(*entry)),
+            None => self.read_cold(tx),
+        }
+    }
+
+    fn put(&mut self, tx: Tx, entry: LedgerEntry) -> Result<(), Error> {
+        if let Some(hot) = self.hot.get_mut(&tx) {
+            *hot = entry;
+        } else if self.read_cold(tx)?.is_some() {
+            self.write_cold(tx, Some(&entry))?;
+        } else {
+            self.hot.insert(tx, entry);
+            self.order.push_back(tx);
+            self.spill()?;
+        }
+        Ok(())
+    }
+
+    fn remove(&mut self, tx: Tx) -> Result<(), Error> {
+        if self.hot.remove(&tx).is_none() && self.read_cold(tx)?.is_some() {
+            self.write_cold(tx, None)?;
+        }
+        Ok(())
+    }
+}
+
+/// Set of transaction ids, as a bitmap split in pages that are only allocated when used.
+///
+/// At most 512MiB are used, when every possible id has been seen.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct SeenTxs(BTreeMap<u32, Box<[u64; SeenTxs::PAGE_WORDS]>>);
+
+impl SeenTxs {
+    /// Number of words in a page, i.e. each page holds 2^16 ids.
+    const PAGE_WORDS: usize = 1024;
+
+    fn position(tx: Tx) -> (u32, usize, u64) {
+        let page = tx >> 16;
+        let bit = (tx & 0xFFFF) as usize;
+        (page, bit / 64, 1 << (bit % 64))
+    }
+
+    pub(crate) fn contains(&self, tx: Tx) -> bool {
+        let (page, word, mask) = Self::position(tx);
+        self.0.get(&page).is_some_and(|p| p[word] & mask != 0)
+    }
+
+    pub(crate) fn insert(&mut self, tx: Tx) {
+        let (page, word, mask) = Self::position(tx);
+        self.0
+            .entry(page)
+            .or_insert_with(|| Box::new([0; Self::PAGE_WORDS]))[word] |= mask;
+    }
+
+    pub(crate) fn remove(&mut self, tx: Tx) {
+        let (page, word, mask) = Self::position(tx);
+        if let Some(p) = self.0.get_mut(&page) {
+            p[word] &= !mask;
+        }
+    }
+
+    /// Iterates over the ids, in ascending order.
+    pub(crate) fn iter(&self) -> impl Iterator<Item = Tx> + '_ {
+        self.0.iter().flat_map(|(page, words)| {
+            words.iter().enumerate().flat_map(move |(word, bits)| {
+                (0..64)
+                    .filter(move |bit| bits & (1 << bit) != 0)
+                    .map(move |bit| (page << 16) | (word as u32 * 64 + bit))
+            })
+        })
+    }
+
+    /// The ids as inclusive ranges, in ascending order. Much more compact than the ids themselves
+    /// when they are mostly consecutive.
+    pub(crate) fn ranges(&self) -> Vec<(Tx, Tx)> {
+        let mut ranges: Vec<(Tx, Tx)> = Vec::new();
+        for tx in self.iter() {
+            match ranges.last_mut() {
+                Some((_, end)) if *end + 1 == tx => *end = tx,
+                _ => ranges.push((tx, tx)),
+            }
+        }
+        ranges
+    }
+}
+
+/// The historical records of the [`Engine`](crate::engine::Engine).
+pub(crate) struct Ledger {
+    store: Box<dyn LedgerStore>,
+    seen: SeenTxs,
+    retention: Retention,
+    window: Option<u64>,
+    /// Records that are subject to the window, oldest first.
+    windowed: VecDeque<(u64, Tx)>,
+    /// The scheduled adjustments applied to the accounts, in order, within the window.
+    adjustments: VecDeque<Adjustment>,
+}
+
+impl Ledger {
+    /// Creates a ledger keeping every record in memory.
+    pub(crate) fn new() -> Self {
+        Self {
+            store: Box::new(MemoryStore::default()),
+            seen: SeenTxs::default(),
+            retention: Retention::All,
+            window: None,
+            windowed: VecDeque::new(),
+            adjustments: VecDeque::new(),
+        }
+    }
+
+    /// Creates a ledger with the given options.
+    pub(crate) fn with_options(options: &LedgerOptions) -> Result<Self, Error> {
+        let store: Box<dyn LedgerStore> = match &options.spill {
+            Some((path, capacity)) => Box::new(SpillStore::new(path, *capacity)?),
+            None => Box::new(MemoryStore::default()),
+        };
+
+        Ok(Self {
+            store,
+            retention: options.retention,
+            window: options.window,
+            ..Self::new()
+        })
+    }
+
+    /// The ids of all the transactions recorded so far.
+    pub(crate) fn seen(&self) -> &SeenTxs {
+        &self.seen
+    }
+
+    /// Checks if a transaction id was already recorded, even if its record is no longer kept.
+    pub(crate) fn contains(&self, tx: Tx) -> bool {
+        self.seen.contains(tx)
+    }
+
+    /// Records an applied [`Transaction`], and the fee charged on it, at the given engine sequence.
+    pub(crate) fn record(
+        &mut self,
+        transaction: &Transaction,
+        fee: Funds,
+        seq: u64,
+    ) -> Result<(), Error> {
+        self.seen.insert(transaction.tx);
+
+        let kept = match self.retention {
+            Retention::All => true,
+            Retention::Disputable => {
+                matches!(
+                    transaction.variant,
+                    TxType::Deposit | TxType::Transfer | TxType::Authorize
+                )
+            }
+        };
+        if let (true, Some(amount)) = (kept, transaction.amount) {
+            self.store.put(
+                transaction.tx,
+                LedgerEntry {
+                    variant: transaction.variant,
+                    client: transaction.client,
+                    amount,
+                    to: transaction.to,
+                    state: TxState::Processed,
+                    seq,
+                    timestamp: transaction.timestamp,
+                    disputed_at: None,
+                    fee,
+                    refunded: Funds::ZERO,
+                },
+            )?;
+            if self.window.is_some() {
+                self.windowed.push_back((seq, transaction.tx));
+            }
+        }
+
+        self.expire(seq)
+    }
+
+    /// Forgets a transaction recorded by a batch that was then rejected, as if it was never seen.
+    pub(crate) fn forget(&mut self, tx: Tx) -> Result<(), Error> {
+        self.seen.remove(tx);
+        self.store.remove(tx)
+    }
+
+    /// Records a scheduled adjustment applied to an account, if adjustments are kept at all.
+    pub(crate) fn record_adjustment(&mut self, adjustment: Adjustment) {
+        if self.retention == Retention::All {
+            self.adjustments.push_back(adjustment);
+        }
+        self.expire_adjustments(adjustment.seq);
+    }
+
+    /// The scheduled adjustments applied so far and still within the window, in order.
+    pub(crate) fn adjustments(&self) -> &VecDeque<Adjustment> {
+        &self.adjustments
+    }
+
+    /// Drops the adjustments that fell out of the window.
+    fn expire_adjustments(&mut self, now: u64) {
+        let Some(window) = self.window else {
+            return;
+        };
+        while self
+            .adjustments
+            .front()
+            .is_some_and(|adjustment| now.saturating_sub(adjustment.seq) > window)
+        {
+            self.adjustments.pop_front();
+        }
+    }
+
+    /// Checks if a record is out of the window at the given engine sequence. Transactions under
+    /// dispute never are, like open authorizations.
+    fn is_expired(&self, entry: &LedgerEntry, now: u64) -> bool {
+        let open = match entry.variant {
+            TxType::Authorize => entry.state == TxState::Processed,
+            _ => entry.state == TxState::Disputed,
+        };
+        !open
+            && self
+                .window
+                .is_some_and(|window| now.saturating_sub(entry.seq) > window)
+    }
+
+    /// Drops the records that fell out of the window. Those under dispute or still authorizing
+    /// funds are checked again a window later, until they are settled.
+    fn expire(&mut self, now: u64) -> Result<(), Error> {
+        let Some(window) = self.window else {
+            return Ok(());
+        };
+        self.expire_adjustments(now);
+
+        while let Some(&(seq, tx)) = self.windowed.front() {
+            if now.saturating_sub(seq) <= window {
+                break;
+            }
+            self.windowed.pop_front();
+            match self.store.get(tx)? {
+                Some(entry) if self.is_expired(&entry, now) => self.store.remove(tx)?,
+                Some(_) => self.windowed.push_back((now, tx)),
+                None => {}
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Gets the record of a transaction at the given engine sequence. Records out of the window are
+    /// not returned, unless they are under dispute or still authorizing funds.
+    pub(crate) fn get(&mut self, tx: Tx, now: u64) -> Result<Option<LedgerEntry>, Error> {
+        Ok(self
+            .store
+            .get(tx)?
+            .filter(|entry| !self.is_expired(entry, now)))
+    }
+
+    /// Replaces the record of a transaction.
+    pub(crate) fn update(&mut self, tx: Tx, entry: LedgerEntry) -> Result<(), Error> {
+        self.store.put(tx, entry)
+    }
+
+    /// Updates the dispute state of a recorded transaction.
+    pub(crate) fn set_state(&mut self, tx: Tx, state: TxState) -> Result<(), Error> {
+        if let Some(mut entry) = self.store.get(tx)? {
+            entry.state = state;
+            self.store.put(tx, entry)?;
+        }
+        Ok(())
+    }
+
+    /// All the kept records, in ascending transaction id.
+    pub(crate) fn entries(&mut self) -> Result<Vec<(Tx, LedgerEntry)>, Error> {
+        self.iter_entries().collect()
+    }
+
+    /// Iterates over the kept records, in ascending transaction id, reading them one at a time.
+    pub(crate) fn iter_entries(
+        &mut self,
+    ) -> impl Iterator<Item = Result<(Tx, LedgerEntry), Error>> + '_ {
+        let store = &mut self.store;
+        self.seen.iter().filter_map(move |tx| {
+            store
+                .get(tx)
+                .map(|entry| entry.map(|e| (tx, e)))
+                .transpose()
+        })
+    }
+
+    /// The recorded transaction ids, as inclusive ranges. See [`SeenTxs::ranges`].
+    pub(crate) fn seen_ranges(&self) -> Vec<(Tx, Tx)> {
+        self.seen.ranges()
+    }
+
+    /// Restores previously saved records and transaction ids.
+    pub(crate) fn restore(
+        &mut self,
+        entries: impl IntoIterator<Item = (Tx, LedgerEntry)>,
+        seen: impl IntoIterator<Item = (Tx, Tx)>,
+    ) -> Result<(), Error> {
+        for (start, end) in seen {
+            for tx in start..=end {
+                self.seen.insert(tx);
+            }
+        }
+
+        let mut window = Vec::new();
+        for (tx, entry) in entries {
+            self.seen.insert(tx);
+            self.store.put(tx, entry)?;
+            if self.window.is_some() {
+                window.push((entry.seq, tx));
+            }
+        }
+        window.sort_unstable();
+        self.windowed.extend(window);
+
+        Ok(())
+    }
+
+    /// Restores previously saved adjustments, after the ones already recorded, if adjustments are
+    /// kept at all.
+    pub(crate) fn restore_adjustments(
+        &mut self,
+        adjustments: impl IntoIterator<Item = Adjustment>,
+    ) {
+        if self.retention == Retention::All {
+            self.adjustments.extend(adjustments);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::adjustments::AdjustmentKind;
+    use rust_decimal::Decimal;
+    use tempfile::tempdir;
+
+    fn funds(amount: f32) -> Decimal {
+        Decimal::from_f32_retain(amount).unwrap()
+    }
+
+    fn transaction(variant: TxType, tx: Tx, amount: f32) -> Transaction {
+        Transaction {
+            variant,
+            client: 1,
+            tx,
+            amount: Some(funds(amount)),
+            to: None,
+            batch_id: None,
+            timestamp: None,
+        }
+    }
+
+    #[test]
+    fn test_seen_txs_ranges() {
+        let mut seen = SeenTxs::default();
+        for tx in [1, 2, 3, 7, 65535, 65536, u32::MAX] {
+            seen.insert(tx);
+        }
+
+        assert!(seen.contains(65536));
+        assert!(!seen.contains(4));
+        assert_eq!(
+            seen.ranges(),
+            vec![(1, 3), (7, 7), (65535, 65536), (u32::MAX, u32::MAX)]
+        );
+    }
+
+    #[test]
+    fn test_disputable_retention_only_keeps_deposits() {
+        let mut ledger = Ledger::with_options(&LedgerOptions {
+            retention: Retention::Disputable,
+            ..Default::default()
+        })
+        .unwrap();
+        ledger
+            .record(&transaction(TxType::Deposit, 1, 5.0), Funds::ZERO, 0)
+            .unwrap();
+        ledger
+            .record(&transaction(TxType::Withdrawal, 2, 1.0), Funds::ZERO, 1)
+            .unwrap();
+
+        assert!(ledger.get(1, 2).unwrap().is_some());
+        assert!(ledger.get(2, 2).unwrap().is_none());
+        // Still detected as a duplicate.
+        assert!(ledger.contains(2));
+    }
+
+    #[test]
+    fn test_window_drops_old_records_unless_disputed() {
+        let mut ledger = Ledger::with_options(&LedgerOptions {
+            window: Some(2),
+            ..Default::default()
+        })
+        .unwrap();
+        ledger
+            .record(&transaction(TxType::Deposit, 1, 5.0), Funds::ZERO, 0)
+            .unwrap();
+        ledger
+            .record(&transaction(TxType::Deposit, 2, 5.0), Funds::ZERO, 1)
+            .unwrap();
+        ledger.set_state(2, TxState::Disputed).unwrap();
+        ledger
+            .record(&transaction(TxType::Deposit, 3, 5.0), Funds::ZERO, 4)
+            .unwrap();
+
+        assert!(ledger.get(1, 4).unwrap().is_none());
+        assert_eq!(ledger.get(2, 4).unwrap().unwrap().state, TxState::Disputed);
+        assert!(ledger.get(3, 4).unwrap().is_some());
+        assert_eq!(ledger.entries().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_window_drops_old_adjustments() {
+        let mut ledger = Ledger::with_options(&LedgerOptions {
+            window: Some(2),
+            ..Default::default()
+        })
+        .unwrap();
+        for (timestamp, seq) in [(100, 0), (200, 1), (300, 4)] {
+            ledger.record_adjustment(Adjustment {
+                client: 1,
+                kind: AdjustmentKind::Fee,
+                amount: funds(1.0),
+                timestamp,
+                seq,
+            });
+        }
+
+        let timestamps: Vec<_> = ledger.adjustments().iter().map(|a| a.timestamp).collect();
+        assert_eq!(timestamps, vec![300]);
+    }
+
+    #[test]
+    fn test_window_drops_settled_records() {
+        let mut ledger = Ledger::with_options(&LedgerOptions {
+            window: Some(2),
+            ..Default::default()
+        })
+        .unwrap();
+        ledger
+            .record(&transaction(TxType::Deposit, 1, 5.0), Funds::ZERO, 0)
+            .unwrap();
+        ledger
+            .record(&transaction(TxType::Authorize, 2, 5.0), Funds::ZERO, 1)
+            .unwrap();
+        ledger.set_state(1, TxState::Disputed).unwrap();
+        ledger
+            .record(&transaction(TxType::Deposit, 3, 5.0), Funds::ZERO, 4)
+            .unwrap();
+        assert_eq!(ledger.entries().unwrap().len(), 3);
+
+        // Settled once out of the window, both are dropped a window later.
+        ledger.set_state(1, TxState::Resolved).unwrap();
+        ledger.set_state(2, TxState::Captured).unwrap();
+        assert!(ledger.get(1, 5).unwrap().is_none());
+        ledger
+            .record(&transaction(TxType::Deposit, 4, 5.0), Funds::ZERO, 7)
+            .unwrap();
+        let kept: Vec<Tx> = ledger
+            .entries()
+            .unwrap()
+            .into_iter()
+            .map(|(tx, _)| tx)
+            .collect();
+        assert_eq!(kept, vec![4]);
+    }
+
+    #[test]
+    fn test_spill_store_moves_old_records_to_disk() {
+        let dir = tempdir().unwrap();
+        let mut ledger = Ledger::with_options(&LedgerOptions {
+            spill: Some((dir.path().join("ledger.spill"), 2)),
+            ..Default::default()
+        })
+        .unwrap();
+
+        for tx in 1..=5 {
+            ledger
+                .record(
+                    &transaction(TxType::Deposit, tx, tx as f32),
+                    Funds::ZERO,
+                    tx as u64,
+                )
+                .unwrap();
+        }
+        ledger.set_state(1, TxState::Disputed).unwrap();
+
+        let entry = ledger.get(1, 5).unwrap().unwrap();
+        assert_eq!(entry.amount, funds(1.0));
+        assert_eq!(entry.state, TxState::Disputed);
+        assert_eq!(entry.seq, 1);
+        assert_eq!(ledger.get(5, 5).unwrap().unwrap().amount, funds(5.0));
+        assert!(ledger.get(6, 5).unwrap().is_none());
+        assert_eq!(ledger.entries().unwrap().len(), 5);
+    }
+
+    #[test]
+    fn test_spill_store_encoding_roundtrip() {
+        let entry = LedgerEntry {
+            variant: TxType::Transfer,
+            client: 65535,
+            amount: Decimal::new(-123456789, 4),
+            to: Some(7),
+            state: TxState::ChargedBack,
+            seq: u64::MAX,
+            timestamp: None,
+            disputed_at: Some(1_700_000_000),
+            fee: Decimal::new(25, 2),
+            refunded: Decimal::new(3, 0),
+        };
+
+        let decoded = SpillStore::decode(&SpillStore::encode(Some(&entry))).unwrap();
+        assert_eq!(decoded, Some(entry));
+        assert_eq!(SpillStore::decode(&SpillStore::encode(None)).unwrap(), None);
+    }
+}
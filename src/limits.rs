@@ -0,0 +1,382 @@
+//! This module defines the limits on what each client can move: maximum amount of a single
+//! transaction, daily and monthly withdrawal limits, and velocity limits (number of withdrawals
+//! within a time window). A transfer counts as a withdrawal of its sender. An authorization is
+//! checked against the withdrawal limits like a withdrawal, and counts as one once captured.
+//!
+//! The limits are read from a JSON file, e.g.:
+//!
+//! ```json
+//! {
+//!   "default_tier": "standard",
+//!   "clients": { "7": "trusted", "9": { "max_transaction": "50" } },
+//!   "tiers": {
+//!     "standard": {
+//!       "max_transaction": "1000",
+//!       "daily_withdrawal": "2000",
+//!       "monthly_withdrawal": "20000",
+//!       "velocity": { "count": 5, "window": 3600 }
+//!     },
+//!     "trusted": { "daily_withdrawal": "10000" }
+//!   }
+//! }
+//! ```
+//!
+//! Each client either belongs to a tier or has limits of its own. Clients not listed in `clients`
+//! belong to `default_tier`, or have no limits if there is none.
+//!
+//! Days and months are calendar days and months (UTC) of the transaction timestamps. Without
+//! timestamps, the whole input counts as the same day.
+
+use crate::{
+    error::{AccountError, Error, ScheduleError},
+    primitives::{Client, Funds, Timestamp},
+    transactions::{Transaction, TxType},
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, VecDeque},
+    fs,
+    path::Path,
+};
+
+/// Maximum number of withdrawals within a time window.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Velocity {
+    /// Maximum number of withdrawals.
+    pub(crate) count: u32,
+    /// Length of the window, in seconds.
+    pub(crate) window: u64,
+}
+
+/// The limits of a client. Missing limits are not enforced.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Limits {
+    /// Maximum amount of a single deposit or withdrawal.
+    #[serde(default)]
+    pub(crate) max_transaction: Option<Funds>,
+    /// Maximum amount withdrawn within a day.
+    #[serde(default)]
+    pub(crate) daily_withdrawal: Option<Funds>,
+    /// Maximum amount withdrawn within a month.
+    #[serde(default)]
+    pub(crate) monthly_withdrawal: Option<Funds>,
+    /// Maximum number of withdrawals within a time window.
+    #[serde(default)]
+    pub(crate) velocity: Option<Velocity>,
+}
+
+impl Limits {
+    fn is_valid(&self) -> bool {
+        [
+            self.max_transaction,
+            self.daily_withdrawal,
+            self.monthly_withdrawal,
+        ]
+        .into_iter()
+        .flatten()
+        .all(|limit| limit >= Funds::ZERO)
+    }
+
+    /// Checks that the [`Transaction`] of the given amount, applied at the given time, is within
+    /// the limits, given what the client already used.
+    pub(crate) fn check(
+        &self,
+        usage: &Usage,
+        transaction: &Transaction,
+        amount: Funds,
+        now: Timestamp,
+    ) -> Result<(), AccountError> {
+        let client = transaction.client;
+        if let Some(max) = self.max_transaction
+            && amount > max
+        {
+            return Err(AccountError::TransactionLimitExceeded(client, max, amount));
+        }
+
+        if !matches!(
+            transaction.variant,
+            TxType::Withdrawal | TxType::Transfer | TxType::Authorize
+        ) {
+            return Ok(());
+        }
+
+        let (daily, monthly) = usage.withdrawn(now);
+        if let Some(max) = self.daily_withdrawal
+            && daily.checked_add(amount).is_none_or(|total| total > max)
+        {
+            return Err(AccountError::DailyLimitExceeded(client, max, amount));
+        }
+        if let Some(max) = self.monthly_withdrawal
+            && monthly.checked_add(amount).is_none_or(|total| total > max)
+        {
+            return Err(AccountError::MonthlyLimitExceeded(client, max, amount));
+        }
+        if let Some(velocity) = &self.velocity
+            && usage.recent_withdrawals(now, velocity.window) >= velocity.count as usize
+        {
+            return Err(AccountError::VelocityLimitExceeded(
+                client,
+                velocity.count,
+                velocity.window,
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+/// The limits of a single client: either a tier or its own limits.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(untagged)]
+pub(crate) enum ClientLimits {
+    Tier(String),
+    Custom(Limits),
+}
+
+/// The limits of every client.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct LimitSchedule {
+    /// The tier of the clients not listed in `clients`.
+    #[serde(default)]
+    pub(crate) default_tier: Option<String>,
+    /// The tier, or the limits, of each client.
+    #[serde(default)]
+    pub(crate) clients: HashMap<Client, ClientLimits>,
+    /// The limits of each tier, by name.
+    #[serde(default)]
+    pub(crate) tiers: HashMap<String, Limits>,
+}
+
+impl LimitSchedule {
+    /// Reads and validates a limit schedule from a JSON file.
+    pub(crate) fn load(path: &Path) -> Result<Self, Error> {
+        let content = fs::read_to_string(path)?;
+        let schedule: Self = serde_json::from_str(&content).map_err(ScheduleError::Malformed)?;
+        schedule.validate()?;
+        Ok(schedule)
+    }
+
+    /// Checks that every tier referenced exists, and that no limit is negative.
+    pub(crate) fn validate(&self) -> Result<(), ScheduleError> {
+        let tiers = self.clients.values().filter_map(|limits| match limits {
+            ClientLimits::Tier(tier) => Some(tier),
+            ClientLimits::Custom(_) => None,
+        });
+        for tier in self.default_tier.iter().chain(tiers) {
+            if !self.tiers.contains_key(tier) {
+                return Err(ScheduleError::UnknownTier(tier.clone()));
+            }
+        }
+
+        for (name, limits) in &self.tiers {
+            if !limits.is_valid() {
+                return Err(ScheduleError::InvalidRule(name.clone()));
+            }
+        }
+        for (client, limits) in &self.clients {
+            if let ClientLimits::Custom(limits) = limits
+                && !limits.is_valid()
+            {
+                return Err(ScheduleError::InvalidRule(client.to_string()));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The limits of a client, if any.
+    pub(crate) fn limits(&self, client: Client) -> Option<&Limits> {
+        let tier = match self.clients.get(&client) {
+            Some(ClientLimits::Custom(limits)) => return Some(limits),
+            Some(ClientLimits::Tier(tier)) => Some(tier),
+            None => self.default_tier.as_ref(),
+        };
+        tier.and_then(|tier| self.tiers.get(tier))
+    }
+}
+
+/// What a client already withdrew, to check the limits against.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+pub(crate) struct Usage {
+    /// Day of the latest withdrawal, in days since the Unix epoch.
+    day: u64,
+    /// Amount withdrawn within that day.
+    daily: Funds,
+    /// Month of the latest withdrawal, in months since the Unix epoch.
+    month: u64,
+    /// Amount withdrawn within that month.
+    monthly: Funds,
+    /// Times of the latest withdrawals, oldest first, as long as they matter for the velocity
+    /// limit.
+    recent: VecDeque<Timestamp>,
+}
+
+impl Usage {
+    /// Amounts withdrawn within the day and within the month of the given time.
+    fn withdrawn(&self, now: Timestamp) -> (Funds, Funds) {
+        let daily = if day(now) == self.day {
+            self.daily
+        } else {
+            Funds::ZERO
+        };
+        let monthly = if month(now) == self.month {
+            self.monthly
+        } else {
+            Funds::ZERO
+        };
+        (daily, monthly)
+    }
+
+    /// Number of withdrawals within the window ending at the given time.
+    fn recent_withdrawals(&self, now: Timestamp, window: u64) -> usize {
+        self.recent
+            .iter()
+            .filter(|&&at| now.saturating_sub(at) < window)
+            .count()
+    }
+
+    /// Takes an accepted withdrawal of the given amount, at the given time, into account.
+    pub(crate) fn record(&mut self, limits: &Limits, amount: Funds, now: Timestamp) {
+        let (daily, monthly) = self.withdrawn(now);
+        self.day = day(now);
+        self.daily = daily.saturating_add(amount);
+        self.month = month(now);
+        self.monthly = monthly.saturating_add(amount);
+
+        if let Some(velocity) = &limits.velocity {
+            self.recent.push_back(now);
+            while self
+                .recent
+                .front()
+                .is_some_and(|&at| now.saturating_sub(at) >= velocity.window)
+            {
+                self.recent.pop_front();
+            }
+        }
+    }
+}
+
+/// Day of a timestamp, in days since the Unix epoch.
+fn day(timestamp: Timestamp) -> u64 {
+    timestamp / 86_400
+}
+
+/// Calendar month of a timestamp, in months since the Unix epoch.
+fn month(timestamp: Timestamp) -> u64 {
+    // Converts days to a civil date, see http://howardhinnant.github.io/date_algorithms.html
+    let z = day(timestamp) + 719_468;
+    let era = z / 146_097;
+    let doe = z % 146_097;
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    // Months counted from March, so January and February belong to the next year.
+    let year = yoe + era * 400 + u64::from(mp >= 10);
+    let month = if mp < 10 { mp + 2 } else { mp - 10 };
+    (year - 1970) * 12 + month
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal::Decimal;
+
+    fn funds(amount: f32) -> Decimal {
+        Decimal::from_f32_retain(amount).unwrap()
+    }
+
+    fn withdrawal(amount: f32) -> Transaction {
+        Transaction {
+            variant: TxType::Withdrawal,
+            client: 1,
+            tx: 1,
+            amount: Some(funds(amount)),
+            to: None,
+            batch_id: None,
+            timestamp: None,
+        }
+    }
+
+    #[test]
+    fn test_month_follows_the_calendar() {
+        assert_eq!(month(0), 0);
+        // 2024-01-31T23:59:59Z and 2024-02-01T00:00:00Z.
+        assert_eq!(month(1_706_745_599), 54 * 12);
+        assert_eq!(month(1_706_745_600), 54 * 12 + 1);
+        // 2024-12-31T12:00:00Z.
+        assert_eq!(month(1_735_646_400), 54 * 12 + 11);
+    }
+
+    #[test]
+    fn test_daily_limit_resets_the_next_day() {
+        let limits = Limits {
+            daily_withdrawal: Some(funds(100.0)),
+            ..Default::default()
+        };
+        let mut usage = Usage::default();
+        let now = 1_000_000;
+
+        limits
+            .check(&usage, &withdrawal(60.0), funds(60.0), now)
+            .unwrap();
+        usage.record(&limits, funds(60.0), now);
+        assert!(matches!(
+            limits.check(&usage, &withdrawal(60.0), funds(60.0), now + 60),
+            Err(AccountError::DailyLimitExceeded(1, limit, _)) if limit == funds(100.0)
+        ));
+        limits
+            .check(&usage, &withdrawal(60.0), funds(60.0), now + 86_400)
+            .unwrap();
+    }
+
+    #[test]
+    fn test_velocity_limit_counts_withdrawals_in_the_window() {
+        let limits = Limits {
+            velocity: Some(Velocity {
+                count: 2,
+                window: 60,
+            }),
+            ..Default::default()
+        };
+        let mut usage = Usage::default();
+
+        usage.record(&limits, funds(1.0), 100);
+        usage.record(&limits, funds(1.0), 130);
+        assert!(matches!(
+            limits.check(&usage, &withdrawal(1.0), funds(1.0), 150),
+            Err(AccountError::VelocityLimitExceeded(1, 2, 60))
+        ));
+        limits
+            .check(&usage, &withdrawal(1.0), funds(1.0), 160)
+            .unwrap();
+    }
+
+    #[test]
+    fn test_clients_use_their_tier_or_their_own_limits() {
+        let schedule: LimitSchedule = serde_json::from_str(
+            r#"{
+                "default_tier": "standard",
+                "clients": { "7": "trusted", "9": { "max_transaction": "50" } },
+                "tiers": {
+                    "standard": { "max_transaction": "1000" },
+                    "trusted": {}
+                }
+            }"#,
+        )
+        .unwrap();
+        schedule.validate().unwrap();
+
+        assert_eq!(
+            schedule.limits(1).unwrap().max_transaction,
+            Some(funds(1000.0))
+        );
+        assert_eq!(schedule.limits(7).unwrap().max_transaction, None);
+        assert_eq!(
+            schedule.limits(9).unwrap().max_transaction,
+            Some(funds(50.0))
+        );
+    }
+}
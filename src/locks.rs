@@ -0,0 +1,192 @@
+//! This module defines the lock policies: the conditions under which an account gets locked.
+//!
+//! The policies are checked after every transaction applied to an account, and only lock it when
+//! the transaction made things worse on the policy it breaks. A locked account records the policy
+//! that locked it, which shows in the output, and only an operator can unlock it (see `--unlock`).
+//! An unlocked account may still break a policy, say with its past chargebacks, but is only locked
+//! again by a transaction adding to it.
+
+use crate::{accounts::Account, primitives::Funds};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+
+/// Why an account was locked.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum LockReason {
+    /// Too many disputes open at once.
+    OpenDisputes,
+    /// Too many disputes for the number of deposits.
+    DisputeRatio,
+    /// Too much charged back overall.
+    Chargebacks,
+    /// A dispute left the available funds below zero.
+    NegativeBalance,
+}
+
+/// The conditions under which an account gets locked. Unset conditions never lock.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct LockPolicies {
+    /// Lock when this many disputes are open at once.
+    pub(crate) max_open_disputes: Option<usize>,
+    /// Lock when the number of disputes divided by the number of deposits goes above this.
+    pub(crate) max_dispute_ratio: Option<Decimal>,
+    /// Lock when the total charged back goes above this.
+    pub(crate) max_charged_back: Option<Funds>,
+    /// Let disputes leave the available funds below zero, and lock when they do. Otherwise, those
+    /// disputes are rejected.
+    pub(crate) negative_balance: bool,
+}
+
+impl Default for LockPolicies {
+    /// Locks on any chargeback, as the engine always did.
+    fn default() -> Self {
+        Self {
+            max_open_disputes: None,
+            max_dispute_ratio: None,
+            max_charged_back: Some(Funds::ZERO),
+            negative_balance: false,
+        }
+    }
+}
+
+/// What the policies look at on an account, taken before a transaction to tell whether it made
+/// things worse.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub(crate) struct Exposure {
+    available: Funds,
+    open_disputes: usize,
+    dispute_ratio: Option<Decimal>,
+    charged_back: Funds,
+}
+
+impl Exposure {
+    pub(crate) fn of(account: &Account) -> Self {
+        Self {
+            available: account.available(),
+            open_disputes: account.open_disputes(),
+            dispute_ratio: account.dispute_ratio(),
+            charged_back: account.charged_back(),
+        }
+    }
+}
+
+impl LockPolicies {
+    /// The policy the account breaks after a transaction, if any, as long as the transaction took
+    /// it further than it was `before` on that policy.
+    pub(crate) fn check(&self, before: &Exposure, account: &Account) -> Option<LockReason> {
+        let after = Exposure::of(account);
+        if self.negative_balance
+            && after.available < Funds::ZERO
+            && after.available < before.available
+        {
+            return Some(LockReason::NegativeBalance);
+        }
+        if self
+            .max_open_disputes
+            .is_some_and(|max| after.open_disputes >= max)
+            && after.open_disputes > before.open_disputes
+        {
+            return Some(LockReason::OpenDisputes);
+        }
+        if let (Some(max), Some(ratio)) = (self.max_dispute_ratio, after.dispute_ratio)
+            && ratio > max
+            && before.dispute_ratio.is_none_or(|before| ratio > before)
+        {
+            return Some(LockReason::DisputeRatio);
+        }
+        if self
+            .max_charged_back
+            .is_some_and(|max| after.charged_back > max)
+            && after.charged_back > before.charged_back
+        {
+            return Some(LockReason::Chargebacks);
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn funds(amount: f32) -> Decimal {
+        Decimal::from_f32_retain(amount).unwrap()
+    }
+
+    #[test]
+    fn test_default_policies_lock_on_any_chargeback() {
+        let policies = LockPolicies::default();
+        let mut account = Account::new(1);
+        account.credit(funds(10.0)).unwrap();
+        account.count_deposit();
+        account.dispute(funds(5.0), 1).unwrap();
+        assert_eq!(policies.check(&Exposure::default(), &account), None);
+
+        let before = Exposure::of(&account);
+        account.chargeback(1).unwrap();
+        assert_eq!(
+            policies.check(&before, &account),
+            Some(LockReason::Chargebacks)
+        );
+    }
+
+    #[test]
+    fn test_unlocked_account_is_only_locked_again_by_a_new_breach() {
+        let policies = LockPolicies::default();
+        let mut account = Account::new(1);
+        account.credit(funds(10.0)).unwrap();
+        account.count_deposit();
+        account.dispute(funds(5.0), 1).unwrap();
+        account.chargeback(1).unwrap();
+        account.unlock();
+
+        // Still above the chargeback limit, but a deposit does not add to it.
+        let before = Exposure::of(&account);
+        account.credit(funds(3.0)).unwrap();
+        account.count_deposit();
+        assert_eq!(policies.check(&before, &account), None);
+
+        account.dispute(funds(3.0), 2).unwrap();
+        let before = Exposure::of(&account);
+        account.chargeback(2).unwrap();
+        assert_eq!(
+            policies.check(&before, &account),
+            Some(LockReason::Chargebacks)
+        );
+    }
+
+    #[test]
+    fn test_open_disputes_and_dispute_ratio() {
+        let mut account = Account::new(1);
+        account.credit(funds(10.0)).unwrap();
+        for _ in 0..4 {
+            account.count_deposit();
+        }
+        account.dispute(funds(1.0), 1).unwrap();
+        account.dispute(funds(1.0), 2).unwrap();
+
+        let open_disputes = LockPolicies {
+            max_open_disputes: Some(2),
+            ..Default::default()
+        };
+        assert_eq!(
+            open_disputes.check(&Exposure::default(), &account),
+            Some(LockReason::OpenDisputes)
+        );
+
+        let ratio = |max: f32| LockPolicies {
+            max_dispute_ratio: Some(funds(max)),
+            ..Default::default()
+        };
+        let before = Exposure::default();
+        assert_eq!(ratio(0.5).check(&before, &account), None);
+        assert_eq!(
+            ratio(0.25).check(&before, &account),
+            Some(LockReason::DisputeRatio)
+        );
+        // No longer worse than it was.
+        let before = Exposure::of(&account);
+        assert_eq!(ratio(0.25).check(&before, &account), None);
+    }
+}
@@ -1,33 +1,241 @@
 //! Main entrypoint of the application.
 
 use behaviors::{CsvTransactionSource, TransactionProcessor, TransactionSource};
+use clap::Parser;
+use cli::{Command, ConfigCommand, QueryCommand};
 use io::csv_reader;
 
 pub(crate) mod accounts;
+pub(crate) mod adjustments;
+pub(crate) mod audit;
+pub(crate) mod batches;
 pub(crate) mod behaviors;
+pub(crate) mod cli;
+pub(crate) mod config;
 pub(crate) mod engine;
 pub(crate) mod error;
+pub(crate) mod fees;
 pub(crate) mod io;
+pub(crate) mod ledger;
+pub(crate) mod limits;
+pub(crate) mod locks;
+pub(crate) mod preview;
 pub(crate) mod primitives;
+pub(crate) mod query;
+pub(crate) mod replay;
+pub(crate) mod report;
+pub(crate) mod risk;
+pub(crate) mod sequencer;
+pub(crate) mod snapshot;
+pub(crate) mod telemetry;
 pub(crate) mod transactions;
+pub(crate) mod wal;
 
 fn main() -> Result<(), crate::error::Error> {
-    // crate::errors::errors_to_file()?;
+    let cli = crate::cli::Cli::parse();
+    crate::telemetry::init_logging(cli.log_level, cli.log_file.as_deref())?;
+
+    // Commands looking at the outcome of previous runs, instead of processing a file.
+    match &cli.command {
+        Some(Command::History { client, audit_log }) => {
+            let history = crate::audit::history(audit_log, *client)?;
+            return crate::io::write_json_lines(history);
+        }
+        Some(Command::BalancesAt(args)) => {
+            let (replay, mut source) = open_run(&args.run)?;
+            let point = (&args.point).into();
+            let balances = replay.balances_at(source.get_transactions(), args.client, point)?;
+            return crate::io::write_json_lines([balances]);
+        }
+        Some(Command::Replay(args)) => {
+            let right = args.right().unwrap_or_else(|e| e.exit());
+            let (left, mut left_source) = open_run(&args.left)?;
+            let (right, mut right_source) = open_run(&right)?;
+            let diff = crate::replay::diff(
+                left,
+                left_source.get_transactions(),
+                right,
+                right_source.get_transactions(),
+            )?;
+            return crate::io::write_json_lines(diff);
+        }
+        Some(Command::Query {
+            snapshot,
+            config,
+            query,
+        }) => {
+            let mut config = match config {
+                Some(path) => crate::config::ConfigFile::load(path)?.engine_config()?,
+                None => crate::engine::EngineConfig::default(),
+            };
+            // A query only reads the snapshot, the spill file of the run must not be truncated.
+            config.ledger.spill = None;
+            let (mut engine, accounts) = crate::snapshot::load(snapshot, &config)?;
+            let lookup = crate::query::Query::new(engine.ledger_mut(), &accounts)?;
+            return run_query(query, &lookup);
+        }
+        Some(Command::Config {
+            command: ConfigCommand::Check { path },
+        }) => {
+            let config = crate::config::ConfigFile::load(path)?;
+            config.engine_config()?;
+            print!(
+                "{}",
+                toml::to_string(&config).map_err(std::io::Error::other)?
+            );
+            return Ok(());
+        }
+        None => {}
+    }
 
     // Create the source of the transactions.
-    let file_path = crate::io::get_filepath()?;
-    let reader = csv_reader(&file_path)?;
+    // Safe to unwrap since the file is required without a command.
+    let reader = csv_reader(cli.file.as_deref().unwrap())?;
     let mut transaction_source = CsvTransactionSource::new(reader);
-    // Create the account holder.
-    let mut accounts = crate::accounts::Accounts::new();
-    // Create the engine.
-    let mut engine = crate::engine::Engine::new();
+    // Create the account holder and the engine, either empty or from a previous snapshot.
+    let mut config = cli.engine.engine_config()?;
+    if cli.dry_run {
+        // The spill file of the configuration file would be truncated, keep the ledger in memory.
+        config.ledger.spill = None;
+    }
+    let sequencing = config.sequencing.clone();
+    let (rounding, output) = (config.rounding, config.output);
+    let (mut engine, mut accounts) = match &cli.snapshot {
+        Some(path) => crate::snapshot::load(path, &config)?,
+        None => (
+            crate::engine::Engine::new(config)?,
+            crate::accounts::Accounts::new(),
+        ),
+    };
+    accounts.set_rounding(rounding);
+
+    // What the accounts hold before the run, for the control totals.
+    let opening_total = crate::report::total(&accounts);
+
+    // Put the transactions back in order. The transactions known at this point are those of the
+    // snapshot, which keeps the order the same when processing the file again after a crash.
+    let known = match sequencing.pending {
+        Some(_) => engine.ledger().seen().clone(),
+        None => Default::default(),
+    };
+    let transactions =
+        crate::sequencer::Sequencer::new(transaction_source.get_transactions(), sequencing, known);
+
+    // Process all the transactions with the engine, going through the write-ahead log if
+    // requested, and audit them if requested. The operator unlocks the requested accounts before
+    // applying anything.
+    let audit_log = cli
+        .audit_log
+        .as_deref()
+        .map(crate::audit::AuditLog::open)
+        .transpose()?;
+    let mut wal = None;
+    let mut rejections = None;
+    if cli.dry_run {
+        for client in &cli.unlock {
+            engine.unlock(&mut accounts, *client);
+        }
+        let mut processor = crate::preview::PreviewProcessor::new(&mut engine);
+        processor.process_transactions(transactions, &mut accounts)?;
+        rejections = Some(processor.into_rejections());
+    } else if let Some(path) = &cli.wal {
+        // Finish whatever an interrupted run left behind before touching the input. The rows
+        // replayed are audited, unless that run already audited them.
+        if let Some(log) = audit_log {
+            engine.set_audit_log(log);
+        }
+        let recovery = crate::wal::recover(path, &mut engine, &mut accounts)?;
+        let wal = wal.insert(crate::wal::Wal::open(path, cli.wal_sync_every)?);
+        let mut processor = crate::wal::WalProcessor::new(&mut engine, wal, recovery.resume_after);
+        // The interrupted run already unlocked them, at the same point.
+        for client in &cli.unlock {
+            if !recovery.unlocked.contains(client) {
+                processor.unlock(&mut accounts, *client)?;
+            }
+        }
+        processor.process_transactions(transactions, &mut accounts)?;
+    } else {
+        if let Some(log) = audit_log {
+            engine.set_audit_log(log);
+        }
+        for client in &cli.unlock {
+            engine.unlock(&mut accounts, *client);
+        }
+        engine.process_transactions(transactions, &mut accounts)?;
+    }
+    engine.flush_audit_log()?;
+
+    // Keep the state around for the next run, if requested.
+    if let Some(path) = &cli.save_snapshot {
+        crate::snapshot::save(path, &mut engine, &accounts)?;
+        // The log is now part of the snapshot.
+        if let Some(wal) = &mut wal {
+            wal.truncate()?;
+        }
+    }
+
+    // Leave the held transactions for review, if requested.
+    if let Some(path) = &cli.held_output {
+        crate::io::write_held(path, engine.held())?;
+    }
 
-    // Process all the transactions with the engine.
-    engine.process_transactions(&mut transaction_source.get_transactions(), &mut accounts)?;
+    // Dump the metrics of the run, if requested.
+    if let Some(path) = &cli.metrics {
+        std::fs::write(path, engine.metrics().prometheus())?;
+    }
+    if cli.metrics_summary {
+        eprint!("{}", engine.metrics().summary());
+    }
 
-    // Output the accounts.
-    crate::io::write_csv(accounts)?;
+    // Reconcile the control totals with the accounts, if requested.
+    if let Some(path) = &cli.report {
+        let report = crate::report::Report::new(&engine, &accounts, opening_total);
+        if !report.reconciled {
+            tracing::error!(
+                "The control totals do not reconcile: expected {}, the accounts hold {}",
+                report.opening_total + report.totals.net(),
+                report.closing_total
+            );
+        }
+        report.write(path)?;
+    }
+
+    // Output the accounts, or what the run would do to them when dry running.
+    if let Some(rejections) = rejections {
+        let preview = crate::preview::Preview::new(&rejections, &accounts);
+        serde_json::to_writer_pretty(std::io::stdout().lock(), &preview)
+            .map_err(std::io::Error::from)?;
+        println!();
+        return Ok(());
+    }
+    crate::io::write_accounts(&accounts, output)?;
 
     Ok(())
 }
+
+/// Prepares the replay of a run, and the source of its transactions.
+fn open_run(
+    run: &crate::cli::RunArgs,
+) -> Result<(crate::replay::Replay, CsvTransactionSource<std::fs::File>), crate::error::Error> {
+    let replay = crate::replay::Replay::new(run.engine.engine_config()?, run.snapshot.as_deref())?;
+    Ok((replay, CsvTransactionSource::new(csv_reader(&run.file)?)))
+}
+
+/// Prints the results of a query over a snapshot, as JSON lines.
+fn run_query(
+    query: &QueryCommand,
+    lookup: &crate::query::Query,
+) -> Result<(), crate::error::Error> {
+    match query {
+        QueryCommand::Transaction { tx } => crate::io::write_json_lines(lookup.transaction(*tx)),
+        QueryCommand::Client { client } => {
+            crate::io::write_json_lines(lookup.transactions_of(*client))
+        }
+        QueryCommand::State { state } => crate::io::write_json_lines(lookup.with_state(*state)),
+        QueryCommand::OpenDisputes => crate::io::write_json_lines(lookup.open_disputes()),
+        QueryCommand::Locked => crate::io::write_json_lines(lookup.locked_accounts()),
+        QueryCommand::Balance { on, above, below } => {
+            crate::io::write_json_lines(lookup.accounts_between(*on, *above, *below))
+        }
+    }
+}
@@ -0,0 +1,181 @@
+//! This module defines the dry runs: a file goes through every check of the [`Engine`] as in a real
+//! run, but nothing is persisted, and the outcome is a preview of the rejections and the balances
+//! the run would end with.
+//!
+//! The accounts and the engine only change in memory, so the rows of the file can refer to each
+//! other (e.g. a dispute on a deposit of the same file), and nothing of the snapshot, write-ahead
+//! log or audit log is written. Unlike a real run, malformed rows are reported with the others
+//! instead of stopping the run.
+
+use crate::{
+    accounts::{AccountRow, Accounts},
+    batches::Batches,
+    behaviors::TransactionProcessor,
+    engine::Engine,
+    error::{Error, ErrorReport},
+    primitives::{Client, Tx},
+    transactions::Transaction,
+};
+use serde::Serialize;
+
+/// A row the run would reject.
+#[derive(Debug, Serialize)]
+pub(crate) struct Rejection {
+    /// The transaction, unless the row is malformed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) tx: Option<Tx>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) client: Option<Client>,
+    #[serde(rename = "type", skip_serializing_if = "Option::is_none")]
+    pub(crate) variant: Option<&'static str>,
+    pub(crate) error: ErrorReport,
+}
+
+/// The outcome of a dry run.
+#[derive(Debug, Serialize)]
+pub(crate) struct Preview<'a> {
+    /// The rows the run would reject, in the order they were applied.
+    pub(crate) rejections: &'a [Rejection],
+    /// The balances the run would end with, in ascending client id.
+    pub(crate) accounts: Vec<AccountRow<'a>>,
+}
+
+impl<'a> Preview<'a> {
+    pub(crate) fn new(rejections: &'a [Rejection], accounts: &'a Accounts) -> Self {
+        let mut accounts: Vec<AccountRow> = accounts.rows().collect();
+        accounts.sort_by_key(|row| row.account.client());
+        Self {
+            rejections,
+            accounts,
+        }
+    }
+}
+
+/// Processor applying the transactions in memory only, and keeping the rejections.
+pub(crate) struct PreviewProcessor<'a> {
+    engine: &'a mut Engine,
+    rejections: Vec<Rejection>,
+}
+
+impl<'a> PreviewProcessor<'a> {
+    pub(crate) fn new(engine: &'a mut Engine) -> Self {
+        Self {
+            engine,
+            rejections: Vec::new(),
+        }
+    }
+
+    /// The rows rejected, once the transactions were processed.
+    pub(crate) fn into_rejections(self) -> Vec<Rejection> {
+        self.rejections
+    }
+}
+
+impl TransactionProcessor for PreviewProcessor<'_> {
+    fn process_transactions<I>(
+        &mut self,
+        transactions: I,
+        accounts: &mut Accounts,
+    ) -> Result<(), Error>
+    where
+        I: IntoIterator<Item = Result<Transaction, csv::Error>>,
+    {
+        let batch_id = |record: &Result<Transaction, csv::Error>| {
+            record
+                .as_ref()
+                .ok()
+                .and_then(|transaction| transaction.batch_id)
+        };
+
+        for rows in Batches::new(transactions, batch_id) {
+            let rows: Vec<Transaction> = match rows {
+                Ok(rows) => rows,
+                Err(e) => {
+                    self.rejections.push(Rejection {
+                        tx: None,
+                        client: None,
+                        variant: None,
+                        error: Error::from(e).report(),
+                    });
+                    continue;
+                }
+            };
+
+            let legs: Vec<(Tx, Client, &'static str)> = rows
+                .iter()
+                .map(|row| (row.tx, row.client, row.variant.name()))
+                .collect();
+            if let Err(e) = self.engine.apply_group(accounts, rows) {
+                for (tx, client, variant) in legs {
+                    self.rejections.push(Rejection {
+                        tx: Some(tx),
+                        client: Some(client),
+                        variant: Some(variant),
+                        error: e.report_for(tx),
+                    });
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{io::csv_reader, transactions::TxType};
+    use rust_decimal::Decimal;
+    use std::io::Write;
+    use tempfile::NamedTempFile;
+
+    #[test]
+    fn test_preview_reports_every_rejection() {
+        let mut file = NamedTempFile::new().unwrap();
+        writeln!(
+            file,
+            "type,client,tx,amount,to,batch_id\n\
+             deposit,1,1,10,,\n\
+             withdrawal,1,2,20,,\n\
+             deposit,1,three,1,,\n\
+             dispute,1,1,,,\n\
+             deposit,2,4,5,,7\n\
+             withdrawal,2,5,50,,7"
+        )
+        .unwrap();
+        let mut reader = csv_reader(file.path().to_str().unwrap()).unwrap();
+
+        let mut engine = Engine::default();
+        let mut accounts = Accounts::new();
+        let mut processor = PreviewProcessor::new(&mut engine);
+        processor
+            .process_transactions(reader.deserialize::<Transaction>(), &mut accounts)
+            .unwrap();
+
+        let rejections = processor.into_rejections();
+        let preview = Preview::new(&rejections, &accounts);
+        let codes: Vec<(Option<Tx>, &str)> = preview
+            .rejections
+            .iter()
+            .map(|rejection| (rejection.tx, rejection.error.code))
+            .collect();
+        assert_eq!(
+            codes,
+            vec![
+                (Some(2), "account.insufficient_funds"),
+                (None, "csv"),
+                (Some(4), "batch.rejected"),
+                (Some(5), "account.insufficient_funds"),
+            ]
+        );
+        assert_eq!(preview.rejections[1].error.context.line, Some(4));
+        assert_eq!(
+            preview.rejections[0].variant,
+            Some(TxType::Withdrawal.name())
+        );
+
+        // The dispute on the deposit of the same file went through.
+        assert_eq!(preview.accounts.len(), 2);
+        assert_eq!(preview.accounts[0].account.held(), Decimal::TEN);
+    }
+}
@@ -3,3 +3,7 @@ use rust_decimal::Decimal;
 pub(crate) type Client = u16;
 pub(crate) type Funds = Decimal;
 pub(crate) type Tx = u32;
+/// Identifier shared by the legs of a batch.
+pub(crate) type BatchId = u32;
+/// Seconds since the Unix epoch.
+pub(crate) type Timestamp = u64;
@@ -0,0 +1,197 @@
+//! This module defines the lookups support runs over the state of the engine: the transactions
+//! recorded in the [`Ledger`] (by id, by client, by dispute state) and the [`Accounts`] (locked, or
+//! by balance).
+//!
+//! A [`Query`] reads every kept ledger record once and indexes them by client and by state, so it
+//! is meant for looking at a snapshot, not for running alongside the engine.
+
+use crate::{
+    accounts::{Account, AccountRow, Accounts},
+    adjustments::Balance,
+    error::Error,
+    ledger::{Ledger, LedgerEntry, TxState},
+    primitives::{Client, Funds, Tx},
+};
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+
+/// A recorded transaction, with its id.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub(crate) struct TransactionView {
+    pub(crate) tx: Tx,
+    #[serde(flatten)]
+    pub(crate) entry: LedgerEntry,
+}
+
+/// Lookups over the ledger and the accounts, with secondary indexes.
+pub(crate) struct Query<'a> {
+    accounts: &'a Accounts,
+    entries: BTreeMap<Tx, LedgerEntry>,
+    /// The transactions of each client, sending or receiving, in ascending id.
+    by_client: HashMap<Client, Vec<Tx>>,
+    /// The transactions in each state, in ascending id.
+    by_state: HashMap<TxState, Vec<Tx>>,
+}
+
+impl<'a> Query<'a> {
+    /// Indexes the records kept in the ledger, and the accounts.
+    pub(crate) fn new(ledger: &mut Ledger, accounts: &'a Accounts) -> Result<Self, Error> {
+        let entries: BTreeMap<Tx, LedgerEntry> = ledger.entries()?.into_iter().collect();
+        let mut by_client: HashMap<Client, Vec<Tx>> = HashMap::new();
+        let mut by_state: HashMap<TxState, Vec<Tx>> = HashMap::new();
+        for (tx, entry) in &entries {
+            for client in std::iter::once(entry.client).chain(entry.to) {
+                by_client.entry(client).or_default().push(*tx);
+            }
+            by_state.entry(entry.state).or_default().push(*tx);
+        }
+
+        Ok(Self {
+            accounts,
+            entries,
+            by_client,
+            by_state,
+        })
+    }
+
+    /// A recorded transaction, with its dispute state, if the ledger kept it.
+    pub(crate) fn transaction(&self, tx: Tx) -> Option<TransactionView> {
+        self.entries
+            .get(&tx)
+            .map(|entry| TransactionView { tx, entry: *entry })
+    }
+
+    /// The recorded transactions sending from, or receiving to, the account of a client.
+    pub(crate) fn transactions_of(&self, client: Client) -> Vec<TransactionView> {
+        self.views(self.by_client.get(&client))
+    }
+
+    /// The recorded transactions in a state.
+    pub(crate) fn with_state(&self, state: TxState) -> Vec<TransactionView> {
+        self.views(self.by_state.get(&state))
+    }
+
+    /// The transactions under an open dispute.
+    pub(crate) fn open_disputes(&self) -> Vec<TransactionView> {
+        self.with_state(TxState::Disputed)
+    }
+
+    /// The locked accounts, in ascending client id.
+    pub(crate) fn locked_accounts(&self) -> Vec<AccountRow<'a>> {
+        self.accounts_where(|account| account.is_locked())
+    }
+
+    /// The accounts whose balance is strictly above and/or below the given amounts, in ascending
+    /// client id.
+    pub(crate) fn accounts_between(
+        &self,
+        balance: Balance,
+        above: Option<Funds>,
+        below: Option<Funds>,
+    ) -> Vec<AccountRow<'a>> {
+        self.accounts_where(|account| {
+            let funds = balance.of(account);
+            above.is_none_or(|above| funds > above) && below.is_none_or(|below| funds < below)
+        })
+    }
+
+    fn accounts_where(&self, filter: impl Fn(&Account) -> bool) -> Vec<AccountRow<'a>> {
+        let mut accounts: Vec<AccountRow> = self
+            .accounts
+            .rows()
+            .filter(|row| filter(row.account))
+            .collect();
+        accounts.sort_by_key(|row| row.account.client());
+        accounts
+    }
+
+    fn views(&self, txs: Option<&Vec<Tx>>) -> Vec<TransactionView> {
+        txs.into_iter()
+            .flatten()
+            .filter_map(|tx| self.transaction(*tx))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        engine::Engine,
+        transactions::{Transaction, TxType},
+    };
+    use rust_decimal::Decimal;
+
+    fn funds(amount: f32) -> Decimal {
+        Decimal::from_f32_retain(amount).unwrap()
+    }
+
+    fn transaction(
+        variant: TxType,
+        client: Client,
+        tx: Tx,
+        amount: Option<f32>,
+        to: Option<Client>,
+    ) -> Transaction {
+        Transaction {
+            variant,
+            client,
+            tx,
+            amount: amount.map(funds),
+            to,
+            batch_id: None,
+            timestamp: None,
+        }
+    }
+
+    #[test]
+    fn test_lookups_by_id_client_and_state() {
+        let mut engine = Engine::default();
+        let mut accounts = Accounts::new();
+        for transaction in [
+            transaction(TxType::Deposit, 1, 1, Some(10.0), None),
+            transaction(TxType::Deposit, 2, 2, Some(20.0), None),
+            transaction(TxType::Transfer, 2, 3, Some(5.0), Some(1)),
+            transaction(TxType::Deposit, 3, 4, Some(1.0), None),
+            transaction(TxType::Dispute, 1, 1, None, None),
+            transaction(TxType::Dispute, 3, 4, None, None),
+            transaction(TxType::Chargeback, 3, 4, None, None),
+        ] {
+            engine.apply(&mut accounts, transaction).unwrap();
+        }
+
+        let query = Query::new(engine.ledger_mut(), &accounts).unwrap();
+        let disputed = query.transaction(1).unwrap();
+        assert_eq!(disputed.entry.state, TxState::Disputed);
+        assert!(query.transaction(5).is_none());
+
+        let txs = |views: Vec<TransactionView>| -> Vec<Tx> {
+            views.into_iter().map(|view| view.tx).collect()
+        };
+        assert_eq!(txs(query.transactions_of(1)), vec![1, 3]);
+        assert_eq!(txs(query.transactions_of(2)), vec![2, 3]);
+        assert_eq!(txs(query.open_disputes()), vec![1]);
+        assert_eq!(txs(query.with_state(TxState::ChargedBack)), vec![4]);
+        assert_eq!(txs(query.with_state(TxState::Processed)), vec![2, 3]);
+
+        let clients = |accounts: Vec<AccountRow>| -> Vec<Client> {
+            accounts
+                .into_iter()
+                .map(|row| row.account.client())
+                .collect()
+        };
+        assert_eq!(clients(query.locked_accounts()), vec![3]);
+        assert_eq!(
+            clients(query.accounts_between(Balance::Total, Some(funds(10.0)), None)),
+            vec![1, 2]
+        );
+        assert_eq!(
+            clients(query.accounts_between(Balance::Available, None, Some(funds(10.0)))),
+            vec![1, 3]
+        );
+        assert_eq!(
+            clients(query.accounts_between(Balance::Held, Some(Funds::ZERO), Some(funds(20.0)))),
+            vec![1]
+        );
+    }
+}
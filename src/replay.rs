@@ -0,0 +1,467 @@
+//! This module defines how the state of the accounts is rebuilt at a past point, by replaying the
+//! transactions from a checkpoint.
+//!
+//! The ledger only keeps the latest state of each transaction, not what happened to it, so the
+//! transactions themselves are replayed: the input files, starting from the snapshot saved before
+//! them (or from nothing). The snapshots saved after each file are the checkpoints, so a replay
+//! never has to go further back than the file containing the point.
+//!
+//! The rows go through the same steps as in a run (put back in order, grouped into batches) with
+//! the same engine configuration, so the replay reproduces the run exactly. Operator unlocks are
+//! not replayed.
+//!
+//! Two replays can also run side by side, e.g. the same file with two engine configurations, to
+//! [`diff`] the accounts they end up with and find where each account diverged.
+
+use crate::{
+    accounts::Accounts,
+    audit::Balances,
+    batches::Batches,
+    engine::{Engine, EngineConfig},
+    error::{Error, TransactionError},
+    primitives::{BatchId, Client, Timestamp, Tx},
+    sequencer::{Sequencer, SequencerOptions},
+    transactions::Transaction,
+};
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::Path,
+};
+
+/// A point in the history of the transactions.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Point {
+    /// Right after the transaction with this id was applied, or rejected.
+    Tx(Tx),
+    /// Once the engine clock is moved to this timestamp, right before a transaction moves it past.
+    /// The adjustments due by then are applied.
+    Timestamp(Timestamp),
+}
+
+/// The balances of a client at a point.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub(crate) struct BalancesAt {
+    pub(crate) client: Client,
+    #[serde(flatten)]
+    pub(crate) balances: Balances,
+    /// The engine sequence at the point.
+    pub(crate) seq: u64,
+    /// The engine clock at the point, if set.
+    pub(crate) timestamp: Option<Timestamp>,
+}
+
+/// The first step of two replays after which an account differs between them.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub(crate) struct Divergence {
+    /// Number of groups of rows (transactions, or batches) applied by both replays, `0` if the
+    /// account differed from the start.
+    pub(crate) step: u64,
+    /// The first transaction applied by each replay at that step, if any.
+    pub(crate) left_tx: Option<Tx>,
+    pub(crate) right_tx: Option<Tx>,
+}
+
+/// An account that differs between two replays.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub(crate) struct AccountDiff {
+    pub(crate) client: Client,
+    /// The balances each replay ended with.
+    pub(crate) left: Balances,
+    pub(crate) right: Balances,
+    /// Where the account first diverged, if known.
+    pub(crate) diverged_at: Option<Divergence>,
+}
+
+/// The rows of a replay, put back in order and grouped into batches.
+type Groups<I> = Batches<Sequencer<I>>;
+
+/// An engine and its accounts, replaying transactions from a checkpoint.
+pub(crate) struct Replay {
+    engine: Engine,
+    accounts: Accounts,
+    sequencing: SequencerOptions,
+    /// The house accounts of the fees and adjustments, which most transactions may touch.
+    houses: Vec<Client>,
+}
+
+impl Replay {
+    /// Starts from the state in a snapshot, or from an empty one.
+    pub(crate) fn new(config: EngineConfig, checkpoint: Option<&Path>) -> Result<Self, Error> {
+        let sequencing = config.sequencing.clone();
+        let houses = config
+            .fees
+            .as_ref()
+            .map(|fees| fees.house)
+            .into_iter()
+            .chain(config.adjustments.as_ref().map(|schedule| schedule.house))
+            .collect();
+        let (engine, accounts) = match checkpoint {
+            Some(path) => crate::snapshot::load(path, &config)?,
+            None => (Engine::new(config)?, Accounts::new()),
+        };
+        Ok(Self {
+            engine,
+            accounts,
+            sequencing,
+            houses,
+        })
+    }
+
+    /// Puts the transactions back in order and groups them into batches, as a run does.
+    fn groups<I>(&self, transactions: I) -> Groups<I::IntoIter>
+    where
+        I: IntoIterator<Item = Result<Transaction, csv::Error>>,
+    {
+        let known = match self.sequencing.pending {
+            Some(_) => self.engine.ledger().seen().clone(),
+            None => Default::default(),
+        };
+        let transactions = Sequencer::new(transactions.into_iter(), self.sequencing.clone(), known);
+        Batches::new(transactions, batch_id)
+    }
+
+    /// The next group of rows, skipping the rows that cannot be read if the configuration says so.
+    fn next_group<I>(&mut self, groups: &mut Groups<I>) -> Result<Option<Vec<Transaction>>, Error>
+    where
+        I: Iterator<Item = Result<Transaction, csv::Error>>,
+    {
+        for rows in groups {
+            match rows {
+                Ok(rows) => return Ok(Some(rows)),
+                Err(e) => self.engine.malformed(e)?,
+            }
+        }
+        Ok(None)
+    }
+
+    /// Applies a group of rows. Rejections are part of the replay, they are only logged.
+    fn apply(&mut self, rows: Vec<Transaction>) {
+        if let Err(e) = self.engine.apply_group(&mut self.accounts, rows) {
+            tracing::debug!(code = e.code(), "{}", e);
+        }
+    }
+
+    /// Applies the transactions until the point, or all of them without one. Returns whether the
+    /// point was reached: a transaction id must be in the input, while a timestamp is reached at
+    /// the end of the input if no transaction went past it. The clock is then moved to the
+    /// timestamp.
+    pub(crate) fn until<I>(&mut self, transactions: I, point: Option<Point>) -> Result<bool, Error>
+    where
+        I: IntoIterator<Item = Result<Transaction, csv::Error>>,
+    {
+        let mut groups = self.groups(transactions);
+        while let Some(rows) = self.next_group(&mut groups)? {
+            if let Some(Point::Timestamp(at)) = point
+                && rows
+                    .iter()
+                    .any(|row| row.timestamp.is_some_and(|timestamp| timestamp > at))
+            {
+                self.engine.advance_clock(&mut self.accounts, at)?;
+                return Ok(true);
+            }
+
+            let reached = match point {
+                Some(Point::Tx(tx)) => rows
+                    .iter()
+                    .any(|row| row.tx == tx && row.variant.creates_record()),
+                _ => false,
+            };
+            self.apply(rows);
+            if reached {
+                return Ok(true);
+            }
+        }
+
+        if let Some(Point::Timestamp(at)) = point {
+            self.engine.advance_clock(&mut self.accounts, at)?;
+            return Ok(true);
+        }
+        Ok(false)
+    }
+
+    /// The balances of a client at a point, replaying the transactions up to it.
+    pub(crate) fn balances_at<I>(
+        mut self,
+        transactions: I,
+        client: Client,
+        point: Point,
+    ) -> Result<BalancesAt, Error>
+    where
+        I: IntoIterator<Item = Result<Transaction, csv::Error>>,
+    {
+        if !self.until(transactions, Some(point))?
+            && let Point::Tx(tx) = point
+        {
+            return Err(TransactionError::NotFound(tx).into());
+        }
+
+        Ok(BalancesAt {
+            client,
+            balances: Balances::of(self.accounts.get(client)),
+            seq: self.engine.sequence(),
+            timestamp: self.engine.clock(),
+        })
+    }
+}
+
+/// The batch of a row, to group the rows into batches.
+fn batch_id(record: &Result<Transaction, csv::Error>) -> Option<BatchId> {
+    record
+        .as_ref()
+        .ok()
+        .and_then(|transaction| transaction.batch_id)
+}
+
+/// Runs two replays side by side, one group of rows at a time, and returns the accounts that differ
+/// between them at the end or diverged at some point, in ascending client id.
+///
+/// After each step, the accounts of the clients of the rows (and the house accounts) are compared,
+/// so an account changed by something else (e.g. a scheduled adjustment) is found diverging at the
+/// next step touching it.
+pub(crate) fn diff<L, R>(
+    mut left: Replay,
+    left_rows: L,
+    mut right: Replay,
+    right_rows: R,
+) -> Result<Vec<AccountDiff>, Error>
+where
+    L: IntoIterator<Item = Result<Transaction, csv::Error>>,
+    R: IntoIterator<Item = Result<Transaction, csv::Error>>,
+{
+    let differs = |left: &Replay, right: &Replay, client: Client| {
+        Balances::of(left.accounts.get(client)) != Balances::of(right.accounts.get(client))
+    };
+
+    // The checkpoints may already differ.
+    let mut divergences: BTreeMap<Client, Divergence> = BTreeMap::new();
+    let start = Divergence {
+        step: 0,
+        left_tx: None,
+        right_tx: None,
+    };
+    let clients: BTreeSet<Client> = left
+        .accounts
+        .iter()
+        .chain(right.accounts.iter())
+        .map(|account| account.client())
+        .collect();
+    for client in clients {
+        if differs(&left, &right, client) {
+            divergences.insert(client, start);
+        }
+    }
+
+    let mut left_groups = left.groups(left_rows);
+    let mut right_groups = right.groups(right_rows);
+    let mut step = 0;
+    loop {
+        let left_group = left.next_group(&mut left_groups)?;
+        let right_group = right.next_group(&mut right_groups)?;
+        if left_group.is_none() && right_group.is_none() {
+            break;
+        }
+        step += 1;
+
+        let first_tx = |group: &Option<Vec<Transaction>>| {
+            group
+                .as_ref()
+                .and_then(|rows| rows.first())
+                .map(|row| row.tx)
+        };
+        let at = Divergence {
+            step,
+            left_tx: first_tx(&left_group),
+            right_tx: first_tx(&right_group),
+        };
+        let mut touched: BTreeSet<Client> = left_group
+            .iter()
+            .chain(right_group.iter())
+            .flatten()
+            .flat_map(|row| std::iter::once(row.client).chain(row.to))
+            .collect();
+        touched.extend(left.houses.iter().chain(right.houses.iter()));
+
+        if let Some(rows) = left_group {
+            left.apply(rows);
+        }
+        if let Some(rows) = right_group {
+            right.apply(rows);
+        }
+        for client in touched {
+            if !divergences.contains_key(&client) && differs(&left, &right, client) {
+                divergences.insert(client, at);
+            }
+        }
+    }
+
+    // Accounts changed outside of the rows compared may only differ at the end.
+    let clients: BTreeSet<Client> = left
+        .accounts
+        .iter()
+        .chain(right.accounts.iter())
+        .map(|account| account.client())
+        .collect();
+    Ok(clients
+        .into_iter()
+        .filter(|client| divergences.contains_key(client) || differs(&left, &right, *client))
+        .map(|client| AccountDiff {
+            client,
+            left: Balances::of(left.accounts.get(client)),
+            right: Balances::of(right.accounts.get(client)),
+            diverged_at: divergences.get(&client).copied(),
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{primitives::Funds, transactions::TxType};
+    use rust_decimal::Decimal;
+
+    fn funds(amount: f32) -> Decimal {
+        Decimal::from_f32_retain(amount).unwrap()
+    }
+
+    fn rows() -> Vec<Result<Transaction, csv::Error>> {
+        let row = |variant, tx, amount: Option<f32>, timestamp| {
+            Ok(Transaction {
+                variant,
+                client: 1,
+                tx,
+                amount: amount.map(funds),
+                to: None,
+                batch_id: None,
+                timestamp: Some(timestamp),
+            })
+        };
+        vec![
+            row(TxType::Deposit, 1, Some(10.0), 100),
+            row(TxType::Deposit, 2, Some(5.0), 200),
+            row(TxType::Dispute, 1, None, 300),
+            row(TxType::Withdrawal, 3, Some(2.0), 400),
+        ]
+    }
+
+    #[test]
+    fn test_balances_at_a_transaction() {
+        let replay = Replay::new(EngineConfig::default(), None).unwrap();
+        let at = replay.balances_at(rows(), 1, Point::Tx(2)).unwrap();
+        assert_eq!(at.balances.total, funds(15.0));
+        assert_eq!(at.seq, 2);
+
+        // The dispute refers to transaction 1, it is not transaction 1.
+        let replay = Replay::new(EngineConfig::default(), None).unwrap();
+        let at = replay.balances_at(rows(), 1, Point::Tx(1)).unwrap();
+        assert_eq!(at.balances.held, Funds::ZERO);
+
+        let replay = Replay::new(EngineConfig::default(), None).unwrap();
+        assert!(matches!(
+            replay.balances_at(rows(), 1, Point::Tx(9)),
+            Err(Error::Transaction(TransactionError::NotFound(9)))
+        ));
+    }
+
+    #[test]
+    fn test_balances_at_a_timestamp() {
+        let replay = Replay::new(EngineConfig::default(), None).unwrap();
+        let at = replay
+            .balances_at(rows(), 1, Point::Timestamp(350))
+            .unwrap();
+        assert_eq!(at.balances.available, funds(5.0));
+        assert_eq!(at.balances.held, funds(10.0));
+        assert_eq!(at.timestamp, Some(350));
+
+        let replay = Replay::new(EngineConfig::default(), None).unwrap();
+        let at = replay
+            .balances_at(rows(), 1, Point::Timestamp(1000))
+            .unwrap();
+        assert_eq!(at.balances.total, funds(13.0));
+
+        // Before the first transaction, the account does not exist yet.
+        let replay = Replay::new(EngineConfig::default(), None).unwrap();
+        let at = replay.balances_at(rows(), 1, Point::Timestamp(50)).unwrap();
+        assert_eq!(at.balances, Balances::default());
+    }
+
+    #[test]
+    fn test_balances_at_a_timestamp_include_the_adjustments_due() {
+        let config = || EngineConfig {
+            adjustments: Some(
+                serde_json::from_str(
+                    r#"{
+                        "house": 0,
+                        "default_tier": "basic",
+                        "tiers": { "basic": { "every": 50, "fee": "1" } }
+                    }"#,
+                )
+                .unwrap(),
+            ),
+            ..Default::default()
+        };
+
+        // The dispute at 300 leaves 1 available, taken by the fee due at 350.
+        let replay = Replay::new(config(), None).unwrap();
+        let at = replay
+            .balances_at(rows(), 1, Point::Timestamp(350))
+            .unwrap();
+        assert_eq!(at.balances.available, Funds::ZERO);
+        assert_eq!(at.balances.total, funds(10.0));
+        assert_eq!(at.timestamp, Some(350));
+
+        // The withdrawal is rejected, the clock still moves up to the timestamp.
+        let replay = Replay::new(config(), None).unwrap();
+        let at = replay
+            .balances_at(rows(), 1, Point::Timestamp(800))
+            .unwrap();
+        assert_eq!(at.balances.total, funds(10.0));
+        assert_eq!(at.timestamp, Some(800));
+    }
+
+    #[test]
+    fn test_diff_finds_where_accounts_diverged() {
+        // Locking accounts with an open dispute rejects the last withdrawal on the right.
+        let right = EngineConfig {
+            locks: crate::locks::LockPolicies {
+                max_open_disputes: Some(1),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let mut right_rows = rows();
+        right_rows.push(Ok(Transaction {
+            variant: TxType::Deposit,
+            client: 2,
+            tx: 4,
+            amount: Some(funds(1.0)),
+            to: None,
+            batch_id: None,
+            timestamp: Some(500),
+        }));
+
+        let diff = diff(
+            Replay::new(EngineConfig::default(), None).unwrap(),
+            rows(),
+            Replay::new(right, None).unwrap(),
+            right_rows,
+        )
+        .unwrap();
+        assert_eq!(diff.len(), 2);
+        assert_eq!(diff[0].client, 1);
+        assert_eq!(diff[0].left.total, funds(13.0));
+        assert_eq!(diff[0].right.total, funds(15.0));
+        assert!(diff[0].right.locked);
+        assert_eq!(
+            diff[0].diverged_at,
+            Some(Divergence {
+                step: 3,
+                left_tx: Some(1),
+                right_tx: Some(1),
+            })
+        );
+        assert_eq!(diff[1].client, 2);
+        assert_eq!(diff[1].left, Balances::default());
+        assert_eq!(diff[1].diverged_at.unwrap().right_tx, Some(4));
+    }
+}
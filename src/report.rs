@@ -0,0 +1,206 @@
+//! This module defines the end-of-run report: what was read and applied, the control totals of the
+//! funds that entered and left the accounts, and whether they reconcile with the accounts.
+//!
+//! Funds only enter the accounts through deposits, and only leave them through withdrawals,
+//! captures, refunds and chargebacks. Everything else (transfers, fees, scheduled adjustments)
+//! moves funds between accounts, the house account included. So the sum of the account totals must
+//! be the one at the start of the run, plus the deposits, minus everything that left.
+
+use crate::{
+    accounts::Accounts,
+    engine::Engine,
+    error::Error,
+    primitives::{Client, Funds},
+};
+use serde::Serialize;
+use std::{collections::BTreeMap, fs, path::Path};
+
+/// Funds that entered and left the accounts through accepted transactions.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub(crate) struct Totals {
+    pub(crate) deposited: Funds,
+    pub(crate) withdrawn: Funds,
+    pub(crate) captured: Funds,
+    pub(crate) refunded: Funds,
+    /// Charged back out of the accounts, including the fees reversed by the house account.
+    pub(crate) charged_back: Funds,
+}
+
+impl Totals {
+    /// The funds that entered the accounts, minus the ones that left them.
+    pub(crate) fn net(&self) -> Funds {
+        self.deposited - self.withdrawn - self.captured - self.refunded - self.charged_back
+    }
+}
+
+/// The summary of a run, for the sign-off of the processed rows.
+#[derive(Debug, Serialize)]
+pub(crate) struct Report {
+    /// Rows that reached the engine.
+    pub(crate) rows_read: u64,
+    /// Rows that could not be read, skipped with `--skip-malformed`.
+    pub(crate) malformed: u64,
+    /// Rows accepted, by transaction type.
+    pub(crate) applied: BTreeMap<&'static str, u64>,
+    /// Rows rejected, by error code.
+    pub(crate) rejected: BTreeMap<&'static str, u64>,
+    pub(crate) totals: Totals,
+    pub(crate) open_disputes: usize,
+    /// Funds held by the open disputes.
+    pub(crate) disputed: Funds,
+    pub(crate) locked_accounts: Vec<Client>,
+    /// Sum of the account totals before the run.
+    pub(crate) opening_total: Funds,
+    /// Sum of the account totals after the run.
+    pub(crate) closing_total: Funds,
+    /// Whether the closing total is the opening total plus the net of the control totals.
+    pub(crate) reconciled: bool,
+}
+
+impl Report {
+    /// Builds the report of a run from the engine and the accounts, given the sum of the account
+    /// totals before the run (see [`total`]).
+    pub(crate) fn new(engine: &Engine, accounts: &Accounts, opening_total: Funds) -> Self {
+        let mut applied = BTreeMap::new();
+        let mut rejected = BTreeMap::new();
+        let mut rows_read = 0;
+        for (variant, result, count) in engine.metrics().rows() {
+            rows_read += count;
+            match result {
+                "accepted" => *applied.entry(variant).or_default() += count,
+                code => *rejected.entry(code).or_default() += count,
+            }
+        }
+
+        let mut locked_accounts: Vec<Client> = accounts
+            .iter()
+            .filter(|account| account.is_locked())
+            .map(|account| account.client())
+            .collect();
+        locked_accounts.sort_unstable();
+
+        let totals = engine.totals().clone();
+        let closing_total = total(accounts);
+        Self {
+            rows_read,
+            malformed: engine.metrics().malformed(),
+            applied,
+            rejected,
+            reconciled: opening_total + totals.net() == closing_total,
+            totals,
+            open_disputes: accounts.iter().map(|account| account.open_disputes()).sum(),
+            disputed: accounts.iter().map(|account| account.disputed()).sum(),
+            locked_accounts,
+            opening_total,
+            closing_total,
+        }
+    }
+
+    /// Writes the report as JSON to the given path.
+    pub(crate) fn write(&self, path: &Path) -> Result<(), Error> {
+        let file = fs::File::create(path)?;
+        serde_json::to_writer_pretty(file, self).map_err(std::io::Error::from)?;
+        Ok(())
+    }
+}
+
+/// Sum of the totals of the accounts.
+pub(crate) fn total(accounts: &Accounts) -> Funds {
+    accounts.iter().map(|account| account.total()).sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        engine::EngineConfig,
+        transactions::{Transaction, TxType},
+    };
+    use rust_decimal::Decimal;
+
+    fn funds(amount: f32) -> Decimal {
+        Decimal::from_f32_retain(amount).unwrap()
+    }
+
+    fn transaction(variant: TxType, client: Client, tx: u32, amount: Option<f32>) -> Transaction {
+        Transaction {
+            variant,
+            client,
+            tx,
+            amount: amount.map(funds),
+            to: None,
+            batch_id: None,
+            timestamp: None,
+        }
+    }
+
+    #[test]
+    fn test_report_reconciles_the_control_totals() {
+        let fees = serde_json::from_str(
+            r#"{"house":0,"default_tier":"a","tiers":{"a":{"deposit":{"percent":"10"}}}}"#,
+        )
+        .unwrap();
+        let mut engine = Engine::new(EngineConfig {
+            fees: Some(fees),
+            ..Default::default()
+        })
+        .unwrap();
+        let mut accounts = Accounts::new();
+        for transaction in [
+            transaction(TxType::Deposit, 1, 1, Some(100.0)),
+            transaction(TxType::Deposit, 1, 2, Some(50.0)),
+            transaction(TxType::Withdrawal, 1, 3, Some(20.0)),
+            transaction(TxType::Withdrawal, 1, 4, Some(1000.0)),
+            transaction(TxType::Refund, 1, 2, Some(5.0)),
+            transaction(TxType::Dispute, 1, 1, None),
+            transaction(TxType::Chargeback, 1, 1, None),
+            transaction(TxType::Deposit, 2, 5, Some(10.0)),
+            transaction(TxType::Dispute, 2, 5, None),
+        ] {
+            let _ = engine.apply(&mut accounts, transaction);
+        }
+
+        let report = Report::new(&engine, &accounts, Funds::ZERO);
+        assert_eq!(report.rows_read, 9);
+        assert_eq!(report.applied["deposit"], 3);
+        assert_eq!(report.rejected["account.insufficient_funds"], 1);
+        assert_eq!(
+            report.totals,
+            Totals {
+                deposited: funds(160.0),
+                withdrawn: funds(20.0),
+                captured: Funds::ZERO,
+                refunded: funds(5.0),
+                // The 90 charged back, and the fee of 10 taken back from the house account.
+                charged_back: funds(100.0),
+            }
+        );
+        assert_eq!(report.open_disputes, 1);
+        assert_eq!(report.disputed, funds(9.0));
+        assert_eq!(report.locked_accounts, vec![1]);
+        assert_eq!(report.closing_total, funds(35.0));
+        assert!(report.reconciled);
+    }
+
+    #[test]
+    fn test_report_counts_the_malformed_rows_skipped() {
+        let mut engine = Engine::new(EngineConfig {
+            skip_malformed: true,
+            ..Default::default()
+        })
+        .unwrap();
+        let mut accounts = Accounts::new();
+        let input = "type,client,tx,amount\ndeposit,1,1,1.0\ndeposit,one,2,1.0\n";
+        for record in csv::Reader::from_reader(input.as_bytes()).deserialize() {
+            match record {
+                Ok(transaction) => engine.apply(&mut accounts, transaction).unwrap(),
+                Err(e) => engine.malformed(e).unwrap(),
+            }
+        }
+
+        let report = Report::new(&engine, &accounts, Funds::ZERO);
+        assert_eq!(report.rows_read, 1);
+        assert_eq!(report.malformed, 1);
+        assert!(report.rejected.is_empty());
+    }
+}
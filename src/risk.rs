@@ -0,0 +1,430 @@
+//! This module defines the risk rules: checks run on every [`Transaction`] before the engine
+//! applies it, which can let it through, hold it for review, or reject it.
+//!
+//! Any type implementing [`RiskRule`] can be plugged into the engine. A few rules are built in, and
+//! enabled from a JSON file, e.g.:
+//!
+//! ```json
+//! {
+//!   "rules": [
+//!     { "rule": "deposit_then_withdrawal", "min_deposit": "10000", "window": 3600, "action": "hold" },
+//!     { "rule": "repeated_disputes", "max_disputes": 2, "window": 86400, "action": "reject" },
+//!     { "rule": "structuring", "threshold": "10000", "margin": "500", "max_deposits": 2,
+//!       "window": 86400, "action": "hold" }
+//!   ]
+//! }
+//! ```
+//!
+//! Rules only learn from the transactions that were applied, and their history is not kept in
+//! snapshots: an engine restored from a snapshot starts with rules that know nothing of the
+//! transactions before it. The write-ahead log holds every transaction applied since the snapshot,
+//! so a recovery rebuilds the history of the interrupted run. Without timestamps, every
+//! transaction happens at the same time.
+
+use crate::{
+    accounts::Account,
+    error::{Error, ScheduleError},
+    ledger::Ledger,
+    primitives::{BatchId, Client, Funds, Timestamp, Tx},
+    transactions::{Transaction, TxType},
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, VecDeque},
+    fs,
+    path::Path,
+};
+
+/// The outcome of a [`RiskRule`].
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Verdict {
+    /// The transaction is applied.
+    Allow,
+    /// The transaction is not applied, but kept aside for review.
+    Hold,
+    /// The transaction is not applied.
+    Reject,
+}
+
+/// What a [`RiskRule`] can look at, besides the transaction itself.
+pub(crate) struct RiskContext<'a> {
+    /// The account of the client of the transaction.
+    pub(crate) account: &'a Account,
+    /// The historical records of the engine.
+    pub(crate) ledger: &'a Ledger,
+    /// The time at which the transaction is applied, if known.
+    pub(crate) now: Option<Timestamp>,
+}
+
+/// Behavior expected from a check run before applying a [`Transaction`].
+pub(crate) trait RiskRule {
+    /// Name of the rule, reported when it holds or rejects a transaction.
+    fn name(&self) -> &str;
+
+    /// Decides what to do with a transaction that is about to be applied.
+    fn evaluate(&self, transaction: &Transaction, context: &RiskContext) -> Verdict;
+
+    /// Learns from a transaction that was applied.
+    fn observe(&mut self, _transaction: &Transaction, _now: Option<Timestamp>) {}
+}
+
+/// Times of the latest events of each client, oldest first.
+#[derive(Debug, Default)]
+struct History(HashMap<Client, VecDeque<Timestamp>>);
+
+impl History {
+    /// Number of events of the client within the window ending at the given time.
+    fn count(&self, client: Client, now: Timestamp, window: u64) -> usize {
+        self.0.get(&client).map_or(0, |events| {
+            events
+                .iter()
+                .filter(|&&at| now.saturating_sub(at) < window)
+                .count()
+        })
+    }
+
+    /// Adds an event, forgetting the ones out of the window.
+    fn push(&mut self, client: Client, now: Timestamp, window: u64) {
+        let events = self.0.entry(client).or_default();
+        events.push_back(now);
+        while events
+            .front()
+            .is_some_and(|&at| now.saturating_sub(at) >= window)
+        {
+            events.pop_front();
+        }
+    }
+}
+
+/// Flags withdrawals taking out the funds of a large deposit made shortly before.
+#[derive(Debug)]
+pub(crate) struct DepositThenWithdrawal {
+    min_deposit: Funds,
+    window: u64,
+    action: Verdict,
+    /// Latest large deposit of each client: when it happened, and its amount.
+    deposits: HashMap<Client, (Timestamp, Funds)>,
+}
+
+impl RiskRule for DepositThenWithdrawal {
+    fn name(&self) -> &str {
+        "deposit_then_withdrawal"
+    }
+
+    fn evaluate(&self, transaction: &Transaction, context: &RiskContext) -> Verdict {
+        let (TxType::Withdrawal, Some(amount)) = (transaction.variant, transaction.amount) else {
+            return Verdict::Allow;
+        };
+        let Some(&(at, deposited)) = self.deposits.get(&transaction.client) else {
+            return Verdict::Allow;
+        };
+
+        // Only the part of the withdrawal that the deposit pays for is suspicious.
+        let recent = context.now.unwrap_or_default().saturating_sub(at) < self.window;
+        let funded_before = context.account.available() - deposited;
+        if recent && amount > funded_before {
+            self.action
+        } else {
+            Verdict::Allow
+        }
+    }
+
+    fn observe(&mut self, transaction: &Transaction, now: Option<Timestamp>) {
+        if let (TxType::Deposit, Some(amount)) = (transaction.variant, transaction.amount)
+            && amount >= self.min_deposit
+        {
+            self.deposits
+                .insert(transaction.client, (now.unwrap_or_default(), amount));
+        }
+    }
+}
+
+/// Flags disputes from clients that already opened many disputes recently.
+#[derive(Debug)]
+pub(crate) struct RepeatedDisputes {
+    max_disputes: usize,
+    window: u64,
+    action: Verdict,
+    disputes: History,
+}
+
+impl RiskRule for RepeatedDisputes {
+    fn name(&self) -> &str {
+        "repeated_disputes"
+    }
+
+    fn evaluate(&self, transaction: &Transaction, context: &RiskContext) -> Verdict {
+        // Disputes on unknown transactions are rejected by the engine anyway.
+        if transaction.variant != TxType::Dispute || !context.ledger.contains(transaction.tx) {
+            return Verdict::Allow;
+        }
+
+        let now = context.now.unwrap_or_default();
+        if self.disputes.count(transaction.client, now, self.window) >= self.max_disputes {
+            self.action
+        } else {
+            Verdict::Allow
+        }
+    }
+
+    fn observe(&mut self, transaction: &Transaction, now: Option<Timestamp>) {
+        if transaction.variant == TxType::Dispute {
+            self.disputes
+                .push(transaction.client, now.unwrap_or_default(), self.window);
+        }
+    }
+}
+
+/// Flags repeated deposits just under a reporting threshold.
+#[derive(Debug)]
+pub(crate) struct Structuring {
+    threshold: Funds,
+    margin: Funds,
+    max_deposits: usize,
+    window: u64,
+    action: Verdict,
+    deposits: History,
+}
+
+impl Structuring {
+    fn is_just_under(&self, transaction: &Transaction) -> bool {
+        transaction.variant == TxType::Deposit
+            && transaction.amount.is_some_and(|amount| {
+                amount < self.threshold && amount >= self.threshold - self.margin
+            })
+    }
+}
+
+impl RiskRule for Structuring {
+    fn name(&self) -> &str {
+        "structuring"
+    }
+
+    fn evaluate(&self, transaction: &Transaction, context: &RiskContext) -> Verdict {
+        if !self.is_just_under(transaction) {
+            return Verdict::Allow;
+        }
+
+        let now = context.now.unwrap_or_default();
+        if self.deposits.count(transaction.client, now, self.window) >= self.max_deposits {
+            self.action
+        } else {
+            Verdict::Allow
+        }
+    }
+
+    fn observe(&mut self, transaction: &Transaction, now: Option<Timestamp>) {
+        if self.is_just_under(transaction) {
+            self.deposits
+                .push(transaction.client, now.unwrap_or_default(), self.window);
+        }
+    }
+}
+
+/// A transaction held for review, with the rule that held it. Written out as the input rows, plus
+/// a `rule` column.
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct HeldTransaction {
+    #[serde(rename = "type")]
+    pub(crate) variant: TxType,
+    pub(crate) client: Client,
+    pub(crate) tx: Tx,
+    pub(crate) amount: Option<Funds>,
+    pub(crate) to: Option<Client>,
+    pub(crate) batch_id: Option<BatchId>,
+    pub(crate) timestamp: Option<Timestamp>,
+    pub(crate) rule: String,
+}
+
+impl HeldTransaction {
+    pub(crate) fn new(transaction: &Transaction, rule: String) -> Self {
+        Self {
+            variant: transaction.variant,
+            client: transaction.client,
+            tx: transaction.tx,
+            amount: transaction.amount,
+            to: transaction.to,
+            batch_id: transaction.batch_id,
+            timestamp: transaction.timestamp,
+            rule,
+        }
+    }
+}
+
+/// Configuration of a built-in rule.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(tag = "rule", rename_all = "snake_case", deny_unknown_fields)]
+pub(crate) enum RuleConfig {
+    /// See [`DepositThenWithdrawal`].
+    DepositThenWithdrawal {
+        min_deposit: Funds,
+        window: u64,
+        action: Verdict,
+    },
+    /// See [`RepeatedDisputes`].
+    RepeatedDisputes {
+        max_disputes: usize,
+        window: u64,
+        action: Verdict,
+    },
+    /// See [`Structuring`].
+    Structuring {
+        threshold: Funds,
+        margin: Funds,
+        max_deposits: usize,
+        window: u64,
+        action: Verdict,
+    },
+}
+
+impl RuleConfig {
+    /// Creates the rule.
+    pub(crate) fn build(&self) -> Box<dyn RiskRule> {
+        match self.clone() {
+            RuleConfig::DepositThenWithdrawal {
+                min_deposit,
+                window,
+                action,
+            } => Box::new(DepositThenWithdrawal {
+                min_deposit,
+                window,
+                action,
+                deposits: HashMap::new(),
+            }),
+            RuleConfig::RepeatedDisputes {
+                max_disputes,
+                window,
+                action,
+            } => Box::new(RepeatedDisputes {
+                max_disputes,
+                window,
+                action,
+                disputes: History::default(),
+            }),
+            RuleConfig::Structuring {
+                threshold,
+                margin,
+                max_deposits,
+                window,
+                action,
+            } => Box::new(Structuring {
+                threshold,
+                margin,
+                max_deposits,
+                window,
+                action,
+                deposits: History::default(),
+            }),
+        }
+    }
+}
+
+/// The file enabling the built-in rules.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RulesFile {
+    rules: Vec<RuleConfig>,
+}
+
+/// Reads the built-in rules to enable from a JSON file.
+pub(crate) fn load(path: &Path) -> Result<Vec<RuleConfig>, Error> {
+    let content = fs::read_to_string(path)?;
+    let file: RulesFile = serde_json::from_str(&content).map_err(ScheduleError::Malformed)?;
+    Ok(file.rules)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal::Decimal;
+
+    fn funds(amount: f32) -> Decimal {
+        Decimal::from_f32_retain(amount).unwrap()
+    }
+
+    fn transaction(variant: TxType, tx: u32, amount: Option<f32>) -> Transaction {
+        Transaction {
+            variant,
+            client: 1,
+            tx,
+            amount: amount.map(funds),
+            to: None,
+            batch_id: None,
+            timestamp: None,
+        }
+    }
+
+    fn build(config: &str) -> Box<dyn RiskRule> {
+        serde_json::from_str::<RuleConfig>(config).unwrap().build()
+    }
+
+    #[test]
+    fn test_deposit_then_withdrawal_flags_the_deposited_funds() {
+        let mut rule = build(
+            r#"{"rule":"deposit_then_withdrawal","min_deposit":"1000","window":60,"action":"hold"}"#,
+        );
+        let mut account = Account::new(1);
+        let ledger = Ledger::new();
+        account.credit(funds(100.0)).unwrap();
+        account.credit(funds(1000.0)).unwrap();
+        rule.observe(&transaction(TxType::Deposit, 1, Some(1000.0)), Some(100));
+
+        let context = |now| RiskContext {
+            account: &account,
+            ledger: &ledger,
+            now: Some(now),
+        };
+        let small = transaction(TxType::Withdrawal, 2, Some(100.0));
+        let large = transaction(TxType::Withdrawal, 2, Some(500.0));
+        assert_eq!(rule.evaluate(&small, &context(110)), Verdict::Allow);
+        assert_eq!(rule.evaluate(&large, &context(110)), Verdict::Hold);
+        assert_eq!(rule.evaluate(&large, &context(160)), Verdict::Allow);
+    }
+
+    #[test]
+    fn test_repeated_disputes_are_flagged() {
+        let mut rule =
+            build(r#"{"rule":"repeated_disputes","max_disputes":1,"window":60,"action":"reject"}"#);
+        let account = Account::new(1);
+        let mut ledger = Ledger::new();
+        ledger
+            .record(&transaction(TxType::Deposit, 1, Some(1.0)), Funds::ZERO, 0)
+            .unwrap();
+        let context = RiskContext {
+            account: &account,
+            ledger: &ledger,
+            now: None,
+        };
+
+        let dispute = transaction(TxType::Dispute, 1, None);
+        assert_eq!(rule.evaluate(&dispute, &context), Verdict::Allow);
+        rule.observe(&dispute, None);
+        assert_eq!(rule.evaluate(&dispute, &context), Verdict::Reject);
+        // Unknown transactions are left to the engine.
+        let unknown = transaction(TxType::Dispute, 2, None);
+        assert_eq!(rule.evaluate(&unknown, &context), Verdict::Allow);
+    }
+
+    #[test]
+    fn test_structuring_flags_deposits_just_under_the_threshold() {
+        let mut rule = build(
+            r#"{"rule":"structuring","threshold":"10000","margin":"500","max_deposits":1,"window":3600,"action":"hold"}"#,
+        );
+        let account = Account::new(1);
+        let ledger = Ledger::new();
+        let context = RiskContext {
+            account: &account,
+            ledger: &ledger,
+            now: Some(0),
+        };
+
+        let deposit = transaction(TxType::Deposit, 1, Some(9900.0));
+        rule.observe(&deposit, Some(0));
+        assert_eq!(rule.evaluate(&deposit, &context), Verdict::Hold);
+        let above = transaction(TxType::Deposit, 2, Some(10000.0));
+        assert_eq!(rule.evaluate(&above, &context), Verdict::Allow);
+        let far_under = transaction(TxType::Deposit, 3, Some(5000.0));
+        assert_eq!(rule.evaluate(&far_under, &context), Verdict::Allow);
+    }
+}
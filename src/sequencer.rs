@@ -0,0 +1,540 @@
+//! This module defines the [`Sequencer`], which puts the [`Transaction`]s back in order before they
+//! reach the engine.
+//!
+//! It works in two stages:
+//! 1. A reorder buffer: transactions are held until the latest timestamp seen is `watermark`
+//!    seconds past theirs, and then released in timestamp order. Transactions arriving after
+//!    others with a later timestamp were already released are passed on right away.
+//! 2. A pending queue: disputes, resolutions, chargebacks, captures and voids referencing a
+//!    transaction that is not known yet wait for it to arrive, and are released right after it.
+//!    If it does not arrive within the limits, they are released anyway, and the engine rejects
+//!    them.
+//!
+//! The legs of a batch (consecutive rows with the same `batch_id`) go through both stages as one
+//! unit, so that nothing is released between them: the batch is ordered by the timestamp of its
+//! first leg, and the transactions waiting for one of its legs are released after the whole batch.
+//!
+//! The output only depends on the input and on the transactions known beforehand, so processing
+//! the same file again (e.g. when recovering from the write-ahead log) yields the same order.
+
+use crate::{
+    ledger::SeenTxs,
+    primitives::{Timestamp, Tx},
+    transactions::{Transaction, TxType},
+};
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    iter::Peekable,
+};
+
+/// Limits of the pending queue.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct PendingLimits {
+    /// Maximum number of transactions waiting at once. The oldest ones are released first.
+    pub(crate) max_rows: usize,
+    /// Maximum number of seconds a transaction waits, measured with the timestamps of the input.
+    pub(crate) max_wait: Option<u64>,
+}
+
+/// Options of the [`Sequencer`]. Each stage is disabled when its option is not set.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct SequencerOptions {
+    /// Number of seconds transactions are held in the reorder buffer.
+    pub(crate) watermark: Option<u64>,
+    /// Limits of the pending queue.
+    pub(crate) pending: Option<PendingLimits>,
+}
+
+/// Transactions waiting for the same transaction to arrive.
+struct PendingGroup {
+    /// Identifies the group in the queue order.
+    id: u64,
+    /// When the first transaction of the group started waiting.
+    since: Option<Timestamp>,
+    transactions: Vec<Transaction>,
+}
+
+/// Iterator adapter releasing the [`Transaction`]s of another iterator in order. See the module
+/// documentation.
+pub(crate) struct Sequencer<I: Iterator> {
+    inner: Peekable<I>,
+    options: SequencerOptions,
+    /// Transaction ids that are known, either beforehand or released already.
+    known: SeenTxs,
+    /// Reorder buffer, by timestamp and then arrival. Each entry is a transaction alone or the
+    /// legs of a batch.
+    buffer: BTreeMap<(Timestamp, u64), Vec<Transaction>>,
+    /// Number of transactions received so far.
+    arrivals: u64,
+    /// Latest timestamp received.
+    latest: Option<Timestamp>,
+    /// Latest timestamp released from the reorder buffer.
+    released: Option<Timestamp>,
+    /// Pending queue, by referenced transaction.
+    pending: HashMap<Tx, PendingGroup>,
+    /// Order of the pending groups, oldest first. May contain groups already released.
+    pending_order: VecDeque<(u64, Tx)>,
+    pending_rows: usize,
+    /// Transactions ready to be returned.
+    ready: VecDeque<Result<Transaction, csv::Error>>,
+    exhausted: bool,
+}
+
+impl<I> Sequencer<I>
+where
+    I: Iterator<Item = Result<Transaction, csv::Error>>,
+{
+    /// Creates a sequencer over the given transactions, with the ids of the transactions that are
+    /// already known (e.g. from a snapshot).
+    pub(crate) fn new(inner: I, options: SequencerOptions, known: SeenTxs) -> Self {
+        Self {
+            inner: inner.peekable(),
+            options,
+            known,
+            buffer: BTreeMap::new(),
+            arrivals: 0,
+            latest: None,
+            released: None,
+            pending: HashMap::new(),
+            pending_order: VecDeque::new(),
+            pending_rows: 0,
+            ready: VecDeque::new(),
+            exhausted: false,
+        }
+    }
+
+    /// Stage 1: holds the transaction, or the legs of a batch, in the reorder buffer, and releases
+    /// the ones that passed the watermark.
+    fn receive(&mut self, unit: Vec<Transaction>) {
+        let Some(watermark) = self.options.watermark else {
+            return self.release(unit);
+        };
+
+        // Transactions without a timestamp are ordered as if they happened at the latest time.
+        let timestamp = unit[0].timestamp.or(self.latest).unwrap_or(Timestamp::MIN);
+        if self.released.is_some_and(|released| timestamp < released) {
+            tracing::warn!(
+                "Transaction {} arrived after the watermark, it is applied as is",
+                unit[0].tx
+            );
+            return self.release(unit);
+        }
+
+        self.latest = Some(
+            self.latest
+                .map_or(timestamp, |latest| latest.max(timestamp)),
+        );
+        self.buffer.insert((timestamp, self.arrivals), unit);
+        self.arrivals += 1;
+
+        let limit = self.latest.unwrap_or_default().saturating_sub(watermark);
+        while let Some(entry) = self.buffer.first_entry()
+            && entry.key().0 <= limit
+        {
+            let ((timestamp, _), unit) = entry.remove_entry();
+            self.released = Some(timestamp);
+            self.release(unit);
+        }
+    }
+
+    /// Stage 2: passes the transaction on, unless it has to wait for the transaction it references.
+    /// The legs of a batch are passed on together, followed by what was waiting for them.
+    fn release(&mut self, mut unit: Vec<Transaction>) {
+        let Some(limits) = self.options.pending.clone() else {
+            return self.ready.extend(unit.into_iter().map(Ok));
+        };
+
+        if let Some(timestamp) = unit[0].timestamp {
+            self.expire_pending(timestamp, &limits);
+        }
+
+        if unit.len() > 1 {
+            let created: Vec<Tx> = unit
+                .iter()
+                .filter(|leg| leg.variant.creates_record())
+                .map(|leg| leg.tx)
+                .collect();
+            self.ready.extend(unit.into_iter().map(Ok));
+            for tx in created {
+                self.known.insert(tx);
+                self.release_waiting(tx);
+            }
+            return;
+        }
+        // SAFETY: safe to unwrap, since units are never empty.
+        let transaction = unit.pop().unwrap();
+
+        // Ticks reference nothing.
+        if transaction.variant == TxType::Tick {
+            return self.ready.push_back(Ok(transaction));
+        }
+
+        let tx = transaction.tx;
+        if transaction.variant.creates_record() {
+            self.known.insert(tx);
+            self.ready.push_back(Ok(transaction));
+            return self.release_waiting(tx);
+        }
+
+        // Transactions referencing one that is already waiting must wait too, to keep their order.
+        if self.known.contains(tx) && !self.pending.contains_key(&tx) {
+            return self.ready.push_back(Ok(transaction));
+        }
+
+        let id = self.arrivals;
+        self.arrivals += 1;
+        let group = self.pending.entry(tx).or_insert_with(|| PendingGroup {
+            id,
+            since: transaction.timestamp,
+            transactions: Vec::new(),
+        });
+        if group.id == id {
+            self.pending_order.push_back((id, tx));
+        }
+        group.transactions.push(transaction);
+        self.pending_rows += 1;
+
+        while self.pending_rows > limits.max_rows {
+            if !self.release_oldest_pending() {
+                break;
+            }
+        }
+    }
+
+    /// Releases whatever was waiting for the given transaction, which just arrived.
+    fn release_waiting(&mut self, tx: Tx) {
+        if let Some(group) = self.pending.remove(&tx) {
+            self.pending_rows -= group.transactions.len();
+            self.ready.extend(group.transactions.into_iter().map(Ok));
+        }
+    }
+
+    /// Reads the next transaction, or all the legs of a batch. A malformed row among the legs is
+    /// passed on in place with them, for the batch to fail as a whole.
+    fn read(&mut self) -> Option<Vec<Result<Transaction, csv::Error>>> {
+        let first = self.inner.next()?;
+        let Some(batch) = first.as_ref().ok().and_then(|t| t.batch_id) else {
+            return Some(vec![first]);
+        };
+        let mut unit = vec![first];
+        while let Some(item) = self.inner.next_if(|item| match item {
+            Ok(transaction) => transaction.batch_id == Some(batch),
+            Err(_) => true,
+        }) {
+            unit.push(item);
+        }
+        Some(unit)
+    }
+
+    /// Releases the pending groups that waited for too long.
+    fn expire_pending(&mut self, now: Timestamp, limits: &PendingLimits) {
+        let Some(max_wait) = limits.max_wait else {
+            return;
+        };
+
+        while let Some(&(id, tx)) = self.pending_order.front() {
+            match self.pending.get(&tx) {
+                Some(group) if group.id == id => {
+                    if group
+                        .since
+                        .is_some_and(|since| now.saturating_sub(since) > max_wait)
+                    {
+                        self.release_oldest_pending();
+                    } else {
+                        break;
+                    }
+                }
+                // Already released.
+                _ => {
+                    self.pending_order.pop_front();
+                }
+            }
+        }
+    }
+
+    /// Releases the oldest pending group, for the engine to reject it. Returns `false` if there
+    /// was none.
+    fn release_oldest_pending(&mut self) -> bool {
+        while let Some((id, tx)) = self.pending_order.pop_front() {
+            if self.pending.get(&tx).is_some_and(|group| group.id == id) {
+                // SAFETY: safe to unwrap, since the group exists by previous check.
+                let group = self.pending.remove(&tx).unwrap();
+                tracing::warn!(
+                    "Transaction {} did not arrive in time for {} transaction(s) referencing it",
+                    tx,
+                    group.transactions.len()
+                );
+                self.pending_rows -= group.transactions.len();
+                self.ready.extend(group.transactions.into_iter().map(Ok));
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Releases everything that is held, in order.
+    fn flush(&mut self) {
+        while let Some(((timestamp, _), unit)) = self.buffer.pop_first() {
+            self.released = Some(timestamp);
+            self.release(unit);
+        }
+        while self.release_oldest_pending() {}
+    }
+}
+
+impl<I> Iterator for Sequencer<I>
+where
+    I: Iterator<Item = Result<Transaction, csv::Error>>,
+{
+    type Item = Result<Transaction, csv::Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(item) = self.ready.pop_front() {
+                return Some(item);
+            }
+            if self.exhausted {
+                return None;
+            }
+
+            match self.read() {
+                Some(unit) if unit.iter().all(Result::is_ok) => {
+                    self.receive(unit.into_iter().flatten().collect())
+                }
+                Some(unit) => {
+                    // Errors stop the processing, so everything before them goes first.
+                    self.flush();
+                    self.ready.extend(unit);
+                }
+                None => {
+                    self.exhausted = true;
+                    self.flush();
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::primitives::BatchId;
+    use rust_decimal::Decimal;
+
+    fn transaction(variant: TxType, tx: Tx, timestamp: Option<Timestamp>) -> Transaction {
+        let amount =
+            matches!(variant, TxType::Deposit | TxType::Withdrawal).then(|| Decimal::new(1, 0));
+        Transaction {
+            variant,
+            client: 1,
+            tx,
+            amount,
+            to: None,
+            batch_id: None,
+            timestamp,
+        }
+    }
+
+    fn sequence(
+        options: SequencerOptions,
+        known: SeenTxs,
+        transactions: Vec<Transaction>,
+    ) -> Vec<(TxType, Tx)> {
+        Sequencer::new(transactions.into_iter().map(Ok), options, known)
+            .map(|t| t.unwrap())
+            .map(|t| (t.variant, t.tx))
+            .collect()
+    }
+
+    fn reorder(watermark: u64) -> SequencerOptions {
+        SequencerOptions {
+            watermark: Some(watermark),
+            pending: None,
+        }
+    }
+
+    fn pending(max_rows: usize, max_wait: Option<u64>) -> SequencerOptions {
+        SequencerOptions {
+            watermark: None,
+            pending: Some(PendingLimits { max_rows, max_wait }),
+        }
+    }
+
+    #[test]
+    fn test_reorder_buffer_releases_in_timestamp_order() {
+        let ordered = sequence(
+            reorder(10),
+            SeenTxs::default(),
+            vec![
+                transaction(TxType::Deposit, 1, Some(100)),
+                transaction(TxType::Deposit, 3, Some(105)),
+                transaction(TxType::Deposit, 2, Some(102)),
+                transaction(TxType::Deposit, 4, Some(120)),
+                // Late: 100 and 102 and 105 are already released.
+                transaction(TxType::Deposit, 5, Some(101)),
+            ],
+        );
+
+        let txs: Vec<Tx> = ordered.into_iter().map(|(_, tx)| tx).collect();
+        assert_eq!(txs, vec![1, 2, 3, 5, 4]);
+    }
+
+    #[test]
+    fn test_dispute_waits_for_its_deposit() {
+        let ordered = sequence(
+            pending(10, None),
+            SeenTxs::default(),
+            vec![
+                transaction(TxType::Dispute, 1, None),
+                transaction(TxType::Resolve, 1, None),
+                transaction(TxType::Deposit, 2, None),
+                transaction(TxType::Deposit, 1, None),
+            ],
+        );
+
+        assert_eq!(
+            ordered,
+            vec![
+                (TxType::Deposit, 2),
+                (TxType::Deposit, 1),
+                (TxType::Dispute, 1),
+                (TxType::Resolve, 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_disputes_on_known_transactions_do_not_wait() {
+        let mut known = SeenTxs::default();
+        known.insert(1);
+
+        let ordered = sequence(
+            pending(10, None),
+            known,
+            vec![
+                transaction(TxType::Dispute, 1, None),
+                transaction(TxType::Deposit, 2, None),
+            ],
+        );
+
+        assert_eq!(ordered, vec![(TxType::Dispute, 1), (TxType::Deposit, 2)]);
+    }
+
+    #[test]
+    fn test_pending_queue_is_bounded_by_rows() {
+        let ordered = sequence(
+            pending(1, None),
+            SeenTxs::default(),
+            vec![
+                transaction(TxType::Dispute, 1, None),
+                transaction(TxType::Dispute, 2, None),
+                transaction(TxType::Deposit, 3, None),
+                transaction(TxType::Deposit, 2, None),
+            ],
+        );
+
+        assert_eq!(
+            ordered,
+            vec![
+                (TxType::Dispute, 1),
+                (TxType::Deposit, 3),
+                (TxType::Deposit, 2),
+                (TxType::Dispute, 2),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_pending_queue_is_bounded_by_time() {
+        let ordered = sequence(
+            pending(10, Some(60)),
+            SeenTxs::default(),
+            vec![
+                transaction(TxType::Dispute, 1, Some(100)),
+                transaction(TxType::Deposit, 2, Some(161)),
+                transaction(TxType::Deposit, 1, Some(162)),
+            ],
+        );
+
+        assert_eq!(
+            ordered,
+            vec![
+                (TxType::Dispute, 1),
+                (TxType::Deposit, 2),
+                (TxType::Deposit, 1),
+            ]
+        );
+    }
+
+    fn leg(mut transaction: Transaction, batch_id: BatchId) -> Transaction {
+        transaction.batch_id = Some(batch_id);
+        transaction
+    }
+
+    #[test]
+    fn test_late_row_is_not_released_inside_a_batch() {
+        let ordered = sequence(
+            reorder(10),
+            SeenTxs::default(),
+            vec![
+                leg(transaction(TxType::Deposit, 1, Some(100)), 7),
+                leg(transaction(TxType::Deposit, 2, Some(110)), 7),
+                transaction(TxType::Deposit, 3, Some(105)),
+            ],
+        );
+
+        let txs: Vec<Tx> = ordered.into_iter().map(|(_, tx)| tx).collect();
+        assert_eq!(txs, vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn test_pending_dispute_is_released_after_the_whole_batch() {
+        let ordered = sequence(
+            pending(10, None),
+            SeenTxs::default(),
+            vec![
+                transaction(TxType::Dispute, 1, None),
+                leg(transaction(TxType::Deposit, 1, None), 7),
+                leg(transaction(TxType::Deposit, 2, None), 7),
+            ],
+        );
+
+        assert_eq!(
+            ordered,
+            vec![
+                (TxType::Deposit, 1),
+                (TxType::Deposit, 2),
+                (TxType::Dispute, 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_everything_is_released_at_the_end() {
+        let options = SequencerOptions {
+            watermark: Some(1000),
+            pending: Some(PendingLimits {
+                max_rows: 10,
+                max_wait: None,
+            }),
+        };
+        let ordered = sequence(
+            options,
+            SeenTxs::default(),
+            vec![
+                transaction(TxType::Chargeback, 9, Some(3)),
+                transaction(TxType::Deposit, 2, Some(2)),
+                transaction(TxType::Deposit, 1, Some(1)),
+            ],
+        );
+
+        assert_eq!(
+            ordered,
+            vec![
+                (TxType::Deposit, 1),
+                (TxType::Deposit, 2),
+                (TxType::Chargeback, 9),
+            ]
+        );
+    }
+}
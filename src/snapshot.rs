@@ -0,0 +1,635 @@
+//! This module defines how the full state of the application ([`Accounts`] and the [`Engine`]
+//! ledger) is dumped to, and loaded from, a snapshot file.
+//!
+//! A snapshot is a JSON document with a `version` field, so the format can evolve without
+//! silently misreading old files. Loading a snapshot with an unknown version is an error.
+//!
+//! Versions:
+//! - 1: accounts and ledger.
+//! - 2: adds the engine `sequence`, i.e. the position in the write-ahead log (see
+//!   [`crate::wal`]) the snapshot corresponds to. Version 1 snapshots are read with a sequence
+//!   of zero.
+//! - 3: the ledger holds compact records with their dispute state (see [`crate::ledger`]),
+//!   together with the ranges of transaction ids already seen. Older ledgers of full transactions
+//!   are converted on load.
+//! - 4: adds the engine `clock`, and the ledger records carry the timestamps of the transaction
+//!   and of its last dispute. Version 3 snapshots are read without timestamps.
+//! - 5: accounts carry the fees paid, and the ledger records the fee charged on each transaction.
+//!   Older snapshots are read without fees.
+//! - 6: adds what each client already withdrew, for the limits (see [`crate::limits`]). Older
+//!   snapshots are read as if nothing was withdrawn.
+//! - 7: accounts carry the reason they were locked, and the counters of the lock policies (see
+//!   [`crate::locks`]). Older snapshots are read without them.
+//! - 8: accounts carry their open authorizations. Older snapshots are read without any.
+//! - 9: the ledger records carry the amount refunded. Older snapshots are read without refunds.
+//! - 10: adds the scheduled adjustments applied to the accounts (see [`crate::adjustments`]).
+//!   Older snapshots are read without any.
+
+use crate::{
+    accounts::{Account, Accounts},
+    adjustments::Adjustment,
+    engine::{Engine, EngineConfig},
+    error::{Error, SnapshotError},
+    ledger::{Ledger, LedgerEntry, TxState},
+    limits::Usage,
+    locks::LockReason,
+    primitives::{Client, Funds, Timestamp, Tx},
+    transactions::Transaction,
+};
+use serde::{
+    Deserialize, Deserializer, Serialize, Serializer,
+    de::{self, DeserializeSeed, MapAccess, SeqAccess, Visitor},
+    ser::{self, SerializeSeq},
+};
+use std::{
+    cell::RefCell,
+    fmt, fs,
+    io::{self, BufReader, BufWriter},
+    path::Path,
+};
+
+/// Version of the snapshot format written by this build.
+pub(crate) const SNAPSHOT_VERSION: u32 = 10;
+
+/// The full state of an [`Account`], unlike its CSV output which is rounded and omits the open
+/// disputes.
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub(crate) struct AccountSnapshot {
+    pub(crate) client: Client,
+    pub(crate) available: Funds,
+    pub(crate) held: Funds,
+    pub(crate) total: Funds,
+    pub(crate) locked: bool,
+    /// Fees paid, net of reversals.
+    #[serde(default)]
+    pub(crate) fees: Funds,
+    /// Why the account is locked, if known.
+    #[serde(default)]
+    pub(crate) lock_reason: Option<LockReason>,
+    /// Open disputes, as pairs of disputed transaction and disputed amount.
+    pub(crate) disputed_transactions: Vec<(Tx, Funds)>,
+    /// Open authorizations, as pairs of authorization and amount still held.
+    #[serde(default)]
+    pub(crate) authorizations: Vec<(Tx, Funds)>,
+    /// Number of deposits applied.
+    #[serde(default)]
+    pub(crate) deposits: u64,
+    /// Number of disputes opened.
+    #[serde(default)]
+    pub(crate) disputes: u64,
+    /// Total amount charged back.
+    #[serde(default)]
+    pub(crate) charged_back: Funds,
+}
+
+/// A record kept in the ledger, with the transaction it belongs to.
+#[derive(Deserialize, Serialize)]
+struct LedgerRecord {
+    tx: Tx,
+    #[serde(flatten)]
+    entry: LedgerEntry,
+}
+
+/// The records of a ledger, written one at a time as they are read from it, so that the ones
+/// spilled to disk are never all in memory.
+struct LedgerRecords<'a> {
+    ledger: &'a RefCell<&'a mut Ledger>,
+    /// The error reading the records, if any, reported instead of the serialization error.
+    error: RefCell<Option<Error>>,
+}
+
+impl Serialize for LedgerRecords<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut ledger = self.ledger.borrow_mut();
+        let mut records = serializer.serialize_seq(None)?;
+        for record in ledger.iter_entries() {
+            match record {
+                Ok((tx, entry)) => records.serialize_element(&LedgerRecord { tx, entry })?,
+                Err(e) => {
+                    let message = e.to_string();
+                    *self.error.borrow_mut() = Some(e);
+                    return Err(ser::Error::custom(message));
+                }
+            }
+        }
+        records.end()
+    }
+}
+
+/// The scheduled adjustments kept in a ledger, written without copying them.
+struct LedgerAdjustments<'a>(&'a RefCell<&'a mut Ledger>);
+
+impl Serialize for LedgerAdjustments<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.0.borrow().adjustments())
+    }
+}
+
+/// Snapshot as it is written.
+#[derive(Serialize)]
+struct Snapshot<'a> {
+    /// Comes first, for the loader to know how to read the rest.
+    version: u32,
+    sequence: u64,
+    clock: Option<Timestamp>,
+    accounts: Vec<AccountSnapshot>,
+    ledger: LedgerRecords<'a>,
+    /// Inclusive ranges of the transaction ids seen by the ledger.
+    seen: Vec<(Tx, Tx)>,
+    usage: Vec<(Client, Usage)>,
+    adjustments: LedgerAdjustments<'a>,
+}
+
+/// Snapshot as it is read, but for the ledger records, which are restored into the ledger as they
+/// are read.
+#[derive(Default)]
+struct LoadedSnapshot {
+    version: u32,
+    sequence: u64,
+    clock: Option<Timestamp>,
+    accounts: Vec<AccountSnapshot>,
+    /// The ledger of full transactions written by versions 1 and 2, converted once read.
+    legacy_ledger: Vec<Transaction>,
+    seen: Vec<(Tx, Tx)>,
+    usage: Vec<(Client, Usage)>,
+    adjustments: Vec<Adjustment>,
+}
+
+impl LoadedSnapshot {
+    /// Restores the ledger of full transactions written by versions 1 and 2 into the given ledger,
+    /// converted into records.
+    fn restore_legacy_ledger(&self, into: &mut Ledger) -> Result<(), Error> {
+        let ledger = &self.legacy_ledger;
+        let disputed = |client: Client, tx: Tx| {
+            self.accounts.iter().any(|a| {
+                a.client == client && a.disputed_transactions.iter().any(|(t, _)| *t == tx)
+            })
+        };
+
+        let records = ledger.iter().filter_map(|t| {
+            Some((
+                t.tx,
+                LedgerEntry {
+                    variant: t.variant,
+                    client: t.client,
+                    amount: t.amount?,
+                    to: t.to,
+                    state: if disputed(t.client, t.tx) {
+                        TxState::Disputed
+                    } else {
+                        TxState::Processed
+                    },
+                    // The original sequence is unknown, the window starts anew.
+                    seq: self.sequence,
+                    timestamp: None,
+                    disputed_at: None,
+                    fee: Funds::ZERO,
+                    refunded: Funds::ZERO,
+                },
+            ))
+        });
+        into.restore(records, ledger.iter().map(|t| (t.tx, t.tx)))
+    }
+}
+
+/// Reads a snapshot in a single pass, restoring the ledger records into the ledger one at a time.
+struct SnapshotReader<'a> {
+    ledger: &'a mut Ledger,
+    /// The error that is not about the format of the document, if any, reported instead of the
+    /// deserialization error.
+    error: &'a mut Option<Error>,
+}
+
+impl<'de> DeserializeSeed<'de> for SnapshotReader<'_> {
+    type Value = LoadedSnapshot;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<Self::Value, D::Error> {
+        deserializer.deserialize_map(self)
+    }
+}
+
+impl<'de> Visitor<'de> for SnapshotReader<'_> {
+    type Value = LoadedSnapshot;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a snapshot")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+        let mut snapshot = LoadedSnapshot::default();
+        let (mut version, mut accounts, mut ledger, mut seen) = (None, false, false, false);
+        while let Some(key) = map.next_key::<String>()? {
+            match key.as_str() {
+                "version" => {
+                    let read = map.next_value()?;
+                    if !(1..=SNAPSHOT_VERSION).contains(&read) {
+                        *self.error = Some(SnapshotError::UnsupportedVersion(read).into());
+                        return Err(de::Error::custom("unsupported version"));
+                    }
+                    version = Some(read);
+                }
+                "sequence" => snapshot.sequence = map.next_value()?,
+                "clock" => snapshot.clock = map.next_value()?,
+                "accounts" => {
+                    snapshot.accounts = map.next_value()?;
+                    accounts = true;
+                }
+                "ledger" => {
+                    match version {
+                        None => return Err(de::Error::custom("the version must come first")),
+                        Some(1 | 2) => snapshot.legacy_ledger = map.next_value()?,
+                        Some(_) => map.next_value_seed(RecordsReader {
+                            ledger: &mut *self.ledger,
+                            error: &mut *self.error,
+                        })?,
+                    }
+                    ledger = true;
+                }
+                "seen" => {
+                    snapshot.seen = map.next_value()?;
+                    seen = true;
+                }
+                "usage" => snapshot.usage = map.next_value()?,
+                "adjustments" => snapshot.adjustments = map.next_value()?,
+                _ => {
+                    map.next_value::<de::IgnoredAny>()?;
+                }
+            }
+        }
+
+        snapshot.version = version.ok_or_else(|| de::Error::missing_field("version"))?;
+        if !accounts {
+            return Err(de::Error::missing_field("accounts"));
+        }
+        if !ledger {
+            return Err(de::Error::missing_field("ledger"));
+        }
+        if !seen && snapshot.version > 2 {
+            return Err(de::Error::missing_field("seen"));
+        }
+        Ok(snapshot)
+    }
+}
+
+/// Reads the ledger records of a snapshot, restoring them into the ledger one at a time.
+struct RecordsReader<'a> {
+    ledger: &'a mut Ledger,
+    error: &'a mut Option<Error>,
+}
+
+impl<'de> DeserializeSeed<'de> for RecordsReader<'_> {
+    type Value = ();
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        deserializer.deserialize_seq(self)
+    }
+}
+
+impl<'de> Visitor<'de> for RecordsReader<'_> {
+    type Value = ();
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a list of ledger records")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<(), A::Error> {
+        let mut malformed = None;
+        let records = std::iter::from_fn(|| match seq.next_element::<LedgerRecord>() {
+            Ok(record) => record.map(|r| (r.tx, r.entry)),
+            Err(e) => {
+                malformed = Some(e);
+                None
+            }
+        });
+        let restored = self.ledger.restore(records, []);
+        if let Some(e) = malformed {
+            return Err(e);
+        }
+        restored.map_err(|e| {
+            let message = e.to_string();
+            *self.error = Some(e);
+            de::Error::custom(message)
+        })
+    }
+}
+
+/// Writes the state of the [`Engine`] and the [`Accounts`] to the given path.
+///
+/// The snapshot is first written to a temporary file next to the target, and then renamed, so a
+/// crash while writing never leaves a truncated snapshot behind. The ledger records are written
+/// as they are read from the ledger.
+pub(crate) fn save(path: &Path, engine: &mut Engine, accounts: &Accounts) -> Result<(), Error> {
+    let mut accounts: Vec<AccountSnapshot> = accounts.iter().map(Account::snapshot).collect();
+    accounts.sort_by_key(|a| a.client);
+
+    let sequence = engine.sequence();
+    let clock = engine.clock();
+    let usage = engine.usage();
+    let seen = engine.ledger_mut().seen_ranges();
+    let ledger = RefCell::new(engine.ledger_mut());
+    let snapshot = Snapshot {
+        version: SNAPSHOT_VERSION,
+        sequence,
+        clock,
+        accounts,
+        ledger: LedgerRecords {
+            ledger: &ledger,
+            error: RefCell::new(None),
+        },
+        seen,
+        usage,
+        adjustments: LedgerAdjustments(&ledger),
+    };
+
+    let tmp_path = path.with_extension("tmp");
+    let mut writer = BufWriter::new(fs::File::create(&tmp_path)?);
+    if let Err(e) = serde_json::to_writer(&mut writer, &snapshot) {
+        return Err(match snapshot.ledger.error.into_inner() {
+            Some(error) => error,
+            None => SnapshotError::Malformed(e).into(),
+        });
+    }
+    let file = writer
+        .into_inner()
+        .map_err(io::IntoInnerError::into_error)?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, path)?;
+
+    Ok(())
+}
+
+/// Reads the state of the [`Engine`] and the [`Accounts`] from the given path, into an engine with
+/// the given configuration. The ledger records are restored as they are read from the file.
+pub(crate) fn load(path: &Path, config: &EngineConfig) -> Result<(Engine, Accounts), Error> {
+    let mut ledger = Ledger::with_options(&config.ledger)?;
+    let mut error = None;
+    let mut deserializer =
+        serde_json::Deserializer::from_reader(BufReader::new(fs::File::open(path)?));
+    let read = SnapshotReader {
+        ledger: &mut ledger,
+        error: &mut error,
+    }
+    .deserialize(&mut deserializer)
+    .and_then(|snapshot| deserializer.end().map(|_| snapshot));
+    let snapshot = match (read, error) {
+        (Ok(snapshot), _) => snapshot,
+        (Err(_), Some(error)) => return Err(error),
+        (Err(e), None) => return Err(SnapshotError::Malformed(e).into()),
+    };
+
+    snapshot.restore_legacy_ledger(&mut ledger)?;
+    ledger.restore([], snapshot.seen)?;
+    let mut accounts = Accounts::new();
+    for account in snapshot.accounts {
+        accounts.insert(Account::from_snapshot(account));
+    }
+
+    ledger.restore_adjustments(snapshot.adjustments);
+    let engine = Engine::restore(
+        config.clone(),
+        ledger,
+        snapshot.sequence,
+        snapshot.clock,
+        snapshot.usage.into_iter().collect(),
+    )?;
+
+    Ok((engine, accounts))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        behaviors::TransactionProcessor,
+        engine::DisputeWindows,
+        ledger::{LedgerOptions, Retention},
+        transactions::TxType,
+    };
+    use rust_decimal::Decimal;
+    use tempfile::tempdir;
+
+    fn funds(amount: f32) -> Decimal {
+        Decimal::from_f32_retain(amount).unwrap()
+    }
+
+    fn deposit(client: Client, tx: Tx, amount: f32) -> Result<Transaction, csv::Error> {
+        Ok(Transaction {
+            variant: TxType::Deposit,
+            client,
+            tx,
+            amount: Some(funds(amount)),
+            to: None,
+            batch_id: None,
+            timestamp: None,
+        })
+    }
+
+    #[test]
+    fn test_snapshot_roundtrip_keeps_accounts_and_ledger() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("state.json");
+
+        let mut engine = Engine::default();
+        let mut accounts = Accounts::new();
+        engine
+            .process_transactions(vec![deposit(1, 1, 10.0), deposit(2, 2, 3.5)], &mut accounts)
+            .unwrap();
+        accounts.get_mut(1).dispute(funds(4.0), 1).unwrap();
+
+        save(&path, &mut engine, &accounts).unwrap();
+        let (mut restored_engine, mut restored_accounts) =
+            load(&path, &EngineConfig::default()).unwrap();
+
+        assert_eq!(
+            restored_accounts.get_mut(1).snapshot(),
+            accounts.get_mut(1).snapshot()
+        );
+        assert_eq!(
+            restored_accounts.get_mut(2).snapshot(),
+            accounts.get_mut(2).snapshot()
+        );
+        assert_eq!(
+            restored_accounts
+                .get_mut(1)
+                .snapshot()
+                .disputed_transactions,
+            vec![(1, funds(4.0))]
+        );
+        assert_eq!(restored_engine.ledger_mut().entries().unwrap().len(), 2);
+        assert_eq!(restored_engine.sequence(), 2);
+    }
+
+    #[test]
+    fn test_snapshot_streams_spilled_records_in_and_out() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("state.json");
+        let config = |spill: &str| EngineConfig {
+            ledger: LedgerOptions {
+                spill: Some((dir.path().join(spill), 1)),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        let mut engine = Engine::new(config("saved.spill")).unwrap();
+        let mut accounts = Accounts::new();
+        engine
+            .process_transactions(
+                vec![deposit(1, 1, 1.0), deposit(1, 2, 2.0), deposit(2, 3, 3.0)],
+                &mut accounts,
+            )
+            .unwrap();
+        save(&path, &mut engine, &accounts).unwrap();
+
+        let (mut restored, _) = load(&path, &config("loaded.spill")).unwrap();
+        assert_eq!(
+            restored.ledger_mut().entries().unwrap(),
+            engine.ledger_mut().entries().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_load_reads_version_one_without_sequence() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("state.json");
+        fs::write(
+            &path,
+            r#"{"version":1,"accounts":[],"ledger":[{"type":"deposit","client":1,"tx":1,"amount":"2"}]}"#,
+        )
+        .unwrap();
+
+        let (mut engine, _) = load(&path, &EngineConfig::default()).unwrap();
+        assert_eq!(engine.sequence(), 0);
+        assert_eq!(engine.ledger_mut().entries().unwrap().len(), 1);
+        assert!(engine.ledger_mut().contains(1));
+    }
+
+    #[test]
+    fn test_restored_engine_rejects_duplicates_from_snapshot() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("state.json");
+
+        let mut engine = Engine::default();
+        let mut accounts = Accounts::new();
+        engine
+            .process_transactions(vec![deposit(1, 1, 10.0)], &mut accounts)
+            .unwrap();
+        save(&path, &mut engine, &accounts).unwrap();
+
+        let (mut engine, mut accounts) = load(&path, &EngineConfig::default()).unwrap();
+        engine
+            .process_transactions(vec![deposit(1, 1, 10.0)], &mut accounts)
+            .unwrap();
+
+        assert_eq!(accounts.get_mut(1).snapshot().available, funds(10.0));
+    }
+
+    #[test]
+    fn test_snapshot_keeps_ids_of_records_not_retained() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("state.json");
+        let config = EngineConfig {
+            ledger: LedgerOptions {
+                retention: Retention::Disputable,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        let mut engine = Engine::new(config.clone()).unwrap();
+        let mut accounts = Accounts::new();
+        let withdrawal = Transaction {
+            variant: TxType::Withdrawal,
+            client: 1,
+            tx: 2,
+            amount: Some(funds(1.0)),
+            to: None,
+            batch_id: None,
+            timestamp: None,
+        };
+        engine
+            .process_transactions(
+                vec![deposit(1, 1, 10.0), Ok(withdrawal.clone())],
+                &mut accounts,
+            )
+            .unwrap();
+        save(&path, &mut engine, &accounts).unwrap();
+
+        let (mut engine, mut accounts) = load(&path, &config).unwrap();
+        assert_eq!(engine.ledger_mut().entries().unwrap().len(), 1);
+        engine
+            .process_transactions(vec![Ok(withdrawal)], &mut accounts)
+            .unwrap();
+        assert_eq!(accounts.get_mut(1).snapshot().available, funds(9.0));
+    }
+
+    #[test]
+    fn test_snapshot_keeps_clock_and_stale_disputes_expire_after_load() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("state.json");
+        let config = EngineConfig {
+            windows: DisputeWindows {
+                dispute: None,
+                resolve: Some(60),
+            },
+            ..Default::default()
+        };
+        let at = |mut t: Transaction, timestamp: Timestamp| {
+            t.timestamp = Some(timestamp);
+            Ok(t)
+        };
+        let dispute = Transaction {
+            variant: TxType::Dispute,
+            client: 1,
+            tx: 1,
+            amount: None,
+            to: None,
+            batch_id: None,
+            timestamp: None,
+        };
+
+        let mut engine = Engine::new(config.clone()).unwrap();
+        let mut accounts = Accounts::new();
+        engine
+            .process_transactions(
+                vec![at(deposit(1, 1, 10.0).unwrap(), 100), at(dispute, 110)],
+                &mut accounts,
+            )
+            .unwrap();
+        save(&path, &mut engine, &accounts).unwrap();
+
+        let (mut engine, mut accounts) = load(&path, &config).unwrap();
+        assert_eq!(engine.clock(), Some(110));
+        engine
+            .process_transactions(vec![at(deposit(2, 2, 1.0).unwrap(), 200)], &mut accounts)
+            .unwrap();
+        assert_eq!(accounts.get_mut(1).snapshot().held, Funds::ZERO);
+        assert_eq!(accounts.get_mut(1).snapshot().available, funds(10.0));
+    }
+
+    #[test]
+    fn test_load_rejects_unknown_version() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("state.json");
+        fs::write(&path, r#"{"version":99,"accounts":[],"ledger":[]}"#).unwrap();
+
+        let result = load(&path, &EngineConfig::default());
+        assert!(matches!(
+            result,
+            Err(Error::Snapshot(SnapshotError::UnsupportedVersion(99)))
+        ));
+    }
+
+    #[test]
+    fn test_load_rejects_malformed_file() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("state.json");
+        fs::write(&path, "not a snapshot").unwrap();
+
+        let result = load(&path, &EngineConfig::default());
+        assert!(matches!(
+            result,
+            Err(Error::Snapshot(SnapshotError::Malformed(_)))
+        ));
+    }
+}
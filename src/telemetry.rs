@@ -0,0 +1,231 @@
+//! This module defines the observability of the application: where the logs go, and the metrics
+//! the [`Engine`] keeps while applying transactions.
+//!
+//! The metrics are:
+//! - the rows applied, by transaction type and result (`accepted`, or the code of the error, see
+//!   [`Error::code`]),
+//! - a histogram of the time taken to apply each row (or batch of rows).
+//!
+//! They can be dumped as a short summary at the end of a run, or in the Prometheus text format,
+//! e.g. for the textfile collector of the node exporter.
+//!
+//! [`Engine`]: crate::engine::Engine
+
+use crate::{error::Error, transactions::TxType};
+use std::{collections::BTreeMap, fmt::Write, fs, path::Path, sync::Mutex, time::Duration};
+use tracing::level_filters::LevelFilter;
+
+/// How verbose the logs are.
+#[derive(Debug, Clone, Copy, Default, PartialEq, clap::ValueEnum)]
+pub(crate) enum LogLevel {
+    #[default]
+    Off,
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl From<LogLevel> for LevelFilter {
+    fn from(level: LogLevel) -> Self {
+        match level {
+            LogLevel::Off => LevelFilter::OFF,
+            LogLevel::Error => LevelFilter::ERROR,
+            LogLevel::Warn => LevelFilter::WARN,
+            LogLevel::Info => LevelFilter::INFO,
+            LogLevel::Debug => LevelFilter::DEBUG,
+            LogLevel::Trace => LevelFilter::TRACE,
+        }
+    }
+}
+
+/// Sends the logs up to the given level to a file, or to stderr (stdout is kept for the output).
+pub(crate) fn init_logging(level: LogLevel, file: Option<&Path>) -> Result<(), Error> {
+    if level == LogLevel::Off {
+        return Ok(());
+    }
+
+    let subscriber = tracing_subscriber::fmt()
+        .with_max_level(LevelFilter::from(level))
+        .compact();
+    match file {
+        Some(path) => subscriber
+            .with_ansi(false)
+            .with_writer(Mutex::new(fs::File::create(path)?))
+            .init(),
+        None => subscriber.with_writer(std::io::stderr).init(),
+    }
+
+    Ok(())
+}
+
+/// Upper bounds of the buckets of the latency histogram, in seconds.
+const LATENCY_BUCKETS: [f64; 10] = [
+    0.000_001, 0.000_005, 0.000_01, 0.000_05, 0.000_1, 0.000_5, 0.001, 0.005, 0.01, 0.05,
+];
+
+/// Counters and histograms of the rows applied by the engine.
+#[derive(Debug, Default)]
+pub(crate) struct Metrics {
+    /// Rows applied, by transaction type and result.
+    rows: BTreeMap<(&'static str, &'static str), u64>,
+    /// Number of observations in each latency bucket, the last one being above every bound.
+    latency: [u64; LATENCY_BUCKETS.len() + 1],
+    latency_sum: Duration,
+    latency_count: u64,
+    /// Rows that could not be read, and were skipped.
+    malformed: u64,
+}
+
+impl Metrics {
+    /// Counts a row of the given type, accepted or rejected with the given error.
+    pub(crate) fn count(&mut self, variant: TxType, error: Option<&Error>) {
+        let result = error.map_or("accepted", Error::code);
+        *self.rows.entry((variant.name(), result)).or_default() += 1;
+    }
+
+    /// Counts a row that could not be read, and was skipped.
+    pub(crate) fn count_malformed(&mut self) {
+        self.malformed += 1;
+    }
+
+    /// Number of rows that could not be read, and were skipped.
+    pub(crate) fn malformed(&self) -> u64 {
+        self.malformed
+    }
+
+    /// Observes the time taken to apply a row, or a batch of rows.
+    pub(crate) fn observe(&mut self, elapsed: Duration) {
+        let seconds = elapsed.as_secs_f64();
+        let bucket = LATENCY_BUCKETS
+            .iter()
+            .position(|bound| seconds <= *bound)
+            .unwrap_or(LATENCY_BUCKETS.len());
+        self.latency[bucket] += 1;
+        self.latency_sum += elapsed;
+        self.latency_count += 1;
+    }
+
+    /// Rows applied, as (transaction type, result, count), in that order.
+    pub(crate) fn rows(&self) -> impl Iterator<Item = (&'static str, &'static str, u64)> + '_ {
+        self.rows
+            .iter()
+            .map(|((variant, result), count)| (*variant, *result, *count))
+    }
+
+    /// The metrics in the Prometheus text format.
+    pub(crate) fn prometheus(&self) -> String {
+        let mut text = String::new();
+        let _ = writeln!(
+            text,
+            "# HELP payments_engine_rows_total Rows applied by the engine, by type and result."
+        );
+        let _ = writeln!(text, "# TYPE payments_engine_rows_total counter");
+        for ((variant, result), count) in &self.rows {
+            let _ = writeln!(
+                text,
+                "payments_engine_rows_total{{type=\"{}\",result=\"{}\"}} {}",
+                variant, result, count
+            );
+        }
+
+        let _ = writeln!(
+            text,
+            "# HELP payments_engine_apply_seconds Time taken to apply a row, or a batch of rows."
+        );
+        let _ = writeln!(text, "# TYPE payments_engine_apply_seconds histogram");
+        let mut cumulative = 0;
+        for (bound, count) in LATENCY_BUCKETS.iter().zip(&self.latency) {
+            cumulative += count;
+            let _ = writeln!(
+                text,
+                "payments_engine_apply_seconds_bucket{{le=\"{}\"}} {}",
+                bound, cumulative
+            );
+        }
+        let _ = writeln!(
+            text,
+            "payments_engine_apply_seconds_bucket{{le=\"+Inf\"}} {}",
+            self.latency_count
+        );
+        let _ = writeln!(
+            text,
+            "payments_engine_apply_seconds_sum {}",
+            self.latency_sum.as_secs_f64()
+        );
+        let _ = writeln!(
+            text,
+            "payments_engine_apply_seconds_count {}",
+            self.latency_count
+        );
+        text
+    }
+
+    /// A short summary of the metrics, for humans.
+    pub(crate) fn summary(&self) -> String {
+        let mut text = String::new();
+        let total: u64 = self.rows.values().sum();
+        let accepted: u64 = self
+            .rows
+            .iter()
+            .filter(|((_, result), _)| *result == "accepted")
+            .map(|(_, count)| count)
+            .sum();
+        let _ = writeln!(
+            text,
+            "{} rows: {} accepted, {} rejected",
+            total,
+            accepted,
+            total - accepted
+        );
+        for ((variant, result), count) in &self.rows {
+            let _ = writeln!(text, "  {:<10} {:<40} {}", variant, result, count);
+        }
+        if self.latency_count > 0 {
+            let _ = writeln!(
+                text,
+                "mean apply time: {:?}",
+                self.latency_sum.div_f64(self.latency_count as f64)
+            );
+        }
+        text
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::TransactionError;
+
+    #[test]
+    fn test_metrics_in_prometheus_format() {
+        let mut metrics = Metrics::default();
+        metrics.count(TxType::Deposit, None);
+        metrics.count(TxType::Deposit, None);
+        metrics.count(
+            TxType::Dispute,
+            Some(&TransactionError::MissingDispute(1).into()),
+        );
+        metrics.observe(Duration::from_micros(3));
+        metrics.observe(Duration::from_secs(1));
+
+        let text = metrics.prometheus();
+        assert!(
+            text.contains("payments_engine_rows_total{type=\"deposit\",result=\"accepted\"} 2")
+        );
+        assert!(text.contains(
+            "payments_engine_rows_total{type=\"dispute\",result=\"transaction.missing_dispute\"} 1"
+        ));
+        assert!(text.contains("payments_engine_apply_seconds_bucket{le=\"0.000001\"} 0"));
+        assert!(text.contains("payments_engine_apply_seconds_bucket{le=\"0.000005\"} 1"));
+        assert!(text.contains("payments_engine_apply_seconds_bucket{le=\"0.05\"} 1"));
+        assert!(text.contains("payments_engine_apply_seconds_bucket{le=\"+Inf\"} 2"));
+        assert!(text.contains("payments_engine_apply_seconds_count 2"));
+        assert!(
+            metrics
+                .summary()
+                .starts_with("3 rows: 2 accepted, 1 rejected")
+        );
+    }
+}
@@ -1,13 +1,14 @@
 //! This module defines the shape of a transaction, its types and checks based on them.
 
 use crate::{
+    accounts::RoundingStrategy,
     error::TransactionError,
-    primitives::{Client, Funds, Tx},
+    primitives::{BatchId, Client, Funds, Timestamp, Tx},
 };
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
 /// The representation of a transaction.
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub(crate) struct Transaction {
     /// The type of transaction.
     #[serde(rename = "type")]
@@ -18,10 +19,20 @@ pub(crate) struct Transaction {
     pub(crate) tx: Tx,
     /// The (optional) amount for this transaction.
     pub(crate) amount: Option<Funds>,
+    /// The client receiving the funds of a transfer. The column is optional.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) to: Option<Client>,
+    /// The batch the transaction is a leg of, applied atomically with the other legs. The column
+    /// is optional.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) batch_id: Option<BatchId>,
+    /// When the transaction happened, in seconds since the Unix epoch. The column is optional.
+    #[serde(default)]
+    pub(crate) timestamp: Option<Timestamp>,
 }
 
 /// Transaction types available.
-#[derive(Debug, PartialEq, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
 #[serde(rename_all = "lowercase")]
 pub(crate) enum TxType {
     /// A credit to the client's asset account, i.e., increase the available and total funds.
@@ -38,28 +49,135 @@ pub(crate) enum TxType {
     /// The final state of a dispute, when a client reverses a transaction: held funds are
     /// withdrawn (i.e, the total funds decrease). Freezes the client's account.
     Chargeback,
+    /// The first phase of a two-phase withdrawal: the amount is moved from the available funds to
+    /// the held funds, until it is captured or voided.
+    Authorize,
+    /// Withdraws the funds held by an authorization: all of them, or only the amount given. A
+    /// partial capture leaves the rest held for later captures.
+    Capture,
+    /// Releases what is left of an authorization back to the available funds.
+    Void,
+    /// Moves funds from the client's account to the account of the `to` client, atomically: either
+    /// both sides are applied, or none. Can be disputed like a deposit, holding the funds on the
+    /// receiving side.
+    Transfer,
+    /// Gives back (part of) a deposit, debiting the available and total funds. Unlike a
+    /// chargeback, there is no dispute before and the account is not locked. Refunds the amount
+    /// given, or all that is left to refund of the deposit.
+    Refund,
+    /// Only moves the engine clock to its timestamp, e.g. to apply the scheduled adjustments due by
+    /// then. The client and transaction id are ignored.
+    Tick,
+}
+
+impl TxType {
+    /// The name of the type, as in the input.
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            Self::Deposit => "deposit",
+            Self::Withdrawal => "withdrawal",
+            Self::Dispute => "dispute",
+            Self::Resolve => "resolve",
+            Self::Chargeback => "chargeback",
+            Self::Authorize => "authorize",
+            Self::Capture => "capture",
+            Self::Void => "void",
+            Self::Transfer => "transfer",
+            Self::Refund => "refund",
+            Self::Tick => "tick",
+        }
+    }
+
+    /// Whether the transaction creates a record under its own id, as opposed to referencing a
+    /// previous one.
+    pub(crate) fn creates_record(self) -> bool {
+        matches!(
+            self,
+            Self::Deposit | Self::Withdrawal | Self::Authorize | Self::Transfer
+        )
+    }
+}
+
+/// What happens to the amounts of the input with more decimal places than allowed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, Serialize, clap::ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum ExcessPrecision {
+    /// The transaction is rejected.
+    #[default]
+    Reject,
+    /// The amount is rounded, and the transaction goes on.
+    Round,
+}
+
+/// How precise the amounts of the input can be.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub(crate) struct InputPrecision {
+    /// Maximum number of decimal places of the amounts. Unset, amounts are kept at full precision.
+    pub(crate) places: Option<u32>,
+    pub(crate) excess: ExcessPrecision,
+    /// How the amounts are rounded, when rounding the excess.
+    pub(crate) strategy: RoundingStrategy,
 }
 
 impl Transaction {
+    /// Brings the amount to the input precision, if set: an amount with more decimal places is
+    /// rejected, or rounded, depending on the precision. Done before [`Transaction::is_valid`],
+    /// which checks the amount once rounded.
+    pub(crate) fn normalize(&mut self, precision: &InputPrecision) -> Result<(), TransactionError> {
+        if let (Some(amount), Some(places)) = (self.amount, precision.places)
+            && amount.normalize().scale() > places
+        {
+            match precision.excess {
+                ExcessPrecision::Reject => {
+                    return Err(TransactionError::ExcessPrecision(self.tx, places));
+                }
+                ExcessPrecision::Round => {
+                    self.amount = Some(precision.strategy.round(amount, places));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
     /// Check if the transaction has the necessary fields based on its type.
     ///
     /// The checks are:
-    /// - for [`TxType::Deposit`] and [`TxType::Withdrawal`], an amount must be present.
-    /// - for [`TxType::Dispute`], [`TxType::Resolve`] and [`TxType::Chargeback`], an amount must
-    /// not be present.
+    /// - for [`TxType::Deposit`], [`TxType::Withdrawal`] and [`TxType::Authorize`], an amount must
+    ///   be present.
+    /// - for [`TxType::Dispute`], [`TxType::Resolve`], [`TxType::Chargeback`] and
+    ///   [`TxType::Void`], an amount must not be present.
+    /// - for [`TxType::Capture`] and [`TxType::Refund`], the amount is optional.
+    /// - for [`TxType::Transfer`], the receiving client must be present, and be another client.
+    ///   Other types must not have one.
+    /// - for [`TxType::Tick`], a timestamp must be present and an amount must not.
+    /// - the amount, if present, must be positive.
     pub(crate) fn is_valid(&self) -> Result<(), TransactionError> {
-        if matches!(self.variant, TxType::Deposit | TxType::Withdrawal) && self.amount.is_none() {
+        if self.variant.creates_record() && self.amount.is_none() {
             return Err(TransactionError::MissingAmount(self.tx));
         }
 
         if matches!(
             self.variant,
-            TxType::Dispute | TxType::Resolve | TxType::Chargeback
+            TxType::Dispute | TxType::Resolve | TxType::Chargeback | TxType::Void | TxType::Tick
         ) && self.amount.is_some()
         {
             return Err(TransactionError::AmountPresent(self.tx));
         }
 
+        if self.variant == TxType::Tick && self.timestamp.is_none() {
+            return Err(TransactionError::MissingTimestamp(self.tx));
+        }
+
+        match (self.variant, self.to) {
+            (TxType::Transfer, None) => return Err(TransactionError::MissingRecipient(self.tx)),
+            (TxType::Transfer, Some(to)) if to == self.client => {
+                return Err(TransactionError::InvalidRecipient(self.tx));
+            }
+            (TxType::Transfer, Some(_)) | (_, None) => {}
+            (_, Some(_)) => return Err(TransactionError::InvalidRecipient(self.tx)),
+        }
+
         if let Some(value) = self.amount
             && value <= Funds::ZERO
         {
@@ -86,6 +204,9 @@ mod tests {
             client: 1,
             tx: 100,
             amount: Some(funds(10.0)),
+            to: None,
+            batch_id: None,
+            timestamp: None,
         };
 
         assert!(t.is_valid().is_ok());
@@ -98,6 +219,9 @@ mod tests {
             client: 2,
             tx: 101,
             amount: Some(funds(5.0)),
+            to: None,
+            batch_id: None,
+            timestamp: None,
         };
 
         assert!(t.is_valid().is_ok());
@@ -110,6 +234,9 @@ mod tests {
             client: 3,
             tx: 102,
             amount: None,
+            to: None,
+            batch_id: None,
+            timestamp: None,
         };
 
         assert_eq!(
@@ -125,6 +252,9 @@ mod tests {
             client: 4,
             tx: 103,
             amount: None,
+            to: None,
+            batch_id: None,
+            timestamp: None,
         };
 
         assert_eq!(
@@ -140,6 +270,9 @@ mod tests {
             client: 5,
             tx: 104,
             amount: Some(funds(10.0)),
+            to: None,
+            batch_id: None,
+            timestamp: None,
         };
 
         assert_eq!(
@@ -155,6 +288,9 @@ mod tests {
             client: 6,
             tx: 105,
             amount: Some(funds(1.0)),
+            to: None,
+            batch_id: None,
+            timestamp: None,
         };
 
         assert_eq!(
@@ -170,6 +306,9 @@ mod tests {
             client: 7,
             tx: 106,
             amount: Some(funds(1.0)),
+            to: None,
+            batch_id: None,
+            timestamp: None,
         };
 
         assert_eq!(
@@ -185,6 +324,9 @@ mod tests {
             client: 8,
             tx: 107,
             amount: None,
+            to: None,
+            batch_id: None,
+            timestamp: None,
         };
 
         assert!(t.is_valid().is_ok());
@@ -197,6 +339,9 @@ mod tests {
             client: 9,
             tx: 108,
             amount: Some(funds(-5.0)),
+            to: None,
+            batch_id: None,
+            timestamp: None,
         };
 
         assert_eq!(
@@ -212,6 +357,9 @@ mod tests {
             client: 10,
             tx: 109,
             amount: Some(funds(0.0)),
+            to: None,
+            batch_id: None,
+            timestamp: None,
         };
 
         assert_eq!(
@@ -219,4 +367,142 @@ mod tests {
             TransactionError::NonPositiveAmount(109)
         );
     }
+
+    #[test]
+    fn test_capture_amount_is_optional() {
+        let mut t = Transaction {
+            variant: TxType::Capture,
+            client: 11,
+            tx: 110,
+            amount: None,
+            to: None,
+            batch_id: None,
+            timestamp: None,
+        };
+        assert!(t.is_valid().is_ok());
+
+        t.amount = Some(funds(2.5));
+        assert!(t.is_valid().is_ok());
+
+        t.variant = TxType::Void;
+        assert_eq!(
+            t.is_valid().unwrap_err(),
+            TransactionError::AmountPresent(110)
+        );
+    }
+
+    #[test]
+    fn test_refund_amount_is_optional() {
+        let mut t = Transaction {
+            variant: TxType::Refund,
+            client: 14,
+            tx: 112,
+            amount: None,
+            to: None,
+            batch_id: None,
+            timestamp: None,
+        };
+        assert!(t.is_valid().is_ok());
+
+        t.amount = Some(funds(2.5));
+        assert!(t.is_valid().is_ok());
+
+        t.amount = Some(funds(-2.5));
+        assert_eq!(
+            t.is_valid().unwrap_err(),
+            TransactionError::NonPositiveAmount(112)
+        );
+    }
+
+    #[test]
+    fn test_tick_needs_a_timestamp() {
+        let mut t = Transaction {
+            variant: TxType::Tick,
+            client: 0,
+            tx: 0,
+            amount: None,
+            to: None,
+            batch_id: None,
+            timestamp: None,
+        };
+        assert_eq!(
+            t.is_valid().unwrap_err(),
+            TransactionError::MissingTimestamp(0)
+        );
+
+        t.timestamp = Some(1_700_000_000);
+        assert!(t.is_valid().is_ok());
+    }
+
+    #[test]
+    fn test_transfer_needs_another_recipient() {
+        let mut t = Transaction {
+            variant: TxType::Transfer,
+            client: 12,
+            tx: 111,
+            amount: Some(funds(1.0)),
+            to: None,
+            batch_id: None,
+            timestamp: None,
+        };
+        assert_eq!(
+            t.is_valid().unwrap_err(),
+            TransactionError::MissingRecipient(111)
+        );
+
+        t.to = Some(12);
+        assert_eq!(
+            t.is_valid().unwrap_err(),
+            TransactionError::InvalidRecipient(111)
+        );
+
+        t.to = Some(13);
+        assert!(t.is_valid().is_ok());
+
+        t.variant = TxType::Deposit;
+        assert_eq!(
+            t.is_valid().unwrap_err(),
+            TransactionError::InvalidRecipient(111)
+        );
+    }
+
+    #[test]
+    fn test_amounts_beyond_the_input_precision() {
+        let mut t = Transaction {
+            variant: TxType::Deposit,
+            client: 14,
+            tx: 112,
+            amount: Some(Decimal::new(100_005, 5)),
+            to: None,
+            batch_id: None,
+            timestamp: None,
+        };
+        let mut precision = InputPrecision {
+            places: Some(4),
+            ..Default::default()
+        };
+        assert_eq!(
+            t.normalize(&precision).unwrap_err(),
+            TransactionError::ExcessPrecision(112, 4)
+        );
+
+        // Trailing zeros do not count.
+        t.amount = Some(Decimal::new(1_000_000, 6));
+        assert!(t.normalize(&precision).is_ok());
+        assert_eq!(t.amount, Some(Decimal::new(1_000_000, 6)));
+
+        precision.excess = ExcessPrecision::Round;
+        precision.strategy = RoundingStrategy::HalfUp;
+        t.amount = Some(Decimal::new(100_005, 5));
+        assert!(t.normalize(&precision).is_ok());
+        assert_eq!(t.amount, Some(Decimal::new(10_001, 4)));
+
+        // An amount rounded to zero is not positive.
+        t.amount = Some(Decimal::new(1, 5));
+        assert!(t.normalize(&precision).is_ok());
+        assert_eq!(
+            t.is_valid().unwrap_err(),
+            TransactionError::NonPositiveAmount(112)
+        );
+    }
 }
@@ -0,0 +1,691 @@
+//! This module defines the write-ahead log (WAL) of accepted [`Transaction`]s, and the recovery
+//! procedure that rebuilds the state after the process died halfway through a file.
+//!
+//! The WAL is an append-only file with one JSON entry per line. Each entry holds:
+//! - `seq`: the [`Engine::sequence`] right after the transaction was applied,
+//! - `row`: the (1-based) position of the transaction in the input, once put back in order by the
+//!   [`Sequencer`](crate::sequencer::Sequencer), i.e. in the input file if nothing was reordered,
+//! - `transaction`: the transaction itself.
+//!
+//! The accounts unlocked by an operator are logged too, with the `seq` they were unlocked at and
+//! the `unlock`ed client, so the recovery unlocks them at the same point.
+//!
+//! Entries are fsynced in batches (see [`Wal::open`]). Losing the last, not yet synced, batch in a
+//! crash is harmless: those rows are simply processed again from the input file.
+//!
+//! The recovery procedure is:
+//! 1. Load the latest snapshot (or start empty).
+//! 2. Replay every WAL entry whose `seq` is newer than the snapshot's.
+//! 3. Process the *same* input file again, through the same sequencing, skipping the rows up to the
+//!    last `row` in the WAL.
+//!
+//! The WAL is truncated once the state has been checkpointed into a new snapshot, so any entries
+//! left in it belong to the run that was interrupted. If the process dies between saving the
+//! snapshot and truncating the WAL, its entries are all in the snapshot: they are neither replayed
+//! nor used to skip rows, so the next run (on any input) starts from its first row. This is what
+//! guarantees that every row is applied exactly once.
+
+use crate::{
+    accounts::Accounts,
+    batches::Batches,
+    behaviors::TransactionProcessor,
+    engine::Engine,
+    error::{Error, WalError},
+    primitives::Client,
+    transactions::Transaction,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs,
+    io::{self, BufWriter, Write},
+    path::Path,
+};
+
+/// A single record of the write-ahead log.
+#[derive(Debug, Deserialize, Serialize)]
+pub(crate) struct WalEntry {
+    /// The engine sequence after applying the operation.
+    pub(crate) seq: u64,
+    #[serde(flatten)]
+    pub(crate) operation: WalOperation,
+}
+
+/// What a [`WalEntry`] applied.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(untagged)]
+pub(crate) enum WalOperation {
+    Transaction {
+        /// Position of the transaction in the sequenced input.
+        row: u64,
+        /// The transaction that was applied.
+        transaction: Transaction,
+    },
+    /// An account unlocked by an operator, which leaves the sequence as it is.
+    Unlock { unlock: Client },
+}
+
+impl WalEntry {
+    fn transaction(&self) -> Option<&Transaction> {
+        match &self.operation {
+            WalOperation::Transaction { transaction, .. } => Some(transaction),
+            WalOperation::Unlock { .. } => None,
+        }
+    }
+}
+
+/// Append-only writer for the write-ahead log.
+pub(crate) struct Wal {
+    writer: BufWriter<fs::File>,
+    /// Number of entries to write before forcing them to disk.
+    sync_every: usize,
+    /// Number of entries written since the last sync.
+    pending: usize,
+}
+
+impl Wal {
+    /// Opens (or creates) the write-ahead log at the given path, appending new entries at the end.
+    ///
+    /// Entries are synced to disk every `sync_every` appends, and whenever [`Wal::sync`] is
+    /// called.
+    pub(crate) fn open(path: &Path, sync_every: usize) -> Result<Self, Error> {
+        let file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?;
+
+        Ok(Self {
+            writer: BufWriter::new(file),
+            sync_every: sync_every.max(1),
+            pending: 0,
+        })
+    }
+
+    /// Appends an entry, syncing the log if the batch is full.
+    pub(crate) fn append(&mut self, entry: &WalEntry) -> Result<(), Error> {
+        serde_json::to_writer(&mut self.writer, entry).map_err(io::Error::from)?;
+        self.writer.write_all(b"\n")?;
+        self.pending += 1;
+
+        if self.pending >= self.sync_every {
+            self.sync()?;
+        }
+
+        Ok(())
+    }
+
+    /// Whether appending the given number of entries syncs the log.
+    pub(crate) fn syncs_after(&self, entries: usize) -> bool {
+        self.pending + entries >= self.sync_every
+    }
+
+    /// Forces all the written entries to disk.
+    pub(crate) fn sync(&mut self) -> Result<(), Error> {
+        self.writer.flush()?;
+        self.writer.get_ref().sync_data()?;
+        self.pending = 0;
+        Ok(())
+    }
+
+    /// Empties the log. Only call this once its entries are part of a saved snapshot.
+    pub(crate) fn truncate(&mut self) -> Result<(), Error> {
+        self.writer.flush()?;
+        self.writer.get_ref().set_len(0)?;
+        self.writer.get_ref().sync_all()?;
+        self.pending = 0;
+        Ok(())
+    }
+}
+
+/// Outcome of replaying a write-ahead log.
+#[derive(Debug, PartialEq)]
+pub(crate) struct Recovery {
+    /// Number of entries applied onto the state.
+    pub(crate) replayed: usize,
+    /// Last row of the sequenced input present in the log: rows up to this one must not be
+    /// processed again.
+    pub(crate) resume_after: u64,
+    /// The accounts unlocked again, in the order they were.
+    pub(crate) unlocked: Vec<Client>,
+}
+
+/// Replays the write-ahead log at the given path onto the [`Engine`] and the [`Accounts`].
+///
+/// Entries already included in the state (i.e. with a `seq` not newer than
+/// [`Engine::sequence`]) are skipped, and do not move the row to resume after. A partially
+/// written last line, left by a crash in the middle of an append, is discarded and cut from the
+/// file. If the engine has an audit log, the entries the interrupted run did not get to audit are
+/// audited (see [`AuditLog::start_replay`]).
+///
+/// [`AuditLog::start_replay`]: crate::audit::AuditLog::start_replay
+pub(crate) fn recover(
+    path: &Path,
+    engine: &mut Engine,
+    accounts: &mut Accounts,
+) -> Result<Recovery, Error> {
+    let mut recovery = Recovery {
+        replayed: 0,
+        resume_after: 0,
+        unlocked: Vec::new(),
+    };
+
+    let content = match fs::read(path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(recovery),
+        Err(e) => return Err(e.into()),
+    };
+    if let Some(log) = engine.audit_log_mut() {
+        log.start_replay();
+    }
+
+    // Only lines terminated by a newline were completely written.
+    let complete_len = content
+        .iter()
+        .rposition(|b| *b == b'\n')
+        .map_or(0, |i| i + 1);
+
+    let entries = content[..complete_len]
+        .split(|b| *b == b'\n')
+        .filter(|line| !line.is_empty())
+        .enumerate()
+        .map(|(index, line)| {
+            serde_json::from_slice::<WalEntry>(line)
+                .map_err(|_| WalError::Corrupted(index as u64 + 1))
+        });
+    let batch_id = |entry: &Result<WalEntry, WalError>| {
+        entry
+            .as_ref()
+            .ok()
+            .and_then(WalEntry::transaction)
+            .and_then(|transaction| transaction.batch_id)
+    };
+
+    // The legs of a batch are replayed as a batch, as they were applied.
+    // Entries already in the snapshot belong to a run that finished, but died before truncating
+    // the log, so they say nothing about the rows of the input to skip.
+    // An unlock logged at the sequence of the snapshot came after every transaction in it.
+    for entries in Batches::new(entries, batch_id) {
+        let mut rows = Vec::new();
+        for entry in entries? {
+            match entry.operation {
+                WalOperation::Unlock { unlock } if entry.seq >= engine.sequence() => {
+                    engine.unlock(accounts, unlock);
+                    recovery.unlocked.push(unlock);
+                    recovery.replayed += 1;
+                }
+                WalOperation::Transaction { row, transaction } if entry.seq > engine.sequence() => {
+                    rows.push((entry.seq, row, transaction));
+                }
+                _ => {}
+            }
+        }
+        let (Some((seq, ..)), Some((_, last_row, _))) = (rows.first(), rows.last()) else {
+            continue;
+        };
+        recovery.resume_after = *last_row;
+
+        let (seq, expected) = (*seq, engine.sequence() + 1);
+        if seq != expected {
+            return Err(WalError::Gap(expected, seq).into());
+        }
+
+        let replayed = rows.len();
+        engine
+            .apply_group(
+                accounts,
+                rows.into_iter()
+                    .map(|(_, _, transaction)| transaction)
+                    .collect(),
+            )
+            .map_err(|e| WalError::ReplayRejected(seq, Box::new(e)))?;
+        recovery.replayed += replayed;
+    }
+
+    if complete_len < content.len() {
+        tracing::warn!(
+            "Discarding {} bytes of a partially written WAL entry",
+            content.len() - complete_len
+        );
+        fs::OpenOptions::new()
+            .write(true)
+            .open(path)?
+            .set_len(complete_len as u64)?;
+    }
+
+    if let Some(log) = engine.audit_log_mut() {
+        log.end_replay();
+    }
+    Ok(recovery)
+}
+
+/// Processes [`Transaction`]s with an [`Engine`], logging every accepted one to a [`Wal`].
+pub(crate) struct WalProcessor<'a> {
+    engine: &'a mut Engine,
+    wal: &'a mut Wal,
+    /// Rows up to this one were already applied before a restart, and are skipped.
+    resume_after: u64,
+}
+
+impl<'a> WalProcessor<'a> {
+    pub(crate) fn new(engine: &'a mut Engine, wal: &'a mut Wal, resume_after: u64) -> Self {
+        Self {
+            engine,
+            wal,
+            resume_after,
+        }
+    }
+
+    /// Unlocks an account on behalf of an operator, and logs it right away.
+    pub(crate) fn unlock(&mut self, accounts: &mut Accounts, client: Client) -> Result<(), Error> {
+        self.engine.unlock(accounts, client);
+        self.engine.flush_audit_log()?;
+        self.wal.append(&WalEntry {
+            seq: self.engine.sequence(),
+            operation: WalOperation::Unlock { unlock: client },
+        })?;
+        self.wal.sync()
+    }
+}
+
+impl TransactionProcessor for WalProcessor<'_> {
+    fn process_transactions<I>(
+        &mut self,
+        transactions: I,
+        accounts: &mut Accounts,
+    ) -> Result<(), Error>
+    where
+        I: IntoIterator<Item = Result<Transaction, csv::Error>>,
+    {
+        let rows = transactions
+            .into_iter()
+            .enumerate()
+            .map(|(index, record)| (index as u64 + 1, record))
+            .filter(|(row, _)| *row > self.resume_after)
+            .map(|(row, record)| record.map(|transaction| (row, transaction)));
+        let batch_id = |record: &Result<(u64, Transaction), csv::Error>| {
+            record
+                .as_ref()
+                .ok()
+                .and_then(|(_, transaction)| transaction.batch_id)
+        };
+
+        for rows in Batches::new(rows, batch_id) {
+            let rows: Vec<(u64, Transaction)> = match rows {
+                Ok(rows) => rows,
+                Err(e) => {
+                    self.engine.malformed(e)?;
+                    continue;
+                }
+            };
+            let transactions = rows.iter().map(|(_, t)| t.clone()).collect();
+            let sequence = self.engine.sequence();
+            match self.engine.apply_group(accounts, transactions) {
+                // The legs of a batch are logged once all of them were accepted. The audit log
+                // goes to disk before the log is synced, so that every row synced is audited.
+                Ok(_) => {
+                    if self.wal.syncs_after(rows.len()) {
+                        self.engine.flush_audit_log()?;
+                    }
+                    for (seq, (row, transaction)) in (sequence + 1..).zip(rows) {
+                        self.wal.append(&WalEntry {
+                            seq,
+                            operation: WalOperation::Transaction { row, transaction },
+                        })?;
+                    }
+                }
+                Err(e) => {
+                    tracing::error!(code = e.code(), category = ?e.category(), "{}", e);
+                }
+            }
+        }
+
+        self.engine.flush_audit_log()?;
+        self.wal.sync()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{audit::AuditLog, primitives::Tx, transactions::TxType};
+    use rust_decimal::Decimal;
+    use tempfile::tempdir;
+
+    fn funds(amount: f32) -> Decimal {
+        Decimal::from_f32_retain(amount).unwrap()
+    }
+
+    fn transaction(variant: TxType, client: Client, tx: Tx, amount: f32) -> Transaction {
+        Transaction {
+            variant,
+            client,
+            tx,
+            amount: Some(funds(amount)),
+            to: None,
+            batch_id: None,
+            timestamp: None,
+        }
+    }
+
+    fn rows(transactions: &[Transaction]) -> Vec<Result<Transaction, csv::Error>> {
+        transactions.iter().cloned().map(Ok).collect()
+    }
+
+    fn input() -> Vec<Transaction> {
+        vec![
+            transaction(TxType::Deposit, 1, 1, 10.0),
+            // Rejected, insufficient funds.
+            transaction(TxType::Withdrawal, 2, 2, 5.0),
+            transaction(TxType::Withdrawal, 1, 3, 4.0),
+            transaction(TxType::Deposit, 2, 4, 1.0),
+        ]
+    }
+
+    #[test]
+    fn test_wal_logs_only_accepted_transactions() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("wal.log");
+
+        let mut engine = Engine::default();
+        let mut accounts = Accounts::new();
+        let mut wal = Wal::open(&path, 2).unwrap();
+        WalProcessor::new(&mut engine, &mut wal, 0)
+            .process_transactions(rows(&input()), &mut accounts)
+            .unwrap();
+
+        let content = fs::read_to_string(&path).unwrap();
+        let entries: Vec<WalEntry> = content
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        let positions: Vec<(u64, u64)> = entries
+            .iter()
+            .map(|e| match e.operation {
+                WalOperation::Transaction { row, .. } => (e.seq, row),
+                WalOperation::Unlock { .. } => panic!("no unlock was logged"),
+            })
+            .collect();
+        assert_eq!(positions, vec![(1, 1), (2, 3), (3, 4)]);
+    }
+
+    #[test]
+    fn test_recovery_audits_the_rows_the_interrupted_run_did_not() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("wal.log");
+        let audit_path = dir.path().join("audit.log");
+
+        // First run dies after the first three rows.
+        {
+            let mut engine = Engine::default();
+            engine.set_audit_log(AuditLog::open(&audit_path).unwrap());
+            let mut accounts = Accounts::new();
+            let mut wal = Wal::open(&path, 1).unwrap();
+            WalProcessor::new(&mut engine, &mut wal, 0)
+                .process_transactions(rows(&input()[..3]), &mut accounts)
+                .unwrap();
+        }
+
+        // Only the first entry made it to the audit log, and part of the second.
+        let content = fs::read(&audit_path).unwrap();
+        let first_len = content.iter().position(|b| *b == b'\n').unwrap() + 1;
+        fs::write(&audit_path, &content[..first_len + 10]).unwrap();
+
+        let mut engine = Engine::default();
+        engine.set_audit_log(AuditLog::open(&audit_path).unwrap());
+        let mut accounts = Accounts::new();
+        let recovery = recover(&path, &mut engine, &mut accounts).unwrap();
+        let mut wal = Wal::open(&path, 1).unwrap();
+        WalProcessor::new(&mut engine, &mut wal, recovery.resume_after)
+            .process_transactions(rows(&input()), &mut accounts)
+            .unwrap();
+
+        let history: Vec<(u64, Option<Tx>)> = crate::audit::history(&audit_path, 1)
+            .unwrap()
+            .into_iter()
+            .map(|entry| (entry.seq, entry.tx))
+            .collect();
+        assert_eq!(history, vec![(1, Some(1)), (2, Some(3))]);
+        let history = crate::audit::history(&audit_path, 2).unwrap();
+        assert_eq!(history.len(), 1);
+        assert_eq!((history[0].seq, history[0].tx), (3, Some(4)));
+    }
+
+    #[test]
+    fn test_recovery_after_crash_applies_each_row_once() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("wal.log");
+
+        // First run dies after the first three rows.
+        {
+            let mut engine = Engine::default();
+            let mut accounts = Accounts::new();
+            let mut wal = Wal::open(&path, 1).unwrap();
+            WalProcessor::new(&mut engine, &mut wal, 0)
+                .process_transactions(rows(&input()[..3]), &mut accounts)
+                .unwrap();
+        }
+
+        // Second run recovers and processes the whole file again.
+        let mut engine = Engine::default();
+        let mut accounts = Accounts::new();
+        let recovery = recover(&path, &mut engine, &mut accounts).unwrap();
+        assert_eq!(
+            recovery,
+            Recovery {
+                replayed: 2,
+                resume_after: 3,
+                unlocked: vec![],
+            }
+        );
+
+        let mut wal = Wal::open(&path, 1).unwrap();
+        WalProcessor::new(&mut engine, &mut wal, recovery.resume_after)
+            .process_transactions(rows(&input()), &mut accounts)
+            .unwrap();
+
+        assert_eq!(engine.sequence(), 3);
+        assert_eq!(accounts.get_mut(1).snapshot().available, funds(6.0));
+        assert_eq!(accounts.get_mut(2).snapshot().available, funds(1.0));
+    }
+
+    #[test]
+    fn test_batches_are_logged_and_replayed_whole() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("wal.log");
+        let leg = |variant, client, tx, amount, batch_id| Transaction {
+            batch_id: Some(batch_id),
+            ..transaction(variant, client, tx, amount)
+        };
+        let input = vec![
+            transaction(TxType::Deposit, 1, 1, 10.0),
+            leg(TxType::Withdrawal, 1, 2, 4.0, 7),
+            leg(TxType::Deposit, 2, 3, 4.0, 7),
+            // Rejected as a whole, the second leg has insufficient funds.
+            leg(TxType::Deposit, 3, 4, 1.0, 8),
+            leg(TxType::Withdrawal, 2, 5, 9.0, 8),
+        ];
+
+        {
+            let mut engine = Engine::default();
+            let mut accounts = Accounts::new();
+            let mut wal = Wal::open(&path, 1).unwrap();
+            WalProcessor::new(&mut engine, &mut wal, 0)
+                .process_transactions(rows(&input), &mut accounts)
+                .unwrap();
+        }
+
+        let mut engine = Engine::default();
+        let mut accounts = Accounts::new();
+        let recovery = recover(&path, &mut engine, &mut accounts).unwrap();
+        assert_eq!(
+            recovery,
+            Recovery {
+                replayed: 3,
+                resume_after: 3,
+                unlocked: vec![],
+            }
+        );
+        assert_eq!(accounts.get_mut(1).snapshot().available, funds(6.0));
+        assert_eq!(accounts.get_mut(2).snapshot().available, funds(4.0));
+        assert_eq!(accounts.get_mut(3).snapshot().available, Decimal::ZERO);
+    }
+
+    #[test]
+    fn test_recovery_skips_entries_already_in_snapshot() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("wal.log");
+
+        let mut engine = Engine::default();
+        let mut accounts = Accounts::new();
+        let mut wal = Wal::open(&path, 1).unwrap();
+        WalProcessor::new(&mut engine, &mut wal, 0)
+            .process_transactions(rows(&input()), &mut accounts)
+            .unwrap();
+
+        let recovery = recover(&path, &mut engine, &mut accounts).unwrap();
+        assert_eq!(recovery.replayed, 0);
+        assert_eq!(recovery.resume_after, 0);
+        assert_eq!(accounts.get_mut(1).snapshot().available, funds(6.0));
+    }
+
+    #[test]
+    fn test_crash_between_snapshot_and_truncate_skips_no_rows() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("wal.log");
+        let snapshot = dir.path().join("state.json");
+
+        // First run saves its snapshot, then dies before truncating the log.
+        {
+            let mut engine = Engine::default();
+            let mut accounts = Accounts::new();
+            let mut wal = Wal::open(&path, 1).unwrap();
+            WalProcessor::new(&mut engine, &mut wal, 0)
+                .process_transactions(rows(&input()), &mut accounts)
+                .unwrap();
+            crate::snapshot::save(&snapshot, &mut engine, &accounts).unwrap();
+        }
+
+        // Second run, on another file, must apply all of its rows.
+        let (mut engine, mut accounts) =
+            crate::snapshot::load(&snapshot, &Default::default()).unwrap();
+        let recovery = recover(&path, &mut engine, &mut accounts).unwrap();
+        assert_eq!(
+            recovery,
+            Recovery {
+                replayed: 0,
+                resume_after: 0,
+                unlocked: vec![],
+            }
+        );
+
+        let next = [
+            transaction(TxType::Deposit, 3, 10, 2.0),
+            transaction(TxType::Deposit, 3, 11, 3.0),
+        ];
+        let mut wal = Wal::open(&path, 1).unwrap();
+        WalProcessor::new(&mut engine, &mut wal, recovery.resume_after)
+            .process_transactions(rows(&next), &mut accounts)
+            .unwrap();
+        assert_eq!(accounts.get_mut(3).snapshot().available, funds(5.0));
+    }
+
+    #[test]
+    fn test_recovery_unlocks_accounts_at_the_same_point() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("wal.log");
+        let refer = |variant| Transaction {
+            amount: None,
+            ..transaction(variant, 1, 1, 0.0)
+        };
+        let input = [
+            transaction(TxType::Deposit, 1, 1, 10.0),
+            refer(TxType::Dispute),
+            refer(TxType::Chargeback),
+            transaction(TxType::Deposit, 1, 2, 3.0),
+        ];
+
+        // First run: the account is locked by a chargeback and unlocked between two files.
+        {
+            let mut engine = Engine::default();
+            let mut accounts = Accounts::new();
+            let mut wal = Wal::open(&path, 10).unwrap();
+            let mut processor = WalProcessor::new(&mut engine, &mut wal, 0);
+            processor
+                .process_transactions(rows(&input[..3]), &mut accounts)
+                .unwrap();
+            processor.unlock(&mut accounts, 1).unwrap();
+            processor
+                .process_transactions(rows(&input[3..]), &mut accounts)
+                .unwrap();
+        }
+
+        let mut engine = Engine::default();
+        let mut accounts = Accounts::new();
+        let recovery = recover(&path, &mut engine, &mut accounts).unwrap();
+        assert_eq!(recovery.unlocked, vec![1]);
+        assert_eq!(recovery.replayed, 5);
+        let account = accounts.get_mut(1).snapshot();
+        assert!(!account.locked);
+        assert_eq!(account.available, funds(3.0));
+    }
+
+    #[test]
+    fn test_recovery_discards_partially_written_entry() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("wal.log");
+
+        {
+            let mut engine = Engine::default();
+            let mut accounts = Accounts::new();
+            let mut wal = Wal::open(&path, 1).unwrap();
+            WalProcessor::new(&mut engine, &mut wal, 0)
+                .process_transactions(rows(&input()[..1]), &mut accounts)
+                .unwrap();
+        }
+        let mut file = fs::OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(br#"{"seq":2,"row":3,"transa"#).unwrap();
+
+        let mut engine = Engine::default();
+        let mut accounts = Accounts::new();
+        let recovery = recover(&path, &mut engine, &mut accounts).unwrap();
+
+        assert_eq!(recovery.resume_after, 1);
+        assert!(fs::read_to_string(&path).unwrap().ends_with('\n'));
+    }
+
+    #[test]
+    fn test_recovery_rejects_gaps() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("wal.log");
+        let entry = WalEntry {
+            seq: 5,
+            operation: WalOperation::Transaction {
+                row: 1,
+                transaction: transaction(TxType::Deposit, 1, 1, 1.0),
+            },
+        };
+        fs::write(
+            &path,
+            format!("{}\n", serde_json::to_string(&entry).unwrap()),
+        )
+        .unwrap();
+
+        let result = recover(&path, &mut Engine::default(), &mut Accounts::new());
+        assert!(matches!(result, Err(Error::Wal(WalError::Gap(1, 5)))));
+    }
+
+    #[test]
+    fn test_truncate_empties_the_log() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("wal.log");
+
+        let mut engine = Engine::default();
+        let mut accounts = Accounts::new();
+        let mut wal = Wal::open(&path, 10).unwrap();
+        WalProcessor::new(&mut engine, &mut wal, 0)
+            .process_transactions(rows(&input()), &mut accounts)
+            .unwrap();
+        wal.truncate().unwrap();
+
+        assert!(fs::read(&path).unwrap().is_empty());
+    }
+}